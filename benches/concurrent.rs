@@ -0,0 +1,101 @@
+//! Compares concurrent increments and snapshots of a shared counter replica.
+//!
+//! Run with `cargo bench --bench concurrent`.
+
+extern crate crdt;
+
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crdt::Crdt;
+use crdt::concurrent::{AtomicGCounter, AtomicPnCounter, ShardedGCounter};
+use crdt::counter::{GCounter, PnCounter};
+
+const INCREMENTS: u64 = 1_000_000;
+const SNAPSHOTS: u64 = 1_000;
+
+/// Runs `increment` `INCREMENTS` times on each of `threads` threads, and
+/// returns the elapsed time.
+fn run<T, F>(threads: usize, counter: Arc<T>, increment: F) -> Duration
+where T: Send + Sync + 'static, F: Fn(&T) + Send + Sync + Copy + 'static {
+    let start = Instant::now();
+    let handles: Vec<_> = (0..threads).map(|_| {
+        let counter = counter.clone();
+        thread::spawn(move || for _ in 0..INCREMENTS { increment(&counter); })
+    }).collect();
+    for handle in handles {
+        handle.join().unwrap();
+    }
+    start.elapsed()
+}
+
+fn report(name: &str, operation: &str, elapsed: Duration, count: u64) {
+    let nanos = elapsed.as_secs() as f64 * 1e9 + elapsed.subsec_nanos() as f64;
+    println!("{:<18} {:<22} {:>10.2} ns/op", name, operation, nanos / count as f64);
+}
+
+fn main() {
+    for &threads in &[1, 2, 4, 8] {
+        let operation = format!("increment, {} threads", threads);
+        let count = threads as u64 * INCREMENTS;
+        report("Mutex<GCounter>", &operation,
+               run(threads, Arc::new(Mutex::new(GCounter::new(1))), |c| { c.lock().unwrap().increment(1); }), count);
+        report("AtomicGCounter", &operation,
+               run(threads, Arc::new(AtomicGCounter::new(1)), |c| c.increment(1)), count);
+        report("ShardedGCounter", &operation,
+               run(threads, Arc::new(ShardedGCounter::new(1)), |c| c.increment(1)), count);
+        report("Mutex<PnCounter>", &operation,
+               run(threads, Arc::new(Mutex::new(PnCounter::new(1))), |c| { c.lock().unwrap().increment(-1); }), count);
+        report("AtomicPnCounter", &operation,
+               run(threads, Arc::new(AtomicPnCounter::new(1)), |c| c.increment(-1)), count);
+    }
+
+    // Snapshots of a counter with many remote replicas, taken while another
+    // thread increments it.
+    let mut remote = GCounter::new(2);
+    for id in 3..1000u64 {
+        let mut replica = GCounter::new(id);
+        replica.increment(id);
+        remote.merge(replica);
+    }
+    let mutex = Arc::new(Mutex::new(GCounter::new(1)));
+    mutex.lock().unwrap().merge(remote.clone());
+    let atomic = Arc::new(AtomicGCounter::new(1));
+    atomic.merge_from(&remote);
+
+    let writer = {
+        let mutex = mutex.clone();
+        thread::spawn(move || for _ in 0..INCREMENTS { mutex.lock().unwrap().increment(1); })
+    };
+    let start = Instant::now();
+    for _ in 0..SNAPSHOTS {
+        let _ = mutex.lock().unwrap().clone();
+    }
+    report("Mutex<GCounter>", "snapshot", start.elapsed(), SNAPSHOTS);
+    writer.join().unwrap();
+
+    let writer = {
+        let atomic = atomic.clone();
+        thread::spawn(move || for _ in 0..INCREMENTS { atomic.increment(1); })
+    };
+    let start = Instant::now();
+    for _ in 0..SNAPSHOTS {
+        atomic.snapshot();
+    }
+    report("AtomicGCounter", "snapshot", start.elapsed(), SNAPSHOTS);
+    writer.join().unwrap();
+
+    let sharded = Arc::new(ShardedGCounter::new(1));
+    sharded.merge_from(&remote);
+    let writer = {
+        let sharded = sharded.clone();
+        thread::spawn(move || for _ in 0..INCREMENTS { sharded.increment(1); })
+    };
+    let start = Instant::now();
+    for _ in 0..SNAPSHOTS {
+        sharded.snapshot();
+    }
+    report("ShardedGCounter", "snapshot", start.elapsed(), SNAPSHOTS);
+    writer.join().unwrap();
+}
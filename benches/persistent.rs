@@ -0,0 +1,95 @@
+//! Compares clones and merges of sets backed by `HashMap` and by
+//! `HashTrieMap`.
+//!
+//! Run with `cargo bench --bench persistent --features persistent`.
+
+extern crate crdt;
+
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+use crdt::{Crdt, TransactionId};
+use crdt::hamt::HashTrieMap;
+use crdt::set::{LwwSet, PnSet};
+
+const ELEMENTS: u64 = 100_000;
+const ITERATIONS: u64 = 100;
+
+fn report(name: &str, operation: &str, elapsed: Duration, count: u64) {
+    let nanos = elapsed.as_secs() as f64 * 1e9 + elapsed.subsec_nanos() as f64;
+    println!("{:<18} {:<30} {:>14.2} ns/op", name, operation, nanos / count as f64);
+}
+
+/// Runs `f` `ITERATIONS` times, and returns the elapsed time.
+fn time<F>(mut f: F) -> Duration where F: FnMut() {
+    let start = Instant::now();
+    for _ in 0..ITERATIONS {
+        f();
+    }
+    start.elapsed()
+}
+
+/// Times clones and merges of an `LwwSet` and a `PnSet` created by the
+/// provided constructors.
+macro_rules! compare_sets {
+    ($backing:expr, $lwwset:expr, $pnset:expr) => {{
+        let backing = $backing;
+        let mut lwwset = $lwwset;
+        let mut pnset = $pnset(1);
+        for element in 0..ELEMENTS {
+            lwwset.insert(element, TransactionId::from(element));
+            pnset.insert(element);
+        }
+
+        report(backing, "LwwSet clone", time(|| { let _ = lwwset.clone(); }), ITERATIONS);
+        report(backing, "PnSet clone", time(|| { let _ = pnset.clone(); }), ITERATIONS);
+
+        // A snapshot is taken before each small update, as a reader
+        // publishing views of a replica would.
+        let mut snapshots = Vec::with_capacity(ITERATIONS as usize);
+        let mut tid = ELEMENTS;
+        let elapsed = time(|| {
+            snapshots.push(lwwset.clone());
+            tid += 1;
+            lwwset.insert(tid % ELEMENTS, TransactionId::from(tid));
+        });
+        report(backing, "LwwSet snapshot and insert", elapsed, ITERATIONS);
+        snapshots.clear();
+
+        // Merges of a replica which differs from the local replica in a few
+        // elements.
+        let mut remote = lwwset.clone();
+        for element in 0..10 {
+            remote.remove(element, TransactionId::from(tid + 1 + element));
+        }
+        let elapsed = time(|| {
+            let mut local = lwwset.clone();
+            local.merge(remote.clone());
+        });
+        report(backing, "LwwSet clone and merge", elapsed, ITERATIONS);
+
+        let mut remote = $pnset(2);
+        for element in 0..10 {
+            remote.insert(element);
+        }
+        let elapsed = time(|| {
+            let mut local = pnset.clone();
+            local.merge(remote.clone());
+        });
+        report(backing, "PnSet clone and merge", elapsed, ITERATIONS);
+    }};
+}
+
+fn main() {
+    compare_sets!("HashMap", LwwSet::new(), PnSet::new);
+    compare_sets!("HashTrieMap", LwwSet::persistent(), PnSet::persistent);
+
+    // The maps themselves.
+    let map: HashMap<u64, u64> = (0..ELEMENTS).map(|i| (i, i)).collect();
+    report("HashMap", "clone", time(|| { let _ = map.clone(); }), ITERATIONS);
+    report("HashMap", "lookup", time(|| for i in 0..ELEMENTS { map.get(&i).unwrap(); }), ITERATIONS * ELEMENTS);
+
+    let map: HashTrieMap<u64, u64> = (0..ELEMENTS).map(|i| (i, i)).collect();
+    report("HashTrieMap", "clone", time(|| { let _ = map.clone(); }), ITERATIONS);
+    report("HashTrieMap", "lookup", time(|| for i in 0..ELEMENTS { map.get(&i).unwrap(); }), ITERATIONS * ELEMENTS);
+}
@@ -0,0 +1,323 @@
+use std::cmp;
+
+use {ReplicaId, TransactionId};
+use clock::{Error, SystemClock, TimeSource};
+
+/// The number of bits of an ID holding the replica ID.
+const REPLICA_BITS: u32 = 10;
+/// The number of bits of an ID holding the logical counter.
+const LOGICAL_BITS: u32 = 10;
+/// The number of bits of an ID holding the physical time.
+const PHYSICAL_BITS: u32 = 64 - LOGICAL_BITS - REPLICA_BITS;
+
+const MAX_REPLICA_ID: u64 = (1 << REPLICA_BITS) - 1;
+const MAX_LOGICAL: u64 = (1 << LOGICAL_BITS) - 1;
+const MAX_PHYSICAL: u64 = (1 << PHYSICAL_BITS) - 1;
+
+/// The default maximum drift, in milliseconds.
+pub const DEFAULT_MAX_DRIFT: u64 = 1000;
+
+/// A hybrid logical clock which generates transaction IDs.
+///
+/// Each ID holds, from most to least significant bits, a 44 bit physical time
+/// in milliseconds since the Unix epoch, a 10 bit logical counter, and the 10
+/// bit replica ID, so replica IDs must be less than 1024. IDs generated by a
+/// clock are strictly increasing, and IDs generated by clocks with different
+/// replica IDs are unique.
+///
+/// The physical component of the clock is the greatest physical time seen,
+/// either locally or in a remote ID. The logical counter orders IDs generated
+/// within the same millisecond; when it is exhausted the physical component
+/// moves ahead of physical time. The clock refuses to move further ahead of
+/// the local physical time than the maximum drift, which bounds the damage
+/// done by a remote replica with a fast clock.
+///
+/// ##### Example
+///
+/// ```
+/// use crdt::clock::{Hlc, ManualClock};
+///
+/// let time = ManualClock::new(1000);
+/// let mut local = Hlc::with_time_source(1, time.clone()).unwrap();
+/// let mut remote = Hlc::with_time_source(2, time.clone()).unwrap();
+///
+/// let first = local.now().unwrap();
+/// let second = local.now().unwrap();
+/// assert!(first < second);
+///
+/// remote.observe(second).unwrap();
+/// assert!(remote.now().unwrap() > second);
+/// ```
+#[derive(Clone, Debug)]
+pub struct Hlc<S = SystemClock> {
+    replica_id: ReplicaId,
+    time_source: S,
+    max_drift: u64,
+    physical: u64,
+    logical: u64,
+}
+
+/// The components of an ID generated by an `Hlc`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct HlcTimestamp {
+    /// The physical time, in milliseconds since the Unix epoch.
+    pub physical: u64,
+    /// The logical counter.
+    pub logical: u64,
+    /// The ID of the replica which generated the ID.
+    pub replica_id: u64,
+}
+
+impl HlcTimestamp {
+
+    /// Returns the components of an ID generated by an `Hlc`.
+    pub fn from_id(id: TransactionId) -> HlcTimestamp {
+        let id = id.id();
+        HlcTimestamp {
+            physical: id >> (LOGICAL_BITS + REPLICA_BITS),
+            logical: (id >> REPLICA_BITS) & MAX_LOGICAL,
+            replica_id: id & MAX_REPLICA_ID,
+        }
+    }
+
+    fn to_id(&self) -> TransactionId {
+        TransactionId::from(self.physical << (LOGICAL_BITS + REPLICA_BITS)
+                            | self.logical << REPLICA_BITS
+                            | self.replica_id)
+    }
+}
+
+impl Hlc<SystemClock> {
+
+    /// Create a new hybrid logical clock reading the system time.
+    pub fn new<R>(replica_id: R) -> Result<Hlc<SystemClock>, Error> where R: Into<ReplicaId> {
+        Hlc::with_time_source(replica_id, SystemClock)
+    }
+}
+
+impl <S> Hlc<S> where S: TimeSource {
+
+    /// Create a new hybrid logical clock reading physical time from
+    /// `time_source`.
+    ///
+    /// Returns an error if the replica ID is not less than 1024.
+    pub fn with_time_source<R>(replica_id: R, time_source: S) -> Result<Hlc<S>, Error>
+    where R: Into<ReplicaId> {
+        let replica_id = replica_id.into();
+        if replica_id.id() > MAX_REPLICA_ID {
+            return Err(Error::ReplicaIdOutOfRange(replica_id));
+        }
+        Ok(Hlc {
+            replica_id: replica_id,
+            time_source: time_source,
+            max_drift: DEFAULT_MAX_DRIFT,
+            physical: 0,
+            logical: 0,
+        })
+    }
+
+    /// Set the maximum number of milliseconds the clock may run ahead of
+    /// physical time.
+    pub fn set_max_drift(&mut self, max_drift: u64) {
+        self.max_drift = max_drift;
+    }
+
+    /// Returns the replica ID of the clock.
+    pub fn replica_id(&self) -> ReplicaId {
+        self.replica_id
+    }
+
+    /// Generate a new transaction ID.
+    ///
+    /// Returns an error if the logical counter is exhausted, and moving the
+    /// clock to the next millisecond would put it ahead of physical time by
+    /// more than the maximum drift.
+    pub fn now(&mut self) -> Result<TransactionId, Error> {
+        let time = self.physical_time();
+        let (physical, logical) = if time > self.physical {
+            (time, 0)
+        } else if self.logical < MAX_LOGICAL {
+            (self.physical, self.logical + 1)
+        } else {
+            try!(self.check_drift(self.physical + 1, time));
+            (self.physical + 1, 0)
+        };
+        self.physical = physical;
+        self.logical = logical;
+        Ok(HlcTimestamp {
+            physical: physical,
+            logical: logical,
+            replica_id: self.replica_id.id(),
+        }.to_id())
+    }
+
+    /// Update the clock on receipt of a transaction ID generated by a remote
+    /// clock, so that IDs generated afterwards are greater than it.
+    ///
+    /// Returns an error, leaving the clock unchanged, if the remote ID is
+    /// ahead of physical time by more than the maximum drift.
+    pub fn observe(&mut self, id: TransactionId) -> Result<(), Error> {
+        let remote = HlcTimestamp::from_id(id);
+        let time = self.physical_time();
+        try!(self.check_drift(remote.physical, time));
+        if (remote.physical, remote.logical) > (self.physical, self.logical) {
+            self.physical = remote.physical;
+            self.logical = remote.logical;
+        }
+        Ok(())
+    }
+
+    fn physical_time(&self) -> u64 {
+        cmp::min(self.time_source.now_millis(), MAX_PHYSICAL)
+    }
+
+    fn check_drift(&self, physical: u64, time: u64) -> Result<(), Error> {
+        let drift = physical.saturating_sub(time);
+        if drift > self.max_drift {
+            Err(Error::DriftExceeded { drift: drift, max_drift: self.max_drift })
+        } else {
+            Ok(())
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+
+    use std::cmp;
+
+    use quickcheck::{Arbitrary, Gen};
+
+    use TransactionId;
+    use clock::{Error, ManualClock, TimeSource};
+    use super::{Hlc, HlcTimestamp, DEFAULT_MAX_DRIFT, MAX_LOGICAL};
+
+    /// An event at a clock.
+    #[derive(Clone, Debug)]
+    enum Event {
+        /// Physical time moves by the given number of milliseconds.
+        Tick(i8),
+        /// The local clock generates an ID.
+        Now,
+        /// The local clock receives the next ID generated by the remote clock.
+        Observe,
+    }
+
+    impl Arbitrary for Event {
+        fn arbitrary<G: Gen>(g: &mut G) -> Event {
+            match u8::arbitrary(g) % 3 {
+                0 => Event::Tick(Arbitrary::arbitrary(g)),
+                1 => Event::Now,
+                _ => Event::Observe,
+            }
+        }
+    }
+
+    #[quickcheck]
+    fn check_ids_are_monotonic(events: Vec<Event>) -> bool {
+        let local_time = ManualClock::new(1_000_000);
+        let remote_time = ManualClock::new(1_000_500);
+        let mut local = Hlc::with_time_source(1, local_time.clone()).unwrap();
+        let mut remote = Hlc::with_time_source(2, remote_time.clone()).unwrap();
+
+        let mut last = TransactionId::from(0);
+        for event in events {
+            match event {
+                Event::Tick(millis) => {
+                    local_time.set((local_time.now_millis() as i64 + millis as i64) as u64);
+                    remote_time.advance(millis.abs() as u64);
+                },
+                Event::Now => {
+                    let id = local.now().unwrap();
+                    if id <= last {
+                        return false;
+                    }
+                    last = id;
+                },
+                Event::Observe => {
+                    let id = remote.now().unwrap();
+                    if local.observe(id).is_ok() {
+                        last = cmp::max(last, id);
+                    }
+                },
+            }
+        }
+        true
+    }
+
+    #[test]
+    fn check_timestamp() {
+        let time = ManualClock::new(1_500_000_000_000);
+        let mut hlc = Hlc::with_time_source(1023, time.clone()).unwrap();
+        hlc.now().unwrap();
+        let id = hlc.now().unwrap();
+        assert_eq!(HlcTimestamp { physical: 1_500_000_000_000, logical: 1, replica_id: 1023 },
+                   HlcTimestamp::from_id(id));
+        assert_eq!(id, HlcTimestamp::from_id(id).to_id());
+
+        time.advance(1);
+        assert_eq!(HlcTimestamp { physical: 1_500_000_000_001, logical: 0, replica_id: 1023 },
+                   HlcTimestamp::from_id(hlc.now().unwrap()));
+    }
+
+    #[test]
+    fn check_replica_ids_are_unique() {
+        let time = ManualClock::new(1000);
+        let mut a = Hlc::with_time_source(1, time.clone()).unwrap();
+        let mut b = Hlc::with_time_source(2, time.clone()).unwrap();
+        for _ in 0..10 {
+            assert!(a.now().unwrap() != b.now().unwrap());
+        }
+        assert_eq!(Err(Error::ReplicaIdOutOfRange(1024.into())),
+                   Hlc::with_time_source(1024, time).map(|_| ()));
+    }
+
+    #[test]
+    fn check_clock_moving_backwards() {
+        let time = ManualClock::new(1000);
+        let mut hlc = Hlc::with_time_source(1, time.clone()).unwrap();
+        let before = hlc.now().unwrap();
+        time.set(10);
+        let after = hlc.now().unwrap();
+        assert!(after > before);
+        assert_eq!(1000, HlcTimestamp::from_id(after).physical);
+    }
+
+    #[test]
+    fn check_max_drift() {
+        let time = ManualClock::new(1000);
+        let mut hlc = Hlc::with_time_source(1, time.clone()).unwrap();
+        let mut fast = Hlc::with_time_source(2, move || 1000 + DEFAULT_MAX_DRIFT + 1).unwrap();
+
+        let before = hlc.now().unwrap();
+        assert_eq!(Err(Error::DriftExceeded { drift: DEFAULT_MAX_DRIFT + 1, max_drift: DEFAULT_MAX_DRIFT }),
+                   hlc.observe(fast.now().unwrap()));
+        // The clock is unchanged by the rejected ID.
+        assert_eq!(HlcTimestamp::from_id(before).logical + 1,
+                   HlcTimestamp::from_id(hlc.now().unwrap()).logical);
+
+        hlc.set_max_drift(DEFAULT_MAX_DRIFT + 1);
+        let remote = fast.now().unwrap();
+        assert_eq!(Ok(()), hlc.observe(remote));
+        assert!(hlc.now().unwrap() > remote);
+    }
+
+    #[test]
+    fn check_logical_overflow() {
+        let time = ManualClock::new(1000);
+        let mut hlc = Hlc::with_time_source(1, time.clone()).unwrap();
+        hlc.set_max_drift(2);
+
+        let mut last = TransactionId::from(0);
+        for _ in 0..(MAX_LOGICAL + 1) * 3 {
+            let id = hlc.now().unwrap();
+            assert!(id > last);
+            last = id;
+        }
+        assert_eq!(1002, HlcTimestamp::from_id(last).physical);
+        assert_eq!(Err(Error::DriftExceeded { drift: 3, max_drift: 2 }), hlc.now());
+
+        time.advance(1);
+        assert!(hlc.now().unwrap() > last);
+    }
+}
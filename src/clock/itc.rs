@@ -0,0 +1,808 @@
+use std::cmp::{self, Ordering};
+#[cfg(feature = "serde")]
+use std::convert::TryFrom;
+use std::fmt::{self, Debug, Formatter};
+
+#[cfg(any(feature = "quickcheck", test))]
+use quickcheck::{Arbitrary, Gen};
+
+use clock::Error;
+use wire::{Decode, DecodeError, Encode, Message, Reader};
+
+/// The maximum depth of a decoded identity or event tree.
+const MAX_DEPTH: usize = 256;
+
+/// The cost of expanding an event tree leaf when growing an event tree. It
+/// is large enough that growing never expands a leaf when an existing node
+/// can be incremented instead.
+const EXPAND_COST: u64 = 1 << 32;
+
+/// The identity of an interval tree clock.
+///
+/// An identity owns a subset of the unit interval. Forking an identity splits
+/// it into two disjoint identities, and joining two identities takes their
+/// union, so identities of live replicas never overlap without any
+/// coordination among replicas.
+#[derive(Clone, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[cfg_attr(feature = "serde", serde(try_from = "IdTree"))]
+pub struct Id(IdTree);
+
+#[derive(Clone, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+enum IdTree {
+    Zero,
+    One,
+    Node(Box<IdTree>, Box<IdTree>),
+}
+
+/// A normalized event tree: every node has a child with a base of zero, and
+/// no node has two equal leaves as children.
+#[derive(Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+enum EventTree {
+    Leaf(u64),
+    Node(u64, Box<EventTree>, Box<EventTree>),
+}
+
+/// An interval tree clock stamp.
+///
+/// Interval tree clocks track causality like version vectors, but without
+/// requiring globally unique replica IDs. A stamp is made up of an identity
+/// and an event history. New replicas are created by forking the stamp of an
+/// existing replica, and replicas are retired by joining their stamp into
+/// another replica's stamp, so replicas can come and go without coordination.
+///
+/// See [_Interval Tree Clocks_](http://gsd.di.uminho.pt/members/cbm/ps/itc2008.pdf)
+/// (Almeida, et al.).
+///
+/// ##### Example
+///
+/// ```
+/// use std::cmp::Ordering;
+/// use crdt::clock::Stamp;
+///
+/// let mut a = Stamp::seed();
+/// let mut b = a.fork();
+///
+/// a.event().unwrap();
+/// assert_eq!(Some(Ordering::Greater), a.causal_cmp(&b));
+///
+/// b.event().unwrap();
+/// assert_eq!(None, a.causal_cmp(&b));
+///
+/// // Receive the events of `a` without taking over its identity.
+/// b.join(a.peek());
+/// assert_eq!(Some(Ordering::Greater), b.causal_cmp(&a));
+///
+/// // Retire `a`.
+/// b.join(a);
+/// assert_eq!(Stamp::seed().id(), b.id());
+/// ```
+#[derive(Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[cfg_attr(feature = "serde", serde(try_from = "StampFields"))]
+pub struct Stamp {
+    id: Id,
+    event: EventTree,
+}
+
+/// The fields of a deserialized stamp, before its event tree is checked.
+#[cfg(feature = "serde")]
+#[derive(Deserialize)]
+struct StampFields {
+    id: Id,
+    event: EventTree,
+}
+
+impl Id {
+
+    /// Returns the identity which owns the whole interval.
+    pub fn seed() -> Id {
+        Id(IdTree::One)
+    }
+
+    /// Returns the anonymous identity, which owns nothing.
+    pub fn anonymous() -> Id {
+        Id(IdTree::Zero)
+    }
+
+    /// Returns true if the identity owns nothing.
+    pub fn is_anonymous(&self) -> bool {
+        self.0 == IdTree::Zero
+    }
+
+    /// Splits the identity in two, keeping one half and returning the other.
+    pub fn fork(&mut self) -> Id {
+        let (left, right) = self.0.split();
+        self.0 = left;
+        Id(right)
+    }
+
+    /// Takes over the interval owned by `other`.
+    ///
+    /// The identities **must** be disjoint, which is the case for identities
+    /// created by forking.
+    pub fn join(&mut self, other: Id) {
+        let id = ::std::mem::replace(&mut self.0, IdTree::Zero);
+        self.0 = id.sum(other.0);
+    }
+
+    /// Returns true if this identity owns every part of the interval owned by
+    /// `other`.
+    pub fn contains(&self, other: &Id) -> bool {
+        self.0.contains(&other.0)
+    }
+}
+
+impl Debug for Id {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+impl IdTree {
+
+    /// Creates a normalized node.
+    fn node(left: IdTree, right: IdTree) -> IdTree {
+        match (left, right) {
+            (IdTree::Zero, IdTree::Zero) => IdTree::Zero,
+            (IdTree::One, IdTree::One) => IdTree::One,
+            (left, right) => IdTree::Node(Box::new(left), Box::new(right)),
+        }
+    }
+
+    /// Returns true if a node with these children is normalized.
+    fn is_normalized_node(left: &IdTree, right: &IdTree) -> bool {
+        match (left, right) {
+            (&IdTree::Zero, &IdTree::Zero) | (&IdTree::One, &IdTree::One) => false,
+            _ => true,
+        }
+    }
+
+    #[cfg(feature = "serde")]
+    fn is_normalized(&self) -> bool {
+        match *self {
+            IdTree::Node(ref left, ref right) => {
+                IdTree::is_normalized_node(left, right) && left.is_normalized() && right.is_normalized()
+            },
+            _ => true,
+        }
+    }
+
+    fn split(&self) -> (IdTree, IdTree) {
+        match *self {
+            IdTree::Zero => (IdTree::Zero, IdTree::Zero),
+            IdTree::One => (IdTree::node(IdTree::One, IdTree::Zero), IdTree::node(IdTree::Zero, IdTree::One)),
+            IdTree::Node(ref left, ref right) => {
+                if **left == IdTree::Zero {
+                    let (right1, right2) = right.split();
+                    (IdTree::node(IdTree::Zero, right1), IdTree::node(IdTree::Zero, right2))
+                } else if **right == IdTree::Zero {
+                    let (left1, left2) = left.split();
+                    (IdTree::node(left1, IdTree::Zero), IdTree::node(left2, IdTree::Zero))
+                } else {
+                    (IdTree::node((**left).clone(), IdTree::Zero), IdTree::node(IdTree::Zero, (**right).clone()))
+                }
+            },
+        }
+    }
+
+    fn contains(&self, other: &IdTree) -> bool {
+        match (self, other) {
+            (_, &IdTree::Zero) | (&IdTree::One, _) => true,
+            (&IdTree::Zero, _) | (&IdTree::Node(..), &IdTree::One) => false,
+            (&IdTree::Node(ref left1, ref right1), &IdTree::Node(ref left2, ref right2)) => {
+                left1.contains(left2) && right1.contains(right2)
+            },
+        }
+    }
+
+    fn sum(self, other: IdTree) -> IdTree {
+        match (self, other) {
+            (IdTree::Zero, id) | (id, IdTree::Zero) => id,
+            (IdTree::One, _) | (_, IdTree::One) => IdTree::One,
+            (IdTree::Node(left1, right1), IdTree::Node(left2, right2)) => {
+                IdTree::node((*left1).sum(*left2), (*right1).sum(*right2))
+            },
+        }
+    }
+}
+
+impl Debug for IdTree {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match *self {
+            IdTree::Zero => write!(f, "0"),
+            IdTree::One => write!(f, "1"),
+            IdTree::Node(ref left, ref right) => write!(f, "({:?}, {:?})", left, right),
+        }
+    }
+}
+
+impl EventTree {
+
+    /// Creates a normalized node from children which are normalized.
+    fn node(base: u64, left: EventTree, right: EventTree) -> EventTree {
+        match (left, right) {
+            (EventTree::Leaf(left), EventTree::Leaf(right)) if left == right => EventTree::Leaf(base + left),
+            (left, right) => {
+                let min = cmp::min(left.base(), right.base());
+                EventTree::Node(base + min, Box::new(left.sink(min)), Box::new(right.sink(min)))
+            },
+        }
+    }
+
+    /// Returns true if a node with this base and these normalized children is
+    /// normalized, and its maximum fits in a `u64`.
+    fn is_normalized_node(base: u64, left: &EventTree, right: &EventTree) -> bool {
+        let is_normalized = cmp::min(left.base(), right.base()) == 0 && match (left, right) {
+            (&EventTree::Leaf(left), &EventTree::Leaf(right)) => left != right,
+            _ => true,
+        };
+        is_normalized && left.max().checked_add(base).is_some() && right.max().checked_add(base).is_some()
+    }
+
+    #[cfg(feature = "serde")]
+    fn is_normalized(&self) -> bool {
+        match *self {
+            EventTree::Leaf(_) => true,
+            EventTree::Node(n, ref left, ref right) => {
+                left.is_normalized() && right.is_normalized() && EventTree::is_normalized_node(n, left, right)
+            },
+        }
+    }
+
+    /// Returns the maximum of the tree, or `None` if it overflows a `u64`.
+    fn checked_max(&self) -> Option<u64> {
+        match *self {
+            EventTree::Leaf(n) => Some(n),
+            EventTree::Node(n, ref left, ref right) => {
+                match (left.checked_max(), right.checked_max()) {
+                    (Some(left), Some(right)) => n.checked_add(cmp::max(left, right)),
+                    _ => None,
+                }
+            },
+        }
+    }
+
+    fn normalize(self) -> EventTree {
+        match self {
+            EventTree::Leaf(n) => EventTree::Leaf(n),
+            EventTree::Node(n, left, right) => EventTree::node(n, left.normalize(), right.normalize()),
+        }
+    }
+
+    /// Returns the base of the tree, which is its minimum if it is normalized.
+    fn base(&self) -> u64 {
+        match *self {
+            EventTree::Leaf(n) | EventTree::Node(n, _, _) => n,
+        }
+    }
+
+    fn max(&self) -> u64 {
+        match *self {
+            EventTree::Leaf(n) => n,
+            EventTree::Node(n, ref left, ref right) => n + cmp::max(left.max(), right.max()),
+        }
+    }
+
+    fn lift(self, m: u64) -> EventTree {
+        match self {
+            EventTree::Leaf(n) => EventTree::Leaf(n + m),
+            EventTree::Node(n, left, right) => EventTree::Node(n + m, left, right),
+        }
+    }
+
+    fn sink(self, m: u64) -> EventTree {
+        match self {
+            EventTree::Leaf(n) => EventTree::Leaf(n - m),
+            EventTree::Node(n, left, right) => EventTree::Node(n - m, left, right),
+        }
+    }
+
+    fn join(self, other: EventTree) -> EventTree {
+        match (self, other) {
+            (EventTree::Leaf(n1), EventTree::Leaf(n2)) => EventTree::Leaf(cmp::max(n1, n2)),
+            (EventTree::Leaf(n1), other) => {
+                EventTree::Node(n1, Box::new(EventTree::Leaf(0)), Box::new(EventTree::Leaf(0))).join(other)
+            },
+            (this, EventTree::Leaf(n2)) => {
+                this.join(EventTree::Node(n2, Box::new(EventTree::Leaf(0)), Box::new(EventTree::Leaf(0))))
+            },
+            (EventTree::Node(n1, left1, right1), EventTree::Node(n2, left2, right2)) => {
+                if n1 > n2 {
+                    EventTree::Node(n2, left2, right2).join(EventTree::Node(n1, left1, right1))
+                } else {
+                    EventTree::node(n1, left1.join(left2.lift(n2 - n1)), right1.join(right2.lift(n2 - n1)))
+                }
+            },
+        }
+    }
+
+    /// Returns true if this tree lifted by `lift` is less than or equal to
+    /// `other` lifted by `other_lift` everywhere.
+    fn leq(&self, lift: u64, other: &EventTree, other_lift: u64) -> bool {
+        match (self, other) {
+            (&EventTree::Leaf(n1), _) => n1 + lift <= other.base() + other_lift,
+            (&EventTree::Node(n1, ref left, ref right), &EventTree::Leaf(n2)) => {
+                n1 + lift <= n2 + other_lift
+                    && left.leq(n1 + lift, other, other_lift)
+                    && right.leq(n1 + lift, other, other_lift)
+            },
+            (&EventTree::Node(n1, ref left1, ref right1), &EventTree::Node(n2, ref left2, ref right2)) => {
+                n1 + lift <= n2 + other_lift
+                    && left1.leq(n1 + lift, left2, n2 + other_lift)
+                    && right1.leq(n1 + lift, right2, n2 + other_lift)
+            },
+        }
+    }
+
+    /// Raises the parts of the tree owned by `id` as far as possible without
+    /// recording a new event.
+    fn fill(&self, id: &IdTree) -> EventTree {
+        match (id, self) {
+            (&IdTree::Zero, _) => self.clone(),
+            (&IdTree::One, _) => EventTree::Leaf(self.max()),
+            (_, &EventTree::Leaf(n)) => EventTree::Leaf(n),
+            (&IdTree::Node(ref id_left, ref id_right), &EventTree::Node(n, ref left, ref right)) => {
+                if **id_left == IdTree::One {
+                    let right = right.fill(id_right);
+                    let left = EventTree::Leaf(cmp::max(left.max(), right.base()));
+                    EventTree::node(n, left, right)
+                } else if **id_right == IdTree::One {
+                    let left = left.fill(id_left);
+                    let right = EventTree::Leaf(cmp::max(right.max(), left.base()));
+                    EventTree::node(n, left, right)
+                } else {
+                    EventTree::node(n, left.fill(id_left), right.fill(id_right))
+                }
+            },
+        }
+    }
+
+    /// Records a new event in the part of the tree owned by `id`, returning
+    /// the grown tree and the cost of growing it, or `None` if the counter of
+    /// every part owned by `id` is exhausted.
+    fn grow(&self, id: &IdTree) -> Option<(EventTree, u64)> {
+        match (id, self) {
+            (&IdTree::One, &EventTree::Leaf(n)) => n.checked_add(1).map(|n| (EventTree::Leaf(n), 0)),
+            (_, &EventTree::Leaf(n)) => {
+                let node = EventTree::Node(n, Box::new(EventTree::Leaf(0)), Box::new(EventTree::Leaf(0)));
+                node.grow(id).map(|(tree, cost)| (tree, cost + EXPAND_COST))
+            },
+            (&IdTree::Node(ref id_left, ref id_right), &EventTree::Node(n, ref left, ref right)) => {
+                let grow_left = || {
+                    left.grow(id_left).map(|(left, cost)| {
+                        (EventTree::Node(n, Box::new(left), right.clone()), cost + 1)
+                    })
+                };
+                let grow_right = || {
+                    right.grow(id_right).map(|(right, cost)| {
+                        (EventTree::Node(n, left.clone(), Box::new(right)), cost + 1)
+                    })
+                };
+                if **id_left == IdTree::Zero {
+                    grow_right()
+                } else if **id_right == IdTree::Zero {
+                    grow_left()
+                } else {
+                    match (grow_left(), grow_right()) {
+                        (Some(left), Some(right)) => Some(if left.1 < right.1 { left } else { right }),
+                        (left, right) => left.or(right),
+                    }
+                }
+            },
+            // The anonymous identity cannot record events, and filling a tree
+            // owned by the seed identity always raises it to a leaf.
+            (&IdTree::Zero, _) | (&IdTree::One, &EventTree::Node(..)) => unreachable!(),
+        }
+    }
+}
+
+impl Debug for EventTree {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match *self {
+            EventTree::Leaf(n) => write!(f, "{}", n),
+            EventTree::Node(n, ref left, ref right) => write!(f, "({}, {:?}, {:?})", n, left, right),
+        }
+    }
+}
+
+impl Stamp {
+
+    /// Returns the stamp of the first replica, which owns the whole interval
+    /// and has seen no events.
+    pub fn seed() -> Stamp {
+        Stamp { id: Id::seed(), event: EventTree::Leaf(0) }
+    }
+
+    /// Returns the identity of the stamp.
+    pub fn id(&self) -> &Id {
+        &self.id
+    }
+
+    /// Returns true if the stamp has the anonymous identity, and so cannot
+    /// record events.
+    pub fn is_anonymous(&self) -> bool {
+        self.id.is_anonymous()
+    }
+
+    /// Creates a stamp for a new replica, splitting the identity of this
+    /// stamp between the two.
+    pub fn fork(&mut self) -> Stamp {
+        Stamp { id: self.id.fork(), event: self.event.clone() }
+    }
+
+    /// Returns an anonymous stamp with the events of this stamp, suitable for
+    /// sending to other replicas.
+    pub fn peek(&self) -> Stamp {
+        Stamp { id: Id::anonymous(), event: self.event.clone() }
+    }
+
+    /// Joins another stamp into this stamp, taking over its identity and
+    /// events.
+    ///
+    /// Joining an anonymous stamp merges its events only. Joining a stamp with
+    /// an identity retires the other replica.
+    pub fn join(&mut self, other: Stamp) {
+        self.id.join(other.id);
+        let event = ::std::mem::replace(&mut self.event, EventTree::Leaf(0));
+        self.event = event.join(other.event);
+    }
+
+    /// Records a new event.
+    ///
+    /// Returns an error, leaving the stamp unchanged, if the event counters
+    /// of the stamp's identity are exhausted.
+    ///
+    /// ##### Panics
+    ///
+    /// Panics if the stamp is anonymous.
+    pub fn event(&mut self) -> Result<(), Error> {
+        assert!(!self.is_anonymous(), "an anonymous stamp cannot record events");
+        let filled = self.event.fill(&self.id.0);
+        if filled != self.event {
+            self.event = filled;
+            return Ok(());
+        }
+        match self.event.grow(&self.id.0) {
+            Some((grown, _)) if grown.checked_max().is_some() => {
+                self.event = grown.normalize();
+                Ok(())
+            },
+            _ => Err(Error::CounterOverflow),
+        }
+    }
+
+    /// Returns true if every event seen by this stamp has been seen by
+    /// `other`.
+    pub fn leq(&self, other: &Stamp) -> bool {
+        self.event.leq(0, &other.event, 0)
+    }
+
+    /// Compares the events seen by two stamps.
+    ///
+    /// Returns `None` if each stamp has seen an event which the other has
+    /// not, that is if the stamps are concurrent.
+    pub fn causal_cmp(&self, other: &Stamp) -> Option<Ordering> {
+        match (self.leq(other), other.leq(self)) {
+            (true, true) => Some(Ordering::Equal),
+            (true, false) => Some(Ordering::Less),
+            (false, true) => Some(Ordering::Greater),
+            (false, false) => None,
+        }
+    }
+}
+
+impl Debug for Stamp {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(f, "({:?}, {:?})", self.id, self.event)
+    }
+}
+
+impl Encode for Id {
+    fn encode(&self, buf: &mut Vec<u8>) {
+        fn encode_tree(tree: &IdTree, buf: &mut Vec<u8>) {
+            match *tree {
+                IdTree::Zero => buf.push(0),
+                IdTree::One => buf.push(1),
+                IdTree::Node(ref left, ref right) => {
+                    buf.push(2);
+                    encode_tree(left, buf);
+                    encode_tree(right, buf);
+                },
+            }
+        }
+        encode_tree(&self.0, buf);
+    }
+}
+
+impl Decode for Id {
+    fn decode(reader: &mut Reader) -> Result<Id, DecodeError> {
+        fn decode_tree(reader: &mut Reader, depth: usize) -> Result<IdTree, DecodeError> {
+            if depth > MAX_DEPTH {
+                return Err(DecodeError::InvalidValue("identity tree is too deep"));
+            }
+            match try!(reader.read_u8()) {
+                0 => Ok(IdTree::Zero),
+                1 => Ok(IdTree::One),
+                2 => {
+                    let left = try!(decode_tree(reader, depth + 1));
+                    let right = try!(decode_tree(reader, depth + 1));
+                    if !IdTree::is_normalized_node(&left, &right) {
+                        return Err(DecodeError::InvalidValue("identity tree is not normalized"));
+                    }
+                    Ok(IdTree::Node(Box::new(left), Box::new(right)))
+                },
+                _ => Err(DecodeError::InvalidValue("unknown identity tree node")),
+            }
+        }
+        decode_tree(reader, 0).map(Id)
+    }
+}
+
+impl Encode for Stamp {
+    fn encode(&self, buf: &mut Vec<u8>) {
+        fn encode_tree(tree: &EventTree, buf: &mut Vec<u8>) {
+            match *tree {
+                EventTree::Leaf(n) => {
+                    buf.push(0);
+                    n.encode(buf);
+                },
+                EventTree::Node(n, ref left, ref right) => {
+                    buf.push(1);
+                    n.encode(buf);
+                    encode_tree(left, buf);
+                    encode_tree(right, buf);
+                },
+            }
+        }
+        self.id.encode(buf);
+        encode_tree(&self.event, buf);
+    }
+}
+
+impl Decode for Stamp {
+    fn decode(reader: &mut Reader) -> Result<Stamp, DecodeError> {
+        fn decode_tree(reader: &mut Reader, depth: usize) -> Result<EventTree, DecodeError> {
+            if depth > MAX_DEPTH {
+                return Err(DecodeError::InvalidValue("event tree is too deep"));
+            }
+            let variant = try!(reader.read_u8());
+            let n = try!(u64::decode(reader));
+            match variant {
+                0 => Ok(EventTree::Leaf(n)),
+                1 => {
+                    let left = try!(decode_tree(reader, depth + 1));
+                    let right = try!(decode_tree(reader, depth + 1));
+                    if !EventTree::is_normalized_node(n, &left, &right) {
+                        return Err(DecodeError::InvalidValue("event tree is not normalized"));
+                    }
+                    Ok(EventTree::Node(n, Box::new(left), Box::new(right)))
+                },
+                _ => Err(DecodeError::InvalidValue("unknown event tree node")),
+            }
+        }
+        let id = try!(Id::decode(reader));
+        Ok(Stamp { id: id, event: try!(decode_tree(reader, 0)) })
+    }
+}
+
+#[cfg(feature = "serde")]
+impl TryFrom<IdTree> for Id {
+    type Error = &'static str;
+
+    fn try_from(tree: IdTree) -> Result<Id, &'static str> {
+        if tree.is_normalized() { Ok(Id(tree)) } else { Err("identity tree is not normalized") }
+    }
+}
+
+#[cfg(feature = "serde")]
+impl TryFrom<StampFields> for Stamp {
+    type Error = &'static str;
+
+    fn try_from(fields: StampFields) -> Result<Stamp, &'static str> {
+        if fields.event.is_normalized() {
+            Ok(Stamp { id: fields.id, event: fields.event })
+        } else {
+            Err("event tree is not normalized")
+        }
+    }
+}
+
+impl Message for Stamp {
+    const TAG: u8 = 14;
+}
+
+#[cfg(any(feature = "quickcheck", test))]
+impl Arbitrary for Id {
+    fn arbitrary<G: Gen>(g: &mut G) -> Id {
+        fn arbitrary_tree<G: Gen>(g: &mut G, depth: usize) -> IdTree {
+            match u8::arbitrary(g) % 4 {
+                0 => IdTree::Zero,
+                1 => IdTree::One,
+                _ if depth == 0 => IdTree::One,
+                _ => IdTree::node(arbitrary_tree(g, depth - 1), arbitrary_tree(g, depth - 1)),
+            }
+        }
+        Id(arbitrary_tree(g, 4))
+    }
+}
+
+#[cfg(test)]
+mod test {
+
+    use std::cmp::Ordering;
+    use std::collections::HashSet;
+
+    use std::u64;
+
+    use clock::Error;
+    use wire;
+    use super::{EventTree, Id, IdTree, Stamp};
+
+    /// A stamp together with the IDs of the events it has seen, which
+    /// determine causality like a version vector.
+    #[derive(Clone, Debug)]
+    struct Replica {
+        stamp: Stamp,
+        history: HashSet<usize>,
+    }
+
+    /// Runs a script of fork, join, event, and send actions, returning the
+    /// live replicas and the number of events.
+    fn run(script: &[(u8, u8, u8)]) -> (Vec<Replica>, usize) {
+        let mut replicas = vec![Replica { stamp: Stamp::seed(), history: HashSet::new() }];
+        let mut events = 0;
+        for &(action, a, b) in script {
+            let a = a as usize % replicas.len();
+            let b = b as usize % replicas.len();
+            match action % 4 {
+                0 => {
+                    let stamp = replicas[a].stamp.fork();
+                    let history = replicas[a].history.clone();
+                    replicas.push(Replica { stamp: stamp, history: history });
+                },
+                1 if a != b => {
+                    let other = replicas.remove(b);
+                    let a = if a > b { a - 1 } else { a };
+                    replicas[a].stamp.join(other.stamp);
+                    replicas[a].history.extend(other.history);
+                },
+                2 => {
+                    let peek = replicas[b].stamp.peek();
+                    let history = replicas[b].history.clone();
+                    replicas[a].stamp.join(peek);
+                    replicas[a].history.extend(history);
+                },
+                _ => {
+                    replicas[a].stamp.event().unwrap();
+                    replicas[a].history.insert(events);
+                    events += 1;
+                },
+            }
+        }
+        (replicas, events)
+    }
+
+    #[quickcheck]
+    fn check_causality_matches_history(script: Vec<(u8, u8, u8)>) -> bool {
+        let (replicas, _) = run(&script);
+        replicas.iter().all(|a| replicas.iter().all(|b| {
+            a.stamp.leq(&b.stamp) == a.history.is_subset(&b.history)
+        }))
+    }
+
+    #[quickcheck]
+    fn check_identities_are_disjoint(script: Vec<(u8, u8, u8)>) -> bool {
+        let (replicas, _) = run(&script);
+        let mut stamp = Stamp { id: Id::anonymous(), event: Stamp::seed().event };
+        for replica in replicas {
+            if replica.stamp.is_anonymous() {
+                return false;
+            }
+            stamp.join(replica.stamp);
+        }
+        stamp.id == Id::seed()
+    }
+
+    #[quickcheck]
+    fn check_wire_round_trip(script: Vec<(u8, u8, u8)>) -> bool {
+        let (replicas, _) = run(&script);
+        replicas.iter().all(|replica| {
+            let bytes = wire::to_bytes(&replica.stamp);
+            wire::from_bytes::<Stamp>(&bytes).ok() == Some(replica.stamp.clone())
+        })
+    }
+
+    #[test]
+    fn check_paper_example() {
+        let mut a = Stamp::seed();
+        let mut b = a.fork();
+        assert_eq!("((1, 0), 0)", format!("{:?}", a));
+        assert_eq!("((0, 1), 0)", format!("{:?}", b));
+
+        a.event().unwrap();
+        b.event().unwrap();
+        assert_eq!("((1, 0), (0, 1, 0))", format!("{:?}", a));
+        assert_eq!("((0, 1), (0, 0, 1))", format!("{:?}", b));
+        assert_eq!(None, a.causal_cmp(&b));
+
+        let mut c = a.fork();
+        b.event().unwrap();
+        a.event().unwrap();
+        b.join(c.peek());
+        c.join(b.peek());
+        assert_eq!(Some(Ordering::Equal), b.causal_cmp(&c));
+        assert_eq!(None, a.causal_cmp(&b));
+
+        b.join(c);
+        b.join(a);
+        // Filling the whole interval counts as the event.
+        b.event().unwrap();
+        assert_eq!("(1, 2)", format!("{:?}", b));
+    }
+
+    #[test]
+    fn check_fork_join() {
+        let mut id = Id::seed();
+        let mut other = id.fork();
+        let third = other.fork();
+        assert_eq!("(1, 0)", format!("{:?}", id));
+        assert_eq!("(0, (1, 0))", format!("{:?}", other));
+        assert_eq!("(0, (0, 1))", format!("{:?}", third));
+        assert!(!id.contains(&other) && !other.contains(&third));
+        other.join(third.clone());
+        assert!(other.contains(&third) && !third.contains(&other));
+        id.join(other.clone());
+        assert!(id.contains(&other) && id.contains(&Id::anonymous()));
+        assert_eq!(Id::seed(), id);
+    }
+
+    #[test]
+    fn check_decode_rejects_invalid_trees() {
+        use wire::{Message, VERSION};
+        let header = [VERSION, <Stamp as Message>::TAG, 1];
+        for body in &[&[2, 0, 0, 0, 0][..], &[0, 1, 0, 1, 0, 1, 0, 1][..], &[2, 1, 0, 0, 1, 1, 0, 1, 0, 2][..]] {
+            let mut bytes = header.to_vec();
+            bytes.extend_from_slice(body);
+            assert!(wire::from_bytes::<Stamp>(&bytes).is_err(), "{:?}", body);
+        }
+        let mut deep = header.to_vec();
+        deep.extend(::std::iter::repeat(2).take(100000));
+        assert!(wire::from_bytes::<Stamp>(&deep).is_err());
+    }
+
+    #[test]
+    fn check_event_overflow() {
+        let mut stamp = Stamp { id: Id::seed(), event: EventTree::Leaf(u64::MAX - 1) };
+        assert_eq!(Ok(()), stamp.event());
+        assert_eq!(Err(Error::CounterOverflow), stamp.event());
+        assert_eq!("(1, 18446744073709551615)", format!("{:?}", stamp));
+
+        // Expanding the leaf would put the new event past the maximum.
+        let id = Id(IdTree::node(IdTree::One, IdTree::Zero));
+        let mut stamp = Stamp { id: id, event: EventTree::Leaf(u64::MAX) };
+        assert_eq!(Err(Error::CounterOverflow), stamp.event());
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn check_serde_rejects_invalid_trees() {
+        use serde_json;
+
+        let mut stamp = Stamp::seed();
+        stamp.fork();
+        stamp.event().unwrap();
+        let json = serde_json::to_string(&stamp).unwrap();
+        assert_eq!(stamp, serde_json::from_str(&json).unwrap());
+
+        assert!(serde_json::from_str::<Id>(r#"{"Node":["Zero","Zero"]}"#).is_err());
+        for json in &[r#"{"id":{"Node":["Zero","Zero"]},"event":{"Leaf":0}}"#,
+                      r#"{"id":"One","event":{"Node":[0,{"Leaf":1},{"Leaf":1}]}}"#,
+                      r#"{"id":"One","event":{"Node":[1,{"Leaf":1},{"Leaf":2}]}}"#,
+                      r#"{"id":"One","event":{"Node":[18446744073709551615,{"Leaf":0},{"Leaf":1}]}}"#] {
+            assert!(serde_json::from_str::<Stamp>(json).is_err(), "{}", json);
+        }
+    }
+}
@@ -0,0 +1,140 @@
+use std::cmp;
+
+use {ReplicaId, TransactionId};
+use clock::Error;
+
+/// The number of bits of an ID holding the replica ID.
+const REPLICA_BITS: u32 = 16;
+
+const MAX_REPLICA_ID: u64 = (1 << REPLICA_BITS) - 1;
+const MAX_COUNTER: u64 = (1 << (64 - REPLICA_BITS)) - 1;
+
+/// A Lamport clock which generates transaction IDs.
+///
+/// Each ID holds a 48 bit counter in its most significant bits and the 16 bit
+/// replica ID in its least significant bits, so replica IDs must be less than
+/// 65536. IDs generated by a clock are strictly increasing, and IDs generated
+/// by clocks with different replica IDs are unique. After observing a remote
+/// ID, the clock generates IDs greater than it, so IDs are consistent with
+/// causality without depending on physical time.
+///
+/// ##### Example
+///
+/// ```
+/// use crdt::clock::LamportClock;
+///
+/// let mut local = LamportClock::new(1).unwrap();
+/// let mut remote = LamportClock::new(2).unwrap();
+///
+/// let id = remote.tick().unwrap();
+/// local.observe(id).unwrap();
+/// assert!(local.tick().unwrap() > id);
+/// ```
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct LamportClock {
+    replica_id: ReplicaId,
+    counter: u64,
+}
+
+impl LamportClock {
+
+    /// Create a new Lamport clock.
+    ///
+    /// Returns an error if the replica ID is not less than 65536.
+    pub fn new<R>(replica_id: R) -> Result<LamportClock, Error> where R: Into<ReplicaId> {
+        let replica_id = replica_id.into();
+        if replica_id.id() > MAX_REPLICA_ID {
+            return Err(Error::ReplicaIdOutOfRange(replica_id));
+        }
+        Ok(LamportClock { replica_id: replica_id, counter: 0 })
+    }
+
+    /// Returns the replica ID of the clock.
+    pub fn replica_id(&self) -> ReplicaId {
+        self.replica_id
+    }
+
+    /// Returns the current value of the counter.
+    pub fn counter(&self) -> u64 {
+        self.counter
+    }
+
+    /// Generate a new transaction ID.
+    ///
+    /// Returns an error if the counter would overflow its 48 bits.
+    pub fn tick(&mut self) -> Result<TransactionId, Error> {
+        if self.counter >= MAX_COUNTER {
+            return Err(Error::CounterOverflow);
+        }
+        self.counter += 1;
+        Ok(TransactionId::from(self.counter << REPLICA_BITS | self.replica_id.id()))
+    }
+
+    /// Update the clock on receipt of a remote transaction ID, so that IDs
+    /// generated afterwards are greater than it.
+    ///
+    /// Returns an error, leaving the clock unchanged, if no ID can be greater
+    /// than the remote ID.
+    pub fn observe(&mut self, id: TransactionId) -> Result<(), Error> {
+        let counter = id.id() >> REPLICA_BITS;
+        if counter >= MAX_COUNTER {
+            return Err(Error::CounterOverflow);
+        }
+        self.counter = cmp::max(self.counter, counter);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+
+    use std::cmp;
+
+    use TransactionId;
+    use clock::Error;
+    use super::LamportClock;
+
+    #[quickcheck]
+    fn check_ids_are_monotonic(remote_ids: Vec<Option<u32>>) -> bool {
+        let mut clock = LamportClock::new(7).unwrap();
+        let mut last = TransactionId::from(0);
+        for remote_id in remote_ids {
+            let id = match remote_id {
+                Some(remote_id) => {
+                    let remote_id = TransactionId::from(remote_id as u64);
+                    clock.observe(remote_id).unwrap();
+                    last = cmp::max(last, remote_id);
+                    continue;
+                },
+                None => clock.tick().unwrap(),
+            };
+            if id <= last {
+                return false;
+            }
+            last = id;
+        }
+        true
+    }
+
+    #[test]
+    fn check_replica_ids() {
+        let mut a = LamportClock::new(0).unwrap();
+        let mut b = LamportClock::new(65535).unwrap();
+        assert_eq!(Ok(TransactionId::from(1 << 16)), a.tick());
+        assert_eq!(Ok(TransactionId::from(1 << 16 | 65535)), b.tick());
+        assert_eq!(Err(Error::ReplicaIdOutOfRange(65536.into())), LamportClock::new(65536));
+    }
+
+    #[test]
+    fn check_overflow() {
+        let mut clock = LamportClock::new(0).unwrap();
+        assert_eq!(Err(Error::CounterOverflow), clock.observe(TransactionId::from(!0)));
+        assert_eq!(0, clock.counter());
+
+        let last = TransactionId::from((super::MAX_COUNTER - 1) << 16 | 65535);
+        assert_eq!(Ok(()), clock.observe(last));
+        assert_eq!(Ok(TransactionId::from(super::MAX_COUNTER << 16)), clock.tick());
+        assert_eq!(Err(Error::CounterOverflow), clock.tick());
+        assert_eq!(super::MAX_COUNTER, clock.counter());
+    }
+}
@@ -0,0 +1,198 @@
+//! Clocks for generating transaction IDs.
+//!
+//! `TransactionId`s provided to a replica **must** be monotonically
+//! increasing and unique across replicas. The clocks in this module generate
+//! IDs which meet these requirements without coordination among replicas.
+//!
+//! ##### Clock Types
+//!
+//! ###### `Hlc`
+//!
+//! A hybrid logical clock. IDs combine physical time with a logical counter
+//! and the replica ID, so they stay close to physical time while remaining
+//! monotonic when the physical clock stalls or moves backwards. On receipt of
+//! a remote ID the clock advances past it, so causally related IDs are
+//! ordered.
+//!
+//! ###### `LamportClock`
+//!
+//! A Lamport clock. IDs combine a logical counter with the replica ID, and do
+//! not depend on physical time at all. On receipt of a remote ID the clock
+//! advances past it. `LwwRegister` can draw its transaction IDs from a
+//! `LamportClock` directly.
+//!
+//! ###### `SnowflakeGenerator`
+//!
+//! A [Snowflake](https://github.com/twitter/snowflake) style generator. IDs
+//! pack a millisecond timestamp, the replica ID, and a per-millisecond
+//! sequence number, with a configurable epoch and bit widths. Unlike `Hlc`,
+//! the generator does not track remote IDs, and it refuses to generate IDs
+//! while the physical clock is behind the last generated ID.
+//!
+//! ##### Interval Tree Clocks
+//!
+//! The clocks above rely on unique replica IDs. A `Stamp` of an interval tree
+//! clock instead tracks causality with an identity which is forked from an
+//! existing replica, and joined back into one when a replica is retired, so
+//! replicas can come and go without coordination. Comparing stamps orders
+//! events like comparing version vectors.
+//!
+//! ##### Time Sources
+//!
+//! Clocks read physical time from a `TimeSource`. `SystemClock` reads the
+//! system time, and `ManualClock` is set explicitly, so clocks can be tested
+//! deterministically. Sleeping on a `ManualClock` advances it instead of
+//! blocking.
+
+use std::error;
+use std::fmt::{self, Display, Formatter};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use ReplicaId;
+
+pub use self::hlc::{Hlc, HlcTimestamp};
+pub use self::itc::{Id, Stamp};
+pub use self::lamport::LamportClock;
+pub use self::snowflake::{Exhaustion, Snowflake, SnowflakeConfig, SnowflakeGenerator};
+
+mod hlc;
+mod itc;
+mod lamport;
+mod snowflake;
+
+/// A source of physical time.
+pub trait TimeSource {
+
+    /// Returns the current time in milliseconds since the Unix epoch.
+    fn now_millis(&self) -> u64;
+
+    /// Blocks for `millis` milliseconds.
+    fn sleep_millis(&self, millis: u64) {
+        thread::sleep(Duration::from_millis(millis));
+    }
+}
+
+/// A time source which reads the system time.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SystemClock;
+
+impl TimeSource for SystemClock {
+    fn now_millis(&self) -> u64 {
+        let elapsed = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+        elapsed.as_secs() * 1000 + (elapsed.subsec_nanos() / 1_000_000) as u64
+    }
+}
+
+/// A time source which only changes when it is explicitly set.
+///
+/// Clones share the same time, so a clock can be driven through a clone of
+/// its time source.
+///
+/// ##### Example
+///
+/// ```
+/// use crdt::clock::{ManualClock, TimeSource};
+///
+/// let time = ManualClock::new(1000);
+/// let handle = time.clone();
+/// handle.advance(5);
+/// assert_eq!(1005, time.now_millis());
+/// ```
+#[derive(Clone, Debug, Default)]
+pub struct ManualClock {
+    millis: Arc<Mutex<u64>>,
+}
+
+impl ManualClock {
+
+    /// Create a new manual clock set to `millis` milliseconds since the Unix
+    /// epoch.
+    pub fn new(millis: u64) -> ManualClock {
+        ManualClock { millis: Arc::new(Mutex::new(millis)) }
+    }
+
+    /// Set the time, which may move backwards.
+    pub fn set(&self, millis: u64) {
+        *self.millis.lock().unwrap() = millis;
+    }
+
+    /// Advance the time by `millis` milliseconds.
+    pub fn advance(&self, millis: u64) {
+        *self.millis.lock().unwrap() += millis;
+    }
+}
+
+impl TimeSource for ManualClock {
+    fn now_millis(&self) -> u64 {
+        *self.millis.lock().unwrap()
+    }
+
+    fn sleep_millis(&self, millis: u64) {
+        self.advance(millis);
+    }
+}
+
+impl <F> TimeSource for F where F: Fn() -> u64 {
+    fn now_millis(&self) -> u64 {
+        self()
+    }
+}
+
+/// An error generating a transaction ID.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Error {
+    /// The replica ID does not fit in the bits reserved for it.
+    ReplicaIdOutOfRange(ReplicaId),
+    /// The clock would run ahead of physical time by more than the maximum
+    /// drift, in milliseconds.
+    DriftExceeded { drift: u64, max_drift: u64 },
+    /// Physical time is behind the last generated ID by this many
+    /// milliseconds.
+    ClockMovedBackwards(u64),
+    /// Every sequence number for the current millisecond has been used.
+    SequenceExhausted,
+    /// Physical time did not advance while waiting this many milliseconds for
+    /// the next millisecond.
+    ClockStalled(u64),
+    /// The physical time, in milliseconds since the Unix epoch, is outside the
+    /// range representable by the generator.
+    TimeOutOfRange(u64),
+    /// The configured bit widths do not fit in a transaction ID.
+    InvalidLayout,
+    /// The logical counter would overflow the bits reserved for it.
+    CounterOverflow,
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match *self {
+            Error::ReplicaIdOutOfRange(replica_id) =>
+                write!(f, "replica ID {} is out of range", replica_id.id()),
+            Error::DriftExceeded { drift, max_drift } =>
+                write!(f, "clock drift of {}ms exceeds the maximum of {}ms", drift, max_drift),
+            Error::ClockMovedBackwards(millis) => write!(f, "clock moved backwards by {}ms", millis),
+            Error::SequenceExhausted => write!(f, "sequence exhausted"),
+            Error::ClockStalled(millis) => write!(f, "clock did not advance for {}ms", millis),
+            Error::TimeOutOfRange(millis) => write!(f, "time {} is out of range", millis),
+            Error::InvalidLayout => write!(f, "invalid ID layout"),
+            Error::CounterOverflow => write!(f, "logical counter overflow"),
+        }
+    }
+}
+
+impl error::Error for Error {
+    fn description(&self) -> &str {
+        match *self {
+            Error::ReplicaIdOutOfRange(_) => "replica ID is out of range",
+            Error::DriftExceeded { .. } => "clock drift exceeds the maximum",
+            Error::ClockMovedBackwards(_) => "clock moved backwards",
+            Error::SequenceExhausted => "sequence exhausted",
+            Error::ClockStalled(_) => "clock did not advance",
+            Error::TimeOutOfRange(_) => "time is out of range",
+            Error::InvalidLayout => "invalid ID layout",
+            Error::CounterOverflow => "logical counter overflow",
+        }
+    }
+}
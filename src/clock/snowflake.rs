@@ -0,0 +1,338 @@
+use {ReplicaId, TransactionId};
+use clock::{Error, SystemClock, TimeSource};
+
+/// The longest a generator waits for the next millisecond, in milliseconds,
+/// before giving up with `Error::ClockStalled`.
+const MAX_WAIT_MILLIS: u64 = 1000;
+
+/// The behavior of a `SnowflakeGenerator` when every sequence number for the
+/// current millisecond has been used.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Exhaustion {
+    /// Sleep until the next millisecond. If physical time does not advance
+    /// within a second, return `Error::ClockStalled`.
+    Wait,
+    /// Return `Error::SequenceExhausted`.
+    Error,
+}
+
+/// The layout of the IDs generated by a `SnowflakeGenerator`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SnowflakeConfig {
+    /// The epoch of the timestamps, in milliseconds since the Unix epoch.
+    pub epoch: u64,
+    /// The number of bits holding the replica ID.
+    pub replica_bits: u32,
+    /// The number of bits holding the per-millisecond sequence number.
+    pub sequence_bits: u32,
+    /// The behavior when the sequence is exhausted.
+    pub exhaustion: Exhaustion,
+}
+
+impl SnowflakeConfig {
+
+    fn timestamp_bits(&self) -> u32 {
+        64 - self.replica_bits - self.sequence_bits
+    }
+}
+
+/// The default configuration, which matches Twitter's Snowflake: timestamps
+/// since 2010-11-04, 10 bit replica IDs, and 12 bit sequence numbers, leaving
+/// 42 bits of timestamp (139 years).
+impl Default for SnowflakeConfig {
+    fn default() -> SnowflakeConfig {
+        SnowflakeConfig {
+            epoch: 1288834974657,
+            replica_bits: 10,
+            sequence_bits: 12,
+            exhaustion: Exhaustion::Wait,
+        }
+    }
+}
+
+/// The components of an ID generated by a `SnowflakeGenerator`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct Snowflake {
+    /// The time the ID was generated, in milliseconds since the Unix epoch.
+    pub time: u64,
+    /// The ID of the replica which generated the ID.
+    pub replica_id: u64,
+    /// The sequence number of the ID within its millisecond.
+    pub sequence: u64,
+}
+
+/// A Snowflake-style transaction ID generator.
+///
+/// Each ID holds, from most to least significant bits, the milliseconds since
+/// the configured epoch, the replica ID, and a sequence number which orders
+/// IDs generated within the same millisecond. IDs generated by a generator are
+/// strictly increasing, and IDs generated by generators with different replica
+/// IDs are unique.
+///
+/// If physical time moves backwards, the generator returns
+/// `Error::ClockMovedBackwards` until it catches up with the last generated
+/// ID.
+///
+/// ##### Example
+///
+/// ```
+/// use crdt::clock::{ManualClock, SnowflakeConfig, SnowflakeGenerator};
+///
+/// let time = ManualClock::new(1500000000000);
+/// let mut generator =
+///     SnowflakeGenerator::with_config(7, SnowflakeConfig::default(), time.clone()).unwrap();
+///
+/// let first = generator.next_id().unwrap();
+/// let second = generator.next_id().unwrap();
+/// assert!(first < second);
+///
+/// let snowflake = generator.decode(second);
+/// assert_eq!(1500000000000, snowflake.time);
+/// assert_eq!(7, snowflake.replica_id);
+/// assert_eq!(1, snowflake.sequence);
+/// ```
+#[derive(Clone, Debug)]
+pub struct SnowflakeGenerator<S = SystemClock> {
+    config: SnowflakeConfig,
+    replica_id: ReplicaId,
+    time_source: S,
+    /// The timestamp and sequence number of the last generated ID.
+    last: Option<(u64, u64)>,
+}
+
+impl SnowflakeGenerator<SystemClock> {
+
+    /// Create a new generator with the default configuration, reading the
+    /// system time.
+    pub fn new<R>(replica_id: R) -> Result<SnowflakeGenerator<SystemClock>, Error>
+    where R: Into<ReplicaId> {
+        SnowflakeGenerator::with_config(replica_id, SnowflakeConfig::default(), SystemClock)
+    }
+}
+
+impl <S> SnowflakeGenerator<S> where S: TimeSource {
+
+    /// Create a new generator reading physical time from `time_source`.
+    ///
+    /// Returns an error if the bit widths leave no room for the timestamp, or
+    /// if the replica ID does not fit in the replica bits.
+    pub fn with_config<R>(replica_id: R,
+                          config: SnowflakeConfig,
+                          time_source: S)
+                          -> Result<SnowflakeGenerator<S>, Error>
+    where R: Into<ReplicaId> {
+        let replica_id = replica_id.into();
+        if config.replica_bits as u64 + config.sequence_bits as u64 >= 64 {
+            return Err(Error::InvalidLayout);
+        }
+        if replica_id.id() > mask(config.replica_bits) {
+            return Err(Error::ReplicaIdOutOfRange(replica_id));
+        }
+        Ok(SnowflakeGenerator {
+            config: config,
+            replica_id: replica_id,
+            time_source: time_source,
+            last: None,
+        })
+    }
+
+    /// Returns the configuration of the generator.
+    pub fn config(&self) -> &SnowflakeConfig {
+        &self.config
+    }
+
+    /// Generate a new transaction ID.
+    pub fn next_id(&mut self) -> Result<TransactionId, Error> {
+        let mut waited = 0;
+        loop {
+            let timestamp = try!(self.timestamp());
+            let sequence = match self.last {
+                Some((last, _)) if timestamp < last => {
+                    return Err(Error::ClockMovedBackwards(last - timestamp));
+                },
+                Some((last, sequence)) if timestamp == last => {
+                    if sequence == mask(self.config.sequence_bits) {
+                        match self.config.exhaustion {
+                            Exhaustion::Wait if waited < MAX_WAIT_MILLIS => {
+                                self.time_source.sleep_millis(1);
+                                waited += 1;
+                                continue;
+                            },
+                            Exhaustion::Wait => return Err(Error::ClockStalled(waited)),
+                            Exhaustion::Error => return Err(Error::SequenceExhausted),
+                        }
+                    }
+                    sequence + 1
+                },
+                _ => 0,
+            };
+            self.last = Some((timestamp, sequence));
+            let id = timestamp << (self.config.replica_bits + self.config.sequence_bits)
+                   | self.replica_id.id() << self.config.sequence_bits
+                   | sequence;
+            return Ok(TransactionId::from(id));
+        }
+    }
+
+    /// Returns the components of an ID generated with the same configuration
+    /// as this generator.
+    pub fn decode(&self, id: TransactionId) -> Snowflake {
+        let id = id.id();
+        Snowflake {
+            time: (id >> (self.config.replica_bits + self.config.sequence_bits)) + self.config.epoch,
+            replica_id: (id >> self.config.sequence_bits) & mask(self.config.replica_bits),
+            sequence: id & mask(self.config.sequence_bits),
+        }
+    }
+
+    /// Returns the milliseconds since the epoch, checking that they fit in the
+    /// timestamp bits.
+    fn timestamp(&self) -> Result<u64, Error> {
+        let time = self.time_source.now_millis();
+        match time.checked_sub(self.config.epoch) {
+            Some(timestamp) if timestamp <= mask(self.config.timestamp_bits()) => Ok(timestamp),
+            _ => Err(Error::TimeOutOfRange(time)),
+        }
+    }
+}
+
+/// Returns the largest value which fits in `bits` bits.
+fn mask(bits: u32) -> u64 {
+    if bits >= 64 { !0 } else { (1 << bits) - 1 }
+}
+
+#[cfg(test)]
+mod test {
+
+    use std::cell::Cell;
+
+    use TransactionId;
+    use clock::{Error, ManualClock, TimeSource};
+    use super::{Exhaustion, Snowflake, SnowflakeConfig, SnowflakeGenerator, MAX_WAIT_MILLIS};
+
+    /// A time source which never advances, even while sleeping.
+    struct FrozenClock {
+        sleeps: Cell<u64>,
+    }
+
+    impl TimeSource for FrozenClock {
+        fn now_millis(&self) -> u64 {
+            1000
+        }
+
+        fn sleep_millis(&self, millis: u64) {
+            self.sleeps.set(self.sleeps.get() + millis);
+        }
+    }
+
+    fn config(replica_bits: u32, sequence_bits: u32, exhaustion: Exhaustion) -> SnowflakeConfig {
+        SnowflakeConfig {
+            epoch: 1000,
+            replica_bits: replica_bits,
+            sequence_bits: sequence_bits,
+            exhaustion: exhaustion,
+        }
+    }
+
+    #[quickcheck]
+    fn check_ids_are_monotonic(ticks: Vec<u8>, replica_bits: u8, sequence_bits: u8) -> bool {
+        let config = config(replica_bits as u32 % 16, sequence_bits as u32 % 8, Exhaustion::Wait);
+        let time = ManualClock::new(1000);
+        let mut generator = SnowflakeGenerator::with_config(0, config, time.clone()).unwrap();
+        let mut last = None;
+        for tick in ticks {
+            time.advance(tick as u64 % 3);
+            let id = generator.next_id().unwrap();
+            if last.map_or(false, |last| id <= last) {
+                return false;
+            }
+            last = Some(id);
+        }
+        true
+    }
+
+    #[quickcheck]
+    fn check_decode(replica_id: u16, sequence: u8, elapsed: u32) -> bool {
+        let config = config(16, 8, Exhaustion::Error);
+        let time = ManualClock::new(1000 + elapsed as u64);
+        let mut generator = SnowflakeGenerator::with_config(replica_id as u64, config, time).unwrap();
+        let mut id = generator.next_id().unwrap();
+        for _ in 0..sequence {
+            id = generator.next_id().unwrap();
+        }
+        generator.decode(id) == Snowflake {
+            time: 1000 + elapsed as u64,
+            replica_id: replica_id as u64,
+            sequence: sequence as u64,
+        }
+    }
+
+    #[test]
+    fn check_replica_ids_are_unique() {
+        let time = ManualClock::new(1000);
+        let mut a = SnowflakeGenerator::with_config(1, config(1, 4, Exhaustion::Wait), time.clone()).unwrap();
+        let mut b = SnowflakeGenerator::with_config(0, config(1, 4, Exhaustion::Wait), time.clone()).unwrap();
+        for _ in 0..100 {
+            assert!(a.next_id().unwrap() != b.next_id().unwrap());
+        }
+    }
+
+    #[test]
+    fn check_sequence_exhaustion() {
+        let time = ManualClock::new(1000);
+        let mut generator = SnowflakeGenerator::with_config(0, config(0, 2, Exhaustion::Error), time.clone()).unwrap();
+        for sequence in 0..4 {
+            assert_eq!(Ok(TransactionId::from(sequence)), generator.next_id());
+        }
+        assert_eq!(Err(Error::SequenceExhausted), generator.next_id());
+        time.advance(1);
+        assert_eq!(Ok(TransactionId::from(4)), generator.next_id());
+
+        let mut generator = SnowflakeGenerator::with_config(0, config(0, 2, Exhaustion::Wait), time.clone()).unwrap();
+        for _ in 0..4 {
+            generator.next_id().unwrap();
+        }
+        // Waiting for the next millisecond advances the manual clock.
+        assert_eq!(Ok(TransactionId::from(8)), generator.next_id());
+        assert_eq!(1002, time.now_millis());
+    }
+
+    #[test]
+    fn check_stalled_clock() {
+        let time = FrozenClock { sleeps: Cell::new(0) };
+        let mut generator = SnowflakeGenerator::with_config(0, config(0, 2, Exhaustion::Wait), time).unwrap();
+        for _ in 0..4 {
+            generator.next_id().unwrap();
+        }
+        assert_eq!(Err(Error::ClockStalled(MAX_WAIT_MILLIS)), generator.next_id());
+        assert_eq!(MAX_WAIT_MILLIS, generator.time_source.sleeps.get());
+    }
+
+    #[test]
+    fn check_clock_moving_backwards() {
+        let time = ManualClock::new(1010);
+        let mut generator = SnowflakeGenerator::with_config(0, config(10, 12, Exhaustion::Wait), time.clone()).unwrap();
+        let before = generator.next_id().unwrap();
+        time.set(1005);
+        assert_eq!(Err(Error::ClockMovedBackwards(5)), generator.next_id());
+        time.set(1010);
+        assert!(generator.next_id().unwrap() > before);
+    }
+
+    #[test]
+    fn check_configuration() {
+        let time = ManualClock::new(1000);
+        assert_eq!(Err(Error::InvalidLayout),
+                   SnowflakeGenerator::with_config(0, config(32, 32, Exhaustion::Wait), time.clone()).map(|_| ()));
+        assert_eq!(Err(Error::ReplicaIdOutOfRange(4.into())),
+                   SnowflakeGenerator::with_config(4, config(2, 10, Exhaustion::Wait), time.clone()).map(|_| ()));
+
+        time.set(999);
+        let mut generator = SnowflakeGenerator::with_config(0, config(31, 31, Exhaustion::Wait), time.clone()).unwrap();
+        assert_eq!(Err(Error::TimeOutOfRange(999)), generator.next_id());
+        time.set(1004);
+        assert_eq!(Err(Error::TimeOutOfRange(1004)), generator.next_id());
+        time.set(1003);
+        assert_eq!(Ok(TransactionId::from(3 << 62)), generator.next_id());
+    }
+}
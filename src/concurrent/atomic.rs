@@ -0,0 +1,413 @@
+use std::cmp;
+use std::sync::{Arc, Mutex, RwLock};
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use {Crdt, ReplicaId};
+use counter::{GCounter, GCounterOp, PnCounter, PnCounterOp};
+
+/// A grow-only counter which many threads may increment concurrently.
+///
+/// The local replica's count is kept in an atomic, so `increment` never
+/// blocks. The state merged from remote replicas is kept behind an `Arc`
+/// which `merge_from` replaces with a merged copy, so readers are blocked
+/// only while the pointer is swapped. `snapshot` returns the combined state
+/// as a plain `GCounter`, for replication.
+///
+/// ##### Example
+///
+/// ```
+/// use std::sync::Arc;
+/// use std::thread;
+///
+/// use crdt::concurrent::AtomicGCounter;
+/// use crdt::counter::GCounter;
+///
+/// let counter = Arc::new(AtomicGCounter::new(1));
+/// let threads: Vec<_> = (0..4).map(|_| {
+///     let counter = counter.clone();
+///     thread::spawn(move || for _ in 0..100 { counter.increment(1); })
+/// }).collect();
+/// for thread in threads {
+///     thread.join().unwrap();
+/// }
+///
+/// let mut remote = GCounter::new(2);
+/// remote.increment(5);
+/// counter.merge_from(&remote);
+///
+/// assert_eq!(405, counter.count());
+/// assert_eq!(405, counter.snapshot().count());
+/// ```
+#[derive(Debug)]
+pub struct AtomicGCounter {
+    replica_id: ReplicaId,
+    local: AtomicU64,
+    state: RwLock<Arc<GCounter>>,
+    merging: Mutex<()>,
+}
+
+/// An incrementable and decrementable counter which many threads may update
+/// concurrently.
+///
+/// The local replica's increments and decrements are kept in separate
+/// atomics; otherwise `AtomicPnCounter` works like `AtomicGCounter`.
+#[derive(Debug)]
+pub struct AtomicPnCounter {
+    replica_id: ReplicaId,
+    increments: AtomicU64,
+    decrements: AtomicU64,
+    state: RwLock<Arc<PnCounter>>,
+    merging: Mutex<()>,
+}
+
+impl AtomicGCounter {
+
+    /// Creates a new counter with the provided replica id and an initial
+    /// count of 0.
+    ///
+    /// Replica IDs **must** be unique among replicas of a counter.
+    pub fn new<R>(replica_id: R) -> AtomicGCounter where R: Into<ReplicaId> {
+        AtomicGCounter::from(GCounter::new(replica_id))
+    }
+
+    /// Get the replica ID of this counter.
+    pub fn replica_id(&self) -> ReplicaId {
+        self.replica_id
+    }
+
+    /// Increment the counter by `amount`.
+    ///
+    /// Incrementing the count by more than `u64::MAX` is undefined behavior,
+    /// as with `GCounter`.
+    pub fn increment(&self, amount: u64) {
+        self.local.fetch_add(amount, Ordering::Relaxed);
+    }
+
+    /// Get the current count of the counter.
+    pub fn count(&self) -> u64 {
+        let state = self.state();
+        let own = state.replica_count(self.replica_id);
+        state.count() - own + cmp::max(own, self.local.load(Ordering::Relaxed))
+    }
+
+    /// Returns the increment operation which carries the local replica's
+    /// count, for operation-based replication.
+    pub fn op(&self) -> GCounterOp {
+        GCounter::new(self.replica_id).increment(self.local.load(Ordering::Relaxed))
+    }
+
+    /// Returns a copy of the counter's state.
+    pub fn snapshot(&self) -> GCounter {
+        let mut snapshot = (*self.state()).clone();
+        let local = self.local.load(Ordering::Relaxed);
+        if local > snapshot.replica_count(self.replica_id) {
+            snapshot.apply(GCounter::new(self.replica_id).increment(local));
+        }
+        snapshot
+    }
+
+    /// Merge a replica into this counter.
+    ///
+    /// Concurrent merges are serialized, but do not block increments, and
+    /// block readers only while the merged state is published.
+    pub fn merge_from(&self, other: &GCounter) {
+        let _merging = self.merging.lock().unwrap();
+        let mut state = (*self.state()).clone();
+        state.merge(other.clone());
+        // The merged state may carry a count for this replica from before a
+        // restart, which later increments must build on. The difference is
+        // added rather than raising the count to the merged one, so that
+        // increments which race with the merge are not lost.
+        let own = state.replica_count(self.replica_id);
+        let local = self.local.load(Ordering::Relaxed);
+        if own > local {
+            self.local.fetch_add(own - local, Ordering::Relaxed);
+        }
+        *self.state.write().unwrap() = Arc::new(state);
+    }
+
+    fn state(&self) -> Arc<GCounter> {
+        self.state.read().unwrap().clone()
+    }
+}
+
+impl From<GCounter> for AtomicGCounter {
+    /// Wraps an existing replica.
+    fn from(counter: GCounter) -> AtomicGCounter {
+        let replica_id = counter.replica_id();
+        AtomicGCounter {
+            replica_id: replica_id,
+            local: AtomicU64::new(counter.replica_count(replica_id)),
+            state: RwLock::new(Arc::new(counter)),
+            merging: Mutex::new(()),
+        }
+    }
+}
+
+impl AtomicPnCounter {
+
+    /// Creates a new counter with the provided replica id and an initial
+    /// count of 0.
+    ///
+    /// Replica IDs **must** be unique among replicas of a counter.
+    pub fn new<R>(replica_id: R) -> AtomicPnCounter where R: Into<ReplicaId> {
+        AtomicPnCounter::from(PnCounter::new(replica_id))
+    }
+
+    /// Get the replica ID of this counter.
+    pub fn replica_id(&self) -> ReplicaId {
+        self.replica_id
+    }
+
+    /// Increment the counter by `amount`. If `amount` is negative, then the
+    /// counter will be decremented.
+    ///
+    /// The overflow limits of `PnCounter` apply.
+    pub fn increment(&self, amount: i64) {
+        if amount >= 0 {
+            self.increments.fetch_add(amount as u64, Ordering::Relaxed);
+        } else {
+            self.decrements.fetch_add(amount.wrapping_neg() as u64, Ordering::Relaxed);
+        }
+    }
+
+    /// Get the current count of the counter.
+    pub fn count(&self) -> i64 {
+        let state = self.state();
+        let (p, n) = state.replica_count(self.replica_id);
+        let (local_p, local_n) = self.local();
+        let own = cmp::max(p, local_p).wrapping_sub(cmp::max(n, local_n)) as i64;
+        state.count().wrapping_sub(p.wrapping_sub(n) as i64).wrapping_add(own)
+    }
+
+    /// Returns the increment operation which carries the local replica's
+    /// increments and decrements, for operation-based replication.
+    pub fn op(&self) -> PnCounterOp {
+        let (p, n) = self.local();
+        own_counter(self.replica_id, p, n).increment(0)
+    }
+
+    /// Returns a copy of the counter's state.
+    pub fn snapshot(&self) -> PnCounter {
+        let mut snapshot = (*self.state()).clone();
+        let (p, n) = self.local();
+        let (state_p, state_n) = snapshot.replica_count(self.replica_id);
+        if p > state_p || n > state_n {
+            snapshot.merge(own_counter(self.replica_id, p, n));
+        }
+        snapshot
+    }
+
+    /// Merge a replica into this counter.
+    ///
+    /// Concurrent merges are serialized, but do not block increments, and
+    /// block readers only while the merged state is published.
+    pub fn merge_from(&self, other: &PnCounter) {
+        let _merging = self.merging.lock().unwrap();
+        let mut state = (*self.state()).clone();
+        state.merge(other.clone());
+        let (p, n) = state.replica_count(self.replica_id);
+        let (local_p, local_n) = self.local();
+        if p > local_p {
+            self.increments.fetch_add(p - local_p, Ordering::Relaxed);
+        }
+        if n > local_n {
+            self.decrements.fetch_add(n - local_n, Ordering::Relaxed);
+        }
+        *self.state.write().unwrap() = Arc::new(state);
+    }
+
+    fn local(&self) -> (u64, u64) {
+        (self.increments.load(Ordering::Relaxed), self.decrements.load(Ordering::Relaxed))
+    }
+
+    fn state(&self) -> Arc<PnCounter> {
+        self.state.read().unwrap().clone()
+    }
+}
+
+impl From<PnCounter> for AtomicPnCounter {
+    /// Wraps an existing replica.
+    fn from(counter: PnCounter) -> AtomicPnCounter {
+        let replica_id = counter.replica_id();
+        let (p, n) = counter.replica_count(replica_id);
+        AtomicPnCounter {
+            replica_id: replica_id,
+            increments: AtomicU64::new(p),
+            decrements: AtomicU64::new(n),
+            state: RwLock::new(Arc::new(counter)),
+            merging: Mutex::new(()),
+        }
+    }
+}
+
+/// Returns a counter holding only the given increments and decrements of a
+/// replica.
+fn own_counter(replica_id: ReplicaId, mut p: u64, mut n: u64) -> PnCounter {
+    let mut counter = PnCounter::new(replica_id);
+    while p > 0 {
+        let step = cmp::min(p, i64::MAX as u64);
+        counter.increment(step as i64);
+        p -= step;
+    }
+    while n > 0 {
+        let step = cmp::min(n, i64::MAX as u64);
+        counter.increment(-(step as i64));
+        n -= step;
+    }
+    counter
+}
+
+#[cfg(test)]
+mod test {
+
+    use std::cmp::Ordering;
+    use std::sync::Arc;
+    use std::thread;
+
+    use Crdt;
+    use concurrent::{AtomicGCounter, AtomicPnCounter};
+    use counter::{GCounter, PnCounter};
+
+    #[test]
+    fn check_concurrent_increments() {
+        let gcounter = Arc::new(AtomicGCounter::new(1));
+        let pncounter = Arc::new(AtomicPnCounter::new(1));
+        let threads: Vec<_> = (0..8).map(|i| {
+            let (gcounter, pncounter) = (gcounter.clone(), pncounter.clone());
+            thread::spawn(move || {
+                for n in 0..1000 {
+                    gcounter.increment(1);
+                    pncounter.increment(if (i + n) % 3 == 0 { -1 } else { 2 });
+                    if n % 100 == 0 {
+                        let mut remote = GCounter::new(2);
+                        remote.increment(10);
+                        gcounter.merge_from(&remote);
+                    }
+                }
+            })
+        }).collect();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+        assert_eq!(8010, gcounter.count());
+        assert_eq!(8010, gcounter.snapshot().count());
+
+        let expected: i64 = (0..8).flat_map(|i| (0..1000).map(move |n| if (i + n) % 3 == 0 { -1 } else { 2 })).sum();
+        assert_eq!(expected, pncounter.count());
+        assert_eq!(expected, pncounter.snapshot().count());
+    }
+
+    #[test]
+    fn check_merges_race_with_increments() {
+        let gcounter = Arc::new(AtomicGCounter::new(1));
+        let pncounter = Arc::new(AtomicPnCounter::new(1));
+        let threads: Vec<_> = (0..4).map(|_| {
+            let (gcounter, pncounter) = (gcounter.clone(), pncounter.clone());
+            thread::spawn(move || {
+                let (mut last_g, mut last_pn) = (0, (0, 0));
+                for _ in 0..1000 {
+                    gcounter.increment(1);
+                    pncounter.increment(1);
+                    pncounter.increment(-1);
+                    // Copies of this replica's own state from before a
+                    // restart, ahead of the local count.
+                    let mut ahead = GCounter::new(1);
+                    ahead.increment(gcounter.count() + 4);
+                    gcounter.merge_from(&ahead);
+                    let mut ahead = PnCounter::new(1);
+                    let (p, n) = pncounter.snapshot().replica_count(1);
+                    ahead.increment(p as i64 + 4);
+                    ahead.increment(-(n as i64 + 2));
+                    pncounter.merge_from(&ahead);
+
+                    let (g, pn) = (gcounter.count(), pncounter.snapshot().replica_count(1));
+                    assert!(g >= last_g + 5, "count went from {} to {}", last_g, g);
+                    assert!(pn.0 >= last_pn.0 + 5 && pn.1 >= last_pn.1 + 3,
+                            "count went from {:?} to {:?}", last_pn, pn);
+                    last_g = g;
+                    last_pn = pn;
+                }
+            })
+        }).collect();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+
+        // Once quiescent, increments build on the merged count exactly.
+        let mut ahead = GCounter::new(1);
+        ahead.increment(gcounter.count() + 1000);
+        let expected = ahead.count() + 5;
+        gcounter.merge_from(&ahead);
+        gcounter.increment(5);
+        assert_eq!(expected, gcounter.count());
+        assert_eq!(expected, gcounter.snapshot().count());
+    }
+
+    /// Checks that an `AtomicGCounter` matches a `GCounter` under the same
+    /// increments and merges.
+    #[quickcheck]
+    fn check_gcounter_matches(updates: Vec<(u8, GCounter)>) -> bool {
+        let atomic = AtomicGCounter::new(1);
+        let mut expected = GCounter::new(1);
+        for (amount, mut remote) in updates {
+            if amount % 2 == 0 {
+                atomic.increment(amount as u64 + 1);
+                expected.increment(amount as u64 + 1);
+            } else {
+                // Some remote states carry counts for the local replica.
+                if amount % 3 == 0 {
+                    let mut own = GCounter::new(1);
+                    own.increment(amount as u64 * 10);
+                    remote.merge(own);
+                }
+                atomic.merge_from(&remote);
+                expected.merge(remote);
+            }
+        }
+        let mut from_op = GCounter::new(2);
+        from_op.apply(atomic.op());
+        atomic.snapshot().partial_cmp(&expected) == Some(Ordering::Equal)
+            && atomic.count() == expected.count()
+            && from_op.replica_count(1) == expected.replica_count(1)
+    }
+
+    /// Checks that an `AtomicPnCounter` matches a `PnCounter` under the same
+    /// increments and merges.
+    #[quickcheck]
+    fn check_pncounter_matches(updates: Vec<(i8, PnCounter)>) -> bool {
+        let atomic = AtomicPnCounter::new(1);
+        let mut expected = PnCounter::new(1);
+        for (amount, mut remote) in updates {
+            if amount % 2 == 0 {
+                atomic.increment(amount as i64 + 1);
+                expected.increment(amount as i64 + 1);
+            } else {
+                if amount % 3 == 0 {
+                    let mut own = PnCounter::new(1);
+                    own.increment(amount as i64 * 10);
+                    remote.merge(own);
+                }
+                atomic.merge_from(&remote);
+                expected.merge(remote);
+            }
+        }
+        let mut from_op = PnCounter::new(2);
+        from_op.apply(atomic.op());
+        atomic.snapshot().partial_cmp(&expected) == Some(Ordering::Equal)
+            && atomic.count() == expected.count()
+            && from_op.replica_count(1) == expected.replica_count(1)
+    }
+
+    #[test]
+    fn check_wraps_existing_replica() {
+        let mut counter = PnCounter::new(1);
+        counter.increment(5);
+        counter.increment(-2);
+        let atomic = AtomicPnCounter::from(counter.clone());
+        atomic.increment(-1);
+        counter.increment(-1);
+        assert_eq!(2, atomic.count());
+        assert!(atomic.snapshot() == counter);
+    }
+}
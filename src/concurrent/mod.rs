@@ -0,0 +1,33 @@
+//! Thread-safe replicas of counter CRDTs.
+//!
+//! Wrapping a counter in a `Mutex` serializes every increment, so threads
+//! which increment the same replica contend on the lock. The counters in
+//! this module keep the local replica's count in atomics, which threads
+//! increment without locking. The counts of remote replicas are kept in a
+//! copy-on-write state which is replaced on merge, so readers take a
+//! consistent snapshot without blocking writers for longer than an `Arc`
+//! clone.
+//!
+//! ##### Counter Types
+//!
+//! ###### `AtomicGCounter`
+//!
+//! A grow-only counter whose local count is a single atomic.
+//!
+//! ###### `AtomicPnCounter`
+//!
+//! An incrementable and decrementable counter whose local increments and
+//! decrements are separate atomics.
+//!
+//! ###### `ShardedGCounter`
+//!
+//! A grow-only counter whose local count is split across cache-line-padded
+//! slots, one per thread, and summed only when read. `ShardedGCounter` should
+//! be preferred to `AtomicGCounter` when many cores increment the counter at
+//! a high rate, since even a single atomic is contended by every core.
+
+pub use self::atomic::{AtomicGCounter, AtomicPnCounter};
+pub use self::sharded::ShardedGCounter;
+
+mod atomic;
+mod sharded;
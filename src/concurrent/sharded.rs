@@ -0,0 +1,303 @@
+use std::cmp;
+use std::sync::{Arc, Mutex, RwLock};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::thread;
+
+use {Crdt, ReplicaId};
+use counter::{GCounter, GCounterOp};
+
+/// The source of thread shard indices.
+static NEXT_SHARD: AtomicUsize = AtomicUsize::new(0);
+
+thread_local! {
+    /// The index of the slot which this thread increments, modulo the number
+    /// of slots.
+    static SHARD: usize = NEXT_SHARD.fetch_add(1, Ordering::Relaxed);
+}
+
+/// A slot holding part of the local replica's count, aligned to a cache line
+/// so that threads incrementing different slots do not contend.
+#[derive(Debug, Default)]
+#[repr(align(64))]
+struct Slot(AtomicU64);
+
+/// A grow-only counter whose local count is split across per-thread slots.
+///
+/// Threads are assigned slots round-robin, and each slot sits on its own
+/// cache line, so threads which increment the counter concurrently mostly
+/// touch memory no other thread writes. The slots are summed into the local
+/// replica's entry only when the count is read, a snapshot is taken, or a
+/// replica is merged, so the state returned by `snapshot` is a plain
+/// `GCounter`.
+///
+/// Merging works as with `AtomicGCounter`.
+///
+/// ##### Example
+///
+/// ```
+/// use std::sync::Arc;
+/// use std::thread;
+///
+/// use crdt::Crdt;
+/// use crdt::concurrent::ShardedGCounter;
+/// use crdt::counter::GCounter;
+///
+/// let counter = Arc::new(ShardedGCounter::with_shards(1, 4));
+/// let threads: Vec<_> = (0..8).map(|_| {
+///     let counter = counter.clone();
+///     thread::spawn(move || for _ in 0..100 { counter.increment(1); })
+/// }).collect();
+/// for thread in threads {
+///     thread.join().unwrap();
+/// }
+///
+/// let mut expected = GCounter::new(1);
+/// expected.increment(800);
+/// assert!(counter.snapshot() == expected);
+/// ```
+#[derive(Debug)]
+pub struct ShardedGCounter {
+    replica_id: ReplicaId,
+    slots: Box<[Slot]>,
+    /// The part of the local replica's count which is not in the slots. The
+    /// base and the slots only grow, so the local count never decreases.
+    base: AtomicU64,
+    state: RwLock<Arc<GCounter>>,
+    merging: Mutex<()>,
+}
+
+impl ShardedGCounter {
+
+    /// Creates a new counter with the provided replica id and an initial
+    /// count of 0, with a slot for each available CPU.
+    ///
+    /// Replica IDs **must** be unique among replicas of a counter.
+    pub fn new<R>(replica_id: R) -> ShardedGCounter where R: Into<ReplicaId> {
+        let shards = thread::available_parallelism().map(|n| n.get()).unwrap_or(1);
+        ShardedGCounter::with_shards(replica_id, shards)
+    }
+
+    /// Creates a new counter with the provided replica id, an initial count
+    /// of 0, and `shards` slots.
+    ///
+    /// ##### Panics
+    ///
+    /// Panics if `shards` is 0.
+    pub fn with_shards<R>(replica_id: R, shards: usize) -> ShardedGCounter where R: Into<ReplicaId> {
+        ShardedGCounter::from_counter(GCounter::new(replica_id), shards)
+    }
+
+    /// Wraps an existing replica, with `shards` slots.
+    ///
+    /// ##### Panics
+    ///
+    /// Panics if `shards` is 0.
+    pub fn from_counter(counter: GCounter, shards: usize) -> ShardedGCounter {
+        assert!(shards > 0, "a sharded counter requires at least one shard");
+        let replica_id = counter.replica_id();
+        ShardedGCounter {
+            replica_id: replica_id,
+            slots: (0..shards).map(|_| Slot::default()).collect::<Vec<_>>().into_boxed_slice(),
+            base: AtomicU64::new(counter.replica_count(replica_id)),
+            state: RwLock::new(Arc::new(counter)),
+            merging: Mutex::new(()),
+        }
+    }
+
+    /// Get the replica ID of this counter.
+    pub fn replica_id(&self) -> ReplicaId {
+        self.replica_id
+    }
+
+    /// Returns the number of slots.
+    pub fn shards(&self) -> usize {
+        self.slots.len()
+    }
+
+    /// Increment the counter by `amount`.
+    ///
+    /// Incrementing the count by more than `u64::MAX` is undefined behavior,
+    /// as with `GCounter`.
+    pub fn increment(&self, amount: u64) {
+        let shard = SHARD.with(|&shard| shard) % self.slots.len();
+        self.slots[shard].0.fetch_add(amount, Ordering::Relaxed);
+    }
+
+    /// Get the current count of the counter.
+    pub fn count(&self) -> u64 {
+        let state = self.state();
+        let own = state.replica_count(self.replica_id);
+        state.count() - own + cmp::max(own, self.local())
+    }
+
+    /// Returns the increment operation which carries the local replica's
+    /// count, for operation-based replication.
+    pub fn op(&self) -> GCounterOp {
+        GCounter::new(self.replica_id).increment(self.local())
+    }
+
+    /// Returns a copy of the counter's state, with the slots folded into the
+    /// local replica's entry.
+    pub fn snapshot(&self) -> GCounter {
+        let mut snapshot = (*self.state()).clone();
+        let local = self.local();
+        if local > snapshot.replica_count(self.replica_id) {
+            snapshot.apply(GCounter::new(self.replica_id).increment(local));
+        }
+        snapshot
+    }
+
+    /// Merge a replica into this counter.
+    ///
+    /// Concurrent merges are serialized, but do not block increments, and
+    /// block readers only while the merged state is published.
+    pub fn merge_from(&self, other: &GCounter) {
+        let _merging = self.merging.lock().unwrap();
+        let mut state = (*self.state()).clone();
+        state.merge(other.clone());
+        let own = state.replica_count(self.replica_id);
+        *self.state.write().unwrap() = Arc::new(state);
+
+        // The merged state may carry a count for this replica from before a
+        // restart, which later increments must build on. The difference is
+        // added to the base rather than draining the slots, so the local
+        // count never decreases, and increments which race with the merge are
+        // neither lost nor counted twice.
+        let local = self.local();
+        if own > local {
+            self.base.fetch_add(own - local, Ordering::Relaxed);
+        }
+    }
+
+    /// Returns the local replica's count.
+    fn local(&self) -> u64 {
+        self.slots.iter().fold(self.base.load(Ordering::Relaxed), |sum, slot| sum + slot.0.load(Ordering::Relaxed))
+    }
+
+    fn state(&self) -> Arc<GCounter> {
+        self.state.read().unwrap().clone()
+    }
+}
+
+#[cfg(test)]
+mod test {
+
+    use std::cmp::Ordering;
+    use std::sync::Arc;
+    use std::thread;
+
+    use Crdt;
+    use concurrent::ShardedGCounter;
+    use counter::GCounter;
+
+    #[test]
+    fn check_concurrent_increments() {
+        let counter = Arc::new(ShardedGCounter::with_shards(1, 4));
+        let threads: Vec<_> = (0..8).map(|_| {
+            let counter = counter.clone();
+            thread::spawn(move || {
+                for n in 0..1000 {
+                    counter.increment(1);
+                    if n % 100 == 0 {
+                        // A stale copy of this replica's own state.
+                        let mut stale = GCounter::new(1);
+                        stale.increment(n);
+                        counter.merge_from(&stale);
+                    }
+                }
+            })
+        }).collect();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+        let mut expected = GCounter::new(1);
+        expected.increment(8000);
+        assert_eq!(8000, counter.count());
+        assert!(counter.snapshot() == expected);
+    }
+
+    #[test]
+    fn check_count_is_monotonic() {
+        let counter = Arc::new(ShardedGCounter::with_shards(1, 4));
+        let reader = {
+            let counter = counter.clone();
+            thread::spawn(move || {
+                let (mut last_count, mut last_op) = (0, 0);
+                while last_count < 20000 {
+                    let mut from_op = GCounter::new(2);
+                    from_op.apply(counter.op());
+                    let (count, op) = (counter.count(), from_op.replica_count(1));
+                    assert!(count >= last_count, "count went from {} to {}", last_count, count);
+                    assert!(op >= last_op, "op went from {} to {}", last_op, op);
+                    last_count = count;
+                    last_op = op;
+                }
+            })
+        };
+        let threads: Vec<_> = (0..4).map(|_| {
+            let counter = counter.clone();
+            thread::spawn(move || {
+                for _ in 0..1000 {
+                    counter.increment(1);
+                    // A copy of this replica's own state from before a
+                    // restart, ahead of the local count.
+                    let mut ahead = GCounter::new(1);
+                    ahead.increment(counter.count() + 4);
+                    counter.merge_from(&ahead);
+                }
+            })
+        }).collect();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+        counter.merge_from(&{
+            let mut ahead = GCounter::new(1);
+            ahead.increment(20000);
+            ahead
+        });
+        reader.join().unwrap();
+    }
+
+    /// Checks that a `ShardedGCounter` matches a `GCounter` under the same
+    /// increments and merges.
+    #[quickcheck]
+    fn check_gcounter_matches(updates: Vec<(u8, GCounter)>, shards: u8) -> bool {
+        let sharded = ShardedGCounter::with_shards(1, shards as usize % 8 + 1);
+        let mut expected = GCounter::new(1);
+        for (amount, mut remote) in updates {
+            if amount % 2 == 0 {
+                sharded.increment(amount as u64 + 1);
+                expected.increment(amount as u64 + 1);
+            } else {
+                // Some remote states carry counts for the local replica.
+                if amount % 3 == 0 {
+                    let mut own = GCounter::new(1);
+                    own.increment(amount as u64 * 10);
+                    remote.merge(own);
+                }
+                sharded.merge_from(&remote);
+                expected.merge(remote);
+            }
+        }
+        let mut from_op = GCounter::new(2);
+        from_op.apply(sharded.op());
+        sharded.snapshot().partial_cmp(&expected) == Some(Ordering::Equal)
+            && sharded.count() == expected.count()
+            && from_op.replica_count(1) == expected.replica_count(1)
+    }
+
+    #[test]
+    fn check_wraps_existing_replica() {
+        let mut counter = GCounter::new(1);
+        counter.increment(5);
+        let mut remote = GCounter::new(2);
+        remote.increment(3);
+        counter.merge(remote);
+
+        let sharded = ShardedGCounter::from_counter(counter.clone(), 2);
+        sharded.increment(4);
+        counter.increment(4);
+        assert_eq!(12, sharded.count());
+        assert!(sharded.snapshot() == counter);
+    }
+}
@@ -3,21 +3,39 @@ use std::cmp::Ordering::{self, Greater, Less, Equal};
 use std::collections::HashMap;
 
 use {Crdt, ReplicaId};
+use observe::Observable;
+use replication;
+use retire::{self, Retirements};
+use wire::{self, Decode, DecodeError, Encode, Message, Migrations, Reader};
 
-#[cfg(any(quickcheck, test))]
+#[cfg(any(feature = "quickcheck", test))]
 use quickcheck::{Arbitrary, Gen};
 
 /// A grow-only counter.
 ///
 /// `GCounter` monotonically increases across increment operations.
+///
+/// ##### Retiring Replicas
+///
+/// Every replica which has incremented the counter has an entry in its
+/// state. The entries of departed replicas can be removed by retiring them:
+/// once every live replica has seen the retirement, the retiring replica
+/// folds the departed replica's count into its own, and the other replicas
+/// discard the departed replica's entry when they merge the folded state.
+/// Every replica remembers the departed replica's ID, and ignores operations
+/// and states which carry a count for it.
 #[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct GCounter {
     replica_id: ReplicaId,
-    counts: HashMap<ReplicaId, u64>
+    counts: HashMap<ReplicaId, u64>,
+    #[cfg_attr(feature = "serde", serde(default))]
+    retirements: Retirements,
 }
 
 /// An increment operation over `GCounter` CRDTs.
 #[derive(Debug, Clone, Copy)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct GCounterOp {
     replica_id: ReplicaId,
     count: u64
@@ -40,7 +58,7 @@ impl GCounter {
     /// ```
     pub fn new<R>(replica_id: R) -> GCounter
     where R: Into<ReplicaId> {
-        GCounter { replica_id: replica_id.into(), counts: HashMap::new() }
+        GCounter { replica_id: replica_id.into(), counts: HashMap::new(), retirements: Retirements::new() }
     }
 
     /// Get the current count of the counter.
@@ -93,10 +111,159 @@ impl GCounter {
         GCounterOp { replica_id: self.replica_id, count: *count }
     }
 
+    /// Returns the increment operations which, when applied to `other`, bring
+    /// it up to date with this counter.
+    ///
+    /// Applying the operations to `other` is equivalent to merging this counter
+    /// into `other`. No operations are returned for replicas whose counts are
+    /// already reflected in `other`.
+    ///
+    /// ##### Example
+    ///
+    /// ```
+    /// # use crdt::counter::GCounter;
+    /// # use crdt::Crdt;
+    /// let mut local = GCounter::new(42);
+    /// let mut remote = GCounter::new(43);
+    ///
+    /// local.increment(12);
+    /// remote.increment(13);
+    ///
+    /// for op in local.diff_ops(&remote) {
+    ///     remote.apply(op);
+    /// }
+    /// assert_eq!(25, remote.count());
+    /// ```
+    pub fn diff_ops(&self, other: &GCounter) -> Vec<GCounterOp> {
+        self.counts
+            .iter()
+            .filter(|&(replica_id, &count)| other.counts.get(replica_id).map_or(true, |&c| c < count))
+            .map(|(&replica_id, &count)| GCounterOp { replica_id: replica_id, count: count })
+            .collect()
+    }
+
     /// Get the replica ID of this counter.
     pub fn replica_id(&self) -> ReplicaId {
         self.replica_id
     }
+
+    /// Get the count contributed by a replica.
+    ///
+    /// ##### Example
+    ///
+    /// ```
+    /// # use crdt::counter::GCounter;
+    /// # use crdt::Crdt;
+    /// let mut local = GCounter::new(42);
+    /// let mut remote = GCounter::new(43);
+    /// remote.increment(13);
+    /// local.merge(remote);
+    /// assert_eq!(13, local.replica_count(43));
+    /// assert_eq!(0, local.replica_count(42));
+    /// ```
+    pub fn replica_count<R>(&self, replica_id: R) -> u64 where R: Into<ReplicaId> {
+        self.counts.get(&replica_id.into()).cloned().unwrap_or(0)
+    }
+
+    /// Returns a hash of the counts of every replica.
+    ///
+    /// The counts are hashed over their canonical encoding, in which they
+    /// are sorted, excluding this replica's ID and retirements, so replicas
+    /// which have converged have the same hash on every platform.
+    ///
+    /// ##### Example
+    ///
+    /// ```
+    /// # use crdt::counter::GCounter;
+    /// # use crdt::Crdt;
+    /// let mut local = GCounter::new(42);
+    /// let mut remote = GCounter::new(43);
+    /// remote.increment(13);
+    /// local.merge(remote.clone());
+    /// remote.merge(local.clone());
+    /// assert_eq!(local.content_hash(), remote.content_hash());
+    /// ```
+    pub fn content_hash(&self) -> u64 {
+        replication::fingerprint(|buf| {
+            wire::write_entries(buf, self.counts.iter(), |(replica_id, count), buf| {
+                replica_id.encode(buf);
+                count.encode(buf);
+            });
+        })
+    }
+
+    /// Begin retiring a departed replica.
+    ///
+    /// The departed replica **must not** increment the counter again. The
+    /// retirement spreads to other replicas through merges, and once every
+    /// live replica has seen it, `compact` folds the departed replica's count
+    /// into this replica's count. If several replicas retire the same replica
+    /// concurrently, only one of them folds its count.
+    ///
+    /// ##### Example
+    ///
+    /// ```
+    /// # use crdt::counter::GCounter;
+    /// # use crdt::Crdt;
+    /// let mut a = GCounter::new(1);
+    /// let mut b = GCounter::new(2);
+    /// let mut departed = GCounter::new(3);
+    /// departed.increment(5);
+    /// b.merge(departed);
+    ///
+    /// a.retire(3);
+    /// b.merge(a.clone());
+    /// a.merge(b.clone());
+    /// assert!(a.is_retirement_stable(3, vec![1, 2]));
+    ///
+    /// assert_eq!(1, a.compact(vec![1, 2]));
+    /// b.merge(a.clone());
+    /// assert_eq!(5, b.count());
+    /// // The entry of the departed replica has been dropped.
+    /// assert_eq!(1, b.diff_ops(&GCounter::new(4)).len());
+    /// ```
+    ///
+    /// ##### Panics
+    ///
+    /// Panics if `departed` is the replica ID of this counter.
+    pub fn retire<R>(&mut self, departed: R) where R: Into<ReplicaId> {
+        self.retirements.retire(departed.into(), self.replica_id);
+    }
+
+    /// Returns true if every replica in `live` has seen the retirement of
+    /// `departed` in its current phase, as far as this replica knows.
+    ///
+    /// A pending retirement which is stable can be folded by its owner, and a
+    /// folded retirement which is stable can be dropped.
+    pub fn is_retirement_stable<R, I, L>(&self, departed: R, live: I) -> bool
+    where R: Into<ReplicaId>, I: IntoIterator<Item=L>, L: Into<ReplicaId> {
+        self.retirements.is_stable(departed.into(), &retire::live_set(self.replica_id, live))
+    }
+
+    /// Advance the retirements owned by this replica which every replica in
+    /// `live` has seen, folding the counts of departed replicas into this
+    /// replica's count, and dropping the retirements which are complete.
+    /// Other replicas drop a complete retirement when they next merge this
+    /// replica's state.
+    ///
+    /// `live` **must** include every replica which may still merge state into
+    /// the counter. Returns the number of departed replicas folded.
+    pub fn compact<I, L>(&mut self, live: I) -> usize
+    where I: IntoIterator<Item=L>, L: Into<ReplicaId> {
+        let live = retire::live_set(self.replica_id, live);
+        let replica_id = self.replica_id;
+        let counts = &mut self.counts;
+        self.retirements.compact(replica_id, &live, |departed| {
+            let count = counts.remove(&departed).unwrap_or(0);
+            *counts.entry(replica_id).or_insert(0) += count;
+        })
+    }
+
+    /// Discards the counts of retired replicas which have been folded.
+    fn discard_retired(&mut self) {
+        let retirements = &self.retirements;
+        self.counts.retain(|&replica_id, _| !retirements.is_folded(replica_id));
+    }
 }
 
 impl Crdt for GCounter {
@@ -123,7 +290,12 @@ impl Crdt for GCounter {
     /// assert_eq!(25, local.count());
     /// ```
     fn merge(&mut self, other: GCounter) {
+        self.retirements.merge(other.retirements, self.replica_id);
+        self.discard_retired();
         for (&replica_id, &other_count) in other.counts.iter() {
+            if self.retirements.is_folded(replica_id) {
+                continue;
+            }
             let count = self.counts.entry(replica_id).or_insert(0);
             *count = cmp::max(*count, other_count);
         }
@@ -150,11 +322,23 @@ impl Crdt for GCounter {
     /// ```
     fn apply(&mut self, op: GCounterOp) {
         let GCounterOp { replica_id, count: other_count } = op;
+        if self.retirements.is_folded(replica_id) {
+            return;
+        }
         let count = self.counts.entry(replica_id).or_insert(0);
         *count = cmp::max(*count, other_count);
     }
 }
 
+impl Observable for GCounter {
+
+    type Value = u64;
+
+    fn value(&self) -> u64 {
+        self.count()
+    }
+}
+
 impl PartialEq for GCounter {
     fn eq(&self, other: &GCounter) -> bool {
         self.counts == other.counts
@@ -196,19 +380,75 @@ impl PartialOrd for GCounter {
     }
 }
 
-#[cfg(any(quickcheck, test))]
+impl Encode for GCounter {
+    fn encode(&self, buf: &mut Vec<u8>) {
+        self.replica_id.encode(buf);
+        wire::write_entries(buf, self.counts.iter(), |(replica_id, count), buf| {
+            replica_id.encode(buf);
+            count.encode(buf);
+        });
+        self.retirements.encode(buf);
+    }
+}
+
+impl Decode for GCounter {
+    fn decode(reader: &mut Reader) -> Result<GCounter, DecodeError> {
+        let mut counter = try!(decode_schema_1(reader));
+        counter.retirements = try!(Decode::decode(reader));
+        Ok(counter)
+    }
+}
+
+/// Decodes the schema 1 encoding, which has no retirements.
+fn decode_schema_1(reader: &mut Reader) -> Result<GCounter, DecodeError> {
+    let replica_id = try!(Decode::decode(reader));
+    let counts = try!(wire::read_map(reader, |reader| {
+        Ok((try!(Decode::decode(reader)), try!(Decode::decode(reader))))
+    }));
+    Ok(GCounter { replica_id: replica_id, counts: counts, retirements: Retirements::new() })
+}
+
+impl Message for GCounter {
+    const TAG: u8 = 1;
+    const SCHEMA: u32 = 2;
+
+    fn migrations() -> Migrations<GCounter> {
+        Migrations::new().register(1, decode_schema_1)
+    }
+}
+
+impl Encode for GCounterOp {
+    fn encode(&self, buf: &mut Vec<u8>) {
+        self.replica_id.encode(buf);
+        self.count.encode(buf);
+    }
+}
+
+impl Decode for GCounterOp {
+    fn decode(reader: &mut Reader) -> Result<GCounterOp, DecodeError> {
+        Ok(GCounterOp { replica_id: try!(Decode::decode(reader)), count: try!(Decode::decode(reader)) })
+    }
+}
+
+impl Message for GCounterOp {
+    const TAG: u8 = 2;
+}
+
+#[cfg(any(feature = "quickcheck", test))]
 impl Arbitrary for GCounter {
     fn arbitrary<G>(g: &mut G) -> GCounter where G: Gen {
         use gen_replica_id;
-        GCounter { replica_id: gen_replica_id(), counts: Arbitrary::arbitrary(g) }
+        GCounter { replica_id: gen_replica_id(), counts: Arbitrary::arbitrary(g), retirements: Retirements::new() }
     }
     fn shrink(&self) -> Box<Iterator<Item=GCounter> + 'static> {
         let replica_id: ReplicaId = self.replica_id();
-        Box::new(self.counts.shrink().map(move |counts| GCounter { replica_id: replica_id, counts: counts }))
+        Box::new(self.counts.shrink().map(move |counts| {
+            GCounter { replica_id: replica_id, counts: counts, retirements: Retirements::new() }
+        }))
     }
 }
 
-#[cfg(any(quickcheck, test))]
+#[cfg(any(feature = "quickcheck", test))]
 impl Arbitrary for GCounterOp {
     fn arbitrary<G>(g: &mut G) -> GCounterOp where G: Gen {
         GCounterOp { replica_id: Arbitrary::arbitrary(g), count: Arbitrary::arbitrary(g) }
@@ -224,7 +464,7 @@ mod test {
 
     use quickcheck::quickcheck;
 
-    use {Crdt, ReplicaId, test};
+    use {Crdt, ReplicaId, testkit, wire};
     use counter::{GCounter, GCounterOp};
 
     type C = GCounter;
@@ -232,22 +472,38 @@ mod test {
 
     #[test]
     fn check_apply_is_commutative() {
-        quickcheck(test::apply_is_commutative::<C> as fn(C, Vec<O>) -> bool);
+        quickcheck(testkit::apply_is_commutative::<C> as fn(C, Vec<O>) -> bool);
     }
 
     #[test]
     fn check_merge_is_commutative() {
-        quickcheck(test::merge_is_commutative::<C> as fn(C, Vec<C>) -> bool);
+        quickcheck(testkit::merge_is_commutative::<C> as fn(C, Vec<C>) -> bool);
     }
 
     #[test]
     fn check_ordering_lte() {
-        quickcheck(test::ordering_lte::<C> as fn(C, C) -> bool);
+        quickcheck(testkit::ordering_lte::<C> as fn(C, C) -> bool);
     }
 
     #[test]
     fn check_ordering_equality() {
-        quickcheck(test::ordering_equality::<C> as fn(C, C) -> bool);
+        quickcheck(testkit::ordering_equality::<C> as fn(C, C) -> bool);
+    }
+
+    #[test]
+    fn check_all() {
+        testkit::check_all::<C>();
+    }
+
+    #[cfg(feature = "serde")]
+    #[quickcheck]
+    fn check_serde_round_trip(crdt: C, op: O) -> bool {
+        ::test_util::serde_round_trip(crdt, op, |a, b| a.replica_id() == b.replica_id())
+    }
+
+    #[quickcheck]
+    fn check_diff_ops_is_merge(a: C, b: C) -> bool {
+        testkit::diff_ops_is_merge(a, b, C::diff_ops)
     }
 
     #[quickcheck]
@@ -274,4 +530,157 @@ mod test {
         b.increment(1);
         a.partial_cmp(&b) == None && b.partial_cmp(&a) == None
     }
+
+    /// Runs a script of merges, concurrent retirements of departed replicas,
+    /// compactions, and increments among three live replicas. Checks that
+    /// merging every replica always gives the full count, and that once the
+    /// replicas converge, the departed replicas leave no counts behind.
+    #[quickcheck]
+    fn check_retirement(script: Vec<(u8, u8, u8)>) -> bool {
+        let live = vec![0u64, 1, 2];
+        let mut replicas: Vec<GCounter> = live.iter().map(|&id| GCounter::new(id)).collect();
+        let mut total = 0;
+        for departed in 10..13 {
+            let mut counter = GCounter::new(departed);
+            counter.increment(departed);
+            total += departed;
+            replicas[departed as usize % 3].merge(counter);
+        }
+
+        let merged_count = |replicas: &[GCounter]| {
+            let mut merged = GCounter::new(99);
+            for replica in replicas {
+                merged.merge(replica.clone());
+            }
+            merged.count()
+        };
+
+        for (action, a, b) in script {
+            let (a, b) = (a as usize % 3, b as usize % 3);
+            match action % 4 {
+                0 => {
+                    let other = replicas[b].clone();
+                    replicas[a].merge(other);
+                },
+                1 => replicas[a].retire(10 + b as u64),
+                2 => { replicas[a].compact(live.clone()); },
+                _ => {
+                    replicas[a].increment(1);
+                    total += 1;
+                },
+            }
+            if merged_count(&replicas) != total {
+                return false;
+            }
+        }
+
+        for departed in 10..13 {
+            replicas[2].retire(departed);
+        }
+        for _ in 0..8 {
+            for a in 0..3 {
+                for b in 0..3 {
+                    let other = replicas[b].clone();
+                    replicas[a].merge(other);
+                }
+            }
+            for replica in replicas.iter_mut() {
+                replica.compact(live.clone());
+            }
+        }
+        replicas.iter().all(|replica| {
+            replica.count() == total
+                && replica.retirements.is_empty()
+                && replica.counts.keys().all(|replica_id| replica_id.id() < 3)
+        })
+    }
+
+    #[test]
+    fn check_retirement_stability() {
+        let mut a = GCounter::new(1);
+        let mut b = GCounter::new(2);
+        b.apply(GCounterOp { replica_id: ReplicaId(3), count: 5 });
+
+        a.retire(3);
+        assert!(!a.is_retirement_stable(3, vec![2]));
+        assert_eq!(0, a.compact(vec![2]));
+
+        b.merge(a.clone());
+        assert!(b.is_retirement_stable(3, vec![1]));
+        // Only the replica which retired the departed replica folds it.
+        assert_eq!(0, b.compact(vec![1]));
+
+        a.merge(b.clone());
+        assert_eq!(1, a.compact(vec![2]));
+        assert_eq!(Some(&5), a.counts.get(&ReplicaId(1)));
+        assert!(!a.is_retirement_stable(3, vec![2]));
+
+        // Increments from the departed replica which arrive late are discarded.
+        b.merge(a.clone());
+        b.apply(GCounterOp { replica_id: ReplicaId(3), count: 6 });
+        assert_eq!(5, b.count());
+
+        // The owner drops the complete retirement, and the other replicas
+        // drop it when they merge the owner's state.
+        a.merge(b.clone());
+        assert!(a.is_retirement_stable(3, vec![2]));
+        assert_eq!(0, a.compact(vec![2]));
+        assert!(a.retirements.is_empty());
+        a.merge(b.clone());
+        assert!(a.retirements.is_empty());
+        b.merge(a.clone());
+        assert!(b.retirements.is_empty());
+
+        // Operations and states from the departed replica which arrive after
+        // the retirement has been dropped are discarded.
+        let mut stale = GCounter::new(4);
+        stale.apply(GCounterOp { replica_id: ReplicaId(3), count: 7 });
+        for counter in &mut [a, b] {
+            counter.apply(GCounterOp { replica_id: ReplicaId(3), count: 8 });
+            counter.merge(stale.clone());
+            assert_eq!(5, counter.count());
+            assert!(!counter.counts.contains_key(&ReplicaId(3)));
+        }
+    }
+
+    #[test]
+    fn check_concurrent_retirement() {
+        let mut a = GCounter::new(1);
+        let mut b = GCounter::new(2);
+        a.apply(GCounterOp { replica_id: ReplicaId(3), count: 5 });
+        b.merge(a.clone());
+
+        a.retire(3);
+        b.retire(3);
+        // The retirement owned by the lower replica wins, so replica 2
+        // acknowledges replica 1's retirement instead of its own.
+        b.merge(a.clone());
+        a.merge(b.clone());
+        assert_eq!(0, b.compact(vec![1]));
+        assert_eq!(1, a.compact(vec![2]));
+        b.merge(a.clone());
+        assert_eq!(5, b.count());
+        assert_eq!(0, b.compact(vec![1]));
+    }
+
+    #[test]
+    fn check_retirement_wire_round_trip() {
+        let mut a = GCounter::new(1);
+        a.increment(3);
+        a.retire(3);
+        let mut b = GCounter::new(2);
+        b.merge(a.clone());
+        let decoded: GCounter = wire::from_bytes(&wire::to_bytes(&b)).unwrap();
+        assert_eq!(b.retirements, decoded.retirements);
+        assert_eq!(wire::to_bytes(&b), wire::to_bytes(&decoded));
+    }
+
+    #[quickcheck]
+    fn check_content_hash_after_convergence(a: C, b: C) -> bool {
+        let mut ab = a.clone();
+        ab.merge(b.clone());
+        let mut ba = b.clone();
+        ba.merge(a);
+        ab.content_hash() == ba.content_hash()
+    }
 }
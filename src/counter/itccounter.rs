@@ -0,0 +1,466 @@
+use std::cmp;
+use std::cmp::Ordering::{self, Greater, Less, Equal};
+use std::collections::HashMap;
+use std::mem;
+
+use Crdt;
+use clock::{Error, Id};
+use observe::Observable;
+use wire::{self, Decode, DecodeError, Encode, Message, Reader};
+
+#[cfg(any(feature = "quickcheck", test))]
+use quickcheck::{Arbitrary, Gen};
+
+/// A grow-only counter whose replicas are identified by interval tree clock
+/// identities.
+///
+/// `ItcCounter` behaves like `GCounter`, but instead of requiring a unique
+/// replica ID, a new replica is created by forking an existing replica, and a
+/// replica is retired by joining it into another replica. This suits fleets
+/// with high churn, where coordinating unique replica IDs is impractical.
+///
+/// Counts are kept per identity and generation. A replica only increments
+/// the count of its current identity, and identities are only ever handed on
+/// by forking and joining, so no two live replicas increment the same count.
+/// Joining starts a new generation, and folds the counts of every identity
+/// within the joined identity into a single count, which supersedes the
+/// folded counts on every replica it reaches. The state therefore stays
+/// proportional to the number of live replicas, however many have come and
+/// gone.
+///
+/// ##### Example
+///
+/// ```
+/// use crdt::Crdt;
+/// use crdt::counter::ItcCounter;
+///
+/// let mut a = ItcCounter::seed();
+/// let mut b = a.fork();
+///
+/// a.increment(12).unwrap();
+/// b.increment(13).unwrap();
+///
+/// a.merge(b.clone());
+/// assert_eq!(25, a.count());
+///
+/// // Retire `b`.
+/// a.join(b);
+/// a.increment(1).unwrap();
+/// assert_eq!(26, a.count());
+/// ```
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct ItcCounter {
+    id: Id,
+    generation: u64,
+    #[cfg_attr(feature = "serde", serde(with = "counts"))]
+    counts: HashMap<(Id, u64), u64>,
+}
+
+/// An increment operation over `ItcCounter` CRDTs.
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct ItcCounterOp {
+    id: Id,
+    generation: u64,
+    count: u64,
+}
+
+impl ItcCounter {
+
+    /// Create the first replica of a new counter, which owns the whole
+    /// identity space, with an initial count of 0.
+    ///
+    /// Further replicas are created by forking this replica; seeding more
+    /// than one replica of the same counter leads to lost increments.
+    pub fn seed() -> ItcCounter {
+        ItcCounter { id: Id::seed(), generation: 0, counts: HashMap::new() }
+    }
+
+    /// Create a new replica of the counter, splitting the identity of this
+    /// replica between the two.
+    ///
+    /// The new replica starts with the counts of this replica.
+    pub fn fork(&mut self) -> ItcCounter {
+        ItcCounter { id: self.id.fork(), generation: self.generation, counts: self.counts.clone() }
+    }
+
+    /// Retire `other`, taking over its identity and merging its counts.
+    ///
+    /// The counts of every identity within the joined identity are folded
+    /// into the count of the joined identity, in a new generation.
+    ///
+    /// `other` **must not** be used afterwards, since its identity now
+    /// belongs to this replica.
+    ///
+    /// ##### Example
+    ///
+    /// ```
+    /// # use crdt::counter::ItcCounter;
+    /// let mut a = ItcCounter::seed();
+    /// let mut b = a.fork();
+    /// b.increment(13).unwrap();
+    ///
+    /// a.join(b);
+    /// assert_eq!(13, a.count());
+    /// assert_eq!(ItcCounter::seed().id(), a.id());
+    /// ```
+    pub fn join(&mut self, other: ItcCounter) {
+        let ItcCounter { id, generation, counts } = other;
+        self.merge(ItcCounter { id: Id::anonymous(), generation: generation, counts: counts });
+        self.id.join(id);
+        self.generation = cmp::max(self.generation, generation) + 1;
+
+        let counts = mem::replace(&mut self.counts, HashMap::new());
+        let (folded, counts): (HashMap<_, _>, HashMap<_, _>) =
+            counts.into_iter().partition(|&((ref id, _), _)| self.id.contains(id));
+        self.counts = counts;
+        let count = folded.values().fold(0, |a, &b| a + b);
+        if count > 0 {
+            self.counts.insert((self.id.clone(), self.generation), count);
+        }
+    }
+
+    /// Get the current count of the counter.
+    pub fn count(&self) -> u64 {
+        self.counts.values().map(|&x| x).fold(0, |a, b| a + b)
+    }
+
+    /// Increment the counter by `amount`.
+    ///
+    /// The increment is recorded against the current identity and
+    /// generation of the replica.
+    ///
+    /// Returns `Error::CounterOverflow` if the count of this replica would
+    /// overflow, in which case the counter is unchanged.
+    ///
+    /// ##### Panics
+    ///
+    /// Panics if the replica has the anonymous identity.
+    pub fn increment(&mut self, amount: u64) -> Result<ItcCounterOp, Error> {
+        assert!(!self.id.is_anonymous(), "an anonymous counter cannot be incremented");
+        let count = self.counts.entry((self.id.clone(), self.generation)).or_insert(0);
+        *count = try!(count.checked_add(amount).ok_or(Error::CounterOverflow));
+        Ok(ItcCounterOp { id: self.id.clone(), generation: self.generation, count: *count })
+    }
+
+    /// Get the identity of this replica.
+    pub fn id(&self) -> &Id {
+        &self.id
+    }
+
+}
+
+/// Returns true if the count of `a` has folded in the count of `b`, that is
+/// if `a` was joined in a later generation from identities including `b`.
+fn supersedes(a: &(Id, u64), b: &(Id, u64)) -> bool {
+    a.1 > b.1 && a.0.contains(&b.0)
+}
+
+/// Records `count` for an identity and generation, unless it has been folded
+/// into a later generation, and discards the counts which it supersedes.
+fn insert(counts: &mut HashMap<(Id, u64), u64>, key: (Id, u64), count: u64) {
+    if counts.keys().any(|other| supersedes(other, &key)) {
+        return;
+    }
+    let superseded: Vec<(Id, u64)> = counts.keys().filter(|other| supersedes(&key, other)).cloned().collect();
+    for other in superseded {
+        counts.remove(&other);
+    }
+    let entry = counts.entry(key).or_insert(0);
+    *entry = cmp::max(*entry, count);
+}
+
+/// Collects counts, discarding those which have been folded.
+fn collect<I>(entries: I) -> HashMap<(Id, u64), u64> where I: IntoIterator<Item=((Id, u64), u64)> {
+    let mut counts = HashMap::new();
+    for (key, count) in entries {
+        insert(&mut counts, key, count);
+    }
+    counts
+}
+
+impl Crdt for ItcCounter {
+
+    type Operation = ItcCounterOp;
+
+    /// Merge a replica into this counter.
+    ///
+    /// This method is used to perform state-based replication. Unlike
+    /// `join`, merging does not change the identity of this replica.
+    fn merge(&mut self, other: ItcCounter) {
+        for (key, count) in other.counts {
+            insert(&mut self.counts, key, count);
+        }
+    }
+
+    /// Apply an increment operation to this counter.
+    ///
+    /// This method is used to perform operation-based replication.
+    ///
+    /// Applying an operation to an `ItcCounter` is idempotent.
+    fn apply(&mut self, op: ItcCounterOp) {
+        let ItcCounterOp { id, generation, count } = op;
+        insert(&mut self.counts, (id, generation), count);
+    }
+}
+
+impl Observable for ItcCounter {
+
+    type Value = u64;
+
+    fn value(&self) -> u64 {
+        self.count()
+    }
+}
+
+impl PartialEq for ItcCounter {
+    fn eq(&self, other: &ItcCounter) -> bool {
+        self.counts == other.counts
+    }
+}
+
+impl Eq for ItcCounter {}
+
+impl PartialOrd for ItcCounter {
+    fn partial_cmp(&self, other: &ItcCounter) -> Option<Ordering> {
+
+        /// Returns true if `a` has a count which `b` has neither reached nor
+        /// folded in.
+        fn a_gt_b(a: &ItcCounter, b: &ItcCounter) -> bool {
+            a.counts.iter().any(|(key, a_count)| {
+                match b.counts.get(key) {
+                    Some(b_count) => a_count > b_count,
+                    None => !b.counts.keys().any(|other| supersedes(other, key)),
+                }
+            })
+        }
+
+        match (a_gt_b(self, other), a_gt_b(other, self)) {
+            (true, true)   => None,
+            (true, false)  => Some(Greater),
+            (false, true)  => Some(Less),
+            (false, false) => Some(Equal)
+        }
+    }
+}
+
+/// Serializes the counts as a sequence of pairs, since identities cannot be
+/// map keys in most formats.
+#[cfg(feature = "serde")]
+mod counts {
+
+    use std::collections::HashMap;
+
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    use clock::Id;
+
+    pub fn serialize<S>(counts: &HashMap<(Id, u64), u64>, serializer: S) -> Result<S::Ok, S::Error>
+    where S: Serializer {
+        serializer.collect_seq(counts.iter())
+    }
+
+    pub fn deserialize<'de, D>(deserializer: D) -> Result<HashMap<(Id, u64), u64>, D::Error>
+    where D: Deserializer<'de> {
+        let counts: Vec<((Id, u64), u64)> = try!(Deserialize::deserialize(deserializer));
+        Ok(super::collect(counts))
+    }
+}
+
+impl Encode for ItcCounter {
+    fn encode(&self, buf: &mut Vec<u8>) {
+        self.id.encode(buf);
+        self.generation.encode(buf);
+        wire::write_entries(buf, self.counts.iter(), |(&(ref id, generation), count), buf| {
+            id.encode(buf);
+            generation.encode(buf);
+            count.encode(buf);
+        });
+    }
+}
+
+impl Decode for ItcCounter {
+    fn decode(reader: &mut Reader) -> Result<ItcCounter, DecodeError> {
+        let id = try!(Decode::decode(reader));
+        let generation = try!(Decode::decode(reader));
+        let counts: HashMap<(Id, u64), u64> = try!(wire::read_map(reader, |reader| {
+            let key = (try!(Decode::decode(reader)), try!(Decode::decode(reader)));
+            Ok((key, try!(Decode::decode(reader))))
+        }));
+        Ok(ItcCounter { id: id, generation: generation, counts: collect(counts) })
+    }
+}
+
+impl Message for ItcCounter {
+    const TAG: u8 = 15;
+}
+
+impl Encode for ItcCounterOp {
+    fn encode(&self, buf: &mut Vec<u8>) {
+        self.id.encode(buf);
+        self.generation.encode(buf);
+        self.count.encode(buf);
+    }
+}
+
+impl Decode for ItcCounterOp {
+    fn decode(reader: &mut Reader) -> Result<ItcCounterOp, DecodeError> {
+        Ok(ItcCounterOp {
+            id: try!(Decode::decode(reader)),
+            generation: try!(Decode::decode(reader)),
+            count: try!(Decode::decode(reader)),
+        })
+    }
+}
+
+impl Message for ItcCounterOp {
+    const TAG: u8 = 16;
+}
+
+#[cfg(any(feature = "quickcheck", test))]
+impl Arbitrary for ItcCounter {
+    fn arbitrary<G>(g: &mut G) -> ItcCounter where G: Gen {
+        let id = Id::arbitrary(g);
+        let id = if id.is_anonymous() { Id::seed() } else { id };
+        // A live replica has a later generation than any count it has seen.
+        let generation = u8::max_value() as u64 + 1;
+        let counts: Vec<((Id, u8), u64)> = Arbitrary::arbitrary(g);
+        let counts = counts.into_iter().map(|((id, generation), count)| ((id, generation as u64), count));
+        ItcCounter { id: id, generation: generation, counts: collect(counts) }
+    }
+    fn shrink(&self) -> Box<Iterator<Item=ItcCounter> + 'static> {
+        let (id, generation) = (self.id.clone(), self.generation);
+        let counts: Vec<((Id, u64), u64)> = self.counts.clone().into_iter().collect();
+        Box::new(counts.shrink().map(move |counts| {
+            ItcCounter { id: id.clone(), generation: generation, counts: collect(counts) }
+        }))
+    }
+}
+
+#[cfg(any(feature = "quickcheck", test))]
+impl Arbitrary for ItcCounterOp {
+    fn arbitrary<G>(g: &mut G) -> ItcCounterOp where G: Gen {
+        let generation = u8::arbitrary(g) as u64;
+        ItcCounterOp { id: Arbitrary::arbitrary(g), generation: generation, count: Arbitrary::arbitrary(g) }
+    }
+    fn shrink(&self) -> Box<Iterator<Item=ItcCounterOp> + 'static> {
+        let (id, generation) = (self.id.clone(), self.generation);
+        Box::new(self.count.shrink().map(move |count| {
+            ItcCounterOp { id: id.clone(), generation: generation, count: count }
+        }))
+    }
+}
+
+#[cfg(test)]
+mod test {
+
+    use quickcheck::quickcheck;
+
+    use {Crdt, testkit, wire};
+    use clock::Error;
+    use counter::{ItcCounter, ItcCounterOp};
+
+    type C = ItcCounter;
+    type O = ItcCounterOp;
+
+    #[test]
+    fn check_apply_is_commutative() {
+        quickcheck(testkit::apply_is_commutative::<C> as fn(C, Vec<O>) -> bool);
+    }
+
+    #[test]
+    fn check_merge_is_commutative() {
+        quickcheck(testkit::merge_is_commutative::<C> as fn(C, Vec<C>) -> bool);
+    }
+
+    #[test]
+    fn check_all() {
+        testkit::check_all::<C>();
+    }
+
+    #[cfg(feature = "serde")]
+    #[quickcheck]
+    fn check_serde_round_trip(crdt: C, op: O) -> bool {
+        ::test_util::serde_round_trip(crdt, op, |a, b| a.id() == b.id())
+    }
+
+    #[quickcheck]
+    fn check_wire_round_trip(crdt: C, op: O) -> bool {
+        let decoded: C = wire::from_bytes(&wire::to_bytes(&crdt)).unwrap();
+        let decoded_op: O = wire::from_bytes(&wire::to_bytes(&op)).unwrap();
+        decoded == crdt && decoded.id() == crdt.id() && wire::to_bytes(&decoded_op) == wire::to_bytes(&op)
+    }
+
+    /// Runs a script of fork, join, merge, and increment actions among
+    /// replicas, checking that no increment is lost once every replica has
+    /// been merged.
+    #[quickcheck]
+    fn check_churn_loses_no_increments(script: Vec<(u8, u8, u8, u8)>) -> bool {
+        let mut replicas = vec![ItcCounter::seed()];
+        let mut retired = Vec::new();
+        let mut total = 0;
+        for (action, a, b, amount) in script {
+            let a = a as usize % replicas.len();
+            let b = b as usize % replicas.len();
+            match action % 4 {
+                0 => {
+                    let replica = replicas[a].fork();
+                    replicas.push(replica);
+                },
+                1 if a != b => {
+                    let other = replicas.remove(b);
+                    let a = if a > b { a - 1 } else { a };
+                    retired.push(other.clone());
+                    replicas[a].join(other);
+                },
+                2 => {
+                    let other = replicas[b].clone();
+                    replicas[a].merge(other);
+                },
+                _ => {
+                    replicas[a].increment(amount as u64).unwrap();
+                    total += amount as u64;
+                },
+            }
+        }
+        // Stale copies of retired replicas do not add their folded counts
+        // again.
+        let mut merged = ItcCounter::seed();
+        for replica in retired.into_iter().chain(replicas) {
+            merged.merge(replica);
+        }
+        merged.count() == total
+    }
+
+    #[test]
+    fn check_join_folds_counts() {
+        let mut a = ItcCounter::seed();
+        for _ in 0..100 {
+            let mut b = a.fork();
+            let mut c = b.fork();
+            b.increment(1).unwrap();
+            c.increment(2).unwrap();
+            a.increment(3).unwrap();
+            b.join(c);
+            a.join(b);
+        }
+        assert_eq!(600, a.count());
+        assert_eq!(1, a.counts.len());
+    }
+
+    #[test]
+    fn check_increment_overflow() {
+        let mut a = ItcCounter::seed();
+        a.increment(!0).unwrap();
+        assert_eq!(Err(Error::CounterOverflow), a.increment(1).map(|_| ()));
+        assert_eq!(!0, a.count());
+    }
+
+    #[quickcheck]
+    fn check_ordering_lt(mut a: C, b: C) -> bool {
+        a.merge(b.clone());
+        a.increment(1).unwrap();
+        a > b && b < a
+    }
+}
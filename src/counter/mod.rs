@@ -1,7 +1,9 @@
 //! Counter CRDTs.
 
 pub use self::gcounter::{GCounter, GCounterOp};
+pub use self::itccounter::{ItcCounter, ItcCounterOp};
 pub use self::pncounter::{PnCounter, PnCounterOp};
 
 mod gcounter;
+mod itccounter;
 mod pncounter;
@@ -2,20 +2,31 @@ use std::cmp::Ordering::{self, Greater, Less, Equal};
 use std::collections::HashMap;
 
 use {Crdt, ReplicaId};
+use observe::Observable;
+use replication;
+use wire::{self, Decode, DecodeError, Encode, Message, Migrations, Reader};
 use pn::Pn;
+use retire::{self, Retirements};
 
-#[cfg(any(quickcheck, test))]
+#[cfg(any(feature = "quickcheck", test))]
 use quickcheck::{Arbitrary, Gen};
 
 /// A incrementable and decrementable counter.
+///
+/// The entries of departed replicas can be removed by retiring them, as
+/// with `GCounter`.
 #[derive(Clone, Debug, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct PnCounter {
     replica_id: ReplicaId,
     counts: HashMap<ReplicaId, Pn>,
+    #[cfg_attr(feature = "serde", serde(default))]
+    retirements: Retirements,
 }
 
 /// An increment operation on a `PnCounter` CRDT.
 #[derive(Clone, Copy, Debug, Eq, PartialEq, Hash)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct PnCounterOp {
     replica_id: ReplicaId,
     pn: Pn,
@@ -38,7 +49,7 @@ impl PnCounter {
     /// ```
     pub fn new<R>(replica_id: R) -> PnCounter
     where R: Into<ReplicaId> {
-        PnCounter { replica_id: replica_id.into(), counts: HashMap::new() }
+        PnCounter { replica_id: replica_id.into(), counts: HashMap::new(), retirements: Retirements::new() }
     }
 
     /// Get the current count of the counter.
@@ -96,10 +107,149 @@ impl PnCounter {
         PnCounterOp { replica_id: self.replica_id, pn: pn.clone() }
     }
 
+    /// Returns the increment operations which, when applied to `other`, bring
+    /// it up to date with this counter.
+    ///
+    /// Applying the operations to `other` is equivalent to merging this counter
+    /// into `other`. No operations are returned for replicas whose counts are
+    /// already reflected in `other`.
+    ///
+    /// ##### Example
+    ///
+    /// ```
+    /// # use crdt::counter::PnCounter;
+    /// # use crdt::Crdt;
+    /// let mut local = PnCounter::new(42);
+    /// let mut remote = PnCounter::new(43);
+    ///
+    /// local.increment(-12);
+    /// remote.increment(13);
+    ///
+    /// for op in local.diff_ops(&remote) {
+    ///     remote.apply(op);
+    /// }
+    /// assert_eq!(1, remote.count());
+    /// ```
+    pub fn diff_ops(&self, other: &PnCounter) -> Vec<PnCounterOp> {
+        self.counts
+            .iter()
+            .filter(|&(replica_id, pn)| {
+                other.counts.get(replica_id).map_or(true, |other_pn| pn.p > other_pn.p || pn.n > other_pn.n)
+            })
+            .map(|(&replica_id, &pn)| PnCounterOp { replica_id: replica_id, pn: pn })
+            .collect()
+    }
+
     /// Get the replica ID of this counter.
     pub fn replica_id(&self) -> ReplicaId {
         self.replica_id
     }
+
+    /// Get the total increments and decrements contributed by a replica.
+    ///
+    /// ##### Example
+    ///
+    /// ```
+    /// # use crdt::counter::PnCounter;
+    /// let mut counter = PnCounter::new(42);
+    /// counter.increment(13);
+    /// counter.increment(-4);
+    /// assert_eq!((13, 4), counter.replica_count(42));
+    /// ```
+    pub fn replica_count<R>(&self, replica_id: R) -> (u64, u64) where R: Into<ReplicaId> {
+        self.counts.get(&replica_id.into()).map_or((0, 0), |pn| (pn.p, pn.n))
+    }
+
+    /// Returns a hash of the counts of every replica.
+    ///
+    /// The counts are hashed over their canonical encoding, in which they
+    /// are sorted, excluding this replica's ID and retirements, so replicas
+    /// which have converged have the same hash on every platform.
+    ///
+    /// ##### Example
+    ///
+    /// ```
+    /// # use crdt::counter::PnCounter;
+    /// # use crdt::Crdt;
+    /// let mut local = PnCounter::new(42);
+    /// let mut remote = PnCounter::new(43);
+    /// remote.increment(-13);
+    /// local.merge(remote.clone());
+    /// remote.merge(local.clone());
+    /// assert_eq!(local.content_hash(), remote.content_hash());
+    /// ```
+    pub fn content_hash(&self) -> u64 {
+        replication::fingerprint(|buf| {
+            wire::write_entries(buf, self.counts.iter(), |(replica_id, count), buf| {
+                replica_id.encode(buf);
+                count.encode(buf);
+            });
+        })
+    }
+
+    /// Begin retiring a departed replica.
+    ///
+    /// The departed replica **must not** increment the counter again. Once
+    /// every live replica has seen the retirement, `compact` folds the
+    /// departed replica's counts into this replica's counts.
+    ///
+    /// ##### Example
+    ///
+    /// ```
+    /// # use crdt::counter::PnCounter;
+    /// # use crdt::Crdt;
+    /// let mut a = PnCounter::new(1);
+    /// let mut b = PnCounter::new(2);
+    /// let mut departed = PnCounter::new(3);
+    /// departed.increment(-5);
+    /// b.merge(departed);
+    ///
+    /// a.retire(3);
+    /// b.merge(a.clone());
+    /// a.merge(b.clone());
+    /// assert_eq!(1, a.compact(vec![1, 2]));
+    ///
+    /// b.merge(a.clone());
+    /// assert_eq!(-5, b.count());
+    /// ```
+    ///
+    /// ##### Panics
+    ///
+    /// Panics if `departed` is the replica ID of this counter.
+    pub fn retire<R>(&mut self, departed: R) where R: Into<ReplicaId> {
+        self.retirements.retire(departed.into(), self.replica_id);
+    }
+
+    /// Returns true if every replica in `live` has seen the retirement of
+    /// `departed` in its current phase, as far as this replica knows.
+    pub fn is_retirement_stable<R, I, L>(&self, departed: R, live: I) -> bool
+    where R: Into<ReplicaId>, I: IntoIterator<Item=L>, L: Into<ReplicaId> {
+        self.retirements.is_stable(departed.into(), &retire::live_set(self.replica_id, live))
+    }
+
+    /// Advance the retirements owned by this replica which every replica in
+    /// `live` has seen, folding the counts of departed replicas into this
+    /// replica's counts, and dropping the retirements which are complete.
+    ///
+    /// `live` **must** include every replica which may still merge state into
+    /// the counter. Returns the number of departed replicas folded.
+    pub fn compact<I, L>(&mut self, live: I) -> usize
+    where I: IntoIterator<Item=L>, L: Into<ReplicaId> {
+        let live = retire::live_set(self.replica_id, live);
+        let replica_id = self.replica_id;
+        let counts = &mut self.counts;
+        self.retirements.compact(replica_id, &live, |departed| {
+            if let Some(departed) = counts.remove(&departed) {
+                counts.entry(replica_id).or_insert(Pn::new()).add(departed);
+            }
+        })
+    }
+
+    /// Discards the counts of retired replicas which have been folded.
+    fn discard_retired(&mut self) {
+        let retirements = &self.retirements;
+        self.counts.retain(|&replica_id, _| !retirements.is_folded(replica_id));
+    }
 }
 
 impl Crdt for PnCounter {
@@ -126,7 +276,12 @@ impl Crdt for PnCounter {
     /// assert_eq!(1, local.count());
     /// ```
     fn merge(&mut self, other: PnCounter) {
+        self.retirements.merge(other.retirements, self.replica_id);
+        self.discard_retired();
         for (replica_id, pn) in other.counts.into_iter() {
+            if self.retirements.is_folded(replica_id) {
+                continue;
+            }
             self.counts.entry(replica_id).or_insert(Pn::new()).merge(pn);
         }
     }
@@ -152,10 +307,22 @@ impl Crdt for PnCounter {
     /// ```
     fn apply(&mut self, op: PnCounterOp) {
         let PnCounterOp { replica_id, pn } = op;
+        if self.retirements.is_folded(replica_id) {
+            return;
+        }
         self.counts.entry(replica_id).or_insert(Pn::new()).merge(pn);
     }
 }
 
+impl Observable for PnCounter {
+
+    type Value = i64;
+
+    fn value(&self) -> i64 {
+        self.count()
+    }
+}
+
 impl PartialEq for PnCounter {
     fn eq(&self, other: &PnCounter) -> bool {
         self.counts == other.counts
@@ -193,19 +360,75 @@ impl PartialOrd for PnCounter {
     }
 }
 
-#[cfg(any(quickcheck, test))]
+impl Encode for PnCounter {
+    fn encode(&self, buf: &mut Vec<u8>) {
+        self.replica_id.encode(buf);
+        wire::write_entries(buf, self.counts.iter(), |(replica_id, pn), buf| {
+            replica_id.encode(buf);
+            pn.encode(buf);
+        });
+        self.retirements.encode(buf);
+    }
+}
+
+impl Decode for PnCounter {
+    fn decode(reader: &mut Reader) -> Result<PnCounter, DecodeError> {
+        let mut counter = try!(decode_schema_1(reader));
+        counter.retirements = try!(Decode::decode(reader));
+        Ok(counter)
+    }
+}
+
+/// Decodes the schema 1 encoding, which has no retirements.
+fn decode_schema_1(reader: &mut Reader) -> Result<PnCounter, DecodeError> {
+    let replica_id = try!(Decode::decode(reader));
+    let counts = try!(wire::read_map(reader, |reader| {
+        Ok((try!(Decode::decode(reader)), try!(Decode::decode(reader))))
+    }));
+    Ok(PnCounter { replica_id: replica_id, counts: counts, retirements: Retirements::new() })
+}
+
+impl Message for PnCounter {
+    const TAG: u8 = 3;
+    const SCHEMA: u32 = 2;
+
+    fn migrations() -> Migrations<PnCounter> {
+        Migrations::new().register(1, decode_schema_1)
+    }
+}
+
+impl Encode for PnCounterOp {
+    fn encode(&self, buf: &mut Vec<u8>) {
+        self.replica_id.encode(buf);
+        self.pn.encode(buf);
+    }
+}
+
+impl Decode for PnCounterOp {
+    fn decode(reader: &mut Reader) -> Result<PnCounterOp, DecodeError> {
+        Ok(PnCounterOp { replica_id: try!(Decode::decode(reader)), pn: try!(Decode::decode(reader)) })
+    }
+}
+
+impl Message for PnCounterOp {
+    const TAG: u8 = 4;
+}
+
+#[cfg(any(feature = "quickcheck", test))]
 impl Arbitrary for PnCounter {
     fn arbitrary<G>(g: &mut G) -> PnCounter where G: Gen {
         use gen_replica_id;
-        PnCounter { replica_id: gen_replica_id(), counts: Arbitrary::arbitrary(g) }
+        PnCounter { replica_id: gen_replica_id(), counts: Arbitrary::arbitrary(g), retirements: Retirements::new() }
     }
     fn shrink(&self) -> Box<Iterator<Item=PnCounter> + 'static> {
         let replica_id = self.replica_id();
-        Box::new(self.counts.shrink().map(move |counts| PnCounter { replica_id: replica_id, counts: counts }))
+        Box::new(self.counts.shrink().map(move |counts| {
+            PnCounter { replica_id: replica_id, counts: counts, retirements: Retirements::new() }
+        }))
     }
 }
 
-#[cfg(any(quickcheck, test))]
+#[cfg(any(feature = "quickcheck", test))]
 impl Arbitrary for PnCounterOp {
     fn arbitrary<G>(g: &mut G) -> PnCounterOp where G: Gen {
         PnCounterOp { replica_id: Arbitrary::arbitrary(g), pn: Arbitrary::arbitrary(g) }
@@ -221,7 +444,7 @@ mod test {
 
     use quickcheck::quickcheck;
 
-    use {Crdt, ReplicaId, test};
+    use {Crdt, ReplicaId, testkit};
     use super::{PnCounter, PnCounterOp};
 
     type C = PnCounter;
@@ -229,22 +452,38 @@ mod test {
 
     #[test]
     fn check_apply_is_commutative() {
-        quickcheck(test::apply_is_commutative::<C> as fn(C, Vec<O>) -> bool);
+        quickcheck(testkit::apply_is_commutative::<C> as fn(C, Vec<O>) -> bool);
     }
 
     #[test]
     fn check_merge_is_commutative() {
-        quickcheck(test::merge_is_commutative::<C> as fn(C, Vec<C>) -> bool);
+        quickcheck(testkit::merge_is_commutative::<C> as fn(C, Vec<C>) -> bool);
     }
 
     #[test]
     fn check_ordering_lte() {
-        quickcheck(test::ordering_lte::<C> as fn(C, C) -> bool);
+        quickcheck(testkit::ordering_lte::<C> as fn(C, C) -> bool);
     }
 
     #[test]
     fn check_ordering_equality() {
-        quickcheck(test::ordering_equality::<C> as fn(C, C) -> bool);
+        quickcheck(testkit::ordering_equality::<C> as fn(C, C) -> bool);
+    }
+
+    #[test]
+    fn check_all() {
+        testkit::check_all::<C>();
+    }
+
+    #[cfg(feature = "serde")]
+    #[quickcheck]
+    fn check_serde_round_trip(crdt: C, op: O) -> bool {
+        ::test_util::serde_round_trip(crdt, op, |a, b| a.replica_id() == b.replica_id())
+    }
+
+    #[quickcheck]
+    fn check_diff_ops_is_merge(a: C, b: C) -> bool {
+        testkit::diff_ops_is_merge(a, b, C::diff_ops)
     }
 
     #[quickcheck]
@@ -270,4 +509,41 @@ mod test {
         b.increment(-1);
         a.partial_cmp(&b) == None && b.partial_cmp(&a) == None
     }
+
+    #[test]
+    fn check_retirement() {
+        let mut a = PnCounter::new(1);
+        let mut b = PnCounter::new(2);
+        let mut departed = PnCounter::new(3);
+        departed.increment(7);
+        departed.increment(-2);
+        b.merge(departed);
+        a.increment(-1);
+
+        a.retire(3);
+        b.merge(a.clone());
+        a.merge(b.clone());
+        assert!(a.is_retirement_stable(3, vec![2]));
+        assert_eq!(1, a.compact(vec![2]));
+        assert_eq!(4, a.count());
+        assert_eq!(1, a.counts.len());
+
+        b.merge(a.clone());
+        assert_eq!(4, b.count());
+        assert!(!b.counts.contains_key(&ReplicaId(3)));
+
+        // Once the retirement is dropped, stale operations and states from
+        // the departed replica are still discarded.
+        a.merge(b.clone());
+        a.compact(vec![2]);
+        b.merge(a.clone());
+        let mut stale = PnCounter::new(3);
+        let op = stale.increment(7);
+        for counter in &mut [a, b] {
+            counter.apply(op);
+            counter.merge(stale.clone());
+            assert_eq!(4, counter.count());
+            assert!(!counter.counts.contains_key(&ReplicaId(3)));
+        }
+    }
 }
@@ -0,0 +1,817 @@
+//! A persistent hash map.
+//!
+//! `HashTrieMap` is a hash array mapped trie: a 32-way tree indexed by
+//! successive 5-bit chunks of each key's hash, whose nodes are shared between
+//! clones through reference counting. Cloning a map is a constant-time
+//! pointer copy, and mutating a clone copies only the nodes on the path to
+//! the mutated entry, so a reader can hold a consistent snapshot of a map
+//! while a writer keeps mutating it.
+//!
+//! `HashTrieMap` implements `MapStore`, so `LwwSet` and `PnSet` can hold their
+//! elements in one rather than a `HashMap`; the `PersistentLwwSet` and
+//! `PersistentPnSet` aliases in `set` name these types.
+//!
+//! This module is only available when the `persistent` feature is enabled.
+
+use std::borrow::Borrow;
+use std::collections::HashMap;
+use std::collections::hash_map::DefaultHasher;
+use std::fmt;
+use std::hash::{Hash, Hasher};
+use std::iter::FromIterator;
+use std::mem;
+use std::slice;
+use std::sync::Arc;
+use std::vec;
+
+#[cfg(any(feature = "quickcheck", test))]
+use quickcheck::{Arbitrary, Gen};
+
+/// The number of hash bits which index each level of the trie.
+const BITS: usize = 5;
+
+/// A persistent hash map with constant-time clones.
+///
+/// `HashTrieMap` supports the subset of the `HashMap` API used by the set
+/// CRDTs. Mutations require `K: Clone` and `V: Clone`, since entries in
+/// nodes shared with a clone are copied before they are mutated.
+///
+/// ##### Example
+///
+/// ```
+/// use crdt::hamt::HashTrieMap;
+///
+/// let mut map = HashTrieMap::new();
+/// map.insert("a", 1);
+/// let snapshot = map.clone();
+/// map.insert("b", 2);
+/// *map.get_mut(&"a").unwrap() = 3;
+///
+/// assert_eq!(Some(&1), snapshot.get(&"a"));
+/// assert_eq!(None, snapshot.get(&"b"));
+/// assert_eq!(Some(&3), map.get(&"a"));
+/// assert_eq!(2, map.len());
+/// ```
+pub struct HashTrieMap<K, V> {
+    root: Arc<Node<K, V>>,
+    len: usize,
+}
+
+/// A node of the trie. `bitmap` has a bit set for each of the 32 indices at
+/// this level which hold a slot, and `slots` holds those slots in index
+/// order.
+#[derive(Clone)]
+struct Node<K, V> {
+    bitmap: u32,
+    slots: Vec<Slot<K, V>>,
+}
+
+#[derive(Clone)]
+enum Slot<K, V> {
+    /// An entry, with the hash of its key.
+    Leaf(u64, K, V),
+    /// Two or more entries whose keys have the same hash.
+    Collision(u64, Vec<(K, V)>),
+    /// The entries whose hashes share this slot's index, which are
+    /// distinguished at the next level.
+    Branch(Arc<Node<K, V>>),
+}
+
+/// An entry in a `HashTrieMap`, which may be occupied or vacant.
+pub enum Entry<'a, K: 'a, V: 'a> {
+    Occupied(OccupiedEntry<'a, K, V>),
+    Vacant(VacantEntry<'a, K, V>),
+}
+
+/// An occupied entry in a `HashTrieMap`.
+pub struct OccupiedEntry<'a, K: 'a, V: 'a> {
+    key: K,
+    value: &'a mut V,
+}
+
+/// A vacant entry in a `HashTrieMap`.
+pub struct VacantEntry<'a, K: 'a, V: 'a> {
+    map: &'a mut HashTrieMap<K, V>,
+    key: K,
+    hash: u64,
+}
+
+/// An iterator over the entries of a `HashTrieMap`.
+pub struct Iter<'a, K: 'a, V: 'a> {
+    stack: Vec<slice::Iter<'a, Slot<K, V>>>,
+    collision: Option<slice::Iter<'a, (K, V)>>,
+    remaining: usize,
+}
+
+/// An iterator over the values of a `HashTrieMap`.
+pub struct Values<'a, K: 'a, V: 'a> {
+    inner: Iter<'a, K, V>,
+}
+
+fn hash<Q>(key: &Q) -> u64 where Q: Hash + ?Sized {
+    let mut hasher = DefaultHasher::new();
+    key.hash(&mut hasher);
+    hasher.finish()
+}
+
+/// Returns the bit for the index of `hash` at a level of the trie.
+fn bit(hash: u64, depth: usize) -> u32 {
+    1 << ((hash >> (depth * BITS)) & 0x1f)
+}
+
+impl <K, V> Node<K, V> {
+
+    fn empty() -> Node<K, V> {
+        Node { bitmap: 0, slots: Vec::new() }
+    }
+
+    /// Returns the position in `slots` of the slot for `bit`.
+    fn position(&self, bit: u32) -> usize {
+        (self.bitmap & (bit - 1)).count_ones() as usize
+    }
+
+    /// Returns a node holding two slots whose hashes differ.
+    fn pair(depth: usize, a: Slot<K, V>, a_hash: u64, b: Slot<K, V>, b_hash: u64) -> Node<K, V> {
+        let (a_bit, b_bit) = (bit(a_hash, depth), bit(b_hash, depth));
+        if a_bit == b_bit {
+            let child = Node::pair(depth + 1, a, a_hash, b, b_hash);
+            Node { bitmap: a_bit, slots: vec![Slot::Branch(Arc::new(child))] }
+        } else if a_bit < b_bit {
+            Node { bitmap: a_bit | b_bit, slots: vec![a, b] }
+        } else {
+            Node { bitmap: a_bit | b_bit, slots: vec![b, a] }
+        }
+    }
+
+    /// Builds a node from entries whose hashes agree below `depth`, keeping
+    /// the last of any entries with equal keys. Returns the node and the
+    /// number of entries it holds.
+    fn build(depth: usize, entries: Vec<(u64, K, V)>) -> (Node<K, V>, usize) where K: Eq {
+        let mut buckets: Vec<Vec<(u64, K, V)>> = (0..32).map(|_| Vec::new()).collect();
+        for entry in entries {
+            let index = bit(entry.0, depth).trailing_zeros() as usize;
+            buckets[index].push(entry);
+        }
+        let mut node = Node::empty();
+        let mut len = 0;
+        for (index, mut bucket) in buckets.into_iter().enumerate() {
+            if bucket.is_empty() {
+                continue;
+            }
+            let hash = bucket[0].0;
+            let slot = if bucket.len() == 1 {
+                let (hash, key, value) = bucket.pop().unwrap();
+                len += 1;
+                Slot::Leaf(hash, key, value)
+            } else if bucket.iter().all(|entry| entry.0 == hash) {
+                let mut entries: Vec<(K, V)> = Vec::with_capacity(bucket.len());
+                for (_, key, value) in bucket {
+                    match entries.iter().position(|entry| entry.0 == key) {
+                        Some(i) => entries[i].1 = value,
+                        None => entries.push((key, value)),
+                    }
+                }
+                len += entries.len();
+                if entries.len() == 1 {
+                    let (key, value) = entries.pop().unwrap();
+                    Slot::Leaf(hash, key, value)
+                } else {
+                    Slot::Collision(hash, entries)
+                }
+            } else {
+                let (child, child_len) = Node::build(depth + 1, bucket);
+                len += child_len;
+                Slot::Branch(Arc::new(child))
+            };
+            node.bitmap |= 1 << index;
+            node.slots.push(slot);
+        }
+        (node, len)
+    }
+
+    fn get<Q>(&self, hash: u64, depth: usize, key: &Q) -> Option<&V> where K: Borrow<Q>, Q: Eq + ?Sized {
+        let bit = bit(hash, depth);
+        if self.bitmap & bit == 0 {
+            return None;
+        }
+        match self.slots[self.position(bit)] {
+            Slot::Leaf(leaf_hash, ref leaf_key, ref value) => {
+                if leaf_hash == hash && leaf_key.borrow() == key { Some(value) } else { None }
+            },
+            Slot::Collision(collision_hash, ref entries) => {
+                if collision_hash != hash {
+                    return None;
+                }
+                entries.iter().find(|entry| entry.0.borrow() == key).map(|entry| &entry.1)
+            },
+            Slot::Branch(ref child) => child.get(hash, depth + 1, key),
+        }
+    }
+}
+
+impl <K, V> Node<K, V> where K: Clone, V: Clone {
+
+    fn get_mut<Q>(&mut self, hash: u64, depth: usize, key: &Q) -> Option<&mut V>
+    where K: Borrow<Q>, Q: Eq + ?Sized {
+        let bit = bit(hash, depth);
+        if self.bitmap & bit == 0 {
+            return None;
+        }
+        let position = self.position(bit);
+        match self.slots[position] {
+            Slot::Leaf(leaf_hash, ref leaf_key, ref mut value) => {
+                if leaf_hash == hash && leaf_key.borrow() == key { Some(value) } else { None }
+            },
+            Slot::Collision(collision_hash, ref mut entries) => {
+                if collision_hash != hash {
+                    return None;
+                }
+                entries.iter_mut().find(|entry| entry.0.borrow() == key).map(|entry| &mut entry.1)
+            },
+            Slot::Branch(ref mut child) => Arc::make_mut(child).get_mut(hash, depth + 1, key),
+        }
+    }
+
+    fn insert(&mut self, hash: u64, depth: usize, key: K, value: V) -> Option<V> where K: Eq {
+        let bit = bit(hash, depth);
+        let position = self.position(bit);
+        if self.bitmap & bit == 0 {
+            self.slots.insert(position, Slot::Leaf(hash, key, value));
+            self.bitmap |= bit;
+            return None;
+        }
+        match self.slots[position] {
+            Slot::Leaf(leaf_hash, ref leaf_key, ref mut leaf_value) if leaf_hash == hash && *leaf_key == key => {
+                return Some(mem::replace(leaf_value, value));
+            },
+            Slot::Collision(collision_hash, ref mut entries) if collision_hash == hash => {
+                if let Some(entry) = entries.iter_mut().find(|entry| entry.0 == key) {
+                    return Some(mem::replace(&mut entry.1, value));
+                }
+                entries.push((key, value));
+                return None;
+            },
+            Slot::Branch(ref mut child) => return Arc::make_mut(child).insert(hash, depth + 1, key, value),
+            _ => (),
+        }
+
+        // The slot holds a different key, so it is replaced by a collision or
+        // a branch holding both.
+        let existing = mem::replace(&mut self.slots[position], Slot::Collision(hash, Vec::new()));
+        self.slots[position] = match existing {
+            Slot::Leaf(leaf_hash, leaf_key, leaf_value) if leaf_hash == hash => {
+                Slot::Collision(hash, vec![(leaf_key, leaf_value), (key, value)])
+            },
+            Slot::Leaf(existing_hash, ..) | Slot::Collision(existing_hash, _) => {
+                let leaf = Slot::Leaf(hash, key, value);
+                Slot::Branch(Arc::new(Node::pair(depth + 1, existing, existing_hash, leaf, hash)))
+            },
+            Slot::Branch(_) => unreachable!(),
+        };
+        None
+    }
+
+    fn remove<Q>(&mut self, hash: u64, depth: usize, key: &Q) -> Option<V> where K: Borrow<Q>, Q: Eq + ?Sized {
+        let bit = bit(hash, depth);
+        if self.bitmap & bit == 0 {
+            return None;
+        }
+        let position = self.position(bit);
+        let removed = match self.slots[position] {
+            Slot::Leaf(leaf_hash, ref leaf_key, _) => {
+                if leaf_hash != hash || leaf_key.borrow() != key {
+                    return None;
+                }
+                None
+            },
+            Slot::Collision(collision_hash, ref mut entries) => {
+                if collision_hash != hash {
+                    return None;
+                }
+                match entries.iter().position(|entry| entry.0.borrow() == key) {
+                    Some(i) => Some(entries.swap_remove(i).1),
+                    None => return None,
+                }
+            },
+            Slot::Branch(ref mut child) => match Arc::make_mut(child).remove(hash, depth + 1, key) {
+                Some(value) => Some(value),
+                None => return None,
+            },
+        };
+        match removed {
+            // The slot is a leaf holding the key.
+            None => match self.remove_slot(position, bit) {
+                Slot::Leaf(_, _, value) => Some(value),
+                _ => unreachable!(),
+            },
+            Some(value) => {
+                self.compress(position);
+                Some(value)
+            },
+        }
+    }
+
+    fn remove_slot(&mut self, position: usize, bit: u32) -> Slot<K, V> {
+        self.bitmap &= !bit;
+        self.slots.remove(position)
+    }
+
+    /// Replaces a collision holding one entry with a leaf, and a branch
+    /// holding one leaf or collision with that slot.
+    fn compress(&mut self, position: usize) {
+        let replacement = match self.slots[position] {
+            Slot::Collision(hash, ref mut entries) if entries.len() == 1 => {
+                let (key, value) = entries.pop().unwrap();
+                Slot::Leaf(hash, key, value)
+            },
+            Slot::Branch(ref mut child) if child.slots.len() == 1 => {
+                match child.slots[0] {
+                    Slot::Branch(_) => return,
+                    _ => Arc::make_mut(child).slots.pop().unwrap(),
+                }
+            },
+            _ => return,
+        };
+        self.slots[position] = replacement;
+    }
+
+    /// Retains the entries for which `f` returns true, returning the number
+    /// of entries removed.
+    fn retain<F>(&mut self, f: &mut F) -> usize where F: FnMut(&K, &mut V) -> bool {
+        let mut removed = 0;
+        let mut bits = self.bitmap;
+        let slots = mem::replace(&mut self.slots, Vec::new());
+        self.bitmap = 0;
+        for slot in slots {
+            let bit = bits & bits.wrapping_neg();
+            bits &= !bit;
+            let slot = match slot {
+                Slot::Leaf(hash, key, mut value) => {
+                    if f(&key, &mut value) {
+                        Slot::Leaf(hash, key, value)
+                    } else {
+                        removed += 1;
+                        continue;
+                    }
+                },
+                Slot::Collision(hash, entries) => {
+                    let len = entries.len();
+                    let entries: Vec<(K, V)> = entries.into_iter()
+                                                      .filter_map(|(key, mut value)| {
+                                                          if f(&key, &mut value) { Some((key, value)) } else { None }
+                                                      })
+                                                      .collect();
+                    removed += len - entries.len();
+                    if entries.is_empty() {
+                        continue;
+                    }
+                    Slot::Collision(hash, entries)
+                },
+                Slot::Branch(mut child) => {
+                    removed += Arc::make_mut(&mut child).retain(f);
+                    if child.slots.is_empty() {
+                        continue;
+                    }
+                    Slot::Branch(child)
+                },
+            };
+            self.bitmap |= bit;
+            self.slots.push(slot);
+            let position = self.slots.len() - 1;
+            self.compress(position);
+        }
+        removed
+    }
+
+    fn values_mut<'a>(&'a mut self, values: &mut Vec<&'a mut V>) {
+        for slot in self.slots.iter_mut() {
+            match *slot {
+                Slot::Leaf(_, _, ref mut value) => values.push(value),
+                Slot::Collision(_, ref mut entries) => values.extend(entries.iter_mut().map(|entry| &mut entry.1)),
+                Slot::Branch(ref mut child) => Arc::make_mut(child).values_mut(values),
+            }
+        }
+    }
+
+    fn into_entries(node: Arc<Node<K, V>>, entries: &mut Vec<(K, V)>) {
+        match Arc::try_unwrap(node) {
+            Ok(node) => {
+                for slot in node.slots {
+                    match slot {
+                        Slot::Leaf(_, key, value) => entries.push((key, value)),
+                        Slot::Collision(_, collision) => entries.extend(collision),
+                        Slot::Branch(child) => Node::into_entries(child, entries),
+                    }
+                }
+            },
+            Err(node) => node.clone_entries(entries),
+        }
+    }
+
+    fn clone_entries(&self, entries: &mut Vec<(K, V)>) {
+        for slot in self.slots.iter() {
+            match *slot {
+                Slot::Leaf(_, ref key, ref value) => entries.push((key.clone(), value.clone())),
+                Slot::Collision(_, ref collision) => entries.extend(collision.iter().cloned()),
+                Slot::Branch(ref child) => child.clone_entries(entries),
+            }
+        }
+    }
+}
+
+impl <K, V> HashTrieMap<K, V> {
+
+    /// Creates an empty map.
+    pub fn new() -> HashTrieMap<K, V> {
+        HashTrieMap { root: Arc::new(Node::empty()), len: 0 }
+    }
+
+    /// Creates an empty map. Tries do not preallocate, so the capacity is
+    /// ignored; this exists for parity with `HashMap`.
+    pub fn with_capacity(_capacity: usize) -> HashTrieMap<K, V> {
+        HashTrieMap::new()
+    }
+
+    /// Returns the number of entries in the map.
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Returns true if the map holds no entries.
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Returns an iterator over the entries of the map, in an unspecified
+    /// order.
+    pub fn iter<'a>(&'a self) -> Iter<'a, K, V> {
+        Iter { stack: vec![self.root.slots.iter()], collision: None, remaining: self.len }
+    }
+
+    /// Returns an iterator over the values of the map.
+    pub fn values<'a>(&'a self) -> Values<'a, K, V> {
+        Values { inner: self.iter() }
+    }
+
+    /// Returns true if both maps share the same root, so that they hold the
+    /// same entries.
+    pub fn ptr_eq(&self, other: &HashTrieMap<K, V>) -> bool {
+        Arc::ptr_eq(&self.root, &other.root)
+    }
+}
+
+impl <K, V> HashTrieMap<K, V> where K: Eq + Hash {
+
+    /// Returns the value for a key.
+    pub fn get<Q>(&self, key: &Q) -> Option<&V> where K: Borrow<Q>, Q: Eq + Hash + ?Sized {
+        self.root.get(hash(key), 0, key)
+    }
+
+    /// Returns true if the map holds a value for the key.
+    pub fn contains_key<Q>(&self, key: &Q) -> bool where K: Borrow<Q>, Q: Eq + Hash + ?Sized {
+        self.get(key).is_some()
+    }
+}
+
+impl <K, V> HashTrieMap<K, V> where K: Clone + Eq + Hash, V: Clone {
+
+    /// Returns a mutable reference to the value for a key, copying the
+    /// nodes on its path which are shared with a clone.
+    pub fn get_mut<Q>(&mut self, key: &Q) -> Option<&mut V> where K: Borrow<Q>, Q: Eq + Hash + ?Sized {
+        if !self.contains_key(key) {
+            return None;
+        }
+        Arc::make_mut(&mut self.root).get_mut(hash(key), 0, key)
+    }
+
+    /// Inserts a value for a key, returning the previous value.
+    pub fn insert(&mut self, key: K, value: V) -> Option<V> {
+        let previous = Arc::make_mut(&mut self.root).insert(hash(&key), 0, key, value);
+        if previous.is_none() {
+            self.len += 1;
+        }
+        previous
+    }
+
+    /// Removes the value for a key, returning it.
+    pub fn remove<Q>(&mut self, key: &Q) -> Option<V> where K: Borrow<Q>, Q: Eq + Hash + ?Sized {
+        if !self.contains_key(key) {
+            return None;
+        }
+        let removed = Arc::make_mut(&mut self.root).remove(hash(key), 0, key);
+        if removed.is_some() {
+            self.len -= 1;
+        }
+        removed
+    }
+
+    /// Returns the entry for a key, for in-place manipulation.
+    pub fn entry<'a>(&'a mut self, key: K) -> Entry<'a, K, V> {
+        let hash = hash(&key);
+        if self.root.get(hash, 0, &key).is_some() {
+            let value = Arc::make_mut(&mut self.root).get_mut(hash, 0, &key).unwrap();
+            Entry::Occupied(OccupiedEntry { key: key, value: value })
+        } else {
+            Entry::Vacant(VacantEntry { map: self, key: key, hash: hash })
+        }
+    }
+
+    /// Retains only the entries for which `f` returns true.
+    pub fn retain<F>(&mut self, mut f: F) where F: FnMut(&K, &mut V) -> bool {
+        let removed = Arc::make_mut(&mut self.root).retain(&mut f);
+        self.len -= removed;
+    }
+
+    /// Returns an iterator over mutable references to the values of the map,
+    /// copying the nodes which are shared with a clone.
+    pub fn values_mut(&mut self) -> vec::IntoIter<&mut V> {
+        let mut values = Vec::with_capacity(self.len);
+        Arc::make_mut(&mut self.root).values_mut(&mut values);
+        values.into_iter()
+    }
+}
+
+impl <'a, K, V> Entry<'a, K, V> where K: Clone + Eq + Hash, V: Clone {
+
+    /// Inserts `default` if the entry is vacant, and returns a mutable
+    /// reference to the value.
+    pub fn or_insert(self, default: V) -> &'a mut V {
+        match self {
+            Entry::Occupied(entry) => entry.into_mut(),
+            Entry::Vacant(entry) => entry.insert(default),
+        }
+    }
+
+    /// Inserts the result of `default` if the entry is vacant, and returns a
+    /// mutable reference to the value.
+    pub fn or_insert_with<F>(self, default: F) -> &'a mut V where F: FnOnce() -> V {
+        match self {
+            Entry::Occupied(entry) => entry.into_mut(),
+            Entry::Vacant(entry) => entry.insert(default()),
+        }
+    }
+}
+
+impl <'a, K, V> OccupiedEntry<'a, K, V> {
+
+    /// Returns the key of the entry.
+    pub fn key(&self) -> &K {
+        &self.key
+    }
+
+    /// Returns the value of the entry.
+    pub fn get(&self) -> &V {
+        self.value
+    }
+
+    /// Returns a mutable reference to the value of the entry.
+    pub fn get_mut(&mut self) -> &mut V {
+        self.value
+    }
+
+    /// Returns a mutable reference to the value with the lifetime of the map.
+    pub fn into_mut(self) -> &'a mut V {
+        self.value
+    }
+
+    /// Replaces the value of the entry, returning the previous value.
+    pub fn insert(&mut self, value: V) -> V {
+        mem::replace(self.value, value)
+    }
+}
+
+impl <'a, K, V> VacantEntry<'a, K, V> where K: Clone + Eq + Hash, V: Clone {
+
+    /// Returns the key of the entry.
+    pub fn key(&self) -> &K {
+        &self.key
+    }
+
+    /// Inserts a value for the entry's key, returning a mutable reference to
+    /// it.
+    pub fn insert(self, value: V) -> &'a mut V {
+        let VacantEntry { map, key, hash } = self;
+        let root = Arc::make_mut(&mut map.root);
+        root.insert(hash, 0, key.clone(), value);
+        map.len += 1;
+        root.get_mut(hash, 0, &key).unwrap()
+    }
+}
+
+impl <'a, K, V> Iterator for Iter<'a, K, V> {
+    type Item = (&'a K, &'a V);
+
+    fn next(&mut self) -> Option<(&'a K, &'a V)> {
+        loop {
+            if let Some(entry) = self.collision.as_mut().and_then(Iterator::next) {
+                self.remaining = self.remaining.saturating_sub(1);
+                return Some((&entry.0, &entry.1));
+            }
+            let slot = match self.stack.last_mut() {
+                Some(slots) => slots.next(),
+                None => return None,
+            };
+            match slot {
+                Some(Slot::Leaf(_, key, value)) => {
+                    self.remaining = self.remaining.saturating_sub(1);
+                    return Some((key, value));
+                },
+                Some(Slot::Collision(_, entries)) => self.collision = Some(entries.iter()),
+                Some(Slot::Branch(child)) => self.stack.push(child.slots.iter()),
+                None => { self.stack.pop(); },
+            }
+        }
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (self.remaining, Some(self.remaining))
+    }
+}
+
+impl <'a, K, V> ExactSizeIterator for Iter<'a, K, V> {}
+
+impl <'a, K, V> Iterator for Values<'a, K, V> {
+    type Item = &'a V;
+
+    fn next(&mut self) -> Option<&'a V> {
+        self.inner.next().map(|(_, value)| value)
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.inner.size_hint()
+    }
+}
+
+impl <'a, K, V> IntoIterator for &'a HashTrieMap<K, V> {
+    type Item = (&'a K, &'a V);
+    type IntoIter = Iter<'a, K, V>;
+
+    fn into_iter(self) -> Iter<'a, K, V> {
+        self.iter()
+    }
+}
+
+impl <K, V> IntoIterator for HashTrieMap<K, V> where K: Clone, V: Clone {
+    type Item = (K, V);
+    type IntoIter = vec::IntoIter<(K, V)>;
+
+    /// Returns the entries of the map, moving them out of the nodes which are
+    /// not shared with a clone, and copying them out of the rest.
+    fn into_iter(self) -> vec::IntoIter<(K, V)> {
+        let mut entries = Vec::with_capacity(self.len);
+        Node::into_entries(self.root, &mut entries);
+        entries.into_iter()
+    }
+}
+
+impl <K, V> FromIterator<(K, V)> for HashTrieMap<K, V> where K: Eq + Hash {
+    /// Builds a map from entries, keeping the last value for each key.
+    fn from_iter<I>(iter: I) -> HashTrieMap<K, V> where I: IntoIterator<Item=(K, V)> {
+        let entries = iter.into_iter().map(|(key, value)| (hash(&key), key, value)).collect();
+        let (root, len) = Node::build(0, entries);
+        HashTrieMap { root: Arc::new(root), len: len }
+    }
+}
+
+impl <K, V> Clone for HashTrieMap<K, V> {
+    /// Clones the map in constant time, by sharing its nodes.
+    fn clone(&self) -> HashTrieMap<K, V> {
+        HashTrieMap { root: self.root.clone(), len: self.len }
+    }
+}
+
+impl <K, V> Default for HashTrieMap<K, V> {
+    fn default() -> HashTrieMap<K, V> {
+        HashTrieMap::new()
+    }
+}
+
+impl <K, V> PartialEq for HashTrieMap<K, V> where K: Eq + Hash, V: PartialEq {
+    fn eq(&self, other: &HashTrieMap<K, V>) -> bool {
+        self.ptr_eq(other)
+            || self.len == other.len && self.iter().all(|(key, value)| other.get(key) == Some(value))
+    }
+}
+
+impl <K, V> Eq for HashTrieMap<K, V> where K: Eq + Hash, V: Eq {}
+
+impl <K, V> fmt::Debug for HashTrieMap<K, V> where K: fmt::Debug, V: fmt::Debug {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_map().entries(self.iter()).finish()
+    }
+}
+
+#[cfg(feature = "serde")]
+impl <K, V> ::serde::Serialize for HashTrieMap<K, V> where K: ::serde::Serialize, V: ::serde::Serialize {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error> where S: ::serde::Serializer {
+        serializer.collect_map(self.iter())
+    }
+}
+
+#[cfg(feature = "serde")]
+impl <'de, K, V> ::serde::Deserialize<'de> for HashTrieMap<K, V>
+where K: ::serde::Deserialize<'de> + Eq + Hash, V: ::serde::Deserialize<'de> {
+    fn deserialize<D>(deserializer: D) -> Result<HashTrieMap<K, V>, D::Error> where D: ::serde::Deserializer<'de> {
+        HashMap::deserialize(deserializer).map(|map: HashMap<K, V>| map.into_iter().collect())
+    }
+}
+
+#[cfg(any(feature = "quickcheck", test))]
+impl <K, V> Arbitrary for HashTrieMap<K, V>
+where K: Arbitrary + Eq + Hash + Sync, V: Arbitrary + Sync {
+    fn arbitrary<G>(g: &mut G) -> HashTrieMap<K, V> where G: Gen {
+        let map: HashMap<K, V> = Arbitrary::arbitrary(g);
+        map.into_iter().collect()
+    }
+    fn shrink(&self) -> Box<Iterator<Item=HashTrieMap<K, V>> + 'static> {
+        let map: HashMap<K, V> = self.clone().into_iter().collect();
+        Box::new(map.shrink().map(|map| map.into_iter().collect()))
+    }
+}
+
+#[cfg(test)]
+mod test {
+
+    use std::collections::HashMap;
+    use std::hash::{Hash, Hasher};
+
+    use super::HashTrieMap;
+
+    /// A key whose hash is shared by many other keys, so that lookups must
+    /// search collisions.
+    #[derive(Clone, Copy, Debug, PartialEq, Eq)]
+    struct Colliding(u16);
+
+    impl Hash for Colliding {
+        fn hash<H>(&self, state: &mut H) where H: Hasher {
+            (self.0 % 16).hash(state);
+        }
+    }
+
+    /// Applies a script of inserts, removes, retains and clones to a trie and
+    /// to a `HashMap`, checking that they agree throughout, and that clones
+    /// are unaffected by later mutations.
+    fn check_matches_hash_map<F>(script: Vec<(u8, u16, u16)>, key: F) -> bool
+    where F: Fn(u16) -> Colliding {
+        let mut map = HashTrieMap::new();
+        let mut expected = HashMap::new();
+        let mut snapshots = Vec::new();
+        for (action, k, value) in script {
+            let k = key(k);
+            match action % 6 {
+                0 | 1 => assert_eq!(expected.insert(k, value), map.insert(k, value)),
+                2 => assert_eq!(expected.remove(&k), map.remove(&k)),
+                3 => {
+                    expected.retain(|key, value| { *value += 1; (key.0 ^ *value) % 3 != 0 });
+                    map.retain(|key, value| { *value += 1; (key.0 ^ *value) % 3 != 0 });
+                },
+                4 => {
+                    if let Some(v) = expected.get_mut(&k) { *v = value; }
+                    if let Some(v) = map.get_mut(&k) { *v = value; }
+                },
+                _ => snapshots.push((map.clone(), expected.clone())),
+            }
+            assert_eq!(expected.get(&k), map.get(&k));
+        }
+        let entries: HashMap<Colliding, u16> = map.iter().map(|(&k, &v)| (k, v)).collect();
+        entries == expected
+            && map.len() == expected.len()
+            && map.iter().len() == expected.len()
+            && map.clone().into_iter().collect::<HashMap<_, _>>() == expected
+            && map == expected.clone().into_iter().collect()
+            && snapshots.into_iter().all(|(snapshot, expected)| {
+                snapshot.len() == expected.len()
+                    && snapshot.clone().into_iter().len() == expected.len()
+                    && expected.iter().all(|(k, v)| snapshot.get(k) == Some(v))
+            })
+    }
+
+    #[quickcheck]
+    fn check_distinct_hashes(script: Vec<(u8, u16, u16)>) -> bool {
+        check_matches_hash_map(script, |k| Colliding(k * 16 + k % 16))
+    }
+
+    #[quickcheck]
+    fn check_colliding_hashes(script: Vec<(u8, u16, u16)>) -> bool {
+        check_matches_hash_map(script, |k| Colliding(k % 64))
+    }
+
+    #[test]
+    fn check_large_map() {
+        let mut map: HashTrieMap<u32, u32> = (0..10000).map(|i| (i, i)).collect();
+        let snapshot = map.clone();
+        for i in 0..10000 {
+            if i % 2 == 0 {
+                map.remove(&i);
+            } else {
+                *map.entry(i).or_insert(0) += 1;
+            }
+        }
+        assert_eq!(5000, map.len());
+        assert!((0..10000).all(|i| snapshot.get(&i) == Some(&i)));
+        assert!((0..10000).all(|i| map.get(&i).cloned() == if i % 2 == 0 { None } else { Some(i + 1) }));
+        map.retain(|_, _| false);
+        assert!(map.is_empty());
+        assert_eq!(0, map.iter().count());
+    }
+}
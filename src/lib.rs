@@ -8,18 +8,46 @@
 #![cfg_attr(test, feature(custom_attribute, plugin))]
 #![cfg_attr(test, plugin(quickcheck_macros))]
 
-#[cfg(any(quickcheck, test))]
+#[cfg(any(feature = "quickcheck", feature = "testkit", test))]
 extern crate quickcheck;
-#[cfg(any(quickcheck, test))]
+#[cfg(any(feature = "testkit", test))]
 extern crate rand;
+#[cfg(feature = "futures")]
+extern crate futures;
+#[cfg(feature = "serde")]
+#[macro_use]
+extern crate serde;
+#[cfg(all(feature = "serde", test))]
+extern crate serde_json;
+#[cfg(test)]
+extern crate tempdir;
 
+pub mod clock;
+pub mod concurrent;
 pub mod counter;
+#[cfg(feature = "persistent")]
+pub mod hamt;
+#[cfg(feature = "net")]
+pub mod net;
+pub mod observe;
 pub mod register;
+pub mod replication;
 pub mod set;
+pub mod storage;
+pub mod wire;
 mod pn;
+mod retire;
 
-#[cfg(test)]
-pub mod test;
+#[cfg(any(feature = "testkit", test))]
+pub mod model;
+#[cfg(any(feature = "testkit", test))]
+pub mod sim;
+
+#[cfg(any(feature = "testkit", test))]
+pub mod testkit;
+
+#[cfg(all(feature = "serde", test))]
+mod test_util;
 
 /// A Conflict-free Replicated Data Type.
 ///
@@ -81,7 +109,12 @@ pub trait Crdt : Clone + Eq + PartialOrd {
 /// be unique among replicas, so it should be taken from unique per-replica
 /// configuration, or from a source of strong coordination such as
 /// [ZooKeeper](http://zookeeper.apache.org/) or [etcd](https://github.com/coreos/etcd).
-#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+///
+/// Where replicas come and go too often to coordinate their IDs, the interval
+/// tree clocks in `clock` and the `ItcCounter` create and retire replica
+/// identities without coordination.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct ReplicaId(u64);
 
 impl ReplicaId {
@@ -96,7 +129,7 @@ impl From<u64> for ReplicaId {
     }
 }
 
-#[cfg(any(quickcheck, test))]
+#[cfg(any(feature = "quickcheck", test))]
 impl quickcheck::Arbitrary for ReplicaId {
     fn arbitrary<G: quickcheck::Gen>(g: &mut G) -> ReplicaId {
         ReplicaId(quickcheck::Arbitrary::arbitrary(g))
@@ -111,7 +144,7 @@ impl quickcheck::Arbitrary for ReplicaId {
 /// The replica ID is guaranteed to be unique within the processes. This
 /// function should **not** be used for generating replica IDs in a distributed
 /// system.
-#[cfg(any(quickcheck, test))]
+#[cfg(any(feature = "quickcheck", test))]
 pub fn gen_replica_id() -> ReplicaId {
     use std::sync::atomic::{AtomicUsize, ATOMIC_USIZE_INIT, Ordering};
     static mut REPLICA_COUNT: AtomicUsize = ATOMIC_USIZE_INIT;
@@ -129,7 +162,9 @@ pub fn gen_replica_id() -> ReplicaId {
 /// requirements do not require strong coordination among replicas. See
 /// [Snowflake](https://github.com/twitter/snowflake) for an example of
 /// distributed, uncoordinated ID generation which meets the requirements.
+/// The `clock` module provides generators which meet the requirements.
 #[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct TransactionId(u64);
 
 impl TransactionId {
@@ -144,7 +179,7 @@ impl From<u64> for TransactionId {
     }
 }
 
-#[cfg(any(quickcheck, test))]
+#[cfg(any(feature = "quickcheck", test))]
 impl quickcheck::Arbitrary for TransactionId {
     fn arbitrary<G: quickcheck::Gen>(g: &mut G) -> TransactionId {
         TransactionId(quickcheck::Arbitrary::arbitrary(g))
@@ -0,0 +1,482 @@
+//! Exhaustive interleaving checker for small CRDT scenarios.
+//!
+//! A `Scenario` describes a handful of replicas and the operations which are
+//! performed locally at each of them. `Scenario::check` explores every order
+//! in which the operations can be performed and delivered to the other
+//! replicas. Every delivery is made either by applying the operation, or by
+//! merging the state of the originating replica at the time of delivery. After
+//! every step the partial order invariants of the CRDT are checked, and once
+//! every operation has been delivered everywhere the replicas are checked for
+//! convergence.
+//!
+//! Configurations which have been reached before through a different
+//! interleaving are not explored again. Every distinct state of a replica is
+//! stored once, so a configuration is remembered by its progress and the
+//! indices of the states of its replicas, and every distinct step is checked
+//! once and looked up whenever it is taken again. This keeps scenarios of up
+//! to eight operations across three replicas tractable when both delivery
+//! methods are explored, although such scenarios reach millions of
+//! configurations.
+//!
+//! This module is only available when the `testkit` feature is enabled.
+//!
+//! ##### Example
+//!
+//! ```
+//! use crdt::counter::GCounter;
+//! use crdt::model::Scenario;
+//!
+//! let mut a = GCounter::new(0);
+//! let mut b = GCounter::new(1);
+//! let ops = vec![(0, a.increment(1)), (1, b.increment(2)), (0, a.increment(3))];
+//!
+//! Scenario::new(vec![GCounter::new(0), GCounter::new(1)], ops).assert_converges();
+//! ```
+
+use std::cmp::Ordering::{Equal, Greater, Less};
+use std::collections::HashMap;
+use std::fmt::{self, Debug, Display, Formatter};
+
+use Crdt;
+
+/// The maximum number of performed and delivered operations in a scenario.
+const MAX_EVENTS: usize = 64;
+
+/// How operations are delivered to remote replicas.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Delivery {
+    /// Operations are delivered by applying them.
+    Apply,
+    /// Operations are delivered by merging the state of the originating
+    /// replica.
+    Merge,
+    /// Every delivery is explored both by applying the operation and by
+    /// merging the state of the originating replica.
+    Both,
+}
+
+/// A set of replicas and the operations performed locally at each of them.
+pub struct Scenario<C> where C: Crdt {
+    replicas: Vec<C>,
+    ops: Vec<(usize, C::Operation)>,
+    delivery: Delivery,
+}
+
+/// A step in an interleaving.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Step {
+    /// Operation `op` is performed at its originating replica.
+    Perform { op: usize, replica: usize },
+    /// Operation `op` is delivered to `replica` by applying it.
+    Apply { op: usize, replica: usize },
+    /// Operation `op` is delivered to `replica` by merging the state of the
+    /// originating replica, `from`.
+    Merge { op: usize, from: usize, replica: usize },
+}
+
+impl Display for Step {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match *self {
+            Step::Perform { op, replica } =>
+                write!(f, "replica {} performs op {}", replica, op),
+            Step::Apply { op, replica } =>
+                write!(f, "replica {} applies op {}", replica, op),
+            Step::Merge { op, from, replica } =>
+                write!(f, "replica {} merges replica {} (delivering op {})", replica, from, op),
+        }
+    }
+}
+
+/// An invariant which was found to be violated.
+#[derive(Clone, Debug)]
+pub enum Violation<C> {
+    /// A step moved a replica backwards or sideways in the partial order.
+    NotMonotonic { replica: usize, before: C, after: C },
+    /// A replica was not greater than or equal to a replica merged into it.
+    NotUpperBound { replica: usize, merged: C, result: C },
+    /// Every operation was delivered everywhere, but the replicas differ.
+    Diverged { replicas: Vec<C> },
+}
+
+/// An interleaving which violates an invariant.
+#[derive(Clone, Debug)]
+pub struct Counterexample<C> {
+    /// The steps leading up to and including the violation.
+    pub trace: Vec<Step>,
+    /// The violated invariant.
+    pub violation: Violation<C>,
+}
+
+impl <C> Display for Counterexample<C> where C: Debug {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        try!(writeln!(f, "counterexample:"));
+        for (i, step) in self.trace.iter().enumerate() {
+            try!(writeln!(f, "  {:>3}. {}", i + 1, step));
+        }
+        match self.violation {
+            Violation::NotMonotonic { replica, ref before, ref after } => {
+                try!(writeln!(f, "replica {} is not greater than or equal to its previous state", replica));
+                try!(writeln!(f, "  before: {:?}", before));
+                writeln!(f, "  after:  {:?}", after)
+            },
+            Violation::NotUpperBound { replica, ref merged, ref result } => {
+                try!(writeln!(f, "replica {} is not greater than or equal to the merged state", replica));
+                try!(writeln!(f, "  merged: {:?}", merged));
+                writeln!(f, "  result: {:?}", result)
+            },
+            Violation::Diverged { ref replicas } => {
+                try!(writeln!(f, "replicas diverged:"));
+                for (i, replica) in replicas.iter().enumerate() {
+                    try!(writeln!(f, "  {}: {:?}", i, replica));
+                }
+                Ok(())
+            },
+        }
+    }
+}
+
+/// The progress of an interleaving, and the replicas it has produced.
+///
+/// Every distinct state of a replica is stored once, and the replicas are
+/// tracked by the indices of their states. The result of a step depends only
+/// on the state of the replica taking it and on the operation applied or the
+/// state merged, so it is checked once and then looked up whenever the same
+/// step is taken from another configuration.
+struct Explorer<'a, C> where C: Crdt + 'a {
+    scenario: &'a Scenario<C>,
+    states: Vec<C>,
+    /// The states of each replica, grouped by the deliveries made to it.
+    groups: HashMap<(usize, u64), Vec<usize>>,
+    /// The state reached from a state by applying an operation, or by
+    /// merging another state to deliver it.
+    transitions: HashMap<(usize, usize, Option<usize>), usize>,
+    /// The visited configurations with each progress, as the concatenated
+    /// indices of the states of their replicas.
+    visited: HashMap<u64, Vec<usize>>,
+    ids: Vec<usize>,
+    trace: Vec<Step>,
+}
+
+impl <C> Scenario<C> where C: Crdt {
+
+    /// Create a new scenario with the provided initial replicas and operations.
+    ///
+    /// Each operation is paired with the index of the replica which performs
+    /// it. Operations performed by the same replica are performed in the order
+    /// given. Every delivery is explored both by applying the operation and by
+    /// merging the originating replica, which is tractable for up to eight
+    /// operations across three replicas.
+    pub fn new(replicas: Vec<C>, ops: Vec<(usize, C::Operation)>) -> Scenario<C> {
+        Scenario::with_delivery(replicas, ops, Delivery::Both)
+    }
+
+    /// Create a new scenario which delivers operations to remote replicas
+    /// using the provided delivery method.
+    pub fn with_delivery(replicas: Vec<C>,
+                         ops: Vec<(usize, C::Operation)>,
+                         delivery: Delivery)
+                         -> Scenario<C> {
+        assert!(ops.iter().all(|&(replica, _)| replica < replicas.len()),
+                "operation performed by an unknown replica");
+        assert!(ops.len() * replicas.len() <= MAX_EVENTS, "scenario is too large to check");
+        Scenario { replicas: replicas, ops: ops, delivery: delivery }
+    }
+
+    /// Explore every interleaving of the scenario.
+    ///
+    /// Returns the number of distinct configurations explored, or the first
+    /// counterexample found.
+    pub fn check(&self) -> Result<usize, Counterexample<C>> {
+        let mut explorer = Explorer {
+            scenario: self,
+            states: Vec::new(),
+            groups: HashMap::new(),
+            transitions: HashMap::new(),
+            visited: HashMap::new(),
+            ids: Vec::new(),
+            trace: Vec::new(),
+        };
+        explorer.ids = self.replicas.iter().enumerate()
+            .map(|(replica, state)| explorer.intern(0, replica, state.clone()))
+            .collect();
+        try!(explorer.explore(0));
+        let explored: usize = explorer.visited.values().map(|configurations| configurations.len()).sum();
+        Ok(explored / self.replicas.len())
+    }
+
+    /// Explore every interleaving of the scenario, panicking with a readable
+    /// trace if a counterexample is found.
+    pub fn assert_converges(&self) where C: Debug {
+        if let Err(counterexample) = self.check() {
+            panic!("{}", counterexample);
+        }
+    }
+
+    /// Returns the bit tracking the delivery of `op` to `replica`.
+    ///
+    /// Delivery to the originating replica tracks that the operation has been
+    /// performed.
+    fn bit(&self, op: usize, replica: usize) -> u64 {
+        1 << (op * self.replicas.len() + replica)
+    }
+
+    /// Returns the bits tracking the delivery of every operation to `replica`.
+    fn deliveries(&self, replica: usize) -> u64 {
+        (0..self.ops.len()).fold(0, |mask, op| mask | self.bit(op, replica))
+    }
+
+    fn is_complete(&self, progress: u64) -> bool {
+        let events = self.ops.len() * self.replicas.len();
+        progress.count_ones() as usize == events
+    }
+}
+
+impl <'a, C> Explorer<'a, C> where C: Crdt {
+
+    /// Returns the index of the state of `replica`, storing it if it is new.
+    fn intern(&mut self, progress: u64, replica: usize, state: C) -> usize {
+        let delivered = progress & self.scenario.deliveries(replica);
+        let group = self.groups.entry((replica, delivered)).or_insert_with(Vec::new);
+        let states = &mut self.states;
+        match group.iter().cloned().find(|&id| states[id] == state) {
+            Some(id) => id,
+            None => {
+                states.push(state);
+                group.push(states.len() - 1);
+                states.len() - 1
+            },
+        }
+    }
+
+    /// Returns `true` if the configuration has not been explored before, and
+    /// marks it as explored.
+    fn visit(&mut self, progress: u64) -> bool {
+        let ids = &self.ids;
+        let configurations = self.visited.entry(progress).or_insert_with(Vec::new);
+        if configurations.chunks(ids.len()).any(|configuration| configuration == &ids[..]) {
+            false
+        } else {
+            configurations.extend_from_slice(ids);
+            true
+        }
+    }
+
+    fn counterexample(&self, violation: Violation<C>) -> Counterexample<C> {
+        Counterexample { trace: self.trace.clone(), violation: violation }
+    }
+
+    fn explore(&mut self, progress: u64) -> Result<(), Counterexample<C>> {
+        if !self.visit(progress) {
+            return Ok(());
+        }
+        let scenario = self.scenario;
+        if scenario.is_complete(progress) {
+            let converged = self.ids.windows(2).all(|pair| {
+                let (a, b) = (&self.states[pair[0]], &self.states[pair[1]]);
+                a == b && a.partial_cmp(b) == Some(Equal)
+            });
+            return if converged {
+                Ok(())
+            } else {
+                let replicas = self.ids.iter().map(|&id| self.states[id].clone()).collect();
+                Err(self.counterexample(Violation::Diverged { replicas: replicas }))
+            };
+        }
+
+        for (op, &(origin, _)) in scenario.ops.iter().enumerate() {
+            if progress & scenario.bit(op, origin) == 0 {
+                // Operations are performed in program order at their origin.
+                let earlier = scenario.ops[..op].iter().enumerate().any(|(earlier, &(replica, _))| {
+                    replica == origin && progress & scenario.bit(earlier, origin) == 0
+                });
+                if !earlier {
+                    let step = Step::Perform { op: op, replica: origin };
+                    try!(self.step(progress | scenario.bit(op, origin), step));
+                }
+                continue;
+            }
+            for replica in (0..scenario.replicas.len()).filter(|&replica| replica != origin) {
+                if progress & scenario.bit(op, replica) != 0 {
+                    continue;
+                }
+                let next = progress | scenario.bit(op, replica);
+                if scenario.delivery != Delivery::Merge {
+                    try!(self.step(next, Step::Apply { op: op, replica: replica }));
+                }
+                if scenario.delivery != Delivery::Apply {
+                    try!(self.step(next, Step::Merge { op: op, from: origin, replica: replica }));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn step(&mut self, progress: u64, step: Step) -> Result<(), Counterexample<C>> {
+        let (op, replica, merged) = match step {
+            Step::Perform { op, replica } | Step::Apply { op, replica } => (op, replica, None),
+            Step::Merge { op, from, replica } => (op, replica, Some(self.ids[from])),
+        };
+        let before = self.ids[replica];
+        self.trace.push(step);
+
+        let after = match self.transitions.get(&(before, op, merged)).cloned() {
+            Some(after) => after,
+            None => {
+                let mut state = self.states[before].clone();
+                match merged {
+                    None => state.apply(self.scenario.ops[op].1.clone()),
+                    Some(merged) => {
+                        state.merge(self.states[merged].clone());
+                        if !is_at_least(&state, &self.states[merged]) {
+                            let merged = self.states[merged].clone();
+                            return Err(self.counterexample(Violation::NotUpperBound {
+                                replica: replica, merged: merged, result: state,
+                            }));
+                        }
+                    },
+                }
+                if !is_at_least(&state, &self.states[before]) {
+                    let before = self.states[before].clone();
+                    return Err(self.counterexample(Violation::NotMonotonic {
+                        replica: replica, before: before, after: state,
+                    }));
+                }
+                let after = self.intern(progress, replica, state);
+                self.transitions.insert((before, op, merged), after);
+                after
+            },
+        };
+
+        self.ids[replica] = after;
+        let result = self.explore(progress);
+        self.ids[replica] = before;
+        self.trace.pop();
+        result
+    }
+}
+
+/// Returns true if `a` is greater than or equal to `b` in the partial order.
+/// Replicas which are incomparable are not.
+fn is_at_least<C>(a: &C, b: &C) -> bool where C: Crdt {
+    match a.partial_cmp(b) {
+        Some(Greater) | Some(Equal) => true,
+        Some(Less) | None => false,
+    }
+}
+
+#[cfg(test)]
+mod test {
+
+    use std::cmp::Ordering;
+
+    use Crdt;
+    use counter::{GCounter, PnCounter};
+    use set::{LwwSet, PnSet, TpSet};
+    use super::{Delivery, Scenario, Step, Violation};
+
+    #[test]
+    fn check_counters() {
+        let (mut a, mut b, mut c) = (GCounter::new(0), GCounter::new(1), GCounter::new(2));
+        let ops = vec![(0, a.increment(1)), (1, b.increment(2)), (2, c.increment(3)),
+                       (0, a.increment(4)), (1, b.increment(5))];
+        Scenario::new(vec![GCounter::new(0), GCounter::new(1), GCounter::new(2)], ops).assert_converges();
+
+        let (mut a, mut b) = (PnCounter::new(0), PnCounter::new(1));
+        let ops = vec![(0, a.increment(1)), (1, b.increment(-2)), (0, a.increment(-3)), (1, b.increment(4))];
+        Scenario::new(vec![PnCounter::new(0), PnCounter::new(1)], ops).assert_converges();
+    }
+
+    #[test]
+    fn check_sets() {
+        let (mut a, mut b, mut c) = (TpSet::new(), TpSet::new(), TpSet::new());
+        let ops = vec![(0, a.insert(1u8).unwrap()), (1, b.insert(1).unwrap()),
+                       (2, c.remove(1).unwrap()), (0, a.insert(2).unwrap())];
+        Scenario::new(vec![TpSet::new(), TpSet::new(), TpSet::new()], ops).assert_converges();
+
+        let (mut a, mut b, mut c) = (LwwSet::new(), LwwSet::new(), LwwSet::new());
+        let ops = vec![(0, a.insert(1u8, 1).unwrap()), (1, b.remove(1, 2).unwrap()),
+                       (2, c.insert(1, 3).unwrap()), (0, a.remove(1, 4).unwrap()),
+                       (1, b.insert(2, 5).unwrap())];
+        Scenario::new(vec![LwwSet::new(), LwwSet::new(), LwwSet::new()], ops).assert_converges();
+
+        let (mut a, mut b, mut c) = (PnSet::new(0), PnSet::new(1), PnSet::new(2));
+        let ops = vec![(0, a.insert(1u8)), (1, b.remove(1)), (2, c.insert(1)),
+                       (0, a.remove(1)), (1, b.insert(2))];
+        Scenario::new(vec![PnSet::new(0), PnSet::new(1), PnSet::new(2)], ops).assert_converges();
+    }
+
+    #[test]
+    fn check_apply_delivery() {
+        let (mut a, mut b, mut c) = (PnSet::new(0), PnSet::new(1), PnSet::new(2));
+        let ops = vec![(0, a.insert(1u8)), (1, b.remove(1)), (2, c.insert(1)),
+                       (0, a.remove(1)), (1, b.insert(2)), (2, c.insert(2)),
+                       (0, a.insert(3)), (1, b.remove(3))];
+        Scenario::with_delivery(vec![PnSet::new(0), PnSet::new(1), PnSet::new(2)], ops, Delivery::Apply)
+            .assert_converges();
+    }
+
+    #[test]
+    fn check_eight_operations() {
+        let (mut a, mut b, mut c) = (LwwSet::new(), LwwSet::new(), LwwSet::new());
+        let ops = vec![(0, a.insert(1u8, 1).unwrap()), (1, b.remove(1, 2).unwrap()),
+                       (2, c.insert(1, 3).unwrap()), (0, a.remove(1, 4).unwrap()),
+                       (1, b.insert(2, 5).unwrap()), (2, c.remove(2, 6).unwrap()),
+                       (0, a.insert(2, 7).unwrap()), (1, b.insert(3, 8).unwrap())];
+        let configurations = Scenario::new(vec![LwwSet::new(), LwwSet::new(), LwwSet::new()], ops)
+            .check().unwrap();
+        assert!(configurations > 1_000_000);
+    }
+
+    /// A counter whose operations are increments rather than totals, which
+    /// breaks idempotent merging.
+    #[derive(Clone, Debug, PartialEq, Eq, PartialOrd)]
+    struct Sum(u64);
+
+    impl Crdt for Sum {
+        type Operation = u64;
+        fn merge(&mut self, other: Sum) { self.0 = self.0.max(other.0); }
+        fn apply(&mut self, op: u64) { self.0 += op; }
+    }
+
+    #[test]
+    fn check_counterexample() {
+        let ops = vec![(0, 1), (1, 2)];
+        assert!(Scenario::with_delivery(vec![Sum(0), Sum(0)], ops.clone(), Delivery::Apply).check().is_ok());
+        let counterexample = Scenario::new(vec![Sum(0), Sum(0)], ops).check().unwrap_err();
+        match counterexample.violation {
+            Violation::Diverged { ref replicas } => assert_eq!(2, replicas.len()),
+            ref violation => panic!("unexpected violation: {:?}", violation),
+        }
+        assert!(counterexample.trace.iter().any(|step| match *step {
+            Step::Merge { .. } => true,
+            _ => false,
+        }));
+        assert!(format!("{}", counterexample).contains("replicas diverged"));
+    }
+
+    /// A register whose merge can move it backwards in its own order.
+    #[derive(Clone, Debug, PartialEq, Eq)]
+    struct Latest(u64);
+
+    impl PartialOrd for Latest {
+        fn partial_cmp(&self, other: &Latest) -> Option<Ordering> {
+            Some(self.0.cmp(&other.0))
+        }
+    }
+
+    impl Crdt for Latest {
+        type Operation = u64;
+        fn merge(&mut self, other: Latest) { self.0 = other.0; }
+        fn apply(&mut self, op: u64) { self.0 = op; }
+    }
+
+    #[test]
+    fn check_monotonicity_violation() {
+        let ops = vec![(0, 2), (1, 1)];
+        let counterexample = Scenario::new(vec![Latest(0), Latest(0)], ops).check().unwrap_err();
+        match counterexample.violation {
+            Violation::NotMonotonic { .. } => (),
+            ref violation => panic!("unexpected violation: {:?}", violation),
+        }
+    }
+}
@@ -0,0 +1,396 @@
+//! Synchronization of replicas over TCP or Unix domain sockets.
+//!
+//! This module is only available with the `net` feature.
+//!
+//! A `Server` owns a replica, and serves it to peers on a background thread.
+//! Local edits are made through `Server::lock`, and `Server::sync_with`
+//! exchanges state with a peer. Any type which is both a `Crdt` and a `wire`
+//! `Message` can be served. The API is blocking, and uses a thread per
+//! server and per connection rather than an async runtime.
+//!
+//! Each connection performs one push/pull exchange:
+//!
+//! 1. Both sides send a hello frame holding the `wire` format version, the
+//!    message tag of the replica type, and their replica ID. A side closes
+//!    the connection if the peer's version or type differs from its own, or
+//!    if the peer has the same replica ID.
+//! 2. The connecting side pushes the state of its replica.
+//! 3. The serving side merges the pushed state into its replica, and replies
+//!    with the merged state, which the connecting side merges into its own.
+//!
+//! Every frame is a 4 byte little-endian length followed by that many bytes.
+//!
+//! ##### Example
+//!
+//! ```
+//! use crdt::counter::GCounter;
+//! use crdt::net::Server;
+//!
+//! let a = Server::bind_tcp("127.0.0.1:0", 1, GCounter::new(1)).unwrap();
+//! let b = Server::bind_tcp("127.0.0.1:0", 2, GCounter::new(2)).unwrap();
+//! a.lock().increment(3);
+//! b.lock().increment(4);
+//!
+//! a.sync_with(b.address()).unwrap();
+//! assert_eq!(7, a.lock().count());
+//! assert_eq!(7, b.lock().count());
+//! ```
+
+use std::error;
+use std::fmt::{self, Display, Formatter};
+#[cfg(unix)]
+use std::fs;
+use std::io::{self, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+#[cfg(unix)]
+use std::os::unix::net::{UnixListener, UnixStream};
+#[cfg(unix)]
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use {Crdt, ReplicaId};
+use wire::{self, DecodeError, Message, Reader};
+
+/// The maximum length of a frame.
+pub const MAX_FRAME_LEN: usize = 64 << 20;
+
+/// The time a connection may block on a read or write before it is closed.
+const TIMEOUT_SECS: u64 = 30;
+
+/// An error synchronizing with a peer.
+#[derive(Debug)]
+pub enum Error {
+    /// An I/O error.
+    Io(io::Error),
+    /// A frame received from the peer could not be decoded.
+    Decode(DecodeError),
+    /// The peer sent a frame longer than `MAX_FRAME_LEN`.
+    FrameTooLarge(usize),
+    /// The peer's hello frame is incompatible with this side.
+    Handshake(&'static str),
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match *self {
+            Error::Io(ref error) => write!(f, "I/O error: {}", error),
+            Error::Decode(ref error) => write!(f, "unable to decode frame: {}", error),
+            Error::FrameTooLarge(len) => write!(f, "frame of {} bytes exceeds the maximum length", len),
+            Error::Handshake(description) => write!(f, "handshake failed: {}", description),
+        }
+    }
+}
+
+impl error::Error for Error {
+    fn description(&self) -> &str {
+        match *self {
+            Error::Io(_) => "I/O error",
+            Error::Decode(_) => "unable to decode frame",
+            Error::FrameTooLarge(_) => "frame exceeds the maximum length",
+            Error::Handshake(description) => description,
+        }
+    }
+
+    fn cause(&self) -> Option<&error::Error> {
+        match *self {
+            Error::Io(ref error) => Some(error),
+            Error::Decode(ref error) => Some(error),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(error: io::Error) -> Error {
+        Error::Io(error)
+    }
+}
+
+impl From<DecodeError> for Error {
+    fn from(error: DecodeError) -> Error {
+        Error::Decode(error)
+    }
+}
+
+/// The address of a server.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub enum Address {
+    /// A TCP socket address.
+    Tcp(SocketAddr),
+    /// The path of a Unix domain socket.
+    #[cfg(unix)]
+    Unix(PathBuf),
+}
+
+/// A replica served to peers over TCP or a Unix domain socket.
+///
+/// The server accepts connections on a background thread, and handles each
+/// on its own thread, so a slow peer does not delay other peers. Connections
+/// which fail are closed without affecting the server. The background thread
+/// stops accepting connections when the server is dropped, and a Unix domain
+/// socket is then removed.
+pub struct Server<C> {
+    replica_id: ReplicaId,
+    replica: Arc<Mutex<C>>,
+    address: Address,
+    shutdown: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl <C> Server<C> where C: Crdt + Message + Send + 'static {
+
+    /// Serves a replica on a TCP socket bound to `address`.
+    ///
+    /// Binding to port 0 picks a free port, which `address` returns.
+    pub fn bind_tcp<A, R>(address: A, replica_id: R, replica: C) -> io::Result<Server<C>>
+    where A: ToSocketAddrs, R: Into<ReplicaId> {
+        let listener = try!(TcpListener::bind(address));
+        let address = Address::Tcp(try!(listener.local_addr()));
+        Ok(Server::spawn(address, replica_id.into(), replica, move || {
+            listener.accept().map(|(stream, _)| Stream::Tcp(stream))
+        }))
+    }
+
+    /// Serves a replica on a Unix domain socket at `path`, which must not
+    /// already exist.
+    #[cfg(unix)]
+    pub fn bind_unix<P, R>(path: P, replica_id: R, replica: C) -> io::Result<Server<C>>
+    where P: AsRef<Path>, R: Into<ReplicaId> {
+        let listener = try!(UnixListener::bind(path.as_ref()));
+        let address = Address::Unix(path.as_ref().to_path_buf());
+        Ok(Server::spawn(address, replica_id.into(), replica, move || {
+            listener.accept().map(|(stream, _)| Stream::Unix(stream))
+        }))
+    }
+
+    fn spawn<F>(address: Address, replica_id: ReplicaId, replica: C, mut accept: F) -> Server<C>
+    where F: FnMut() -> io::Result<Stream> + Send + 'static {
+        let replica = Arc::new(Mutex::new(replica));
+        let shutdown = Arc::new(AtomicBool::new(false));
+        let thread = {
+            let replica = replica.clone();
+            let shutdown = shutdown.clone();
+            thread::spawn(move || {
+                loop {
+                    let stream = accept();
+                    if shutdown.load(Ordering::SeqCst) {
+                        break;
+                    }
+                    if let Ok(mut stream) = stream {
+                        let replica = replica.clone();
+                        thread::spawn(move || {
+                            let _ = stream.set_timeout().map_err(Error::from)
+                                          .and_then(|()| serve(&mut stream, replica_id, &replica));
+                        });
+                    }
+                }
+            })
+        };
+        Server {
+            replica_id: replica_id,
+            replica: replica,
+            address: address,
+            shutdown: shutdown,
+            thread: Some(thread),
+        }
+    }
+
+    /// Returns the ID of the served replica.
+    pub fn replica_id(&self) -> ReplicaId {
+        self.replica_id
+    }
+
+    /// Returns the address the server accepts connections on.
+    pub fn address(&self) -> &Address {
+        &self.address
+    }
+
+    /// Locks the served replica, so that it may be read or edited.
+    ///
+    /// Connections from peers wait while the replica is locked.
+    pub fn lock<'a>(&'a self) -> MutexGuard<'a, C> {
+        self.replica.lock().unwrap()
+    }
+
+    /// Exchanges state with the server at `address`, merging its replica
+    /// into the served replica. Returns the ID of the peer's replica.
+    ///
+    /// The served replica is not locked while waiting for the peer, so local
+    /// edits and connections from other peers may proceed concurrently.
+    pub fn sync_with(&self, address: &Address) -> Result<ReplicaId, Error> {
+        let mut state = self.lock().clone();
+        let peer = try!(sync_with(address, self.replica_id, &mut state));
+        self.lock().merge(state);
+        Ok(peer)
+    }
+}
+
+impl <C> Drop for Server<C> {
+    fn drop(&mut self) {
+        self.shutdown.store(true, Ordering::SeqCst);
+        // Wake the background thread, which is blocked accepting a connection.
+        if connect(&self.address).is_ok() {
+            if let Some(thread) = self.thread.take() {
+                let _ = thread.join();
+            }
+        }
+        #[cfg(unix)]
+        {
+            if let Address::Unix(ref path) = self.address {
+                let _ = fs::remove_file(path);
+            }
+        }
+    }
+}
+
+/// Exchanges state with the server at `address`, merging its replica into
+/// `replica`. Returns the ID of the peer's replica.
+pub fn sync_with<C>(address: &Address, replica_id: ReplicaId, replica: &mut C) -> Result<ReplicaId, Error>
+where C: Crdt + Message {
+    let mut stream = try!(connect(address));
+    try!(stream.set_timeout());
+    sync(&mut stream, replica_id, replica)
+}
+
+/// Performs the connecting side of an exchange on a connected stream,
+/// merging the peer's replica into `replica`. Returns the ID of the peer's
+/// replica.
+pub fn sync<S, C>(stream: &mut S, replica_id: ReplicaId, replica: &mut C) -> Result<ReplicaId, Error>
+where S: Read + Write, C: Crdt + Message {
+    try!(write_hello::<_, C>(stream, replica_id));
+    let peer = try!(read_hello::<_, C>(stream, replica_id));
+    try!(write_frame(stream, &wire::to_bytes(replica)));
+    let state = try!(wire::from_bytes(&try!(read_frame(stream))));
+    replica.merge(state);
+    Ok(peer)
+}
+
+/// Performs the serving side of an exchange on a connected stream, merging
+/// the peer's replica into `replica`. Returns the ID of the peer's replica.
+pub fn serve<S, C>(stream: &mut S, replica_id: ReplicaId, replica: &Mutex<C>) -> Result<ReplicaId, Error>
+where S: Read + Write, C: Crdt + Message {
+    try!(write_hello::<_, C>(stream, replica_id));
+    let peer = try!(read_hello::<_, C>(stream, replica_id));
+    let state = try!(wire::from_bytes(&try!(read_frame(stream))));
+    let merged = {
+        let mut replica = replica.lock().unwrap();
+        replica.merge(state);
+        wire::to_bytes(&*replica)
+    };
+    try!(write_frame(stream, &merged));
+    Ok(peer)
+}
+
+fn write_hello<S, C>(stream: &mut S, replica_id: ReplicaId) -> io::Result<()>
+where S: Write, C: Message {
+    let mut hello = vec![wire::VERSION, C::TAG];
+    wire::write_varint(&mut hello, replica_id.id());
+    write_frame(stream, &hello)
+}
+
+fn read_hello<S, C>(stream: &mut S, replica_id: ReplicaId) -> Result<ReplicaId, Error>
+where S: Read, C: Message {
+    let frame = try!(read_frame(stream));
+    let mut reader = Reader::new(&frame);
+    if try!(reader.read_u8()) != wire::VERSION {
+        return Err(Error::Handshake("peer uses a different wire format version"));
+    }
+    if try!(reader.read_u8()) != C::TAG {
+        return Err(Error::Handshake("peer serves a different replica type"));
+    }
+    let peer = ReplicaId::from(try!(reader.read_varint()));
+    if reader.remaining() != 0 {
+        return Err(Error::Decode(DecodeError::TrailingBytes(reader.remaining())));
+    }
+    if peer == replica_id {
+        return Err(Error::Handshake("peer has the same replica ID"));
+    }
+    Ok(peer)
+}
+
+fn write_frame<S>(stream: &mut S, frame: &[u8]) -> io::Result<()> where S: Write {
+    let len = frame.len() as u32;
+    try!(stream.write_all(&[len as u8, (len >> 8) as u8, (len >> 16) as u8, (len >> 24) as u8]));
+    try!(stream.write_all(frame));
+    stream.flush()
+}
+
+fn read_frame<S>(stream: &mut S) -> Result<Vec<u8>, Error> where S: Read {
+    let mut header = [0; 4];
+    try!(stream.read_exact(&mut header));
+    let len = header[0] as usize | (header[1] as usize) << 8 | (header[2] as usize) << 16 | (header[3] as usize) << 24;
+    if len > MAX_FRAME_LEN {
+        return Err(Error::FrameTooLarge(len));
+    }
+    // Read the frame as it arrives, rather than allocating the claimed length
+    // up front, so a peer cannot make the server allocate bytes it never sends.
+    let mut frame = Vec::new();
+    try!(stream.take(len as u64).read_to_end(&mut frame));
+    if frame.len() < len {
+        return Err(Error::Io(io::Error::new(io::ErrorKind::UnexpectedEof, "frame ended early")));
+    }
+    Ok(frame)
+}
+
+/// A connection over TCP or a Unix domain socket.
+enum Stream {
+    Tcp(TcpStream),
+    #[cfg(unix)]
+    Unix(UnixStream),
+}
+
+fn connect(address: &Address) -> io::Result<Stream> {
+    match *address {
+        Address::Tcp(ref address) => TcpStream::connect(address).map(Stream::Tcp),
+        #[cfg(unix)]
+        Address::Unix(ref path) => UnixStream::connect(path).map(Stream::Unix),
+    }
+}
+
+impl Stream {
+    fn set_timeout(&self) -> io::Result<()> {
+        let timeout = Some(Duration::from_secs(TIMEOUT_SECS));
+        match *self {
+            Stream::Tcp(ref stream) => {
+                try!(stream.set_read_timeout(timeout));
+                stream.set_write_timeout(timeout)
+            },
+            #[cfg(unix)]
+            Stream::Unix(ref stream) => {
+                try!(stream.set_read_timeout(timeout));
+                stream.set_write_timeout(timeout)
+            },
+        }
+    }
+}
+
+impl Read for Stream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match *self {
+            Stream::Tcp(ref mut stream) => stream.read(buf),
+            #[cfg(unix)]
+            Stream::Unix(ref mut stream) => stream.read(buf),
+        }
+    }
+}
+
+impl Write for Stream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match *self {
+            Stream::Tcp(ref mut stream) => stream.write(buf),
+            #[cfg(unix)]
+            Stream::Unix(ref mut stream) => stream.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match *self {
+            Stream::Tcp(ref mut stream) => stream.flush(),
+            #[cfg(unix)]
+            Stream::Unix(ref mut stream) => stream.flush(),
+        }
+    }
+}
@@ -0,0 +1,333 @@
+//! Change notification for CRDT replicas.
+//!
+//! `Observed` wraps a replica and notifies subscribers whenever a local
+//! mutation, a merge, or an applied operation changes the locally visible
+//! value of the replica. Changes to the operation history which are not
+//! visible (for instance, concurrently inserting and removing an element from
+//! a `PnSet`) do not trigger notifications.
+//!
+//! ##### Example
+//!
+//! ```
+//! use std::sync::mpsc::channel;
+//!
+//! use crdt::counter::GCounter;
+//! use crdt::observe::Observed;
+//!
+//! let (tx, rx) = channel();
+//! let mut counter = Observed::new(GCounter::new(42));
+//! counter.subscribe_channel(tx);
+//!
+//! counter.update(|counter| counter.increment(3));
+//! assert_eq!(Ok(3), rx.try_recv());
+//! ```
+
+use std::collections::HashSet;
+use std::hash::Hash;
+use std::ops::Deref;
+use std::sync::mpsc::Sender;
+
+use Crdt;
+
+/// A CRDT with a locally visible value.
+///
+/// The visible value is what readers of a replica observe through methods
+/// such as `count`, `get`, or `contains`. Two replicas with different
+/// operation histories may have the same visible value.
+pub trait Observable : Crdt {
+
+    type Value: Clone + PartialEq;
+
+    /// Returns the current visible value of the replica.
+    fn value(&self) -> Self::Value;
+}
+
+/// A handle to a subscription on an `Observed` replica.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct SubscriptionId(u64);
+
+enum Subscriber<C> where C: Observable {
+    Callback(Box<FnMut(&C::Value)>),
+    Channel(Sender<C::Value>),
+    /// The element predicate, whether the element was last seen present,
+    /// and the callback.
+    Element(Box<Fn(&C::Value) -> bool>, bool, Box<FnMut(bool)>),
+}
+
+impl <C> Subscriber<C> where C: Observable {
+
+    /// Returns true if the subscriber is notified of every change to the
+    /// visible value, and so needs the value from before each mutation.
+    fn needs_before(&self) -> bool {
+        match *self {
+            Subscriber::Element(..) => false,
+            _ => true,
+        }
+    }
+}
+
+/// A replica which notifies subscribers of changes to its visible value.
+pub struct Observed<C> where C: Observable {
+    crdt: C,
+    next_id: u64,
+    subscribers: Vec<(SubscriptionId, Subscriber<C>)>,
+}
+
+impl <C> Observed<C> where C: Observable {
+
+    /// Create a new observed replica wrapping `crdt`.
+    pub fn new(crdt: C) -> Observed<C> {
+        Observed { crdt: crdt, next_id: 0, subscribers: Vec::new() }
+    }
+
+    /// Register a callback which is invoked with the new visible value of the
+    /// replica every time it changes.
+    ///
+    /// ##### Example
+    ///
+    /// ```
+    /// use std::cell::Cell;
+    /// use std::rc::Rc;
+    ///
+    /// use crdt::counter::PnCounter;
+    /// use crdt::observe::Observed;
+    ///
+    /// let last = Rc::new(Cell::new(0));
+    /// let mut counter = Observed::new(PnCounter::new(42));
+    ///
+    /// let observer = last.clone();
+    /// counter.subscribe(move |&count| observer.set(count));
+    ///
+    /// counter.update(|counter| counter.increment(-7));
+    /// assert_eq!(-7, last.get());
+    /// ```
+    pub fn subscribe<F>(&mut self, callback: F) -> SubscriptionId
+    where F: FnMut(&C::Value) + 'static {
+        self.add_subscriber(Subscriber::Callback(Box::new(callback)))
+    }
+
+    /// Register a channel which is sent the new visible value of the replica
+    /// every time it changes.
+    ///
+    /// The subscription is dropped once the receiving half of the channel
+    /// hangs up.
+    pub fn subscribe_channel(&mut self, sender: Sender<C::Value>) -> SubscriptionId {
+        self.add_subscriber(Subscriber::Channel(sender))
+    }
+
+    /// Remove a subscription.
+    ///
+    /// Returns `true` if the subscription was registered with this replica.
+    pub fn unsubscribe(&mut self, id: SubscriptionId) -> bool {
+        let len = self.subscribers.len();
+        self.subscribers.retain(|&(subscription_id, _)| subscription_id != id);
+        self.subscribers.len() != len
+    }
+
+    /// Perform a local mutation on the replica.
+    ///
+    /// Returns the result of the mutation, typically the operation to
+    /// replicate to remote replicas.
+    ///
+    /// The visible value is only computed when there are subscribers, and
+    /// the value from before the mutation only when there are callback or
+    /// channel subscribers.
+    pub fn update<F, R>(&mut self, mutation: F) -> R where F: FnOnce(&mut C) -> R {
+        if self.subscribers.is_empty() {
+            return mutation(&mut self.crdt);
+        }
+        let before = if self.subscribers.iter().any(|&(_, ref subscriber)| subscriber.needs_before()) {
+            Some(self.crdt.value())
+        } else {
+            None
+        };
+        let result = mutation(&mut self.crdt);
+        self.notify(before);
+        result
+    }
+
+    /// Merge a replica into this replica, notifying subscribers if the visible
+    /// value changes.
+    pub fn merge(&mut self, other: C) {
+        self.update(|crdt| crdt.merge(other))
+    }
+
+    /// Apply an operation to this replica, notifying subscribers if the
+    /// visible value changes.
+    pub fn apply(&mut self, op: C::Operation) {
+        self.update(|crdt| crdt.apply(op))
+    }
+
+    /// Returns the wrapped replica, dropping all subscriptions.
+    pub fn into_inner(self) -> C {
+        self.crdt
+    }
+
+    fn add_subscriber(&mut self, subscriber: Subscriber<C>) -> SubscriptionId {
+        let id = SubscriptionId(self.next_id);
+        self.next_id += 1;
+        self.subscribers.push((id, subscriber));
+        id
+    }
+
+    /// Notifies the subscribers of a mutation. `before` is the value from
+    /// before the mutation, if any subscriber needs it.
+    fn notify(&mut self, before: Option<C::Value>) {
+        let after = self.crdt.value();
+        let changed = before.map_or(false, |before| before != after);
+        let mut hung_up = Vec::new();
+        for &mut (id, ref mut subscriber) in self.subscribers.iter_mut() {
+            match *subscriber {
+                Subscriber::Callback(ref mut callback) => if changed { callback(&after) },
+                Subscriber::Channel(ref sender) => {
+                    if changed && sender.send(after.clone()).is_err() {
+                        hung_up.push(id);
+                    }
+                },
+                Subscriber::Element(ref contains, ref mut was_present, ref mut callback) => {
+                    let is_present = contains(&after);
+                    if *was_present != is_present {
+                        *was_present = is_present;
+                        callback(is_present);
+                    }
+                },
+            }
+        }
+        for id in hung_up {
+            self.unsubscribe(id);
+        }
+    }
+}
+
+impl <C, T> Observed<C> where C: Observable<Value=HashSet<T>>, T: Eq + Hash + 'static {
+
+    /// Register a callback which is invoked when `element` is inserted into or
+    /// removed from the visible value of the set.
+    ///
+    /// The callback is passed `true` if the element became present, and
+    /// `false` if it was removed.
+    ///
+    /// ##### Example
+    ///
+    /// ```
+    /// use std::cell::RefCell;
+    /// use std::rc::Rc;
+    ///
+    /// use crdt::observe::Observed;
+    /// use crdt::set::TpSet;
+    ///
+    /// let events = Rc::new(RefCell::new(Vec::new()));
+    /// let mut set = Observed::new(TpSet::new());
+    ///
+    /// let observer = events.clone();
+    /// set.subscribe_element(1i32, move |is_present| observer.borrow_mut().push(is_present));
+    ///
+    /// set.update(|set| set.insert(2));
+    /// set.update(|set| set.insert(1));
+    /// set.update(|set| set.remove(1));
+    /// assert_eq!(vec![true, false], *events.borrow());
+    /// ```
+    pub fn subscribe_element<F>(&mut self, element: T, callback: F) -> SubscriptionId
+    where F: FnMut(bool) + 'static {
+        let is_present = self.crdt.value().contains(&element);
+        let contains = move |value: &HashSet<T>| value.contains(&element);
+        self.add_subscriber(Subscriber::Element(Box::new(contains), is_present, Box::new(callback)))
+    }
+}
+
+impl <C> Deref for Observed<C> where C: Observable {
+    type Target = C;
+
+    fn deref(&self) -> &C {
+        &self.crdt
+    }
+}
+
+#[cfg(test)]
+mod test {
+
+    use std::cell::RefCell;
+    use std::rc::Rc;
+    use std::sync::mpsc::channel;
+
+    use counter::GCounter;
+    use set::PnSet;
+    use super::Observed;
+
+    #[quickcheck]
+    fn check_notified_on_visible_change(increments: Vec<u8>) -> bool {
+        let (tx, rx) = channel();
+        let mut counter = Observed::new(GCounter::new(0));
+        counter.subscribe_channel(tx);
+
+        for &amount in increments.iter() {
+            counter.update(|counter| counter.increment(amount as u64));
+        }
+
+        let expected: Vec<u64> =
+            increments.iter()
+                      .filter(|&&amount| amount != 0)
+                      .scan(0, |count, &amount| { *count += amount as u64; Some(*count) })
+                      .collect();
+        rx.try_iter().collect::<Vec<_>>() == expected
+    }
+
+    #[test]
+    fn check_not_notified_on_invisible_change() {
+        let events = Rc::new(RefCell::new(0));
+        let mut local = Observed::new(PnSet::new(0));
+        let mut remote = PnSet::new(1);
+        remote.insert(1u32);
+        remote.remove(1);
+
+        let observer = events.clone();
+        local.subscribe(move |_| *observer.borrow_mut() += 1);
+        local.merge(remote);
+        assert_eq!(0, *events.borrow());
+        assert!(!local.contains(&1));
+    }
+
+    #[test]
+    fn check_unsubscribe() {
+        let events = Rc::new(RefCell::new(0));
+        let mut counter = Observed::new(GCounter::new(0));
+
+        let observer = events.clone();
+        let id = counter.subscribe(move |_| *observer.borrow_mut() += 1);
+        counter.update(|counter| counter.increment(1));
+        assert!(counter.unsubscribe(id));
+        counter.update(|counter| counter.increment(1));
+        assert!(!counter.unsubscribe(id));
+        assert_eq!(1, *events.borrow());
+    }
+
+    #[test]
+    fn check_element_subscription() {
+        let events = Rc::new(RefCell::new(Vec::new()));
+        let mut set = Observed::new(PnSet::new(0));
+
+        let observer = events.clone();
+        set.subscribe_element(7u32, move |is_present| observer.borrow_mut().push(is_present));
+
+        let mut remote = PnSet::new(1);
+        let op = remote.insert(7);
+        set.update(|set| set.insert(3));
+        set.apply(op.clone());
+        set.apply(op);
+        set.update(|set| set.remove(7));
+        assert_eq!(vec![true, false], *events.borrow());
+    }
+
+    #[test]
+    fn check_element_subscription_to_present_element() {
+        let events = Rc::new(RefCell::new(Vec::new()));
+        let mut set = Observed::new(PnSet::new(0));
+        set.update(|set| set.insert(7u32));
+
+        let observer = events.clone();
+        set.subscribe_element(7, move |is_present| observer.borrow_mut().push(is_present));
+        set.update(|set| set.insert(3));
+        set.update(|set| set.remove(7));
+        assert_eq!(vec![false], *events.borrow());
+    }
+}
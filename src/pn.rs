@@ -1,10 +1,13 @@
 use std::cmp;
 
-#[cfg(any(quickcheck, test))]
+use wire::{Decode, DecodeError, Encode, Reader};
+
+#[cfg(any(feature = "quickcheck", test))]
 use quickcheck::{Arbitrary, Gen};
 
 /// `Pn` is a building block for count-based CRDTs.
 #[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct Pn {
     /// The positive count.
     pub p: u64,
@@ -34,6 +37,12 @@ impl Pn {
         }
     }
 
+    /// Adds the counts of another `Pn` to this one.
+    pub fn add(&mut self, other: Pn) {
+        self.p += other.p;
+        self.n += other.n;
+    }
+
     /// Merges another `Pn` into this one.
     pub fn merge(&mut self, other: Pn) {
         self.p = cmp::max(self.p, other.p);
@@ -41,7 +50,20 @@ impl Pn {
     }
 }
 
-#[cfg(any(quickcheck, test))]
+impl Encode for Pn {
+    fn encode(&self, buf: &mut Vec<u8>) {
+        self.p.encode(buf);
+        self.n.encode(buf);
+    }
+}
+
+impl Decode for Pn {
+    fn decode(reader: &mut Reader) -> Result<Pn, DecodeError> {
+        Ok(Pn { p: try!(Decode::decode(reader)), n: try!(Decode::decode(reader)) })
+    }
+}
+
+#[cfg(any(feature = "quickcheck", test))]
 impl Arbitrary for Pn {
     fn arbitrary<G>(g: &mut G) -> Pn where G: Gen {
         Pn { p: Arbitrary::arbitrary(g), n: Arbitrary::arbitrary(g) }
@@ -1,13 +1,17 @@
-#[cfg(any(quickcheck, test))]
+#[cfg(any(feature = "quickcheck", test))]
 use quickcheck::{Arbitrary, Gen};
 
 use std::cmp::Ordering;
 use std::ops::Deref;
 
 use {Crdt, TransactionId};
+use clock::{Error, LamportClock};
+use observe::Observable;
+use wire::{Decode, DecodeError, Encode, Message, Reader};
 
 /// A last-writer-wins register.
 #[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct LwwRegister<T> {
     value: T,
     transaction_id: TransactionId,
@@ -81,6 +85,78 @@ impl <T> LwwRegister<T> where T: Clone {
     pub fn transaction_id(&self) -> TransactionId {
         self.transaction_id
     }
+
+    /// Create a new last-writer-wins register with the provided initial value,
+    /// and a transaction ID drawn from `clock`.
+    ///
+    /// Returns an error if the clock's counter overflows.
+    ///
+    /// ##### Example
+    ///
+    /// ```
+    /// # use crdt::register::LwwRegister;
+    /// use crdt::clock::LamportClock;
+    ///
+    /// let mut clock = LamportClock::new(1).unwrap();
+    /// let register = LwwRegister::with_clock("my-value", &mut clock).unwrap();
+    /// assert!(register.transaction_id() < clock.tick().unwrap());
+    /// ```
+    pub fn with_clock(value: T, clock: &mut LamportClock) -> Result<LwwRegister<T>, Error> {
+        Ok(LwwRegister::new(value, try!(clock.tick())))
+    }
+
+    /// Set the register to the provided value, with a transaction ID drawn
+    /// from `clock`.
+    ///
+    /// The clock first observes the register's current transaction ID, so the
+    /// set wins over every value the register has held. Returns an operation
+    /// that can be applied to other replicas, or an error, leaving the
+    /// register unchanged, if the clock's counter overflows.
+    ///
+    /// ##### Example
+    ///
+    /// ```
+    /// # use crdt::register::LwwRegister;
+    /// use crdt::clock::LamportClock;
+    ///
+    /// let mut clock = LamportClock::new(1).unwrap();
+    /// let mut register = LwwRegister::new("my-value", 1000);
+    /// register.set_with_clock("new-value", &mut clock).unwrap();
+    /// assert_eq!("new-value", *register);
+    /// ```
+    pub fn set_with_clock(&mut self, value: T, clock: &mut LamportClock) -> Result<LwwRegister<T>, Error> {
+        try!(clock.observe(self.transaction_id));
+        self.transaction_id = try!(clock.tick());
+        self.value = value;
+        Ok(self.clone())
+    }
+
+    /// Merge a remote replica or operation into this register, advancing
+    /// `clock` past the remote transaction ID.
+    ///
+    /// Returns an error, leaving the register and the clock unchanged, if the
+    /// clock cannot advance past the remote transaction ID.
+    ///
+    /// ##### Example
+    ///
+    /// ```
+    /// # use crdt::register::LwwRegister;
+    /// use crdt::clock::LamportClock;
+    ///
+    /// let mut local_clock = LamportClock::new(1).unwrap();
+    /// let mut remote_clock = LamportClock::new(2).unwrap();
+    /// let mut local = LwwRegister::with_clock("local", &mut local_clock).unwrap();
+    /// let remote = LwwRegister::with_clock("remote", &mut remote_clock).unwrap();
+    ///
+    /// local.merge_with_clock(remote.clone(), &mut local_clock).unwrap();
+    /// assert_eq!("remote", *local);
+    /// assert!(local_clock.tick().unwrap() > remote.transaction_id());
+    /// ```
+    pub fn merge_with_clock(&mut self, other: LwwRegister<T>, clock: &mut LamportClock) -> Result<(), Error> {
+        try!(clock.observe(other.transaction_id));
+        self.merge(other);
+        Ok(())
+    }
 }
 
 impl<T> Deref for LwwRegister<T> {
@@ -142,6 +218,15 @@ impl <T> Crdt for LwwRegister<T> where T: Clone {
     }
 }
 
+impl <T> Observable for LwwRegister<T> where T: Clone + PartialEq {
+
+    type Value = T;
+
+    fn value(&self) -> T {
+        self.value.clone()
+    }
+}
+
 impl <T> PartialEq for LwwRegister<T> {
     fn eq(&self, other: &LwwRegister<T>) -> bool {
         self.transaction_id == other.transaction_id
@@ -162,7 +247,24 @@ impl <T> Ord for LwwRegister<T> {
     }
 }
 
-#[cfg(any(quickcheck, test))]
+impl <T> Encode for LwwRegister<T> where T: Encode {
+    fn encode(&self, buf: &mut Vec<u8>) {
+        self.value.encode(buf);
+        self.transaction_id.encode(buf);
+    }
+}
+
+impl <T> Decode for LwwRegister<T> where T: Decode {
+    fn decode(reader: &mut Reader) -> Result<LwwRegister<T>, DecodeError> {
+        Ok(LwwRegister { value: try!(Decode::decode(reader)), transaction_id: try!(Decode::decode(reader)) })
+    }
+}
+
+impl <T> Message for LwwRegister<T> where T: Encode + Decode {
+    const TAG: u8 = 5;
+}
+
+#[cfg(any(feature = "quickcheck", test))]
 impl <T> Arbitrary for LwwRegister<T> where T: Arbitrary {
     fn arbitrary<G: Gen>(g: &mut G) -> LwwRegister<T> {
         LwwRegister { value: Arbitrary::arbitrary(g), transaction_id: Arbitrary::arbitrary(g) }
@@ -178,7 +280,8 @@ mod test {
 
     use quickcheck::quickcheck;
 
-    use {test, Crdt};
+    use {testkit, Crdt};
+    use clock::LamportClock;
     use register::LwwRegister;
 
     type C = LwwRegister<u32>;
@@ -186,22 +289,48 @@ mod test {
 
     #[test]
     fn check_apply_is_commutative() {
-        quickcheck(test::apply_is_commutative::<C> as fn(C, Vec<O>) -> bool);
+        quickcheck(testkit::apply_is_commutative::<C> as fn(C, Vec<O>) -> bool);
     }
 
     #[test]
     fn check_merge_is_commutative() {
-        quickcheck(test::merge_is_commutative::<C> as fn(C, Vec<C>) -> bool);
+        quickcheck(testkit::merge_is_commutative::<C> as fn(C, Vec<C>) -> bool);
     }
 
     #[test]
     fn check_ordering_lte() {
-        quickcheck(test::ordering_lte::<C> as fn(C, C) -> bool);
+        quickcheck(testkit::ordering_lte::<C> as fn(C, C) -> bool);
     }
 
     #[test]
     fn check_ordering_equality() {
-        quickcheck(test::ordering_equality::<C> as fn(C, C) -> bool);
+        quickcheck(testkit::ordering_equality::<C> as fn(C, C) -> bool);
+    }
+
+    #[test]
+    fn check_all() {
+        testkit::check_all::<C>();
+    }
+
+    #[quickcheck]
+    fn check_clock_sets_win(sets: Vec<bool>) -> bool {
+        let mut clocks = [LamportClock::new(0).unwrap(), LamportClock::new(1).unwrap()];
+        let mut replicas = [LwwRegister::new(0, 0), LwwRegister::new(0, 0)];
+        for (value, &local) in sets.iter().enumerate() {
+            let (local, remote) = (local as usize, !local as usize);
+            let op = replicas[local].set_with_clock(value, &mut clocks[local]).unwrap();
+            if *replicas[local] != value {
+                return false;
+            }
+            replicas[remote].merge_with_clock(op, &mut clocks[remote]).unwrap();
+        }
+        replicas[0] == replicas[1] && (sets.is_empty() || *replicas[0] == sets.len() - 1)
+    }
+
+    #[cfg(feature = "serde")]
+    #[quickcheck]
+    fn check_serde_round_trip(crdt: C, op: O) -> bool {
+        ::test_util::serde_round_trip(crdt, op, |a, b| **a == **b)
     }
 
     #[quickcheck]
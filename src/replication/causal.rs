@@ -0,0 +1,624 @@
+use std::cmp;
+use std::collections::HashMap;
+#[cfg(feature = "serde")]
+use std::convert::TryFrom;
+use std::ops::{Deref, Range};
+
+use {Crdt, ReplicaId};
+use replication::Transport;
+use wire::{self, Decode, DecodeError, Encode, Message, Reader};
+
+/// The identity of an operation: the replica which produced it, and its
+/// sequence number among that replica's operations, starting at 1.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[cfg_attr(feature = "serde", serde(try_from = "DotFields"))]
+pub struct Dot {
+    pub replica_id: ReplicaId,
+    pub seq: u64,
+}
+
+/// The fields of a deserialized dot, before its sequence number is checked.
+#[cfg(feature = "serde")]
+#[derive(Deserialize)]
+struct DotFields {
+    replica_id: ReplicaId,
+    seq: u64,
+}
+
+/// An operation tagged with its dot and causal dependencies.
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[cfg_attr(feature = "serde", serde(try_from = "CausalOpFields<O>"))]
+pub struct CausalOp<O> {
+    dot: Dot,
+    deps: HashMap<ReplicaId, u64>,
+    op: O,
+}
+
+/// The fields of a deserialized operation, before its dependencies are
+/// checked.
+#[cfg(feature = "serde")]
+#[derive(Deserialize)]
+struct CausalOpFields<O> {
+    dot: Dot,
+    deps: HashMap<ReplicaId, u64>,
+    op: O,
+}
+
+/// A replica whose operations are delivered exactly once and in causal
+/// order.
+///
+/// `CausalBroadcast` wraps a replica of an operation-based CRDT. Local
+/// mutations are tagged with a `Dot` and with the number of operations from
+/// each replica which had been delivered locally beforehand. Received
+/// operations are buffered until every operation they depend on has been
+/// delivered, duplicates are discarded, and the rest are applied in causal
+/// order. This makes it safe to replicate CRDTs whose operations are not
+/// idempotent, such as `PnSet`, over a transport which duplicates or
+/// reorders messages.
+///
+/// A replica stops delivering the operations of another replica at the first
+/// one missing, so operations which the transport loses must be received
+/// again. `CausalBroadcast` does not keep the operations it produces, so the
+/// application retransmits them from its own record of the operations
+/// returned by `update`, and `missing` returns the operations to ask the
+/// other replicas for. The number of
+/// buffered operations is returned by `pending`, and can be bounded with
+/// `set_max_pending`.
+///
+/// `CausalBroadcast` does not depend on any transport: `update` returns the
+/// tagged operation to send, and `receive` accepts tagged operations however
+/// they arrive. `broadcast` and `poll` drive a `Transport` directly.
+///
+/// ##### Example
+///
+/// ```
+/// use crdt::replication::{CausalBroadcast, MemoryNetwork};
+/// use crdt::set::PnSet;
+///
+/// let network = MemoryNetwork::new();
+/// let (mut a_transport, mut b_transport) = (network.connect(1), network.connect(2));
+/// let mut a = CausalBroadcast::new(1, PnSet::new(1));
+/// let mut b = CausalBroadcast::new(2, PnSet::new(2));
+///
+/// a.broadcast(&mut a_transport, |set| set.insert("x")).unwrap();
+/// a.broadcast(&mut a_transport, |set| set.remove("x")).unwrap();
+///
+/// // The network delivers the operations twice, in reverse order.
+/// network.with_inbox(2, |inbox| {
+///     let ops: Vec<_> = inbox.iter().rev().cloned().collect();
+///     inbox.clear();
+///     inbox.extend(ops.iter().chain(ops.iter()).cloned());
+/// });
+///
+/// assert_eq!(Ok(2), b.poll(&mut b_transport));
+/// assert!(!b.contains(&"x"));
+/// assert_eq!(0, b.pending());
+/// ```
+pub struct CausalBroadcast<C> where C: Crdt {
+    replica_id: ReplicaId,
+    crdt: C,
+    delivered: HashMap<ReplicaId, u64>,
+    /// The buffered operations of each origin, by sequence number.
+    buffered: HashMap<ReplicaId, HashMap<u64, CausalOp<C::Operation>>>,
+    max_pending: Option<usize>,
+}
+
+impl Dot {
+
+    /// Creates a new dot.
+    pub fn new<R>(replica_id: R, seq: u64) -> Dot where R: Into<ReplicaId> {
+        Dot { replica_id: replica_id.into(), seq: seq }
+    }
+}
+
+impl <O> CausalOp<O> {
+
+    /// Returns the dot of the operation.
+    pub fn dot(&self) -> Dot {
+        self.dot
+    }
+
+    /// Returns the number of operations from each replica, other than the
+    /// origin, which must be delivered before the operation.
+    pub fn deps(&self) -> &HashMap<ReplicaId, u64> {
+        &self.deps
+    }
+
+    /// Returns the operation.
+    pub fn op(&self) -> &O {
+        &self.op
+    }
+
+    /// Returns the operation, discarding the dot and dependencies.
+    pub fn into_op(self) -> O {
+        self.op
+    }
+}
+
+impl <C> CausalBroadcast<C> where C: Crdt {
+
+    /// Wraps a replica which has not yet applied any operations.
+    ///
+    /// `replica_id` **must** be unique among the replicas which exchange
+    /// operations.
+    pub fn new<R>(replica_id: R, crdt: C) -> CausalBroadcast<C> where R: Into<ReplicaId> {
+        CausalBroadcast {
+            replica_id: replica_id.into(),
+            crdt: crdt,
+            delivered: HashMap::new(),
+            buffered: HashMap::new(),
+            max_pending: None,
+        }
+    }
+
+    /// Returns the ID of the replica.
+    pub fn replica_id(&self) -> ReplicaId {
+        self.replica_id
+    }
+
+    /// Performs a local mutation on the replica.
+    ///
+    /// Returns the tagged operation to send to the other replicas, if the
+    /// mutation produced one.
+    pub fn update<F, R>(&mut self, mutation: F) -> Option<CausalOp<C::Operation>>
+    where F: FnOnce(&mut C) -> R, R: Into<Option<C::Operation>> {
+        let op = match mutation(&mut self.crdt).into() {
+            Some(op) => op,
+            None => return None,
+        };
+        let replica_id = self.replica_id;
+        let deps = self.delivered
+                       .iter()
+                       .filter(|&(&dep, _)| dep != replica_id)
+                       .map(|(&dep, &seq)| (dep, seq))
+                       .collect();
+        let seq = self.delivered.entry(replica_id).or_insert(0);
+        *seq += 1;
+        Some(CausalOp { dot: Dot::new(replica_id, *seq), deps: deps, op: op })
+    }
+
+    /// Performs a local mutation on the replica, and broadcasts the resulting
+    /// operation.
+    ///
+    /// Returns `true` if the mutation produced an operation.
+    pub fn broadcast<T, F, R>(&mut self, transport: &mut T, mutation: F) -> Result<bool, T::Error>
+    where T: Transport<CausalOp<C::Operation>>, F: FnOnce(&mut C) -> R, R: Into<Option<C::Operation>> {
+        match self.update(mutation) {
+            Some(op) => transport.broadcast(op).map(|_| true),
+            None => Ok(false),
+        }
+    }
+
+    /// Receives an operation from another replica.
+    ///
+    /// The operation is buffered until its dependencies have been delivered,
+    /// and is discarded if it has already been delivered or buffered, or if
+    /// it must wait and the buffer is full. Returns the number of operations
+    /// delivered as a result, including buffered operations which were
+    /// waiting on this one.
+    pub fn receive(&mut self, op: CausalOp<C::Operation>) -> usize {
+        if self.is_delivered(op.dot) || self.is_buffered(op.dot) {
+            return 0;
+        }
+        if !self.is_ready(&op) {
+            if self.max_pending.map_or(true, |max_pending| self.pending() < max_pending) {
+                self.buffered.entry(op.dot.replica_id).or_insert_with(HashMap::new).insert(op.dot.seq, op);
+            }
+            return 0;
+        }
+        self.deliver(op);
+        let mut delivered = 1;
+        loop {
+            // Only the next operation of each origin can be ready.
+            let ready: Vec<Dot> = self.buffered
+                                      .iter()
+                                      .filter_map(|(&replica_id, ops)| ops.get(&(self.delivered_seq(replica_id) + 1)))
+                                      .filter(|op| self.is_ready(op))
+                                      .map(|op| op.dot)
+                                      .collect();
+            if ready.is_empty() {
+                return delivered;
+            }
+            for dot in ready {
+                let op = self.unbuffer(dot);
+                self.deliver(op);
+                delivered += 1;
+            }
+        }
+    }
+
+    /// Receives every operation waiting in a transport.
+    ///
+    /// Returns the number of operations delivered.
+    pub fn poll<T>(&mut self, transport: &mut T) -> Result<usize, T::Error>
+    where T: Transport<CausalOp<C::Operation>> {
+        let mut delivered = 0;
+        while let Some(op) = try!(transport.receive()) {
+            delivered += self.receive(op);
+        }
+        Ok(delivered)
+    }
+
+    /// Returns the number of operations from each replica which have been
+    /// delivered, including those produced locally.
+    pub fn delivered(&self) -> &HashMap<ReplicaId, u64> {
+        &self.delivered
+    }
+
+    /// Returns the number of received operations waiting on their
+    /// dependencies.
+    pub fn pending(&self) -> usize {
+        self.buffered.values().map(|ops| ops.len()).sum()
+    }
+
+    /// Limits the number of received operations waiting on their
+    /// dependencies. Once the limit is reached, further operations which
+    /// must wait are discarded, as if the transport had lost them.
+    ///
+    /// Operations which are already buffered are kept.
+    pub fn set_max_pending(&mut self, max_pending: usize) {
+        self.max_pending = Some(max_pending);
+    }
+
+    /// Returns the sequence numbers of the operations from each replica which
+    /// buffered operations are waiting on, and which have not been delivered.
+    ///
+    /// Some of the operations may be buffered themselves, waiting on earlier
+    /// ones. The others have been lost, or have not arrived yet, and can be
+    /// requested from the other replicas.
+    ///
+    /// ##### Example
+    ///
+    /// ```
+    /// use crdt::replication::CausalBroadcast;
+    /// use crdt::set::GSet;
+    ///
+    /// let mut a = CausalBroadcast::new(1, GSet::new());
+    /// let mut b = CausalBroadcast::new(2, GSet::new());
+    /// let _lost = a.update(|set| set.insert(1)).unwrap();
+    /// let op = a.update(|set| set.insert(2)).unwrap();
+    ///
+    /// b.receive(op);
+    /// assert_eq!(Some(&(1..2)), b.missing().get(&1.into()));
+    /// ```
+    pub fn missing(&self) -> HashMap<ReplicaId, Range<u64>> {
+        let mut needed = HashMap::new();
+        for op in self.buffered.values().flat_map(|ops| ops.values()) {
+            let deps = op.deps.iter().map(|(&replica_id, &seq)| (replica_id, seq));
+            for (replica_id, seq) in deps.chain(Some((op.dot.replica_id, op.dot.seq - 1))) {
+                let needed = needed.entry(replica_id).or_insert(0);
+                *needed = cmp::max(*needed, seq);
+            }
+        }
+        needed.into_iter()
+              .map(|(replica_id, seq)| {
+                  (replica_id, self.delivered_seq(replica_id) + 1..seq + 1)
+              })
+              .filter(|&(_, ref range)| range.start < range.end)
+              .collect()
+    }
+
+    /// Returns the wrapped replica, discarding buffered operations.
+    pub fn into_inner(self) -> C {
+        self.crdt
+    }
+
+    /// Returns the number of operations from a replica which have been
+    /// delivered.
+    fn delivered_seq(&self, replica_id: ReplicaId) -> u64 {
+        self.delivered.get(&replica_id).cloned().unwrap_or(0)
+    }
+
+    fn is_delivered(&self, dot: Dot) -> bool {
+        dot.seq <= self.delivered_seq(dot.replica_id)
+    }
+
+    fn is_buffered(&self, dot: Dot) -> bool {
+        self.buffered.get(&dot.replica_id).map_or(false, |ops| ops.contains_key(&dot.seq))
+    }
+
+    /// Removes a buffered operation.
+    fn unbuffer(&mut self, dot: Dot) -> CausalOp<C::Operation> {
+        let (op, is_empty) = {
+            let ops = self.buffered.get_mut(&dot.replica_id).unwrap();
+            (ops.remove(&dot.seq).unwrap(), ops.is_empty())
+        };
+        if is_empty {
+            self.buffered.remove(&dot.replica_id);
+        }
+        op
+    }
+
+    /// Returns true if the operation is the next from its origin, and every
+    /// operation it depends on has been delivered.
+    fn is_ready(&self, op: &CausalOp<C::Operation>) -> bool {
+        self.delivered_seq(op.dot.replica_id) + 1 == op.dot.seq
+            && op.deps.iter().all(|(&replica_id, &seq)| self.is_delivered(Dot::new(replica_id, seq)))
+    }
+
+    fn deliver(&mut self, op: CausalOp<C::Operation>) {
+        self.delivered.insert(op.dot.replica_id, op.dot.seq);
+        self.crdt.apply(op.op);
+    }
+}
+
+impl <C> Deref for CausalBroadcast<C> where C: Crdt {
+    type Target = C;
+
+    fn deref(&self) -> &C {
+        &self.crdt
+    }
+}
+
+impl Encode for Dot {
+    fn encode(&self, buf: &mut Vec<u8>) {
+        self.replica_id.encode(buf);
+        self.seq.encode(buf);
+    }
+}
+
+impl Decode for Dot {
+    fn decode(reader: &mut Reader) -> Result<Dot, DecodeError> {
+        let replica_id = try!(ReplicaId::decode(reader));
+        let seq = try!(u64::decode(reader));
+        if seq == 0 {
+            return Err(DecodeError::InvalidValue("dot with sequence number 0"));
+        }
+        Ok(Dot { replica_id: replica_id, seq: seq })
+    }
+}
+
+#[cfg(feature = "serde")]
+impl TryFrom<DotFields> for Dot {
+    type Error = &'static str;
+
+    fn try_from(fields: DotFields) -> Result<Dot, &'static str> {
+        if fields.seq == 0 {
+            return Err("dot with sequence number 0");
+        }
+        Ok(Dot { replica_id: fields.replica_id, seq: fields.seq })
+    }
+}
+
+impl <O> Encode for CausalOp<O> where O: Encode {
+    fn encode(&self, buf: &mut Vec<u8>) {
+        self.dot.encode(buf);
+        wire::write_entries(buf, self.deps.iter(), |(replica_id, seq), buf| {
+            replica_id.encode(buf);
+            seq.encode(buf);
+        });
+        self.op.encode(buf);
+    }
+}
+
+impl <O> Decode for CausalOp<O> where O: Decode {
+    fn decode(reader: &mut Reader) -> Result<CausalOp<O>, DecodeError> {
+        let dot = try!(Dot::decode(reader));
+        let deps: HashMap<ReplicaId, u64> = try!(wire::read_map(reader, |reader| {
+            Ok((try!(ReplicaId::decode(reader)), try!(u64::decode(reader))))
+        }));
+        if deps.contains_key(&dot.replica_id) {
+            return Err(DecodeError::InvalidValue("operation depends on its origin"));
+        }
+        Ok(CausalOp { dot: dot, deps: deps, op: try!(Decode::decode(reader)) })
+    }
+}
+
+#[cfg(feature = "serde")]
+impl <O> TryFrom<CausalOpFields<O>> for CausalOp<O> {
+    type Error = &'static str;
+
+    fn try_from(fields: CausalOpFields<O>) -> Result<CausalOp<O>, &'static str> {
+        if fields.deps.contains_key(&fields.dot.replica_id) {
+            return Err("operation depends on its origin");
+        }
+        Ok(CausalOp { dot: fields.dot, deps: fields.deps, op: fields.op })
+    }
+}
+
+impl <O> Message for CausalOp<O> where O: Encode + Decode {
+    const TAG: u8 = 17;
+}
+
+#[cfg(test)]
+mod test {
+
+    use std::cmp::Ordering;
+    use std::collections::{HashMap, HashSet};
+
+    use {Crdt, ReplicaId, wire};
+    use replication::{CausalBroadcast, CausalOp, Dot, MemoryNetwork, MemoryTransport, Transport};
+    use set::{GSet, PnSet, PnSetOp};
+
+    /// A CRDT which records the order in which operations are applied.
+    #[derive(Clone, Debug, Default, PartialEq, Eq)]
+    struct Trace(Vec<u32>);
+
+    impl PartialOrd for Trace {
+        fn partial_cmp(&self, other: &Trace) -> Option<Ordering> {
+            if self == other { Some(Ordering::Equal) } else { None }
+        }
+    }
+
+    impl Crdt for Trace {
+        type Operation = u32;
+        fn merge(&mut self, other: Trace) {
+            for op in other.0 {
+                self.apply(op);
+            }
+        }
+        fn apply(&mut self, op: u32) {
+            self.0.push(op);
+        }
+    }
+
+    /// Runs a script of local operations and network faults among three
+    /// replicas, then retransmits every operation. Checks that every replica
+    /// applies every operation exactly once, and after the operations which
+    /// its origin had applied when it was produced.
+    #[quickcheck]
+    fn check_causal_delivery(script: Vec<(u8, u8, u8)>) -> bool {
+        let network = MemoryNetwork::new();
+        let mut transports: Vec<MemoryTransport<CausalOp<u32>>> = (0..3).map(|i| network.connect(i)).collect();
+        let mut replicas: Vec<_> = (0..3).map(|i| CausalBroadcast::new(i, Trace::default())).collect();
+        let mut sent = Vec::new();
+        let mut happened_before = HashMap::new();
+
+        for (action, replica, n) in script {
+            let i = replica as usize % 3;
+            match action % 5 {
+                0 | 1 => {
+                    let id = sent.len() as u32;
+                    let past: HashSet<u32> = replicas[i].0.iter().cloned().collect();
+                    happened_before.insert(id, past);
+                    let op = replicas[i].update(|trace| { trace.0.push(id); id }).unwrap();
+                    sent.push(op.clone());
+                    transports[i].broadcast(op).unwrap();
+                },
+                2 => network.with_inbox(i as u64, |inbox| {
+                    let len = inbox.len();
+                    if len > 0 { inbox.remove(n as usize % len); }
+                }),
+                3 => network.with_inbox(i as u64, |inbox| {
+                    let len = inbox.len();
+                    if len > 0 {
+                        let op = inbox[n as usize % len].clone();
+                        inbox.push_front(op);
+                    }
+                }),
+                _ => { replicas[i].poll(&mut transports[i]).unwrap(); },
+            }
+        }
+        for (replica, transport) in replicas.iter_mut().zip(transports.iter_mut()) {
+            replica.poll(transport).unwrap();
+            for op in sent.iter().rev() {
+                replica.receive(op.clone());
+            }
+        }
+
+        replicas.iter().all(|replica| {
+            let mut applied = HashSet::new();
+            replica.pending() == 0 && replica.0.len() == sent.len() && replica.0.iter().all(|op| {
+                let is_causal = happened_before[op].is_subset(&applied);
+                applied.insert(*op) && is_causal
+            })
+        })
+    }
+
+    #[test]
+    fn check_pnset_exactly_once() {
+        let mut a = CausalBroadcast::new(1, PnSet::new(1));
+        let mut b = CausalBroadcast::new(2, PnSet::new(2));
+        let insert = a.update(|set| set.insert(1u32)).unwrap();
+        let remove = a.update(|set| set.remove(1)).unwrap();
+        let reinsert = a.update(|set| set.insert(1)).unwrap();
+
+        assert_eq!(0, b.receive(reinsert.clone()));
+        assert_eq!(0, b.receive(remove.clone()));
+        assert_eq!(2, b.pending());
+        assert_eq!(3, b.receive(insert.clone()));
+        for op in vec![insert, remove, reinsert] {
+            assert_eq!(0, b.receive(op));
+        }
+        assert!(b.contains(&1));
+        assert_eq!(Some(&3), b.delivered().get(&ReplicaId::from(1)));
+
+        // Operations which depend on operations from other replicas wait for
+        // them.
+        let mut c = CausalBroadcast::new(3, PnSet::new(3));
+        let remove = b.update(|set| set.remove(1)).unwrap();
+        assert_eq!(Some(&3), remove.deps().get(&ReplicaId::from(1)));
+        assert_eq!(0, c.receive(remove));
+        assert!(!c.contains(&1));
+    }
+
+    #[test]
+    fn check_missing() {
+        let mut a = CausalBroadcast::new(1, GSet::new());
+        let mut b = CausalBroadcast::new(2, GSet::new());
+        let ops: Vec<_> = (0..4u32).map(|i| a.update(|set| set.insert(i)).unwrap()).collect();
+        let op = b.update(|set| set.insert(10)).unwrap();
+        let mut c = CausalBroadcast::new(3, GSet::new());
+        assert!(c.missing().is_empty());
+
+        c.receive(ops[3].clone());
+        c.receive(ops[1].clone());
+        c.receive(op);
+        assert_eq!(Some(&(1..4)), c.missing().get(&ReplicaId::from(1)));
+        assert_eq!(None, c.missing().get(&ReplicaId::from(2)));
+
+        c.receive(ops[0].clone());
+        assert_eq!(Some(&(3..4)), c.missing().get(&ReplicaId::from(1)));
+        c.receive(ops[2].clone());
+        assert!(c.missing().is_empty());
+        assert_eq!(0, c.pending());
+        assert_eq!(5, c.len());
+    }
+
+    #[test]
+    fn check_max_pending() {
+        let mut a = CausalBroadcast::new(1, GSet::new());
+        let mut b = CausalBroadcast::new(2, GSet::new());
+        b.set_max_pending(1);
+        let ops: Vec<_> = (0..3u32).map(|i| a.update(|set| set.insert(i)).unwrap()).collect();
+
+        assert_eq!(0, b.receive(ops[1].clone()));
+        assert_eq!(0, b.receive(ops[2].clone()));
+        assert_eq!(1, b.pending());
+        assert_eq!(2, b.receive(ops[0].clone()));
+        assert!(b.missing().is_empty());
+        assert_eq!(1, b.receive(ops[2].clone()));
+        assert_eq!(3, b.len());
+    }
+
+    #[test]
+    fn check_long_reordered_delivery() {
+        let mut a = CausalBroadcast::new(1, GSet::new());
+        let mut b = CausalBroadcast::new(2, GSet::new());
+        let ops: Vec<_> = (0..1000u32).map(|i| a.update(|set| set.insert(i)).unwrap()).collect();
+
+        for op in ops[1..].iter().rev() {
+            assert_eq!(0, b.receive(op.clone()));
+        }
+        assert_eq!(999, b.pending());
+        assert_eq!(1000, b.receive(ops[0].clone()));
+        assert_eq!(0, b.pending());
+        assert_eq!(a.crdt, b.crdt);
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn check_serde_rejects_invalid_ops() {
+        use serde_json;
+
+        let mut a = CausalBroadcast::new(1, GSet::new());
+        let op = a.update(|set| set.insert(1u32)).unwrap();
+        let json = serde_json::to_string(&op).unwrap();
+        assert_eq!(op, serde_json::from_str(&json).unwrap());
+
+        assert!(serde_json::from_str::<Dot>(r#"{"replica_id":1,"seq":0}"#).is_err());
+        assert!(serde_json::from_str::<CausalOp<u32>>(r#"{"dot":{"replica_id":1,"seq":1},"deps":{"1":1},"op":1}"#).is_err());
+    }
+
+    #[test]
+    fn check_no_op_mutation() {
+        let mut a = CausalBroadcast::new(1, GSet::new());
+        assert!(a.update(|set| set.insert(1u32)).is_some());
+        assert!(a.update(|set| set.insert(1u32)).is_none());
+        assert_eq!(Some(&1), a.delivered().get(&ReplicaId::from(1)));
+    }
+
+    #[quickcheck]
+    fn check_wire_round_trip(op: PnSetOp<u32>, deps: Vec<(u64, u64)>) -> bool {
+        let mut a = CausalBroadcast::new(1000, PnSet::new(1000));
+        for (replica_id, seq) in deps {
+            a.delivered.insert(ReplicaId::from(replica_id % 1000), seq);
+        }
+        a.delivered.insert(ReplicaId::from(1000), 0);
+        let op = a.update(|_| op).unwrap();
+        let decoded: CausalOp<PnSetOp<u32>> = wire::from_bytes(&wire::to_bytes(&op)).unwrap();
+        decoded == op && decoded.dot() == Dot::new(1000, 1)
+    }
+}
@@ -0,0 +1,613 @@
+use std::collections::HashMap;
+use std::hash::Hash;
+
+use {Crdt, ReplicaId};
+use replication::Transport;
+use replication::reconcile::mix;
+use set::OrSet;
+use wire::{self, Decode, DecodeError, Encode, Message, Reader};
+
+/// A message exchanged by `Gossip` replicas.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum GossipMessage<K, C> {
+    /// The sender's members, and the version vector of each of its states.
+    Digest {
+        from: ReplicaId,
+        members: OrSet<ReplicaId>,
+        versions: Vec<(K, HashMap<ReplicaId, u64>)>,
+    },
+    /// The sender's members, the states which the receiver lacks, and the
+    /// keys of the states which the sender lacks.
+    Push {
+        from: ReplicaId,
+        members: OrSet<ReplicaId>,
+        states: Vec<(K, HashMap<ReplicaId, u64>, C)>,
+        request: Vec<K>,
+    },
+}
+
+/// A state, and the number of updates from each replica which it includes.
+struct Versioned<C> {
+    versions: HashMap<ReplicaId, u64>,
+    crdt: C,
+}
+
+/// Epidemic dissemination of a keyed collection of CRDT states.
+///
+/// Each replica holds a state per key, along with a version vector counting
+/// the local updates from each replica which the state includes. Every
+/// round, a replica sends a digest of its version vectors to `fanout` members
+/// chosen at random. The receiver of a digest pushes back the states whose
+/// versions the sender has not seen, and requests the states whose versions
+/// it has not seen, which the sender then pushes in turn. States are merged,
+/// so messages may be dropped, duplicated, or reordered; replicas which miss
+/// a round catch up in a later one.
+///
+/// Membership is itself replicated as an `OrSet` of replica IDs, which is
+/// exchanged with every message. A replica joins by adding any existing
+/// member, and is removed by any member, including itself. A member which is
+/// concurrently removed and re-added remains a member.
+///
+/// States for keys which a replica has not yet seen are created with
+/// `new_replica`, so that local updates are attributed to the local replica.
+///
+/// ##### Example
+///
+/// ```
+/// use crdt::counter::GCounter;
+/// use crdt::replication::{Gossip, MemoryNetwork};
+///
+/// let network = MemoryNetwork::new();
+/// let (mut a_transport, mut b_transport) = (network.connect(1), network.connect(2));
+/// let mut a = Gossip::new(1, 2, |id| GCounter::new(id));
+/// let mut b = Gossip::new(2, 2, |id| GCounter::new(id));
+/// b.join(1);
+///
+/// a.update("hits", |counter| counter.increment(3));
+/// b.update("hits", |counter| counter.increment(4));
+///
+/// // b sends a digest, a pushes its state and requests b's, and b replies.
+/// b.round(&mut b_transport).unwrap();
+/// a.poll(&mut a_transport).unwrap();
+/// b.poll(&mut b_transport).unwrap();
+/// a.poll(&mut a_transport).unwrap();
+///
+/// assert_eq!(7, a.get(&"hits").unwrap().count());
+/// assert_eq!(7, b.get(&"hits").unwrap().count());
+/// assert_eq!(a.members(), b.members());
+/// ```
+pub struct Gossip<K, C, N> where K: Eq + Hash {
+    replica_id: ReplicaId,
+    fanout: usize,
+    rng: u64,
+    members: OrSet<ReplicaId>,
+    states: HashMap<K, Versioned<C>>,
+    new_replica: N,
+}
+
+impl <K, C, N> Gossip<K, C, N> where K: Clone + Eq + Hash, C: Crdt, N: FnMut(ReplicaId) -> C {
+
+    /// Creates a replica whose only member is itself.
+    ///
+    /// `replica_id` **must** be unique among the members. Peers are chosen
+    /// from a pseudo-random sequence seeded by the replica ID, so a run of
+    /// rounds is deterministic.
+    pub fn new<R>(replica_id: R, fanout: usize, new_replica: N) -> Gossip<K, C, N> where R: Into<ReplicaId> {
+        let replica_id = replica_id.into();
+        let mut members = OrSet::new(replica_id);
+        members.insert(replica_id);
+        Gossip {
+            replica_id: replica_id,
+            fanout: fanout,
+            rng: replica_id.id(),
+            members: members,
+            states: HashMap::new(),
+            new_replica: new_replica,
+        }
+    }
+
+    /// Returns the ID of the replica.
+    pub fn replica_id(&self) -> ReplicaId {
+        self.replica_id
+    }
+
+    /// Returns the members, ordered by replica ID.
+    pub fn members(&self) -> Vec<ReplicaId> {
+        let mut members: Vec<ReplicaId> = self.members.iter().cloned().collect();
+        members.sort_by_key(|member| member.id());
+        members
+    }
+
+    /// Adds a member.
+    ///
+    /// Returns `false` if the replica was already a member.
+    pub fn join<R>(&mut self, member: R) -> bool where R: Into<ReplicaId> {
+        let member = member.into();
+        if self.members.contains(&member) {
+            return false;
+        }
+        self.members.insert(member);
+        true
+    }
+
+    /// Removes a member.
+    ///
+    /// Returns `false` if the replica was not a member.
+    pub fn remove_member<R>(&mut self, member: R) -> bool where R: Into<ReplicaId> {
+        self.members.remove(member.into()).is_some()
+    }
+
+    /// Returns the state for a key.
+    pub fn get(&self, key: &K) -> Option<&C> {
+        self.states.get(key).map(|versioned| &versioned.crdt)
+    }
+
+    /// Returns the number of keys.
+    pub fn len(&self) -> usize {
+        self.states.len()
+    }
+
+    /// Returns true if the replica holds no states.
+    pub fn is_empty(&self) -> bool {
+        self.states.is_empty()
+    }
+
+    /// Performs a local mutation on the state for a key, creating the state
+    /// if necessary.
+    pub fn update<F, R>(&mut self, key: K, mutation: F) -> R where F: FnOnce(&mut C) -> R {
+        let replica_id = self.replica_id;
+        let new_replica = &mut self.new_replica;
+        let versioned = self.states.entry(key).or_insert_with(|| {
+            Versioned { versions: HashMap::new(), crdt: new_replica(replica_id) }
+        });
+        *versioned.versions.entry(replica_id).or_insert(0) += 1;
+        mutation(&mut versioned.crdt)
+    }
+
+    /// Returns a digest of the replica's members and versions.
+    pub fn digest(&self) -> GossipMessage<K, C> {
+        GossipMessage::Digest {
+            from: self.replica_id,
+            members: self.members.clone(),
+            versions: self.states
+                          .iter()
+                          .map(|(key, versioned)| (key.clone(), versioned.versions.clone()))
+                          .collect(),
+        }
+    }
+
+    /// Chooses up to `fanout` members other than this replica at random.
+    pub fn choose_peers(&mut self) -> Vec<ReplicaId> {
+        let replica_id = self.replica_id;
+        let mut peers: Vec<ReplicaId> = self.members().into_iter().filter(|&id| id != replica_id).collect();
+        let count = self.fanout.min(peers.len());
+        for i in 0..count {
+            let j = i + (self.next_random() % (peers.len() - i) as u64) as usize;
+            peers.swap(i, j);
+        }
+        peers.truncate(count);
+        peers
+    }
+
+    /// Sends a digest to up to `fanout` random members.
+    ///
+    /// Returns the number of digests sent.
+    pub fn round<T>(&mut self, transport: &mut T) -> Result<usize, T::Error>
+    where T: Transport<GossipMessage<K, C>> {
+        let peers = self.choose_peers();
+        for &peer in peers.iter() {
+            try!(transport.send(peer, self.digest()));
+        }
+        Ok(peers.len())
+    }
+
+    /// Receives a message from another replica.
+    ///
+    /// Returns the reply to send, and the replica to send it to, if one is
+    /// needed.
+    pub fn receive(&mut self, message: GossipMessage<K, C>) -> Option<(ReplicaId, GossipMessage<K, C>)> {
+        match message {
+            GossipMessage::Digest { from, members, versions } => {
+                if from == self.replica_id {
+                    return None;
+                }
+                self.members.merge(members.clone());
+                let peer_versions: HashMap<K, HashMap<ReplicaId, u64>> = versions.into_iter().collect();
+                let states: Vec<_> = self.states
+                                         .iter()
+                                         .filter(|&(key, versioned)| {
+                                             peer_versions.get(key).map_or(true, |peer| !dominates(peer, &versioned.versions))
+                                         })
+                                         .map(|(key, versioned)| {
+                                             (key.clone(), versioned.versions.clone(), versioned.crdt.clone())
+                                         })
+                                         .collect();
+                let request: Vec<K> = peer_versions.into_iter()
+                                                   .filter(|(key, peer)| {
+                                                       self.states.get(key).map_or(true, |versioned| {
+                                                           !dominates(&versioned.versions, peer)
+                                                       })
+                                                   })
+                                                   .map(|(key, _)| key)
+                                                   .collect();
+                if states.is_empty() && request.is_empty() && self.members == members {
+                    return None;
+                }
+                Some((from, self.push(states, request)))
+            },
+            GossipMessage::Push { from, members, states, request } => {
+                if from == self.replica_id {
+                    return None;
+                }
+                self.members.merge(members);
+                for (key, versions, crdt) in states {
+                    self.merge_state(key, versions, crdt);
+                }
+                if request.is_empty() {
+                    return None;
+                }
+                let states = request.into_iter()
+                                    .filter_map(|key| {
+                                        self.states.get(&key).map(|versioned| {
+                                            (key, versioned.versions.clone(), versioned.crdt.clone())
+                                        })
+                                    })
+                                    .collect();
+                Some((from, self.push(states, Vec::new())))
+            },
+        }
+    }
+
+    /// Receives every message waiting in a transport, and sends the replies.
+    ///
+    /// Returns the number of messages received.
+    pub fn poll<T>(&mut self, transport: &mut T) -> Result<usize, T::Error>
+    where T: Transport<GossipMessage<K, C>> {
+        let mut received = 0;
+        while let Some(message) = try!(transport.receive()) {
+            received += 1;
+            if let Some((to, reply)) = self.receive(message) {
+                try!(transport.send(to, reply));
+            }
+        }
+        Ok(received)
+    }
+
+    fn push(&self, states: Vec<(K, HashMap<ReplicaId, u64>, C)>, request: Vec<K>) -> GossipMessage<K, C> {
+        GossipMessage::Push {
+            from: self.replica_id,
+            members: self.members.clone(),
+            states: states,
+            request: request,
+        }
+    }
+
+    fn merge_state(&mut self, key: K, versions: HashMap<ReplicaId, u64>, crdt: C) {
+        let replica_id = self.replica_id;
+        let new_replica = &mut self.new_replica;
+        let versioned = self.states.entry(key).or_insert_with(|| {
+            Versioned { versions: HashMap::new(), crdt: new_replica(replica_id) }
+        });
+        if dominates(&versioned.versions, &versions) {
+            return;
+        }
+        versioned.crdt.merge(crdt);
+        for (replica_id, count) in versions {
+            let local = versioned.versions.entry(replica_id).or_insert(0);
+            *local = (*local).max(count);
+        }
+    }
+
+    /// Steps the replica's pseudo-random sequence.
+    fn next_random(&mut self) -> u64 {
+        self.rng = self.rng.wrapping_add(0x9e3779b97f4a7c15);
+        mix(self.rng)
+    }
+}
+
+/// Returns true if version vector `a` includes every update in `b`.
+fn dominates(a: &HashMap<ReplicaId, u64>, b: &HashMap<ReplicaId, u64>) -> bool {
+    b.iter().all(|(replica_id, &count)| a.get(replica_id).cloned().unwrap_or(0) >= count)
+}
+
+fn encode_versions(versions: &HashMap<ReplicaId, u64>, buf: &mut Vec<u8>) {
+    wire::write_entries(buf, versions.iter(), |(replica_id, count), buf| {
+        replica_id.encode(buf);
+        count.encode(buf);
+    });
+}
+
+fn decode_versions(reader: &mut Reader) -> Result<HashMap<ReplicaId, u64>, DecodeError> {
+    wire::read_map(reader, |reader| Ok((try!(ReplicaId::decode(reader)), try!(u64::decode(reader)))))
+}
+
+impl <K, C> Encode for GossipMessage<K, C> where K: Encode, C: Encode {
+    fn encode(&self, buf: &mut Vec<u8>) {
+        match *self {
+            GossipMessage::Digest { ref from, ref members, ref versions } => {
+                buf.push(0);
+                from.encode(buf);
+                members.encode(buf);
+                wire::write_varint(buf, versions.len() as u64);
+                for (key, versions) in versions {
+                    key.encode(buf);
+                    encode_versions(versions, buf);
+                }
+            },
+            GossipMessage::Push { ref from, ref members, ref states, ref request } => {
+                buf.push(1);
+                from.encode(buf);
+                members.encode(buf);
+                wire::write_varint(buf, states.len() as u64);
+                for (key, versions, crdt) in states {
+                    key.encode(buf);
+                    encode_versions(versions, buf);
+                    crdt.encode(buf);
+                }
+                request.encode(buf);
+            },
+        }
+    }
+}
+
+impl <K, C> Decode for GossipMessage<K, C> where K: Decode, C: Decode {
+    fn decode(reader: &mut Reader) -> Result<GossipMessage<K, C>, DecodeError> {
+        match try!(reader.read_u8()) {
+            0 => {
+                let from = try!(Decode::decode(reader));
+                let members = try!(Decode::decode(reader));
+                let len = try!(reader.read_len());
+                let mut versions = Vec::with_capacity(len);
+                for _ in 0..len {
+                    versions.push((try!(Decode::decode(reader)), try!(decode_versions(reader))));
+                }
+                Ok(GossipMessage::Digest { from: from, members: members, versions: versions })
+            },
+            1 => {
+                let from = try!(Decode::decode(reader));
+                let members = try!(Decode::decode(reader));
+                let len = try!(reader.read_len());
+                let mut states = Vec::with_capacity(len);
+                for _ in 0..len {
+                    let key = try!(Decode::decode(reader));
+                    let versions = try!(decode_versions(reader));
+                    states.push((key, versions, try!(Decode::decode(reader))));
+                }
+                let request = try!(Decode::decode(reader));
+                Ok(GossipMessage::Push { from: from, members: members, states: states, request: request })
+            },
+            _ => Err(DecodeError::InvalidValue("unknown GossipMessage variant")),
+        }
+    }
+}
+
+impl <K, C> Message for GossipMessage<K, C> where K: Encode + Decode, C: Encode + Decode {
+    const TAG: u8 = 23;
+}
+
+#[cfg(test)]
+mod test {
+
+    use {Crdt, ReplicaId, wire};
+    use counter::GCounter;
+    use replication::{Gossip, GossipMessage, MemoryNetwork, MemoryTransport, Transport};
+    use set::GSet;
+
+    type Message = GossipMessage<u32, GCounter>;
+    type Node = Gossip<u32, GCounter, fn(ReplicaId) -> GCounter>;
+
+    fn new_node(id: u64, fanout: usize) -> Node {
+        Gossip::new(id, fanout, GCounter::new::<ReplicaId> as fn(ReplicaId) -> GCounter)
+    }
+
+    /// Connects `count` replicas, each of which joins through replica 1 and
+    /// updates the counters of two keys.
+    fn cluster(network: &MemoryNetwork<Message>, count: u64) -> (Vec<Node>, Vec<MemoryTransport<Message>>) {
+        let mut nodes = Vec::new();
+        let mut transports = Vec::new();
+        for id in 1..count + 1 {
+            let mut node = new_node(id, 2);
+            node.join(1);
+            node.update(0, |counter| counter.increment(id));
+            node.update(id as u32 % 3 + 1, |counter| counter.increment(1));
+            nodes.push(node);
+            transports.push(network.connect(id));
+        }
+        (nodes, transports)
+    }
+
+    /// Runs a round on every replica, and delivers messages until the
+    /// network is quiet.
+    fn run_round(network: &MemoryNetwork<Message>, nodes: &mut [Node], transports: &mut [MemoryTransport<Message>]) {
+        for (node, transport) in nodes.iter_mut().zip(transports.iter_mut()) {
+            node.round(transport).unwrap();
+        }
+        while nodes.iter().any(|node| network.pending(node.replica_id()) > 0) {
+            for (node, transport) in nodes.iter_mut().zip(transports.iter_mut()) {
+                node.poll(transport).unwrap();
+            }
+        }
+    }
+
+    fn has_converged(nodes: &[Node]) -> bool {
+        nodes.iter().all(|node| {
+            node.members() == nodes[0].members()
+                && node.len() == nodes[0].len()
+                && (0..4).all(|key| node.get(&key) == nodes[0].get(&key))
+        })
+    }
+
+    fn check_counts(nodes: &[Node], count: u64) {
+        for node in nodes {
+            assert_eq!(count, node.members().len() as u64);
+            assert_eq!(count * (count + 1) / 2, node.get(&0).unwrap().count());
+            let total: u64 = (1..4).map(|key| node.get(&key).unwrap().count()).sum();
+            assert_eq!(count, total);
+        }
+    }
+
+    #[test]
+    fn check_cluster_converges() {
+        let network = MemoryNetwork::new();
+        let (mut nodes, mut transports) = cluster(&network, 8);
+        let mut rounds = 0;
+        while !has_converged(&nodes) {
+            assert!(rounds < 20, "replicas did not converge");
+            run_round(&network, &mut nodes, &mut transports);
+            rounds += 1;
+        }
+        check_counts(&nodes, 8);
+
+        // Once converged, digests are not answered.
+        for (node, transport) in nodes.iter_mut().zip(transports.iter_mut()) {
+            assert_eq!(2, node.round(transport).unwrap());
+        }
+        for (node, transport) in nodes.iter_mut().zip(transports.iter_mut()) {
+            while let Some(message) = transport.receive().unwrap() {
+                assert!(node.receive(message).is_none());
+            }
+        }
+    }
+
+    #[test]
+    fn check_converges_with_dropped_messages() {
+        let network = MemoryNetwork::new();
+        let (mut nodes, mut transports) = cluster(&network, 8);
+        let mut rounds = 0;
+        while !has_converged(&nodes) {
+            assert!(rounds < 60, "replicas did not converge");
+            for (node, transport) in nodes.iter_mut().zip(transports.iter_mut()) {
+                node.round(transport).unwrap();
+            }
+            while nodes.iter().any(|node| network.pending(node.replica_id()) > 0) {
+                for (i, (node, transport)) in nodes.iter_mut().zip(transports.iter_mut()).enumerate() {
+                    // Drop every other message waiting for each replica.
+                    network.with_inbox(node.replica_id(), |inbox| {
+                        let mut n = rounds + i;
+                        inbox.retain(|_| { n += 1; n % 2 == 0 });
+                    });
+                    node.poll(transport).unwrap();
+                }
+            }
+            rounds += 1;
+        }
+        check_counts(&nodes, 8);
+    }
+
+    #[test]
+    fn check_pushes_missing_states() {
+        let mut a = Gossip::new(1, 1, |_| GSet::new());
+        let mut b = Gossip::new(2, 1, |_| GSet::new());
+        for key in 0..100u32 {
+            a.update(key, |set| set.insert(key));
+        }
+        for key in 0..100u32 {
+            b.receive(GossipMessage::Push {
+                from: ReplicaId::from(1),
+                members: a.members.clone(),
+                states: vec![(key, a.states[&key].versions.clone(), a.get(&key).unwrap().clone())],
+                request: Vec::new(),
+            });
+        }
+        a.update(7, |set| set.insert(1000));
+        b.update(200, |set| set.insert(200));
+
+        // b pushes the state a lacks, and requests the state it lacks.
+        let (to, reply) = b.receive(a.digest()).unwrap();
+        assert_eq!(ReplicaId::from(1), to);
+        match reply {
+            GossipMessage::Push { ref states, ref request, .. } => {
+                assert_eq!(vec![200], states.iter().map(|state| state.0).collect::<Vec<_>>());
+                assert_eq!(vec![7], *request);
+            },
+            _ => panic!("expected a push"),
+        }
+
+        // a pushes the requested state, and b does not reply.
+        let (to, reply) = a.receive(reply).unwrap();
+        assert_eq!(ReplicaId::from(2), to);
+        match reply {
+            GossipMessage::Push { ref states, ref request, .. } => {
+                assert_eq!(vec![7], states.iter().map(|state| state.0).collect::<Vec<_>>());
+                assert!(request.is_empty());
+            },
+            _ => panic!("expected a push"),
+        }
+        assert!(b.receive(reply).is_none());
+        assert_eq!(101, a.len());
+        assert!((0..100).chain(Some(200)).all(|key| a.get(&key) == b.get(&key)));
+    }
+
+    #[test]
+    fn check_membership_converges() {
+        let network = MemoryNetwork::new();
+        let (mut nodes, mut transports) = cluster(&network, 5);
+        for _ in 0..10 {
+            run_round(&network, &mut nodes, &mut transports);
+        }
+        assert!(has_converged(&nodes));
+
+        // Replica 5 is removed by replica 2, and stops gossiping, while
+        // replica 6 joins through replica 3.
+        assert!(nodes[1].remove_member(5));
+        nodes.pop();
+        transports.pop();
+        let mut node = new_node(6, 2);
+        node.join(3);
+        nodes.push(node);
+        transports.push(network.connect(6));
+
+        for _ in 0..10 {
+            run_round(&network, &mut nodes, &mut transports);
+        }
+        assert!(has_converged(&nodes));
+        let expected: Vec<ReplicaId> = vec![1, 2, 3, 4, 6].into_iter().map(ReplicaId::from).collect();
+        for node in nodes.iter() {
+            assert_eq!(expected, node.members());
+        }
+    }
+
+    #[test]
+    fn check_choose_peers() {
+        let mut node = new_node(1, 3);
+        assert!(node.choose_peers().is_empty());
+        for id in 2..10 {
+            node.join(id);
+        }
+        let mut chosen = Vec::new();
+        for _ in 0..20 {
+            let mut peers = node.choose_peers();
+            assert_eq!(3, peers.len());
+            peers.sort_by_key(|peer| peer.id());
+            peers.dedup();
+            assert_eq!(3, peers.len());
+            assert!(!peers.contains(&ReplicaId::from(1)));
+            chosen.extend(peers);
+        }
+        chosen.sort_by_key(|peer| peer.id());
+        chosen.dedup();
+        assert_eq!(8, chosen.len());
+    }
+
+    #[test]
+    fn check_wire_round_trip() {
+        let mut node = new_node(1, 2);
+        node.join(2);
+        node.update(3, |counter| counter.increment(5));
+        let digest = node.digest();
+        assert_eq!(digest, wire::from_bytes::<Message>(&wire::to_bytes(&digest)).unwrap());
+
+        let mut other = new_node(2, 2);
+        other.update(4, |counter| counter.increment(1));
+        let (_, push) = other.receive(digest).unwrap();
+        assert_eq!(push, wire::from_bytes::<Message>(&wire::to_bytes(&push)).unwrap());
+
+        let mut merged = GCounter::new(9);
+        if let GossipMessage::Push { states, .. } = push {
+            for (_, _, counter) in states {
+                merged.merge(counter);
+            }
+        }
+        assert_eq!(1, merged.count());
+    }
+}
@@ -0,0 +1,491 @@
+use std::collections::HashSet;
+
+use replication::Reconcile;
+use replication::reconcile::mix;
+use wire::{Decode, DecodeError, Encode, Message, Reader};
+
+/// The number of cells in the first sketch of a session by default.
+pub const DEFAULT_CELLS: usize = 64;
+
+/// The maximum number of cells in a sketch.
+pub const MAX_CELLS: usize = 1 << 16;
+
+/// The number of cells each entry is added to.
+const HASH_COUNT: usize = 3;
+
+/// A cell of an invertible Bloom lookup table, holding the number of entries
+/// added to it and the XOR of their hashes.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct IbltCell {
+    count: i64,
+    key_sum: u64,
+    entry_sum: u64,
+    check_sum: u64,
+}
+
+/// A message exchanged during IBLT reconciliation.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum IbltMessage<C> {
+    /// A sketch of the entries of the sender's replica.
+    Sketch { cells: Vec<IbltCell> },
+    /// The sender's entries with the key hashes which differ, and the key
+    /// hashes of the entries the sender is missing.
+    Entries { entries: C, request: Vec<u64> },
+    /// The full state of the sender's replica. If `reply` is set, the
+    /// receiver responds with its own full state.
+    State { state: C, reply: bool },
+}
+
+/// One side of a sketch-based reconciliation between two replicas of a
+/// `Reconcile` CRDT.
+///
+/// Each side summarizes its replica with an invertible Bloom lookup table: a
+/// fixed number of cells, each holding the count and the XOR of the hashes of
+/// the entries added to it. Subtracting one side's table from the other's
+/// cancels the entries which the replicas share, and if the replicas differ
+/// in fewer entries than about two thirds of the cells, the remaining entries
+/// can be recovered one by one. The side which recovers the difference sends
+/// the entries the other side is missing, and requests the entries it is
+/// missing itself, so the bytes exchanged depend on the size of the
+/// difference rather than on the size of the replicas.
+///
+/// If the difference cannot be recovered, the side which failed responds
+/// with a sketch of twice as many cells, until a sketch would hold more cells
+/// than `MAX_CELLS` or than the replica has entries, at which point it falls
+/// back to exchanging the full state.
+///
+/// Like `MerkleSync`, `IbltSync` is a pure state machine: `start` returns the
+/// first message, and `handle` returns the response to each message received,
+/// until there is no response.
+///
+/// ##### Example
+///
+/// ```
+/// use crdt::replication::IbltSync;
+/// use crdt::set::GSet;
+///
+/// let mut a = GSet::new();
+/// let mut b = GSet::new();
+/// for i in 0..10000u32 {
+///     a.insert(i);
+///     b.insert(i);
+/// }
+/// a.insert(10000);
+/// b.insert(10001);
+///
+/// let (mut a_sync, mut b_sync) = (IbltSync::new(), IbltSync::new());
+/// let mut message = Some(a_sync.start(&a));
+/// while let Some(m) = message.take().and_then(|m| b_sync.handle(&mut b, m)) {
+///     message = a_sync.handle(&mut a, m);
+/// }
+/// assert_eq!(a, b);
+/// ```
+pub struct IbltSync {
+    cells: usize,
+}
+
+impl IbltSync {
+
+    /// Creates a session whose first sketch has `DEFAULT_CELLS` cells.
+    pub fn new() -> IbltSync {
+        IbltSync::with_cells(DEFAULT_CELLS)
+    }
+
+    /// Creates a session whose first sketch has `cells` cells.
+    ///
+    /// A sketch which is too small for the difference between the replicas
+    /// costs an extra round trip, and one which is too large costs bytes.
+    ///
+    /// ##### Panics
+    ///
+    /// Panics if `cells` is less than 3 or greater than `MAX_CELLS`.
+    pub fn with_cells(cells: usize) -> IbltSync {
+        assert!(cells >= HASH_COUNT && cells <= MAX_CELLS, "invalid IBLT sketch size {}", cells);
+        IbltSync { cells: cells }
+    }
+
+    /// Returns the first message of a session, holding a sketch of the
+    /// replica.
+    pub fn start<C>(&mut self, replica: &C) -> IbltMessage<C> where C: Reconcile {
+        IbltMessage::Sketch { cells: sketch(replica, self.cells) }
+    }
+
+    /// Handles a message from the other side of the session, merging any
+    /// entries it holds into `replica`.
+    ///
+    /// Returns the response to send, or `None` if the session is complete.
+    /// Sketches of an unsupported size are ignored.
+    pub fn handle<C>(&mut self, replica: &mut C, message: IbltMessage<C>) -> Option<IbltMessage<C>>
+    where C: Reconcile {
+        match message {
+            IbltMessage::Sketch { cells } => {
+                if cells.len() < HASH_COUNT || cells.len() > MAX_CELLS {
+                    return None;
+                }
+                let mut difference = sketch(replica, cells.len());
+                for (cell, other) in difference.iter_mut().zip(cells.iter()) {
+                    cell.subtract(other);
+                }
+                match peel(difference) {
+                    Some((ref own, ref request)) if own.is_empty() && request.is_empty() => None,
+                    Some((own, request)) => {
+                        let entries = select(replica, &own);
+                        Some(IbltMessage::Entries { entries: entries, request: request })
+                    },
+                    None => {
+                        let mut len = 0;
+                        replica.fingerprints(|_, _| len += 1);
+                        self.cells = cells.len() * 2;
+                        if self.cells > MAX_CELLS || self.cells > len {
+                            Some(IbltMessage::State { state: replica.clone(), reply: true })
+                        } else {
+                            Some(IbltMessage::Sketch { cells: sketch(replica, self.cells) })
+                        }
+                    },
+                }
+            },
+            IbltMessage::Entries { entries, request } => {
+                let response = if request.is_empty() {
+                    None
+                } else {
+                    let own = select(replica, &request);
+                    Some(IbltMessage::Entries { entries: own, request: Vec::new() })
+                };
+                replica.merge(entries);
+                response
+            },
+            IbltMessage::State { state, reply } => {
+                let response = if reply {
+                    Some(IbltMessage::State { state: replica.clone(), reply: false })
+                } else {
+                    None
+                };
+                replica.merge(state);
+                response
+            },
+        }
+    }
+}
+
+impl Default for IbltSync {
+    fn default() -> IbltSync {
+        IbltSync::new()
+    }
+}
+
+impl IbltCell {
+
+    /// Returns true if the cell, at `index` in a sketch of `len` cells, holds
+    /// exactly one entry, added or subtracted.
+    fn is_pure(&self, index: usize, len: usize) -> bool {
+        (self.count == 1 || self.count == -1)
+            && self.check_sum == hash(self.entry_sum, HASH_COUNT)
+            && indices(self.entry_sum, len).contains(&index)
+    }
+
+    fn is_empty(&self) -> bool {
+        *self == IbltCell::default()
+    }
+
+    /// Adds an entry to the cell if `count` is 1, or removes it if -1.
+    fn toggle(&mut self, count: i64, key_hash: u64, entry_hash: u64) {
+        self.count = self.count.wrapping_add(count);
+        self.key_sum ^= key_hash;
+        self.entry_sum ^= entry_hash;
+        self.check_sum ^= hash(entry_hash, HASH_COUNT);
+    }
+
+    fn subtract(&mut self, other: &IbltCell) {
+        self.count = self.count.wrapping_sub(other.count);
+        self.key_sum ^= other.key_sum;
+        self.entry_sum ^= other.entry_sum;
+        self.check_sum ^= other.check_sum;
+    }
+}
+
+/// Returns the `i`th hash of an entry hash. The first `HASH_COUNT` hashes
+/// choose the entry's cells, and the last is its checksum.
+fn hash(entry_hash: u64, i: usize) -> u64 {
+    mix(entry_hash.wrapping_add(i as u64 + 1))
+}
+
+/// Returns the cells of an entry in a sketch of `len` cells, one in each of
+/// `HASH_COUNT` equal partitions of the sketch.
+fn indices(entry_hash: u64, len: usize) -> [usize; HASH_COUNT] {
+    let mut indices = [0; HASH_COUNT];
+    for (i, index) in indices.iter_mut().enumerate() {
+        let start = i * len / HASH_COUNT;
+        let end = (i + 1) * len / HASH_COUNT;
+        *index = start + (hash(entry_hash, i) % (end - start) as u64) as usize;
+    }
+    indices
+}
+
+/// Returns a sketch of the entries of the replica.
+fn sketch<C>(replica: &C, len: usize) -> Vec<IbltCell> where C: Reconcile {
+    let mut cells = vec![IbltCell::default(); len];
+    replica.fingerprints(|key_hash, entry_hash| {
+        for &index in indices(entry_hash, len).iter() {
+            cells[index].toggle(1, key_hash, entry_hash);
+        }
+    });
+    cells
+}
+
+/// Recovers the entries of the difference between two sketches, returning
+/// the key hashes of the entries only in the first and of those only in the
+/// second, or `None` if the difference cannot be recovered.
+///
+/// Each recovered entry empties a cell, so a difference of more entries than
+/// there are cells cannot be recovered, and peeling stops after that many
+/// recoveries whatever the cells hold.
+fn peel(mut cells: Vec<IbltCell>) -> Option<(Vec<u64>, Vec<u64>)> {
+    let (mut own, mut other) = (Vec::new(), Vec::new());
+    let mut progress = true;
+    while progress {
+        progress = false;
+        for i in 0..cells.len() {
+            if !cells[i].is_pure(i, cells.len()) {
+                continue;
+            }
+            if own.len() + other.len() == cells.len() {
+                return None;
+            }
+            let IbltCell { count, key_sum, entry_sum, .. } = cells[i];
+            if count == 1 { own.push(key_sum) } else { other.push(key_sum) }
+            for &index in indices(entry_sum, cells.len()).iter() {
+                cells[index].toggle(-count, key_sum, entry_sum);
+            }
+            progress = true;
+        }
+    }
+    if cells.iter().all(IbltCell::is_empty) {
+        Some((own, other))
+    } else {
+        None
+    }
+}
+
+/// Returns the entries of the replica with the key hashes.
+fn select<C>(replica: &C, key_hashes: &[u64]) -> C where C: Reconcile {
+    let key_hashes: HashSet<u64> = key_hashes.iter().cloned().collect();
+    replica.select(|key_hash| key_hashes.contains(&key_hash))
+}
+
+impl Encode for IbltCell {
+    fn encode(&self, buf: &mut Vec<u8>) {
+        self.count.encode(buf);
+        self.key_sum.encode(buf);
+        self.entry_sum.encode(buf);
+        self.check_sum.encode(buf);
+    }
+}
+
+impl Decode for IbltCell {
+    fn decode(reader: &mut Reader) -> Result<IbltCell, DecodeError> {
+        Ok(IbltCell {
+            count: try!(Decode::decode(reader)),
+            key_sum: try!(Decode::decode(reader)),
+            entry_sum: try!(Decode::decode(reader)),
+            check_sum: try!(Decode::decode(reader)),
+        })
+    }
+}
+
+impl <C> Encode for IbltMessage<C> where C: Encode {
+    fn encode(&self, buf: &mut Vec<u8>) {
+        match *self {
+            IbltMessage::Sketch { ref cells } => {
+                buf.push(0);
+                cells.encode(buf);
+            },
+            IbltMessage::Entries { ref entries, ref request } => {
+                buf.push(1);
+                entries.encode(buf);
+                request.encode(buf);
+            },
+            IbltMessage::State { ref state, reply } => {
+                buf.push(2);
+                state.encode(buf);
+                reply.encode(buf);
+            },
+        }
+    }
+}
+
+impl <C> Decode for IbltMessage<C> where C: Decode {
+    fn decode(reader: &mut Reader) -> Result<IbltMessage<C>, DecodeError> {
+        match try!(reader.read_u8()) {
+            0 => {
+                let cells: Vec<IbltCell> = try!(Decode::decode(reader));
+                if cells.len() < HASH_COUNT || cells.len() > MAX_CELLS {
+                    return Err(DecodeError::InvalidValue("invalid IBLT sketch size"));
+                }
+                Ok(IbltMessage::Sketch { cells: cells })
+            },
+            1 => {
+                let entries = try!(Decode::decode(reader));
+                let request = try!(Decode::decode(reader));
+                Ok(IbltMessage::Entries { entries: entries, request: request })
+            },
+            2 => {
+                let state = try!(Decode::decode(reader));
+                let reply = try!(Decode::decode(reader));
+                Ok(IbltMessage::State { state: state, reply: reply })
+            },
+            _ => Err(DecodeError::InvalidValue("unknown IbltMessage variant")),
+        }
+    }
+}
+
+impl <C> Message for IbltMessage<C> where C: Encode + Decode {
+    const TAG: u8 = 19;
+}
+
+#[cfg(test)]
+mod test {
+
+    use std::{fmt, i64};
+
+    use Crdt;
+    use replication::{IbltMessage, IbltSync, Reconcile};
+    use super::{hash, indices, IbltCell, HASH_COUNT};
+    use set::{GSet, LwwSet, TpSet};
+    use wire::{self, Message};
+
+    /// Reconciles two replicas, returning the number of bytes exchanged and
+    /// the number of sketches sent.
+    fn sync<C>(a: &mut C, b: &mut C, cells: usize) -> (usize, usize)
+    where C: Reconcile + Message + fmt::Debug {
+        let (mut a_sync, mut b_sync) = (IbltSync::with_cells(cells), IbltSync::new());
+        let mut message = Some(a_sync.start(a));
+        let (mut bytes, mut sketches) = (0, 0);
+        let mut sides = [(b, &mut b_sync), (a, &mut a_sync)];
+        let mut turn = 0;
+        while let Some(m) = message.take() {
+            bytes += wire::to_bytes(&m).len();
+            if let IbltMessage::Sketch { .. } = m {
+                sketches += 1;
+            }
+            let decoded: IbltMessage<C> = wire::from_bytes(&wire::to_bytes(&m)).unwrap();
+            assert_eq!(m, decoded);
+            let (ref mut replica, ref mut session) = sides[turn % 2];
+            message = session.handle(&mut **replica, decoded);
+            turn += 1;
+        }
+        (bytes, sketches)
+    }
+
+    fn check_converges<C>(mut a: C, mut b: C, cells: u8) -> bool where C: Reconcile + Message + fmt::Debug {
+        let mut expected = a.clone();
+        expected.merge(b.clone());
+        sync(&mut a, &mut b, cells as usize + 3);
+        a == expected && b == expected
+    }
+
+    #[quickcheck]
+    fn check_gset_converges(a: GSet<u32>, b: GSet<u32>, cells: u8) -> bool {
+        check_converges(a, b, cells)
+    }
+
+    #[quickcheck]
+    fn check_tpset_converges(a: TpSet<u32>, b: TpSet<u32>, cells: u8) -> bool {
+        check_converges(a, b, cells)
+    }
+
+    #[quickcheck]
+    fn check_lwwset_converges(a: LwwSet<u32>, b: LwwSet<u32>, cells: u8) -> bool {
+        check_converges(a, b, cells)
+    }
+
+    fn large_set() -> GSet<u32> {
+        let mut set = GSet::new();
+        for i in 0..100000u32 {
+            set.insert(i);
+        }
+        set
+    }
+
+    /// Checks that replicas of a large set which differ in a few elements
+    /// exchange a small fraction of the full state.
+    #[test]
+    fn check_exchanges_difference() {
+        let mut a = large_set();
+        let full = wire::to_bytes(&a).len();
+        let mut b = a.clone();
+        for i in 0..10 {
+            a.insert(200000 + i);
+            b.insert(300000 + i);
+        }
+
+        let mut expected = a.clone();
+        expected.merge(b.clone());
+        let (bytes, sketches) = sync(&mut a, &mut b, 64);
+        assert_eq!(expected, a);
+        assert_eq!(expected, b);
+        assert_eq!(1, sketches);
+        assert!(bytes * 100 < full, "{} bytes exchanged, full state is {}", bytes, full);
+
+        // Replicas which are already in sync exchange only the sketch.
+        let (bytes, _) = sync(&mut a, &mut b, 64);
+        assert!(bytes * 100 < full);
+    }
+
+    /// Checks that a sketch which is too small for the difference falls back
+    /// to larger sketches.
+    #[test]
+    fn check_falls_back_to_larger_sketch() {
+        let mut a = large_set();
+        let full = wire::to_bytes(&a).len();
+        let mut b = a.clone();
+        for i in 0..300 {
+            a.insert(200000 + i);
+        }
+
+        let (bytes, sketches) = sync(&mut a, &mut b, 64);
+        assert_eq!(a, b);
+        assert!(sketches > 1);
+        assert!(bytes * 5 < full, "{} bytes exchanged, full state is {}", bytes, full);
+    }
+
+    /// Checks that replicas which differ in most of their entries exchange
+    /// their full state rather than ever larger sketches.
+    #[test]
+    fn check_falls_back_to_full_state() {
+        let mut a = large_set();
+        let full = wire::to_bytes(&a).len();
+        let mut b = GSet::new();
+
+        let (bytes, _) = sync(&mut b, &mut a, 64);
+        assert_eq!(a, b);
+        assert!(bytes < full + 16 * 1024, "{} bytes exchanged, full state is {}", bytes, full);
+    }
+
+    #[test]
+    fn check_ignores_invalid_messages() {
+        let mut set = GSet::new();
+        set.insert(1u32);
+        let message = IbltMessage::Sketch { cells: vec![Default::default(); 2] };
+        assert_eq!(None, IbltSync::new().handle(&mut set, message));
+
+        assert!(wire::from_bytes::<IbltMessage<GSet<u32>>>(&[wire::VERSION, 19, 1, 0, 2, 0, 0, 0, 0, 0, 0, 0, 0]).is_err());
+    }
+
+    /// Checks that a sketch crafted so that peeling never empties its cells
+    /// is answered rather than peeled forever.
+    #[test]
+    fn check_adversarial_sketch() {
+        let len = 64;
+        let entry_hash = (0..).find(|&entry_hash| !indices(entry_hash, len).contains(&0)).unwrap();
+        let mut cells = vec![IbltCell::default(); len];
+        cells[0] = IbltCell { count: 1, key_sum: 1, entry_sum: entry_hash, check_sum: hash(entry_hash, HASH_COUNT) };
+        cells[1].count = i64::MIN;
+
+        let mut set = GSet::new();
+        set.insert(1u32);
+        match IbltSync::new().handle(&mut set, IbltMessage::Sketch { cells: cells }) {
+            Some(IbltMessage::State { reply: true, .. }) => (),
+            response => panic!("unexpected response {:?}", response),
+        }
+    }
+}
@@ -0,0 +1,357 @@
+use std::collections::{BTreeMap, HashSet};
+
+use replication::Reconcile;
+use replication::reconcile::mix;
+use wire::{self, Decode, DecodeError, Encode, Message, Reader};
+
+/// The number of levels in the Merkle tree used by default.
+pub const DEFAULT_DEPTH: u8 = 4;
+
+/// The maximum number of levels in the Merkle tree.
+pub const MAX_DEPTH: u8 = 5;
+
+/// The number of bits of the key hash consumed by each level of the tree.
+const LEVEL_BITS: u32 = 4;
+
+/// The number of children of each interior node of the tree.
+const FANOUT: u64 = 1 << LEVEL_BITS;
+
+/// A message exchanged during Merkle reconciliation.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum MerkleMessage<C> {
+    /// The hashes of tree nodes at `level`, by index within the level.
+    Digests { depth: u8, level: u8, nodes: Vec<(u64, u64)> },
+    /// The entries in the listed leaf buckets. If `reply` is set, the
+    /// receiver responds with its own entries in the same buckets.
+    Entries { depth: u8, buckets: Vec<u64>, entries: C, reply: bool },
+}
+
+/// One side of a digest-based reconciliation between two replicas of a
+/// `Reconcile` CRDT.
+///
+/// Each side builds a Merkle tree over the entries of its replica. Entries
+/// are assigned to leaf buckets by the high bits of their key hash, a leaf's
+/// hash covers the entries in its bucket, and every other node's hash covers
+/// its 16 children. Starting from the root, the sides exchange the hashes of
+/// the children of nodes which differ, and finally the entries of the leaf
+/// buckets which differ, which each side merges into its replica. Replicas
+/// which differ in a few entries exchange a few buckets, regardless of the
+/// size of the replicas.
+///
+/// `MerkleSync` is a pure state machine which does not depend on any
+/// transport: `start` returns the first message, and `handle` returns the
+/// response to each message received, until there is no response. Either
+/// side may start; the side which receives the first message adopts the
+/// tree depth of the side which sent it. A session assumes that the replica
+/// is not otherwise modified while the session is in progress.
+///
+/// ##### Example
+///
+/// ```
+/// use crdt::replication::MerkleSync;
+/// use crdt::set::GSet;
+///
+/// let mut a = GSet::new();
+/// let mut b = GSet::new();
+/// for i in 0..10000u32 {
+///     a.insert(i);
+///     b.insert(i);
+/// }
+/// a.insert(10000);
+/// b.insert(10001);
+///
+/// let (mut a_sync, mut b_sync) = (MerkleSync::new(), MerkleSync::new());
+/// let mut message = Some(a_sync.start(&a));
+/// while let Some(m) = message.take().and_then(|m| b_sync.handle(&mut b, m)) {
+///     message = a_sync.handle(&mut a, m);
+/// }
+/// assert_eq!(a, b);
+/// ```
+pub struct MerkleSync {
+    depth: u8,
+    /// The hashes of the tree's nodes, by level and index, or `None` if the
+    /// replica has changed since the tree was built.
+    tree: Option<Vec<Vec<u64>>>,
+}
+
+impl MerkleSync {
+
+    /// Creates a session with a tree of the default depth.
+    pub fn new() -> MerkleSync {
+        MerkleSync::with_depth(DEFAULT_DEPTH)
+    }
+
+    /// Creates a session with a tree of `depth` levels below the root, and
+    /// `16^depth` leaf buckets.
+    ///
+    /// Deeper trees exchange fewer entries per differing bucket, at the cost
+    /// of more round trips and more memory.
+    ///
+    /// ##### Panics
+    ///
+    /// Panics if `depth` is 0 or greater than `MAX_DEPTH`.
+    pub fn with_depth(depth: u8) -> MerkleSync {
+        assert!(depth > 0 && depth <= MAX_DEPTH, "invalid Merkle tree depth {}", depth);
+        MerkleSync { depth: depth, tree: None }
+    }
+
+    /// Returns the first message of a session, holding the root hash of the
+    /// replica.
+    pub fn start<C>(&mut self, replica: &C) -> MerkleMessage<C> where C: Reconcile {
+        let root = self.tree(replica)[0][0];
+        MerkleMessage::Digests { depth: self.depth, level: 0, nodes: vec![(0, root)] }
+    }
+
+    /// Handles a message from the other side of the session, merging any
+    /// entries it holds into `replica`.
+    ///
+    /// Returns the response to send, or `None` if the session is complete.
+    /// Messages for a tree of an unsupported depth, or for nodes which do not
+    /// exist, are ignored.
+    pub fn handle<C>(&mut self, replica: &mut C, message: MerkleMessage<C>) -> Option<MerkleMessage<C>>
+    where C: Reconcile {
+        match message {
+            MerkleMessage::Digests { depth, level, nodes } => {
+                if depth == 0 || depth > MAX_DEPTH || level > depth {
+                    return None;
+                }
+                if depth != self.depth {
+                    self.depth = depth;
+                    self.tree = None;
+                }
+                let differing: Vec<u64> = {
+                    let hashes = &self.tree(replica)[level as usize];
+                    nodes.into_iter()
+                         .filter(|&(index, hash)| hashes.get(index as usize).map_or(false, |&h| h != hash))
+                         .map(|(index, _)| index)
+                         .collect()
+                };
+                if differing.is_empty() {
+                    None
+                } else if level == depth {
+                    let entries = select(replica, depth, &differing);
+                    Some(MerkleMessage::Entries { depth: depth, buckets: differing, entries: entries, reply: true })
+                } else {
+                    let children = &self.tree(replica)[level as usize + 1];
+                    let nodes = differing.iter()
+                                         .flat_map(|&index| index * FANOUT..(index + 1) * FANOUT)
+                                         .map(|child| (child, children[child as usize]))
+                                         .collect();
+                    Some(MerkleMessage::Digests { depth: depth, level: level + 1, nodes: nodes })
+                }
+            },
+            MerkleMessage::Entries { depth, buckets, entries, reply } => {
+                if depth == 0 || depth > MAX_DEPTH {
+                    return None;
+                }
+                let response = if reply {
+                    let own = select(replica, depth, &buckets);
+                    Some(MerkleMessage::Entries { depth: depth, buckets: buckets, entries: own, reply: false })
+                } else {
+                    None
+                };
+                replica.merge(entries);
+                self.tree = None;
+                response
+            },
+        }
+    }
+
+    /// Returns the tree of the replica, building it if necessary.
+    fn tree<C>(&mut self, replica: &C) -> &Vec<Vec<u64>> where C: Reconcile {
+        if self.tree.is_none() {
+            let depth = self.depth;
+            let mut leaves = vec![0u64; FANOUT.pow(depth as u32) as usize];
+            replica.fingerprints(|key_hash, entry_hash| {
+                let leaf = &mut leaves[bucket(key_hash, depth) as usize];
+                *leaf = leaf.wrapping_add(entry_hash);
+            });
+            let mut levels = vec![leaves];
+            while levels[0].len() > 1 {
+                let parents = levels[0].chunks(FANOUT as usize)
+                                       .map(|children| children.iter().fold(0, |hash, &child| mix(hash ^ child)))
+                                       .collect();
+                levels.insert(0, parents);
+            }
+            self.tree = Some(levels);
+        }
+        self.tree.as_ref().unwrap()
+    }
+}
+
+impl Default for MerkleSync {
+    fn default() -> MerkleSync {
+        MerkleSync::new()
+    }
+}
+
+/// Returns the leaf bucket of a key hash in a tree of `depth` levels.
+fn bucket(key_hash: u64, depth: u8) -> u64 {
+    key_hash >> (64 - LEVEL_BITS * depth as u32)
+}
+
+/// Returns the entries of the replica in the buckets.
+fn select<C>(replica: &C, depth: u8, buckets: &[u64]) -> C where C: Reconcile {
+    let buckets: HashSet<u64> = buckets.iter().cloned().collect();
+    replica.select(|key_hash| buckets.contains(&bucket(key_hash, depth)))
+}
+
+impl <C> Encode for MerkleMessage<C> where C: Encode {
+    fn encode(&self, buf: &mut Vec<u8>) {
+        match *self {
+            MerkleMessage::Digests { depth, level, ref nodes } => {
+                buf.push(0);
+                depth.encode(buf);
+                level.encode(buf);
+                wire::write_entries(buf, nodes.iter(), |&(index, hash), buf| {
+                    index.encode(buf);
+                    hash.encode(buf);
+                });
+            },
+            MerkleMessage::Entries { depth, ref buckets, ref entries, reply } => {
+                buf.push(1);
+                depth.encode(buf);
+                buckets.encode(buf);
+                entries.encode(buf);
+                reply.encode(buf);
+            },
+        }
+    }
+}
+
+impl <C> Decode for MerkleMessage<C> where C: Decode {
+    fn decode(reader: &mut Reader) -> Result<MerkleMessage<C>, DecodeError> {
+        let variant = try!(reader.read_u8());
+        let depth = try!(u8::decode(reader));
+        if depth == 0 || depth > MAX_DEPTH {
+            return Err(DecodeError::InvalidValue("invalid Merkle tree depth"));
+        }
+        match variant {
+            0 => {
+                let level = try!(u8::decode(reader));
+                if level > depth {
+                    return Err(DecodeError::InvalidValue("Merkle tree level exceeds depth"));
+                }
+                let nodes: BTreeMap<u64, u64> = try!(wire::read_map(reader, |reader| {
+                    Ok((try!(u64::decode(reader)), try!(u64::decode(reader))))
+                }));
+                let nodes: Vec<(u64, u64)> = nodes.into_iter().collect();
+                Ok(MerkleMessage::Digests { depth: depth, level: level, nodes: nodes })
+            },
+            1 => {
+                let buckets = try!(Decode::decode(reader));
+                let entries = try!(Decode::decode(reader));
+                let reply = try!(Decode::decode(reader));
+                Ok(MerkleMessage::Entries { depth: depth, buckets: buckets, entries: entries, reply: reply })
+            },
+            _ => Err(DecodeError::InvalidValue("unknown MerkleMessage variant")),
+        }
+    }
+}
+
+impl <C> Message for MerkleMessage<C> where C: Encode + Decode {
+    const TAG: u8 = 18;
+}
+
+#[cfg(test)]
+mod test {
+
+    use std::fmt;
+
+    use Crdt;
+    use replication::{MerkleMessage, MerkleSync, Reconcile};
+    use set::{GSet, LwwSet, TpSet};
+    use wire::{self, Message};
+
+    /// Reconciles two replicas, returning the number of bytes exchanged.
+    fn sync<C>(a: &mut C, b: &mut C, depth: u8) -> usize where C: Reconcile + Message + fmt::Debug {
+        let (mut a_sync, mut b_sync) = (MerkleSync::with_depth(depth), MerkleSync::new());
+        let mut message = Some(a_sync.start(a));
+        let mut bytes = 0;
+        let mut sides = [(b, &mut b_sync), (a, &mut a_sync)];
+        let mut turn = 0;
+        while let Some(m) = message.take() {
+            bytes += wire::to_bytes(&m).len();
+            let decoded: MerkleMessage<C> = wire::from_bytes(&wire::to_bytes(&m)).unwrap();
+            assert_eq!(m, decoded);
+            let (ref mut replica, ref mut session) = sides[turn % 2];
+            message = session.handle(&mut **replica, decoded);
+            turn += 1;
+        }
+        bytes
+    }
+
+    fn check_converges<C>(mut a: C, mut b: C, depth: u8) -> bool where C: Reconcile + Message + fmt::Debug {
+        let mut expected = a.clone();
+        expected.merge(b.clone());
+        sync(&mut a, &mut b, depth % 3 + 1);
+        a == expected && b == expected
+    }
+
+    #[quickcheck]
+    fn check_gset_converges(a: GSet<u32>, b: GSet<u32>, depth: u8) -> bool {
+        check_converges(a, b, depth)
+    }
+
+    #[quickcheck]
+    fn check_tpset_converges(a: TpSet<u32>, b: TpSet<u32>, depth: u8) -> bool {
+        check_converges(a, b, depth)
+    }
+
+    #[quickcheck]
+    fn check_lwwset_converges(a: LwwSet<u32>, b: LwwSet<u32>, depth: u8) -> bool {
+        check_converges(a, b, depth)
+    }
+
+    #[test]
+    fn check_lwwset_frontier_converges() {
+        let mut a = LwwSet::new();
+        let mut b = LwwSet::new();
+        for i in 0..100u32 {
+            a.insert(i, i as u64);
+        }
+        a.remove(1, 200);
+        a.purge_tombstones(100);
+        a.remove(2, 201);
+        b.merge(a.clone());
+        b.purge_tombstones(300);
+        b.insert(1000, 301);
+        assert!(check_converges(a, b, 1));
+    }
+
+    /// Checks that replicas of a large set which differ in a few elements
+    /// exchange a small fraction of the full state.
+    #[test]
+    fn check_exchanges_differing_buckets() {
+        let mut a = GSet::new();
+        for i in 0..100000u32 {
+            a.insert(i);
+        }
+        let full = wire::to_bytes(&a).len();
+        let mut b = a.clone();
+        for i in 0..10 {
+            a.insert(1000000 + i);
+            b.insert(2000000 + i);
+        }
+
+        let bytes = sync(&mut a, &mut b, 4);
+        assert_eq!(a, b);
+        assert_eq!(100020, a.len());
+        assert!(bytes * 20 < full, "exchanged {} bytes, full state is {} bytes", bytes, full);
+
+        // Replicas which are already in sync exchange only the root hash.
+        assert!(sync(&mut a, &mut b, 4) < 24);
+    }
+
+    #[test]
+    fn check_ignores_invalid_messages() {
+        let mut set = GSet::new();
+        set.insert(1u32);
+        let mut session = MerkleSync::new();
+        let message = MerkleMessage::Digests { depth: 9, level: 0, nodes: vec![(0, 0)] };
+        assert_eq!(None, session.handle(&mut set, message));
+        let message = MerkleMessage::Digests { depth: 1, level: 1, nodes: vec![(16, 0)] };
+        assert_eq!(None, session.handle(&mut set, message));
+        assert!(wire::from_bytes::<MerkleMessage<GSet<u32>>>(&[wire::VERSION, 18, 1, 0, 9, 0, 0]).is_err());
+    }
+}
@@ -0,0 +1,42 @@
+//! Replication middleware for CRDTs.
+//!
+//! Operation-based replication requires that operations reach every replica,
+//! and some CRDTs additionally require that each operation is applied exactly
+//! once, or in causal order. `CausalBroadcast` provides both guarantees on top
+//! of any `Transport`, which only has to eventually carry each message.
+//!
+//! State-based replication of large sets is expensive when the full state is
+//! shipped on every sync. `MerkleSync` reconciles two replicas of a
+//! `Reconcile` CRDT by comparing hashes of their entries, and exchanges only
+//! the entries which differ. `IbltSync` instead exchanges a fixed-size sketch
+//! of the entries, which suits replicas that differ in only a few entries.
+//!
+//! With the `futures` feature, `Replicator` drives the replication of a CRDT
+//! over any `Sink` and `Stream` of updates, sending operations as they are
+//! performed and the full state periodically for anti-entropy.
+//!
+//! `Gossip` disseminates a keyed collection of CRDT states among a changing
+//! set of members: each round, a replica exchanges version vectors with a few
+//! random members, and pushes only the states they lack. Membership is itself
+//! replicated as an observed-remove set.
+//!
+//! `MemoryNetwork` is a transport between replicas in a single process, for
+//! testing replication under dropped, duplicated, and reordered messages.
+
+pub use self::causal::{CausalBroadcast, CausalOp, Dot};
+pub use self::gossip::{Gossip, GossipMessage};
+pub use self::iblt::{IbltCell, IbltMessage, IbltSync, DEFAULT_CELLS, MAX_CELLS};
+pub use self::merkle::{MerkleMessage, MerkleSync, DEFAULT_DEPTH, MAX_DEPTH};
+pub use self::reconcile::{fingerprint, Reconcile};
+#[cfg(feature = "futures")]
+pub use self::replicator::{Replicator, ReplicatorHandle, Update};
+pub use self::transport::{MemoryNetwork, MemoryTransport, Transport};
+
+mod causal;
+mod gossip;
+mod iblt;
+mod merkle;
+mod reconcile;
+#[cfg(feature = "futures")]
+mod replicator;
+mod transport;
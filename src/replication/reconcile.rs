@@ -0,0 +1,70 @@
+use Crdt;
+
+/// A CRDT whose state is a collection of independent entries, such as the
+/// elements of a set, which can be reconciled piecewise.
+///
+/// Every entry has a key, for example a set element, and a state, for
+/// example whether the element has been removed. Reconciliation protocols
+/// group entries by a hash of their key, compare the groups by a hash of
+/// their entries, and then exchange only the groups which differ.
+pub trait Reconcile : Crdt {
+
+    /// Calls `f` with the key hash and the entry hash of every entry.
+    ///
+    /// Both hashes **must** be computed with `fingerprint` from the `wire`
+    /// encoding of the entry, so that they agree between replicas. The key
+    /// hash covers only the key of the entry, and the entry hash covers the
+    /// key and the state.
+    fn fingerprints<F>(&self, f: F) where F: FnMut(u64, u64);
+
+    /// Returns a replica holding only the entries whose key hash satisfies
+    /// `keep`.
+    ///
+    /// Merging the returned replica into another replica has the same effect
+    /// on the selected entries as merging this replica.
+    fn select<F>(&self, keep: F) -> Self where F: FnMut(u64) -> bool;
+}
+
+/// Returns a hash of the bytes written by `encode` which is the same on every
+/// platform and in every version of the crate.
+///
+/// ##### Example
+///
+/// ```
+/// use crdt::replication::fingerprint;
+/// use crdt::wire::Encode;
+///
+/// let hash = fingerprint(|buf| "element".to_string().encode(buf));
+/// assert_eq!(hash, fingerprint(|buf| "element".to_string().encode(buf)));
+/// assert!(hash != fingerprint(|buf| "other".to_string().encode(buf)));
+/// ```
+pub fn fingerprint<F>(encode: F) -> u64 where F: FnOnce(&mut Vec<u8>) {
+    let mut buf = Vec::new();
+    encode(&mut buf);
+    // FNV-1a, followed by the MurmurHash3 finalizer so that every bit of the
+    // hash depends on every byte.
+    let hash = buf.iter().fold(0xcbf29ce484222325, |hash, &byte| (hash ^ byte as u64).wrapping_mul(0x100000001b3));
+    mix(hash)
+}
+
+/// The MurmurHash3 64-bit finalizer.
+pub fn mix(mut hash: u64) -> u64 {
+    hash ^= hash >> 33;
+    hash = hash.wrapping_mul(0xff51afd7ed558ccd);
+    hash ^= hash >> 33;
+    hash = hash.wrapping_mul(0xc4ceb9fe1a85ec53);
+    hash ^ (hash >> 33)
+}
+
+#[cfg(test)]
+mod test {
+
+    use wire::Encode;
+    use super::fingerprint;
+
+    #[test]
+    fn check_fingerprint_is_stable() {
+        assert_eq!(0xefd01f60ba992926, fingerprint(|_| ()));
+        assert_eq!(0x1d273896e8641a1d, fingerprint(|buf| 42u64.encode(buf)));
+    }
+}
@@ -0,0 +1,407 @@
+use std::collections::VecDeque;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+
+use futures::channel::mpsc::{self, UnboundedReceiver, UnboundedSender};
+use futures::task::{Context, Poll};
+use futures::{Future, Sink, Stream, StreamExt};
+
+use Crdt;
+use wire::{Decode, DecodeError, Encode, Message, Reader};
+
+/// An update exchanged between replicators.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Update<C> where C: Crdt {
+    /// An operation performed on the sender's replica.
+    Op(C::Operation),
+    /// The full state of the sender's replica, sent for anti-entropy.
+    State(C),
+}
+
+/// An async driver which replicates a CRDT over a `Sink` and `Stream` of
+/// updates.
+///
+/// The replicator owns the replica, and is edited through `ReplicatorHandle`s.
+/// Each local mutation which produces an operation is sent to the sink, and
+/// every update received from the stream is applied to the replica. Each time
+/// the `ticks` stream yields, the full state of the replica is sent as well,
+/// so that replicas converge even if the transport loses operations, or the
+/// CRDT requires an order of delivery which the transport does not provide.
+/// While the sink is not ready, only the latest state is queued.
+///
+/// The replicator is a `Future`, which must be spawned on an executor. It
+/// completes once the stream of remote updates ends and the queued updates
+/// have been flushed to the sink, and fails if the sink fails. It does not
+/// depend on any particular runtime: `ticks` may be an interval from any
+/// timer, or a virtual clock in tests.
+///
+/// ##### Example
+///
+/// ```
+/// extern crate crdt;
+/// extern crate futures;
+///
+/// use crdt::replication::Replicator;
+/// use crdt::set::GSet;
+/// use futures::FutureExt;
+/// use futures::channel::mpsc;
+/// use futures::executor::LocalPool;
+/// use futures::stream;
+/// use futures::task::LocalSpawnExt;
+///
+/// # fn main() {
+/// let (a_tx, a_rx) = mpsc::unbounded();
+/// let (b_tx, b_rx) = mpsc::unbounded();
+/// let (a, a_handle) = Replicator::new(GSet::new(), a_tx, b_rx, stream::pending());
+/// let (b, b_handle) = Replicator::new(GSet::new(), b_tx, a_rx, stream::pending());
+///
+/// let mut pool = LocalPool::new();
+/// pool.spawner().spawn_local(a.map(Result::unwrap)).unwrap();
+/// pool.spawner().spawn_local(b.map(Result::unwrap)).unwrap();
+///
+/// a_handle.update(|set| set.insert(1));
+/// b_handle.update(|set| set.insert(2));
+/// pool.run_until_stalled();
+/// assert!(a_handle.read(|set| set.contains(&2)));
+/// assert!(b_handle.read(|set| set.contains(&1)));
+/// # }
+/// ```
+pub struct Replicator<C, Si, St, T> where C: Crdt {
+    replica: Arc<Mutex<C>>,
+    ops: UnboundedReceiver<C::Operation>,
+    sink: Si,
+    stream: St,
+    ticks: Option<T>,
+    /// The updates waiting to be sent to the sink.
+    outgoing: VecDeque<Update<C>>,
+    /// Whether the stream of remote updates has ended.
+    done: bool,
+}
+
+/// A handle through which the replica of a `Replicator` is read and edited.
+pub struct ReplicatorHandle<C> where C: Crdt {
+    replica: Arc<Mutex<C>>,
+    ops: UnboundedSender<C::Operation>,
+}
+
+impl <C, Si, St, T> Replicator<C, Si, St, T>
+where C: Crdt,
+      Si: Sink<Update<C>> + Unpin,
+      St: Stream<Item=Update<C>> + Unpin,
+      T: Stream<Item=()> + Unpin {
+
+    /// Creates a replicator which sends the updates of `replica` to `sink`,
+    /// applies the updates received from `stream`, and sends the full state
+    /// of the replica each time `ticks` yields.
+    pub fn new(replica: C, sink: Si, stream: St, ticks: T) -> (Replicator<C, Si, St, T>, ReplicatorHandle<C>) {
+        let replica = Arc::new(Mutex::new(replica));
+        let (tx, rx) = mpsc::unbounded();
+        let replicator = Replicator {
+            replica: replica.clone(),
+            ops: rx,
+            sink: sink,
+            stream: stream,
+            ticks: Some(ticks),
+            outgoing: VecDeque::new(),
+            done: false,
+        };
+        (replicator, ReplicatorHandle { replica: replica, ops: tx })
+    }
+}
+
+impl <C> ReplicatorHandle<C> where C: Crdt {
+
+    /// Performs a local mutation on the replica, and queues the resulting
+    /// operation, if any, to be sent to the remote replica.
+    ///
+    /// Returns the operation. The mutation is applied even if the replicator
+    /// has been dropped, in which case the operation is not sent.
+    pub fn update<F, R>(&self, mutation: F) -> Option<C::Operation>
+    where F: FnOnce(&mut C) -> R, R: Into<Option<C::Operation>> {
+        let op = mutation(&mut self.replica.lock().unwrap()).into();
+        if let Some(ref op) = op {
+            let _ = self.ops.unbounded_send(op.clone());
+        }
+        op
+    }
+
+    /// Calls `f` with the replica.
+    pub fn read<F, R>(&self, f: F) -> R where F: FnOnce(&C) -> R {
+        f(&self.replica.lock().unwrap())
+    }
+}
+
+impl <C> Clone for ReplicatorHandle<C> where C: Crdt {
+    fn clone(&self) -> ReplicatorHandle<C> {
+        ReplicatorHandle { replica: self.replica.clone(), ops: self.ops.clone() }
+    }
+}
+
+// The replica and the queued updates are never pinned.
+impl <C, Si, St, T> Unpin for Replicator<C, Si, St, T> where C: Crdt, Si: Unpin, St: Unpin, T: Unpin {}
+
+impl <C, Si, St, T> Future for Replicator<C, Si, St, T>
+where C: Crdt,
+      Si: Sink<Update<C>> + Unpin,
+      St: Stream<Item=Update<C>> + Unpin,
+      T: Stream<Item=()> + Unpin {
+
+    type Output = Result<(), Si::Error>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Result<(), Si::Error>> {
+        let this = &mut *self;
+
+        while let Poll::Ready(Some(op)) = this.ops.poll_next_unpin(cx) {
+            this.outgoing.push_back(Update::Op(op));
+        }
+
+        let mut ticks_done = false;
+        if let Some(ref mut ticks) = this.ticks {
+            loop {
+                match ticks.poll_next_unpin(cx) {
+                    Poll::Ready(Some(())) => {
+                        // A queued state is superseded by the current one, so
+                        // at most one state waits for a slow sink.
+                        this.outgoing.retain(|update| match *update {
+                            Update::Op(_) => true,
+                            Update::State(_) => false,
+                        });
+                        let state = this.replica.lock().unwrap().clone();
+                        this.outgoing.push_back(Update::State(state));
+                    },
+                    Poll::Ready(None) => {
+                        ticks_done = true;
+                        break;
+                    },
+                    Poll::Pending => break,
+                }
+            }
+        }
+        if ticks_done {
+            this.ticks = None;
+        }
+
+        while !this.done {
+            match this.stream.poll_next_unpin(cx) {
+                Poll::Ready(Some(Update::Op(op))) => this.replica.lock().unwrap().apply(op),
+                Poll::Ready(Some(Update::State(state))) => this.replica.lock().unwrap().merge(state),
+                Poll::Ready(None) => this.done = true,
+                Poll::Pending => break,
+            }
+        }
+
+        while !this.outgoing.is_empty() {
+            match Pin::new(&mut this.sink).poll_ready(cx) {
+                Poll::Ready(Ok(())) => {
+                    let update = this.outgoing.pop_front().unwrap();
+                    if let Err(error) = Pin::new(&mut this.sink).start_send(update) {
+                        return Poll::Ready(Err(error));
+                    }
+                },
+                Poll::Ready(Err(error)) => return Poll::Ready(Err(error)),
+                Poll::Pending => break,
+            }
+        }
+
+        match Pin::new(&mut this.sink).poll_flush(cx) {
+            Poll::Ready(Ok(())) if this.done && this.outgoing.is_empty() => Poll::Ready(Ok(())),
+            Poll::Ready(Err(error)) => Poll::Ready(Err(error)),
+            _ => Poll::Pending,
+        }
+    }
+}
+
+impl <C> Encode for Update<C> where C: Crdt + Encode, C::Operation: Encode {
+    fn encode(&self, buf: &mut Vec<u8>) {
+        match *self {
+            Update::Op(ref op) => {
+                buf.push(0);
+                op.encode(buf);
+            },
+            Update::State(ref state) => {
+                buf.push(1);
+                state.encode(buf);
+            },
+        }
+    }
+}
+
+impl <C> Decode for Update<C> where C: Crdt + Decode, C::Operation: Decode {
+    fn decode(reader: &mut Reader) -> Result<Update<C>, DecodeError> {
+        match try!(reader.read_u8()) {
+            0 => Ok(Update::Op(try!(Decode::decode(reader)))),
+            1 => Ok(Update::State(try!(Decode::decode(reader)))),
+            _ => Err(DecodeError::InvalidValue("unknown Update variant")),
+        }
+    }
+}
+
+impl <C> Message for Update<C> where C: Crdt + Encode + Decode, C::Operation: Encode + Decode {
+    const TAG: u8 = 20;
+}
+
+#[cfg(test)]
+mod test {
+
+    use std::cell::{Cell, RefCell};
+    use std::pin::Pin;
+    use std::rc::Rc;
+
+    use futures::channel::mpsc;
+    use futures::executor::LocalPool;
+    use futures::future::{self, FutureExt};
+    use futures::stream::{Stream, StreamExt};
+    use futures::task::{Context, LocalSpawnExt, Poll, Waker};
+
+    use replication::{Replicator, ReplicatorHandle, Update};
+    use set::GSet;
+    use wire;
+
+    /// A clock which only advances when told to, waking the intervals which
+    /// have become due.
+    #[derive(Clone, Default)]
+    struct VirtualClock {
+        now: Rc<Cell<u64>>,
+        wakers: Rc<RefCell<Vec<Waker>>>,
+    }
+
+    /// A stream which yields every `period` ticks of a virtual clock.
+    struct Interval {
+        clock: VirtualClock,
+        period: u64,
+        next: u64,
+    }
+
+    impl VirtualClock {
+        fn advance(&self, ticks: u64) {
+            self.now.set(self.now.get() + ticks);
+            for waker in self.wakers.borrow_mut().drain(..) {
+                waker.wake();
+            }
+        }
+
+        fn interval(&self, period: u64) -> Interval {
+            Interval { clock: self.clone(), period: period, next: self.now.get() + period }
+        }
+    }
+
+    impl Stream for Interval {
+        type Item = ();
+
+        fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<()>> {
+            if self.clock.now.get() >= self.next {
+                self.next += self.period;
+                Poll::Ready(Some(()))
+            } else {
+                self.clock.wakers.borrow_mut().push(cx.waker().clone());
+                Poll::Pending
+            }
+        }
+    }
+
+    /// Spawns two replicators connected by in-memory channels, on which
+    /// operations are lost while `partitioned` is set.
+    fn pair(pool: &LocalPool, clock: &VirtualClock, partitioned: &Rc<Cell<bool>>)
+            -> (ReplicatorHandle<GSet<u32>>, ReplicatorHandle<GSet<u32>>) {
+        let (a_tx, a_rx) = mpsc::unbounded();
+        let (b_tx, b_rx) = mpsc::unbounded();
+        let lossy = |rx: mpsc::UnboundedReceiver<Update<GSet<u32>>>| {
+            let partitioned = partitioned.clone();
+            rx.filter(move |update| future::ready(match *update {
+                Update::Op(_) => !partitioned.get(),
+                Update::State(_) => true,
+            }))
+        };
+        let (a, a_handle) = Replicator::new(GSet::new(), a_tx, lossy(b_rx), clock.interval(10));
+        let (b, b_handle) = Replicator::new(GSet::new(), b_tx, lossy(a_rx), clock.interval(10));
+        pool.spawner().spawn_local(a.map(Result::unwrap)).unwrap();
+        pool.spawner().spawn_local(b.map(Result::unwrap)).unwrap();
+        (a_handle, b_handle)
+    }
+
+    #[test]
+    fn check_replicates_ops() {
+        let mut pool = LocalPool::new();
+        let clock = VirtualClock::default();
+        let (a, b) = pair(&pool, &clock, &Rc::new(Cell::new(false)));
+
+        a.update(|set| set.insert(1));
+        b.update(|set| set.insert(2));
+        assert_eq!(None, a.update(|set| set.insert(1)));
+        pool.run_until_stalled();
+
+        assert!(a.read(|set| set.contains(&2)));
+        assert!(a.read(|set| set.clone()) == b.read(|set| set.clone()));
+    }
+
+    #[test]
+    fn check_anti_entropy_repairs_lost_ops() {
+        let mut pool = LocalPool::new();
+        let clock = VirtualClock::default();
+        let partitioned = Rc::new(Cell::new(true));
+        let (a, b) = pair(&pool, &clock, &partitioned);
+
+        for i in 0..10 {
+            a.update(|set| set.insert(i));
+            b.update(|set| set.insert(i + 100));
+        }
+        pool.run_until_stalled();
+        assert_eq!(10, b.read(GSet::len));
+        partitioned.set(false);
+
+        // Nothing is repaired until the anti-entropy timer fires.
+        clock.advance(9);
+        pool.run_until_stalled();
+        assert_eq!(10, b.read(GSet::len));
+
+        clock.advance(1);
+        pool.run_until_stalled();
+        assert_eq!(20, a.read(GSet::len));
+        assert_eq!(20, b.read(GSet::len));
+    }
+
+    #[test]
+    fn check_completes_when_stream_ends() {
+        let (tx, rx) = mpsc::unbounded();
+        let (remote_tx, remote_rx) = mpsc::unbounded::<Update<GSet<u32>>>();
+        let (replicator, handle) = Replicator::new(GSet::new(), tx, remote_rx, futures::stream::pending());
+
+        let op = handle.update(|set| set.insert(1)).unwrap();
+        remote_tx.unbounded_send(Update::Op(GSet::new().insert(2).unwrap())).unwrap();
+        drop(remote_tx);
+        futures::executor::block_on(replicator).unwrap();
+
+        assert!(handle.read(|set| set.contains(&2)));
+        let sent: Vec<_> = futures::executor::block_on(rx.collect());
+        assert_eq!(vec![Update::Op(op)], sent);
+    }
+
+    #[test]
+    fn check_queues_one_state() {
+        let (mut tx, _rx) = mpsc::channel(0);
+        tx.try_send(Update::State(GSet::new())).unwrap();
+        let ticks = futures::stream::iter(vec![(); 5]);
+        let (mut replicator, handle) = Replicator::new(GSet::new(), tx, futures::stream::pending(), ticks);
+
+        // The sink is full, so every update stays queued.
+        let first = handle.update(|set| set.insert(1)).unwrap();
+        let second = handle.update(|set| set.insert(2)).unwrap();
+        futures::executor::block_on(future::poll_fn(|cx| {
+            assert!(replicator.poll_unpin(cx).is_pending());
+            Poll::Ready(())
+        }));
+
+        let state = handle.read(|set| set.clone());
+        let queued: Vec<_> = replicator.outgoing.iter().cloned().collect();
+        assert_eq!(vec![Update::Op(first), Update::Op(second), Update::State(state)], queued);
+    }
+
+    #[test]
+    fn check_wire_round_trip() {
+        let mut set = GSet::new();
+        set.insert(1u32);
+        for update in vec![Update::Op(GSet::new().insert(7u32).unwrap()), Update::State(set)] {
+            assert_eq!(update, wire::from_bytes(&wire::to_bytes(&update)).unwrap());
+        }
+    }
+}
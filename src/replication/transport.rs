@@ -0,0 +1,155 @@
+use std::cell::RefCell;
+use std::collections::{HashMap, VecDeque};
+use std::rc::Rc;
+
+use ReplicaId;
+
+/// A channel which carries messages between replicas.
+///
+/// Transports may drop, duplicate, delay, or reorder messages; the
+/// replication layers built on top of a transport tolerate all of these.
+pub trait Transport<M> {
+
+    /// The error returned when the transport fails.
+    type Error;
+
+    /// Sends a message to every other replica.
+    fn broadcast(&mut self, message: M) -> Result<(), Self::Error>;
+
+    /// Sends a message to a single replica.
+    ///
+    /// The default implementation broadcasts the message to every other
+    /// replica, so transports which cannot address a single replica need not
+    /// implement it.
+    fn send(&mut self, to: ReplicaId, message: M) -> Result<(), Self::Error> {
+        let _ = to;
+        self.broadcast(message)
+    }
+
+    /// Returns the next message received from another replica, or `None` if
+    /// no message is waiting.
+    fn receive(&mut self) -> Result<Option<M>, Self::Error>;
+}
+
+/// An in-memory network of replicas within a single process.
+///
+/// Each replica connects to the network with `connect`, and receives the
+/// messages broadcast or sent to it by the other replicas in the order they
+/// were sent. Tests can drop, duplicate, or reorder the messages waiting for a
+/// replica with `with_inbox`.
+///
+/// ##### Example
+///
+/// ```
+/// use crdt::replication::{MemoryNetwork, Transport};
+///
+/// let network = MemoryNetwork::new();
+/// let mut a = network.connect(1);
+/// let mut b = network.connect(2);
+///
+/// a.broadcast("hello").unwrap();
+/// assert_eq!(Ok(Some("hello")), b.receive());
+/// assert_eq!(Ok(None), a.receive());
+///
+/// b.send(1.into(), "hi").unwrap();
+/// assert_eq!(Ok(Some("hi")), a.receive());
+/// ```
+pub struct MemoryNetwork<M> {
+    inboxes: Rc<RefCell<HashMap<ReplicaId, VecDeque<M>>>>,
+}
+
+/// A replica's connection to a `MemoryNetwork`.
+pub struct MemoryTransport<M> {
+    replica_id: ReplicaId,
+    inboxes: Rc<RefCell<HashMap<ReplicaId, VecDeque<M>>>>,
+}
+
+impl <M> MemoryNetwork<M> where M: Clone {
+
+    /// Creates a network with no replicas.
+    pub fn new() -> MemoryNetwork<M> {
+        MemoryNetwork { inboxes: Rc::new(RefCell::new(HashMap::new())) }
+    }
+
+    /// Connects a replica to the network.
+    ///
+    /// The replica receives the messages broadcast after it connects, and is
+    /// disconnected when the transport is dropped.
+    ///
+    /// ##### Panics
+    ///
+    /// Panics if a replica with the same ID is already connected.
+    pub fn connect<R>(&self, replica_id: R) -> MemoryTransport<M> where R: Into<ReplicaId> {
+        let replica_id = replica_id.into();
+        let previous = self.inboxes.borrow_mut().insert(replica_id, VecDeque::new());
+        assert!(previous.is_none(), "replica {:?} is already connected", replica_id);
+        MemoryTransport { replica_id: replica_id, inboxes: self.inboxes.clone() }
+    }
+
+    /// Returns the number of messages waiting for a replica.
+    pub fn pending<R>(&self, replica_id: R) -> usize where R: Into<ReplicaId> {
+        self.inboxes.borrow().get(&replica_id.into()).map_or(0, VecDeque::len)
+    }
+
+    /// Calls `f` with the messages waiting for a replica, so that they may be
+    /// dropped, duplicated, or reordered.
+    ///
+    /// ##### Panics
+    ///
+    /// Panics if the replica is not connected.
+    pub fn with_inbox<R, F, T>(&self, replica_id: R, f: F) -> T
+    where R: Into<ReplicaId>, F: FnOnce(&mut VecDeque<M>) -> T {
+        let replica_id = replica_id.into();
+        let mut inboxes = self.inboxes.borrow_mut();
+        let inbox = inboxes.get_mut(&replica_id)
+                           .unwrap_or_else(|| panic!("replica {:?} is not connected", replica_id));
+        f(inbox)
+    }
+}
+
+impl <M> Default for MemoryNetwork<M> where M: Clone {
+    fn default() -> MemoryNetwork<M> {
+        MemoryNetwork::new()
+    }
+}
+
+impl <M> MemoryTransport<M> {
+
+    /// Returns the ID of the replica this transport belongs to.
+    pub fn replica_id(&self) -> ReplicaId {
+        self.replica_id
+    }
+}
+
+impl <M> Transport<M> for MemoryTransport<M> where M: Clone {
+
+    /// The in-memory transport never fails.
+    type Error = ();
+
+    fn broadcast(&mut self, message: M) -> Result<(), ()> {
+        for (&replica_id, inbox) in self.inboxes.borrow_mut().iter_mut() {
+            if replica_id != self.replica_id {
+                inbox.push_back(message.clone());
+            }
+        }
+        Ok(())
+    }
+
+    /// Messages sent to a replica which is not connected are dropped.
+    fn send(&mut self, to: ReplicaId, message: M) -> Result<(), ()> {
+        if let Some(inbox) = self.inboxes.borrow_mut().get_mut(&to) {
+            inbox.push_back(message);
+        }
+        Ok(())
+    }
+
+    fn receive(&mut self) -> Result<Option<M>, ()> {
+        Ok(self.inboxes.borrow_mut().get_mut(&self.replica_id).and_then(VecDeque::pop_front))
+    }
+}
+
+impl <M> Drop for MemoryTransport<M> {
+    fn drop(&mut self) {
+        self.inboxes.borrow_mut().remove(&self.replica_id);
+    }
+}
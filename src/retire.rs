@@ -0,0 +1,241 @@
+use std::cmp;
+use std::collections::{HashMap, HashSet};
+use std::collections::hash_map::Entry;
+
+use ReplicaId;
+use wire::{self, Decode, DecodeError, Encode, Reader};
+
+/// The retirement of a departed replica.
+///
+/// A retirement passes through two phases. While it is pending, replicas
+/// acknowledge it by adding themselves to `seen` when they merge it. Once
+/// every live replica has seen it, the owner folds the departed replica's
+/// counts into its own, and the retirement becomes folded; replicas which see
+/// a folded retirement discard their counts for the departed replica. Once
+/// every live replica has seen the folded retirement, the owner drops it.
+///
+/// A dropped retirement is kept, without its `seen` set, as a permanent
+/// record that the departed replica's counts have been folded, so that
+/// delayed operations and stale states from the departed replica are still
+/// rejected.
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+struct Retirement {
+    /// The replica which folds the departed replica's counts into its own.
+    owner: ReplicaId,
+    /// The sequence number of the retirement among those of its owner.
+    seq: u64,
+    /// The replicas which have seen the retirement in its current phase.
+    seen: HashSet<ReplicaId>,
+    /// Whether the owner has folded the departed replica's counts.
+    folded: bool,
+}
+
+/// `Retirements` is a building block for counting CRDTs which supports
+/// retiring departed replicas.
+///
+/// Owners drop their retirements in sequence order, and record the sequence
+/// number of the last retirement dropped. A retirement at or below its
+/// owner's dropped sequence number is complete, and is dropped by every
+/// replica which learns of it. Dropping a retirement only clears its `seen`
+/// set: every departed replica keeps a small entry, so that its counts are
+/// never accepted again.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct Retirements {
+    retirements: HashMap<ReplicaId, Retirement>,
+    dropped: HashMap<ReplicaId, u64>,
+}
+
+impl Retirement {
+
+    /// Returns true if the owner has dropped the retirement.
+    fn is_dropped(&self, dropped: &HashMap<ReplicaId, u64>) -> bool {
+        dropped.get(&self.owner).map_or(false, |&seq| self.seq <= seq)
+    }
+}
+
+impl Retirements {
+
+    /// Creates an empty set of retirements.
+    pub fn new() -> Retirements {
+        Retirements { retirements: HashMap::new(), dropped: HashMap::new() }
+    }
+
+    /// Begins retiring `departed`, owned by `owner`. Does nothing if `departed`
+    /// is already being retired.
+    ///
+    /// ##### Panics
+    ///
+    /// Panics if `departed` is `owner`.
+    pub fn retire(&mut self, departed: ReplicaId, owner: ReplicaId) {
+        assert!(departed != owner, "a replica cannot retire itself");
+        let seq = self.retirements
+                      .values()
+                      .filter(|retirement| retirement.owner == owner)
+                      .map(|retirement| retirement.seq)
+                      .chain(self.dropped.get(&owner).cloned())
+                      .max()
+                      .unwrap_or(0) + 1;
+        self.retirements.entry(departed).or_insert_with(|| {
+            let mut seen = HashSet::new();
+            seen.insert(owner);
+            Retirement { owner: owner, seq: seq, seen: seen, folded: false }
+        });
+    }
+
+    /// Returns true if no retirement is in progress.
+    #[cfg(test)]
+    pub fn is_empty(&self) -> bool {
+        let dropped = &self.dropped;
+        self.retirements.values().all(|retirement| retirement.is_dropped(dropped))
+    }
+
+    /// Returns true if the counts of `replica_id` have been folded into
+    /// another replica, and must be discarded. This remains true after the
+    /// retirement has been dropped.
+    pub fn is_folded(&self, replica_id: ReplicaId) -> bool {
+        self.retirements.get(&replica_id).map_or(false, |retirement| retirement.folded)
+    }
+
+    /// Returns true if every replica in `live` has seen the retirement of
+    /// `departed` in its current phase.
+    pub fn is_stable(&self, departed: ReplicaId, live: &HashSet<ReplicaId>) -> bool {
+        self.retirements.get(&departed).map_or(false, |retirement| retirement.seen.is_superset(live))
+    }
+
+    /// Merges another set of retirements into this one, and records that
+    /// `replica_id` has seen every retirement.
+    ///
+    /// When two replicas concurrently retire the same replica, a folded
+    /// retirement wins, and otherwise the retirement with the lower owner
+    /// wins. A replica only acknowledges the retirement it keeps, so at most
+    /// one owner ever sees its retirement acknowledged by every live replica.
+    pub fn merge(&mut self, other: Retirements, replica_id: ReplicaId) {
+        for (owner, seq) in other.dropped {
+            let dropped = self.dropped.entry(owner).or_insert(0);
+            *dropped = cmp::max(*dropped, seq);
+        }
+        let key = |retirement: &Retirement| (!retirement.folded, retirement.owner.id(), retirement.seq);
+        for (departed, other) in other.retirements {
+            match self.retirements.entry(departed) {
+                Entry::Vacant(entry) => { entry.insert(other); },
+                Entry::Occupied(mut entry) => {
+                    let retirement = entry.get_mut();
+                    if key(retirement) == key(&other) {
+                        retirement.seen.extend(other.seen);
+                    } else if key(&other) < key(retirement) {
+                        *retirement = other;
+                    }
+                },
+            }
+        }
+        let dropped = &self.dropped;
+        for retirement in self.retirements.values_mut() {
+            if retirement.is_dropped(dropped) {
+                retirement.seen.clear();
+            } else {
+                retirement.seen.insert(replica_id);
+            }
+        }
+    }
+
+    /// Advances the retirements owned by `replica_id` which every replica in
+    /// `live` has seen.
+    ///
+    /// Pending retirements are folded, calling `fold` with each departed
+    /// replica, and folded retirements are dropped in sequence order, keeping
+    /// only the record that the departed replica has been folded. Returns
+    /// the number of departed replicas folded.
+    pub fn compact<F>(&mut self, replica_id: ReplicaId, live: &HashSet<ReplicaId>, mut fold: F) -> usize
+    where F: FnMut(ReplicaId) {
+        let mut folded = 0;
+        // The greatest sequence number of a retirement which can be dropped,
+        // and the least of one which cannot.
+        let mut complete = None;
+        let mut incomplete = None;
+        for (&departed, retirement) in self.retirements.iter_mut() {
+            if retirement.owner != replica_id || retirement.is_dropped(&self.dropped) {
+                continue;
+            }
+            let is_stable = retirement.seen.is_superset(live);
+            if is_stable && retirement.folded {
+                complete = cmp::max(complete, Some(retirement.seq));
+                continue;
+            }
+            if is_stable {
+                fold(departed);
+                folded += 1;
+                retirement.folded = true;
+                retirement.seen.clear();
+                retirement.seen.insert(replica_id);
+            }
+            incomplete = Some(incomplete.map_or(retirement.seq, |seq| cmp::min(seq, retirement.seq)));
+        }
+        // Sequence numbers without a retirement belong to retirements which
+        // lost to a concurrent retirement, and do not hold dropping back.
+        let dropped = match (complete, incomplete) {
+            (Some(complete), Some(incomplete)) if incomplete < complete => incomplete - 1,
+            (Some(complete), _) => complete,
+            (None, _) => return folded,
+        };
+        let seq = self.dropped.entry(replica_id).or_insert(0);
+        *seq = cmp::max(*seq, dropped);
+        let dropped = &self.dropped;
+        for retirement in self.retirements.values_mut() {
+            if retirement.is_dropped(dropped) {
+                retirement.seen.clear();
+            }
+        }
+        folded
+    }
+}
+
+/// Returns the set of live replicas, including `replica_id`.
+pub fn live_set<I, R>(replica_id: ReplicaId, live: I) -> HashSet<ReplicaId>
+where I: IntoIterator<Item=R>, R: Into<ReplicaId> {
+    let mut live: HashSet<ReplicaId> = live.into_iter().map(Into::into).collect();
+    live.insert(replica_id);
+    live
+}
+
+impl Encode for Retirements {
+    fn encode(&self, buf: &mut Vec<u8>) {
+        wire::write_entries(buf, self.retirements.iter(), |(departed, retirement), buf| {
+            departed.encode(buf);
+            retirement.owner.encode(buf);
+            retirement.seq.encode(buf);
+            retirement.folded.encode(buf);
+            wire::write_entries(buf, retirement.seen.iter(), |replica_id, buf| replica_id.encode(buf));
+        });
+        wire::write_entries(buf, self.dropped.iter(), |(owner, seq), buf| {
+            owner.encode(buf);
+            seq.encode(buf);
+        });
+    }
+}
+
+impl Decode for Retirements {
+    fn decode(reader: &mut Reader) -> Result<Retirements, DecodeError> {
+        let retirements = try!(wire::read_map(reader, |reader| {
+            let departed = try!(ReplicaId::decode(reader));
+            let owner = try!(ReplicaId::decode(reader));
+            let seq = try!(u64::decode(reader));
+            let folded = try!(bool::decode(reader));
+            let seen: HashMap<ReplicaId, ()> = try!(wire::read_map(reader, |reader| Ok((try!(ReplicaId::decode(reader)), ()))));
+            if departed == owner {
+                return Err(DecodeError::InvalidValue("replica retires itself"));
+            }
+            Ok((departed, Retirement {
+                owner: owner,
+                seq: seq,
+                seen: seen.into_iter().map(|(replica_id, _)| replica_id).collect(),
+                folded: folded,
+            }))
+        }));
+        let dropped = try!(wire::read_map(reader, |reader| {
+            Ok((try!(ReplicaId::decode(reader)), try!(u64::decode(reader))))
+        }));
+        Ok(Retirements { retirements: retirements, dropped: dropped })
+    }
+}
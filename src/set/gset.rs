@@ -1,20 +1,34 @@
 use std::cmp::Ordering::{self, Greater, Less, Equal};
-use std::collections::HashSet;
+use std::collections::{BTreeSet, HashSet};
 use std::hash::Hash;
+use std::marker::PhantomData;
+use std::ops::RangeBounds;
 
-#[cfg(any(quickcheck, test))]
+#[cfg(any(feature = "quickcheck", test))]
 use quickcheck::{Arbitrary, Gen};
 
 use Crdt;
+use observe::Observable;
+use replication::{self, Reconcile};
+use super::store::{self, SetStore};
+use wire::{self, Decode, DecodeError, Encode, Message, Reader};
 
 /// A grow-only set.
+///
+/// The elements are held in a `HashSet` by default. A set created with
+/// `GSet::ordered` holds them in a `BTreeSet`, so that it iterates, prints
+/// and serializes them in sorted order, and supports range queries.
 #[derive(Debug, Default)]
-pub struct GSet<T> where T: Eq + Hash {
-    elements: HashSet<T>
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct GSet<T, S = HashSet<T>> {
+    elements: S,
+    #[cfg_attr(feature = "serde", serde(skip))]
+    marker: PhantomData<T>,
 }
 
 /// An insert operation over `GSet` CRDTs.
 #[derive(Clone, Debug, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct GSetOp<T> {
     element: T
 }
@@ -32,9 +46,50 @@ impl <T> GSet<T> where T: Clone + Eq + Hash {
     /// assert!(set.is_empty());
     /// ```
     pub fn new() -> GSet<T> {
-        GSet { elements: HashSet::new() }
+        GSet { elements: HashSet::new(), marker: PhantomData }
+    }
+}
+
+impl <T> GSet<T, BTreeSet<T>> where T: Clone + Ord {
+
+    /// Create a new grow-only set which keeps its elements in sorted order.
+    ///
+    /// ### Example
+    ///
+    /// ```
+    /// use crdt::set::GSet;
+    ///
+    /// let mut set = GSet::ordered();
+    /// set.insert(3);
+    /// set.insert(1);
+    /// set.insert(2);
+    /// assert_eq!(vec![&1, &2, &3], set.iter().collect::<Vec<_>>());
+    /// ```
+    pub fn ordered() -> GSet<T, BTreeSet<T>> {
+        GSet { elements: BTreeSet::new(), marker: PhantomData }
     }
 
+    /// Returns an iterator over the elements of the set within `range`, in
+    /// sorted order.
+    ///
+    /// ### Example
+    ///
+    /// ```
+    /// use crdt::set::GSet;
+    ///
+    /// let mut set = GSet::ordered();
+    /// for element in 0..10 {
+    ///     set.insert(element);
+    /// }
+    /// assert_eq!(vec![&3, &4], set.range(3..5).collect::<Vec<_>>());
+    /// ```
+    pub fn range<'a, R>(&'a self, range: R) -> Iter<'a, T> where R: RangeBounds<T> {
+        Iter { inner: Box::new(self.elements.range(range)) }
+    }
+}
+
+impl <T, S> GSet<T, S> where T: Clone, S: SetStore<T> {
+
     /// Insert an element into a grow-only set.
     ///
     /// ### Example
@@ -54,6 +109,39 @@ impl <T> GSet<T> where T: Clone + Eq + Hash {
         }
     }
 
+    /// Returns the insert operations which, when applied to `other`, bring it
+    /// up to date with this set.
+    ///
+    /// Applying the operations to `other` is equivalent to merging this set
+    /// into `other`.
+    ///
+    /// ### Example
+    ///
+    /// ```
+    /// use crdt::Crdt;
+    /// use crdt::set::GSet;
+    ///
+    /// let mut local = GSet::new();
+    /// let mut remote = GSet::new();
+    /// local.insert(1i32);
+    /// local.insert(2);
+    /// remote.insert(2);
+    ///
+    /// let ops = local.diff_ops(&remote);
+    /// assert_eq!(1, ops.len());
+    /// for op in ops {
+    ///     remote.apply(op);
+    /// }
+    /// assert_eq!(local, remote);
+    /// ```
+    pub fn diff_ops(&self, other: &GSet<T, S>) -> Vec<GSetOp<T>> {
+        self.elements
+            .iter()
+            .filter(|element| !other.elements.contains(element))
+            .map(|element| GSetOp { element: element.clone() })
+            .collect()
+    }
+
     /// Returns the number of elements in the set.
     pub fn len(&self) -> usize {
         self.elements.len()
@@ -67,16 +155,53 @@ impl <T> GSet<T> where T: Clone + Eq + Hash {
     /// Returns true if the set contains no elements.
     pub fn is_empty(&self) -> bool { self.elements.is_empty() }
 
-    pub fn is_subset(&self, other: &GSet<T>) -> bool {
-        self.elements.is_subset(&other.elements)
+    pub fn is_subset(&self, other: &GSet<T, S>) -> bool {
+        is_subset(&self.elements, &other.elements)
+    }
+
+    pub fn is_disjoint(&self, other: &GSet<T, S>) -> bool {
+        self.elements.iter().all(|element| !other.elements.contains(element))
     }
 
-    pub fn is_disjoint(&self, other: &GSet<T>) -> bool {
-        self.elements.is_disjoint(&other.elements)
+    /// Returns an iterator over the elements of the set, in sorted order if
+    /// the set is ordered.
+    pub fn iter<'a>(&'a self) -> Iter<'a, T> {
+        Iter { inner: self.elements.iter() }
     }
 }
 
-impl <T> Crdt for GSet<T> where T: Clone + Eq + Hash {
+impl <T, S> GSet<T, S> where T: Encode, S: SetStore<T> {
+
+    /// Returns a hash of the elements of the set.
+    ///
+    /// The hash is computed over the canonical encoding of the elements, so
+    /// replicas holding the same elements have the same hash, whatever their
+    /// backing store, platform, or version of Rust.
+    ///
+    /// ### Example
+    ///
+    /// ```
+    /// use crdt::set::GSet;
+    ///
+    /// let mut a = GSet::new();
+    /// let mut b = GSet::ordered();
+    /// for element in 0..10u32 {
+    ///     a.insert(element);
+    ///     b.insert(9 - element);
+    /// }
+    /// assert_eq!(a.content_hash(), b.content_hash());
+    /// ```
+    pub fn content_hash(&self) -> u64 {
+        replication::fingerprint(|buf| self.encode(buf))
+    }
+}
+
+/// Returns true if every element of `a` is in `b`.
+fn is_subset<T, S>(a: &S, b: &S) -> bool where S: SetStore<T> {
+    a.len() <= b.len() && a.iter().all(|element| b.contains(element))
+}
+
+impl <T, S> Crdt for GSet<T, S> where T: Clone, S: SetStore<T> + Clone + Eq {
 
     type Operation = GSetOp<T>;
 
@@ -99,7 +224,7 @@ impl <T> Crdt for GSet<T> where T: Clone + Eq + Hash {
     /// local.merge(remote);
     /// assert!(local.contains(&2));
     /// ```
-    fn merge(&mut self, other: GSet<T>) {
+    fn merge(&mut self, other: GSet<T, S>) {
         self.elements.extend(other.elements.into_iter());
     }
 
@@ -127,21 +252,30 @@ impl <T> Crdt for GSet<T> where T: Clone + Eq + Hash {
     }
 }
 
-impl <T> PartialEq for GSet<T> where T: Eq + Hash {
-    fn eq(&self, other: &GSet<T>) -> bool {
+impl <T, S> Observable for GSet<T, S> where T: Clone, S: SetStore<T> + Clone + Eq {
+
+    type Value = S;
+
+    fn value(&self) -> S {
+        self.elements.clone()
+    }
+}
+
+impl <T, S> PartialEq for GSet<T, S> where S: PartialEq {
+    fn eq(&self, other: &GSet<T, S>) -> bool {
         self.elements == other.elements
     }
 }
 
-impl <T> Eq for GSet<T> where T: Eq + Hash {}
+impl <T, S> Eq for GSet<T, S> where S: Eq {}
 
-impl <T> PartialOrd for GSet<T> where T: Eq + Hash {
-    fn partial_cmp(&self, other: &GSet<T>) -> Option<Ordering> {
+impl <T, S> PartialOrd for GSet<T, S> where S: SetStore<T> + PartialEq {
+    fn partial_cmp(&self, other: &GSet<T, S>) -> Option<Ordering> {
         if self.elements == other.elements {
             Some(Equal)
-        } else if self.elements.is_subset(&other.elements) {
+        } else if is_subset(&self.elements, &other.elements) {
             Some(Less)
-        } else if self.elements.is_superset(&other.elements) {
+        } else if is_subset(&other.elements, &self.elements) {
             Some(Greater)
         } else {
             None
@@ -149,25 +283,76 @@ impl <T> PartialOrd for GSet<T> where T: Eq + Hash {
     }
 }
 
-impl <T> Clone for GSet<T> where T: Clone + Eq + Hash {
-    fn clone(&self) -> GSet<T> {
-        GSet { elements: self.elements.clone() }
+impl <T, S> Clone for GSet<T, S> where S: Clone {
+    fn clone(&self) -> GSet<T, S> {
+        GSet { elements: self.elements.clone(), marker: PhantomData }
+    }
+}
+
+impl <T, S> Encode for GSet<T, S> where T: Encode, S: SetStore<T> {
+    fn encode(&self, buf: &mut Vec<u8>) {
+        wire::write_entries(buf, self.elements.iter(), |element, buf| element.encode(buf));
+    }
+}
+
+impl <T, S> Decode for GSet<T, S> where T: Decode, S: SetStore<T> {
+    fn decode(reader: &mut Reader) -> Result<GSet<T, S>, DecodeError> {
+        let elements = try!(store::read_set(reader, T::decode));
+        Ok(GSet { elements: elements, marker: PhantomData })
+    }
+}
+
+impl <T, S> Message for GSet<T, S> where T: Encode + Decode, S: SetStore<T> {
+    const TAG: u8 = 6;
+}
+
+impl <T> Encode for GSetOp<T> where T: Encode {
+    fn encode(&self, buf: &mut Vec<u8>) {
+        self.element.encode(buf);
+    }
+}
+
+impl <T> Decode for GSetOp<T> where T: Decode {
+    fn decode(reader: &mut Reader) -> Result<GSetOp<T>, DecodeError> {
+        Ok(GSetOp { element: try!(Decode::decode(reader)) })
+    }
+}
+
+impl <T> Message for GSetOp<T> where T: Encode + Decode {
+    const TAG: u8 = 7;
+}
+
+impl <T, S> Reconcile for GSet<T, S> where T: Clone + Encode, S: SetStore<T> + Clone + Eq {
+    fn fingerprints<F>(&self, mut f: F) where F: FnMut(u64, u64) {
+        for element in self.elements.iter() {
+            let hash = replication::fingerprint(|buf| element.encode(buf));
+            f(hash, hash);
+        }
+    }
+
+    fn select<F>(&self, mut keep: F) -> GSet<T, S> where F: FnMut(u64) -> bool {
+        let elements = self.elements
+                           .iter()
+                           .filter(|element| keep(replication::fingerprint(|buf| element.encode(buf))))
+                           .cloned()
+                           .collect();
+        GSet { elements: elements, marker: PhantomData }
     }
 }
 
-#[cfg(any(quickcheck, test))]
-impl <T> Arbitrary for GSet<T> where T: Arbitrary + Clone + Eq + Hash {
-    fn arbitrary<G>(g: &mut G) -> GSet<T> where G: Gen {
+#[cfg(any(feature = "quickcheck", test))]
+impl <T, S> Arbitrary for GSet<T, S> where T: Arbitrary, S: SetStore<T> + Clone + Send + 'static {
+    fn arbitrary<G>(g: &mut G) -> GSet<T, S> where G: Gen {
         let elements: Vec<T> = Arbitrary::arbitrary(g);
-        GSet { elements: elements.into_iter().collect() }
+        GSet { elements: elements.into_iter().collect(), marker: PhantomData }
     }
-    fn shrink(&self) -> Box<Iterator<Item=GSet<T>> + 'static> {
+    fn shrink(&self) -> Box<Iterator<Item=GSet<T, S>> + 'static> {
         let elements: Vec<T> = self.elements.iter().cloned().collect();
-        Box::new(elements.shrink().map(|es| GSet { elements: es.into_iter().collect() }))
+        Box::new(elements.shrink().map(|es| GSet { elements: es.into_iter().collect(), marker: PhantomData }))
     }
 }
 
-#[cfg(any(quickcheck, test))]
+#[cfg(any(feature = "quickcheck", test))]
 impl <T> Arbitrary for GSetOp<T> where T: Arbitrary {
     fn arbitrary<G: Gen>(g: &mut G) -> GSetOp<T> {
         GSetOp { element: Arbitrary::arbitrary(g) }
@@ -177,12 +362,30 @@ impl <T> Arbitrary for GSetOp<T> where T: Arbitrary {
     }
 }
 
+/// An iterator over the elements of a `GSet`.
+pub struct Iter<'a, T: 'a> {
+    inner: Box<Iterator<Item=&'a T> + 'a>,
+}
+
+impl <'a, T> Iterator for Iter<'a, T> {
+    type Item = &'a T;
+
+    fn next(&mut self) -> Option<&'a T> {
+        self.inner.next()
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.inner.size_hint()
+    }
+}
+
 #[cfg(test)]
 mod test {
 
     use quickcheck::quickcheck;
 
-    use {Crdt, test};
+    use {Crdt, testkit};
+    use set::OrdGSet;
     use super::{GSet, GSetOp};
 
     type C = GSet<u32>;
@@ -190,22 +393,38 @@ mod test {
 
     #[test]
     fn check_apply_is_commutative() {
-        quickcheck(test::apply_is_commutative::<C> as fn(C, Vec<O>) -> bool);
+        quickcheck(testkit::apply_is_commutative::<C> as fn(C, Vec<O>) -> bool);
     }
 
     #[test]
     fn check_merge_is_commutative() {
-        quickcheck(test::merge_is_commutative::<C> as fn(C, Vec<C>) -> bool);
+        quickcheck(testkit::merge_is_commutative::<C> as fn(C, Vec<C>) -> bool);
     }
 
     #[test]
     fn check_ordering_lte() {
-        quickcheck(test::ordering_lte::<C> as fn(C, C) -> bool);
+        quickcheck(testkit::ordering_lte::<C> as fn(C, C) -> bool);
     }
 
     #[test]
     fn check_ordering_equality() {
-        quickcheck(test::ordering_equality::<C> as fn(C, C) -> bool);
+        quickcheck(testkit::ordering_equality::<C> as fn(C, C) -> bool);
+    }
+
+    #[test]
+    fn check_all() {
+        testkit::check_all::<C>();
+    }
+
+    #[cfg(feature = "serde")]
+    #[quickcheck]
+    fn check_serde_round_trip(crdt: C, op: O) -> bool {
+        ::test_util::serde_round_trip(crdt, op, |_, _| true)
+    }
+
+    #[quickcheck]
+    fn check_diff_ops_is_merge(a: C, b: C) -> bool {
+        testkit::diff_ops_is_merge(a, b, C::diff_ops)
     }
 
     #[quickcheck]
@@ -230,4 +449,26 @@ mod test {
         }
         a > b && b < a
     }
+
+    #[test]
+    fn check_all_ordered() {
+        testkit::check_all::<OrdGSet<u32>>();
+    }
+
+    #[quickcheck]
+    fn check_ordered_iteration(elements: Vec<u8>, low: u8) -> bool {
+        let mut hashed = GSet::new();
+        let mut ordered = GSet::ordered();
+        for &element in &elements {
+            hashed.insert(element);
+            ordered.insert(element);
+        }
+        let mut expected = elements.clone();
+        expected.sort();
+        expected.dedup();
+        ordered.iter().cloned().collect::<Vec<_>>() == expected
+            && ordered.range(low..).cloned().collect::<Vec<_>>()
+                == expected.iter().cloned().filter(|&e| e >= low).collect::<Vec<_>>()
+            && hashed.content_hash() == ordered.content_hash()
+    }
 }
@@ -1,25 +1,68 @@
 use std::cmp::Ordering::{self, Greater, Less, Equal};
-use std::collections::HashMap;
-use std::collections::hash_map::Entry::{Occupied, Vacant};
+use std::collections::{BTreeMap, HashMap};
+#[cfg(feature = "serde")]
+use std::convert::TryFrom;
 use std::fmt::{Debug, Formatter, Error};
 use std::hash::Hash;
+use std::marker::PhantomData;
+use std::ops::RangeBounds;
 
-#[cfg(any(quickcheck, test))]
+#[cfg(any(feature = "quickcheck", test))]
 use quickcheck::{Arbitrary, Gen};
 
-use Crdt;
+use {Crdt, TransactionId};
+#[cfg(feature = "persistent")]
+use hamt::HashTrieMap;
+use observe::Observable;
+use replication::{self, Reconcile};
+use super::store::MapStore;
+use wire::{self, Decode, DecodeError, Encode, Message, Migrations, Reader};
 
 /// A last-writer wins set.
+///
+/// ##### Garbage Collection
+///
+/// Removed elements leave a tombstone holding the transaction ID of the
+/// removal, so that an older insert cannot bring the element back. Once every
+/// replica has applied every operation up to some transaction ID, the
+/// tombstones up to that ID can no longer affect the set, and
+/// `purge_tombstones` discards them. See `purge_tombstones` for the
+/// operations which are rejected afterwards.
+///
+/// ##### Ordering
+///
+/// The elements are held in a hash map by default. A set created with
+/// `LwwSet::ordered` holds them in a `BTreeMap`, so that it iterates, prints
+/// and serializes them in sorted order, and supports range queries. With the
+/// `persistent` feature, a set created with `LwwSet::persistent` holds them
+/// in a `HashTrieMap`, so that it is cloned in constant time.
 #[derive(Clone, Default, Eq)]
-pub struct LwwSet<T> where T: Eq + Hash {
-    elements: HashMap<T, (bool, u64)>
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[cfg_attr(feature = "serde", serde(try_from = "LwwSetFields<M>"))]
+#[cfg_attr(feature = "serde", serde(bound(deserialize = "M: MapStore<T, (bool, TransactionId)> + \
+                                                         ::serde::Deserialize<'de>")))]
+pub struct LwwSet<T, M = HashMap<T, (bool, TransactionId)>> {
+    elements: M,
+    frontier: Option<TransactionId>,
+    #[cfg_attr(feature = "serde", serde(skip))]
+    marker: PhantomData<T>,
+}
+
+/// The fields of a deserialized set, before its frontier is checked.
+#[cfg(feature = "serde")]
+#[derive(Deserialize)]
+struct LwwSetFields<M> {
+    elements: M,
+    #[serde(default)]
+    frontier: Option<TransactionId>,
 }
 
 /// An insert or remove operation over `LwwSet` CRDTs.
 #[derive(Clone, Debug, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub enum LwwSetOp<T> {
-    Insert(T, u64),
-    Remove(T, u64),
+    Insert(T, TransactionId),
+    Remove(T, TransactionId),
 }
 
 impl <T> LwwSet<T> where T: Clone + Eq + Hash {
@@ -35,8 +78,74 @@ impl <T> LwwSet<T> where T: Clone + Eq + Hash {
     /// assert!(set.is_empty());
     /// ```
     pub fn new() -> LwwSet<T> {
-        LwwSet { elements: HashMap::new() }
+        LwwSet { elements: HashMap::new(), frontier: None, marker: PhantomData }
+    }
+}
+
+impl <T> LwwSet<T, BTreeMap<T, (bool, TransactionId)>> where T: Clone + Ord {
+
+    /// Create a new last-writer wins set which keeps its elements in sorted
+    /// order.
+    ///
+    /// ### Example
+    ///
+    /// ```
+    /// use crdt::set::LwwSet;
+    ///
+    /// let mut set = LwwSet::ordered();
+    /// set.insert("b", 0);
+    /// set.insert("a", 1);
+    /// set.insert("c", 2);
+    /// assert_eq!(vec![&"a", &"b", &"c"], set.iter().collect::<Vec<_>>());
+    /// ```
+    pub fn ordered() -> LwwSet<T, BTreeMap<T, (bool, TransactionId)>> {
+        LwwSet { elements: BTreeMap::new(), frontier: None, marker: PhantomData }
+    }
+
+    /// Returns an iterator over the elements of the set within `range`, in
+    /// sorted order.
+    ///
+    /// ### Example
+    ///
+    /// ```
+    /// use crdt::set::LwwSet;
+    ///
+    /// let mut set = LwwSet::ordered();
+    /// for element in 0..10u64 {
+    ///     set.insert(element, element);
+    /// }
+    /// set.remove(4, 10);
+    /// assert_eq!(vec![&3, &5], set.range(3..6).collect::<Vec<_>>());
+    /// ```
+    pub fn range<'a, R>(&'a self, range: R) -> Iter<'a, T> where R: RangeBounds<T> {
+        Iter { inner: Box::new(self.elements.range(range)) }
+    }
+}
+
+#[cfg(feature = "persistent")]
+impl <T> LwwSet<T, HashTrieMap<T, (bool, TransactionId)>> where T: Clone + Eq + Hash {
+
+    /// Create a new last-writer wins set which holds its elements in a
+    /// persistent map, so that clones share its elements.
+    ///
+    /// ### Example
+    ///
+    /// ```
+    /// use crdt::set::LwwSet;
+    ///
+    /// let mut set = LwwSet::persistent();
+    /// set.insert("a", 0);
+    /// let snapshot = set.clone();
+    /// set.remove("a", 1);
+    /// assert!(snapshot.contains(&"a"));
+    /// assert!(!set.contains(&"a"));
+    /// ```
+    pub fn persistent() -> LwwSet<T, HashTrieMap<T, (bool, TransactionId)>> {
+        LwwSet { elements: HashTrieMap::new(), frontier: None, marker: PhantomData }
     }
+}
+
+impl <T, M> LwwSet<T, M> where T: Clone, M: MapStore<T, (bool, TransactionId)> {
 
     /// Insert an element into a two-phase set.
     ///
@@ -49,17 +158,24 @@ impl <T> LwwSet<T> where T: Clone + Eq + Hash {
     /// set.insert("first-element", 0);
     /// assert!(set.contains(&"first-element"));
     /// ```
-    pub fn insert(&mut self, element: T, transaction_id: u64) -> Option<LwwSetOp<T>> {
-        match self.elements.entry(element.clone()) {
-            Occupied(ref mut entry) if transaction_id >= entry.get().1 => {
-                entry.insert((true, transaction_id));
-                Some(LwwSetOp::Insert(element, transaction_id))
-            },
-            Vacant(entry) => {
-                entry.insert((true, transaction_id));
+    pub fn insert<I>(&mut self, element: T, transaction_id: I) -> Option<LwwSetOp<T>>
+    where I: Into<TransactionId> {
+        let transaction_id = transaction_id.into();
+        if is_stable(self.frontier, transaction_id) {
+            return None;
+        }
+        self.insert_unchecked(element, transaction_id)
+    }
+
+    /// Inserts an element without checking the transaction ID against the
+    /// frontier.
+    fn insert_unchecked(&mut self, element: T, transaction_id: TransactionId) -> Option<LwwSetOp<T>> {
+        match self.elements.get(&element) {
+            Some(&(_, tid)) if transaction_id < tid => None,
+            _ => {
+                self.elements.insert(element.clone(), (true, transaction_id));
                 Some(LwwSetOp::Insert(element, transaction_id))
             },
-            _ => None,
         }
     }
 
@@ -76,25 +192,119 @@ impl <T> LwwSet<T> where T: Clone + Eq + Hash {
     /// set.remove("first-element", 1);
     /// assert!(!set.contains(&"first-element"));
     /// ```
-    pub fn remove(&mut self, element: T, transaction_id: u64) -> Option<LwwSetOp<T>> {
-
-        let updated = match self.elements.entry(element.clone()) {
-            Occupied(ref mut entry) if transaction_id > entry.get().1 => {
-                entry.insert((false, transaction_id));
-                true
-            },
-            Vacant(entry) => {
-                entry.insert((false, transaction_id));
-                true
+    pub fn remove<I>(&mut self, element: T, transaction_id: I) -> Option<LwwSetOp<T>>
+    where I: Into<TransactionId> {
+        let transaction_id = transaction_id.into();
+        if is_stable(self.frontier, transaction_id) {
+            return None;
+        }
+        match self.elements.get(&element) {
+            Some(&(_, tid)) if transaction_id <= tid => None,
+            _ => {
+                self.elements.insert(element.clone(), (false, transaction_id));
+                Some(LwwSetOp::Remove(element, transaction_id))
             },
-            _ => false,
-        };
+        }
+    }
 
-        if updated {
-            Some(LwwSetOp::Remove(element, transaction_id))
-        } else {
-            None
+    /// Discards the tombstones of elements removed at or before `frontier`,
+    /// returning the number of tombstones discarded.
+    ///
+    /// `frontier` **must** be stable: every replica must have applied every
+    /// insert and remove operation with a transaction ID at or before it,
+    /// for example because every replica has acknowledged it as its low-water
+    /// mark. The frontier spreads to other replicas through merges, which
+    /// purge their own tombstones in turn.
+    ///
+    /// Afterwards, every insert and remove with a transaction ID at or before
+    /// the frontier is rejected, whether it is applied locally, applied as an
+    /// operation, or merged from another replica's state. This includes
+    /// operations which would otherwise have won, such as an insert newer
+    /// than a purged tombstone. When the frontier is stable, each such
+    /// operation has already been applied, so rejecting it changes nothing.
+    /// Operations after the frontier are applied as usual.
+    ///
+    /// ### Example
+    ///
+    /// ```
+    /// use crdt::set::LwwSet;
+    ///
+    /// let mut set = LwwSet::new();
+    /// set.insert("a", 1);
+    /// set.remove("a", 2);
+    /// set.insert("b", 3);
+    ///
+    /// assert_eq!(1, set.purge_tombstones(2));
+    /// assert!(set.insert("a", 2).is_none());
+    /// assert!(set.remove("b", 2).is_none());
+    /// assert!(set.insert("a", 4).is_some());
+    /// ```
+    pub fn purge_tombstones<I>(&mut self, frontier: I) -> usize
+    where I: Into<TransactionId> {
+        let frontier = frontier.into();
+        if is_stable(self.frontier, frontier) {
+            return 0;
         }
+        self.frontier = Some(frontier);
+        let len = self.elements.len();
+        self.elements.retain(|_, &mut (is_present, tid)| is_present || tid > frontier);
+        len - self.elements.len()
+    }
+
+    /// Returns the stability frontier of the set, if its tombstones have been
+    /// purged.
+    pub fn frontier(&self) -> Option<TransactionId> {
+        self.frontier
+    }
+
+    /// Returns the insert and remove operations which, when applied to `other`,
+    /// bring it up to date with this set.
+    ///
+    /// Applying the operations to `other` is equivalent to merging this set
+    /// into `other`. Operations are only returned for elements whose state in
+    /// this set would win over the state in `other`, and which `other` would
+    /// not reject as being at or before its frontier.
+    ///
+    /// ### Example
+    ///
+    /// ```
+    /// use crdt::Crdt;
+    /// use crdt::set::LwwSet;
+    ///
+    /// let mut local = LwwSet::new();
+    /// let mut remote = LwwSet::new();
+    /// local.insert(1i32, 0);
+    /// remote.insert(2, 1);
+    /// local.remove(2, 2);
+    ///
+    /// for op in local.diff_ops(&remote) {
+    ///     remote.apply(op);
+    /// }
+    /// assert_eq!(local, remote);
+    /// ```
+    pub fn diff_ops(&self, other: &LwwSet<T, M>) -> Vec<LwwSetOp<T>> {
+        self.elements
+            .iter()
+            .filter(|&(element, &(is_present, tid))| {
+                if is_stable(other.frontier, tid) {
+                    return false;
+                }
+                match other.elements.get(element) {
+                    None => true,
+                    Some(&(_, other_tid)) if tid > other_tid => true,
+                    Some(&(other_is_present, other_tid)) => {
+                        tid == other_tid && is_present && !other_is_present
+                    },
+                }
+            })
+            .map(|(element, &(is_present, tid))| {
+                if is_present {
+                    LwwSetOp::Insert(element.clone(), tid)
+                } else {
+                    LwwSetOp::Remove(element.clone(), tid)
+                }
+            })
+            .collect()
     }
 
     /// Returns the number of elements in the set.
@@ -110,20 +320,68 @@ impl <T> LwwSet<T> where T: Clone + Eq + Hash {
     /// Returns true if the set contains no elements.
     pub fn is_empty(&self) -> bool { self.len() == 0 }
 
-    pub fn is_subset(&self, other: &LwwSet<T>) -> bool {
+    pub fn is_subset(&self, other: &LwwSet<T, M>) -> bool {
         self.elements
             .iter()
             .all(|(element, &(is_present, _))| !is_present || other.contains(element))
     }
 
-    pub fn is_disjoint(&self, other: &LwwSet<T>) -> bool {
+    pub fn is_disjoint(&self, other: &LwwSet<T, M>) -> bool {
         self.elements
             .iter()
             .all(|(element, &(is_present, _))| !is_present || !other.contains(element))
     }
+
+    /// Returns an iterator over the elements of the set, in sorted order if
+    /// the set is ordered.
+    pub fn iter<'a>(&'a self) -> Iter<'a, T> {
+        Iter { inner: self.elements.iter() }
+    }
 }
 
-impl <T> Crdt for LwwSet<T> where T: Clone + Eq + Hash {
+impl <T, M> LwwSet<T, M> where T: Encode, M: MapStore<T, (bool, TransactionId)> {
+
+    /// Returns a hash of the elements and tombstones of the set.
+    ///
+    /// The hash is computed over the canonical encoding of the set, so
+    /// replicas holding the same state have the same hash, whatever their
+    /// backing store, platform, or version of Rust.
+    ///
+    /// ##### Example
+    ///
+    /// ```
+    /// use crdt::set::LwwSet;
+    ///
+    /// let mut hashed = LwwSet::new();
+    /// let mut ordered = LwwSet::ordered();
+    /// for element in 0..100u64 {
+    ///     hashed.insert(element, element);
+    ///     ordered.insert(element, element);
+    /// }
+    /// assert_eq!(hashed.content_hash(), ordered.content_hash());
+    /// ```
+    pub fn content_hash(&self) -> u64 {
+        replication::fingerprint(|buf| self.encode(buf))
+    }
+}
+
+impl <T, M> LwwSet<T, M> where M: MapStore<T, (bool, TransactionId)> {
+
+    /// Checks that no tombstone at or before the frontier remains.
+    fn validate(&self) -> Result<(), &'static str> {
+        if self.elements.iter().any(|(_, &(is_present, tid))| !is_present && is_stable(self.frontier, tid)) {
+            return Err("tombstone at or before the frontier");
+        }
+        Ok(())
+    }
+}
+
+/// Returns true if the transaction ID is at or before the frontier.
+fn is_stable(frontier: Option<TransactionId>, transaction_id: TransactionId) -> bool {
+    frontier.map_or(false, |frontier| transaction_id <= frontier)
+}
+
+impl <T, M> Crdt for LwwSet<T, M> where T: Clone + Eq, M: MapStore<T, (bool, TransactionId)> + Clone + Eq {
 
     type Operation = LwwSetOp<T>;
 
@@ -150,9 +408,18 @@ impl <T> Crdt for LwwSet<T> where T: Clone + Eq + Hash {
     /// assert!(!local.contains(&1));
     /// assert_eq!(1, local.len());
     /// ```
-    fn merge(&mut self, other: LwwSet<T>) {
+    fn merge(&mut self, other: LwwSet<T, M>) {
+        if let Some(frontier) = other.frontier {
+            self.purge_tombstones(frontier);
+        }
+        // A replica which has reached this set's frontier holds the final
+        // state of its elements at or before it, so its elements are kept
+        // even though the equivalent operations would be rejected.
+        let complete = other.frontier >= self.frontier;
         for (element, (is_present, tid)) in other.elements.into_iter() {
-            if is_present {
+            if is_present && complete {
+                self.insert_unchecked(element, tid);
+            } else if is_present {
                 self.insert(element, tid);
             } else {
                 self.remove(element, tid);
@@ -187,34 +454,48 @@ impl <T> Crdt for LwwSet<T> where T: Clone + Eq + Hash {
     }
 }
 
-impl <T : Eq + Hash> PartialEq for LwwSet<T> {
-    fn eq(&self, other: &LwwSet<T>) -> bool {
-        self.elements == other.elements
+impl <T, M> Observable for LwwSet<T, M>
+where T: Clone + Eq, M: MapStore<T, (bool, TransactionId)> + Clone + Eq, M::Keys: Clone + PartialEq {
+
+    type Value = M::Keys;
+
+    fn value(&self) -> M::Keys {
+        self.elements
+            .iter()
+            .filter(|&(_, &(is_present, _))| is_present)
+            .map(|(element, _)| element.clone())
+            .collect()
+    }
+}
+
+impl <T, M> PartialEq for LwwSet<T, M> where M: PartialEq {
+    fn eq(&self, other: &LwwSet<T, M>) -> bool {
+        self.elements == other.elements && self.frontier == other.frontier
     }
 }
 
-impl <T> PartialOrd for LwwSet<T> where T: Eq + Hash {
-    fn partial_cmp(&self, other: &LwwSet<T>) -> Option<Ordering> {
-        if self.elements == other.elements {
+impl <T, M> PartialOrd for LwwSet<T, M> where M: MapStore<T, (bool, TransactionId)> + PartialEq {
+    fn partial_cmp(&self, other: &LwwSet<T, M>) -> Option<Ordering> {
+        if self == other {
             return Some(Equal);
         }
-        let self_is_greater =
-            self.elements
-                .iter()
-                .any(|(element, &(_, self_tid))| {
-                    other.elements.get(element).map_or(true, |&(_, other_tid)| {
-                        self_tid > other_tid
-                    })
-                });
-
-        let other_is_greater =
-            other.elements
-                .iter()
-                .any(|(element, &(_, other_tid))| {
-                        self.elements.get(element).map_or(true, |&(_, self_tid)| {
-                        other_tid > self_tid
-                    })
-                });
+        // An insert wins over a remove with the same transaction ID, and a
+        // tombstone at or before the other set's frontier has been purged by
+        // it.
+        fn a_gt_b<T, M>(a: &LwwSet<T, M>, b: &LwwSet<T, M>) -> bool where M: MapStore<T, (bool, TransactionId)> {
+            a.frontier > b.frontier ||
+            a.elements
+             .iter()
+             .any(|(element, &(a_is_present, a_tid))| {
+                 match b.elements.get(element) {
+                     Some(&(b_is_present, b_tid)) => (a_tid, a_is_present) > (b_tid, b_is_present),
+                     None => a_is_present || !is_stable(b.frontier, a_tid),
+                 }
+             })
+        }
+
+        let self_is_greater = a_gt_b(self, other);
+        let other_is_greater = a_gt_b(other, self);
 
         if self_is_greater && other_is_greater {
             None
@@ -226,7 +507,7 @@ impl <T> PartialOrd for LwwSet<T> where T: Eq + Hash {
     }
 }
 
-impl <T> Debug for LwwSet<T> where T: Debug + Eq + Hash {
+impl <T, M> Debug for LwwSet<T, M> where T: Debug, M: MapStore<T, (bool, TransactionId)> {
      fn fmt(&self, f: &mut Formatter) -> Result<(), Error> {
          try!(write!(f, "{{present: {{"));
          for (i, x) in self.elements
@@ -246,21 +527,155 @@ impl <T> Debug for LwwSet<T> where T: Debug + Eq + Hash {
              if i != 0 { try!(write!(f, ", ")); }
              try!(write!(f, "{:?}", x))
          }
-         write!(f, "}}}}")
+         try!(write!(f, "}}"));
+         if let Some(frontier) = self.frontier {
+             try!(write!(f, ", frontier: {:?}", frontier));
+         }
+         write!(f, "}}")
      }
 }
 
-#[cfg(any(quickcheck, test))]
-impl <T : Arbitrary + Eq + Hash + Clone> Arbitrary for LwwSet<T> {
-    fn arbitrary<G: Gen>(g: &mut G) -> LwwSet<T> {
-        LwwSet { elements: Arbitrary::arbitrary(g) }
+impl <T, M> Encode for LwwSet<T, M> where T: Encode, M: MapStore<T, (bool, TransactionId)> {
+    fn encode(&self, buf: &mut Vec<u8>) {
+        // The present elements are followed by the removed elements, so the
+        // presence of each element is not encoded.
+        let (present, removed): (Vec<_>, Vec<_>) =
+            self.elements.iter().partition(|&(_, &(is_present, _))| is_present);
+        for entries in vec![present, removed] {
+            wire::write_entries(buf, entries.into_iter(), |(element, &(_, tid)), buf| {
+                element.encode(buf);
+                tid.encode(buf);
+            });
+        }
+        self.frontier.encode(buf);
+    }
+}
+
+impl <T, M> Decode for LwwSet<T, M> where T: Decode, M: MapStore<T, (bool, TransactionId)> {
+    fn decode(reader: &mut Reader) -> Result<LwwSet<T, M>, DecodeError> {
+        let mut set: LwwSet<T, M> = try!(decode_schema_2(reader));
+        set.frontier = try!(Decode::decode(reader));
+        try!(set.validate().map_err(DecodeError::InvalidValue));
+        Ok(set)
+    }
+}
+
+/// Decodes the schema 2 encoding, which predates tombstone garbage
+/// collection.
+fn decode_schema_2<T, M>(reader: &mut Reader) -> Result<LwwSet<T, M>, DecodeError>
+where T: Decode, M: MapStore<T, (bool, TransactionId)> {
+    let mut elements = M::default();
+    for &is_present in &[true, false] {
+        let len = try!(reader.read_len());
+        for _ in 0..len {
+            let element = try!(Decode::decode(reader));
+            let tid = try!(TransactionId::decode(reader));
+            match elements.insert(element, (is_present, tid)) {
+                Some((true, _)) if !is_present => {
+                    return Err(DecodeError::InvalidValue("element is both present and removed"));
+                },
+                Some(_) => return Err(DecodeError::InvalidValue("duplicate entry")),
+                None => (),
+            }
+        }
+    }
+    Ok(LwwSet { elements: elements, frontier: None, marker: PhantomData })
+}
+
+/// Decodes the schema 1 encoding, the first encoding of the wire format, in
+/// which each entry holds a presence flag and a `u64` timestamp.
+fn decode_schema_1<T, M>(reader: &mut Reader) -> Result<LwwSet<T, M>, DecodeError>
+where T: Decode, M: MapStore<T, (bool, TransactionId)> {
+    let elements = try!(wire::read_map(reader, |reader| {
+        let element = try!(Decode::decode(reader));
+        let is_present = try!(Decode::decode(reader));
+        let timestamp = try!(u64::decode(reader));
+        Ok((element, (is_present, TransactionId::from(timestamp))))
+    }));
+    Ok(LwwSet { elements: elements, frontier: None, marker: PhantomData })
+}
+
+#[cfg(feature = "serde")]
+impl <T, M> TryFrom<LwwSetFields<M>> for LwwSet<T, M> where M: MapStore<T, (bool, TransactionId)> {
+    type Error = &'static str;
+
+    fn try_from(fields: LwwSetFields<M>) -> Result<LwwSet<T, M>, &'static str> {
+        let set = LwwSet { elements: fields.elements, frontier: fields.frontier, marker: PhantomData };
+        try!(set.validate());
+        Ok(set)
+    }
+}
+
+impl <T, M> Message for LwwSet<T, M> where T: Encode + Decode, M: MapStore<T, (bool, TransactionId)> {
+    const TAG: u8 = 10;
+    const SCHEMA: u32 = 3;
+
+    fn migrations() -> Migrations<LwwSet<T, M>> {
+        Migrations::new().register(1, decode_schema_1).register(2, decode_schema_2)
     }
-    fn shrink(&self) -> Box<Iterator<Item=LwwSet<T>> + 'static> {
-        Box::new(self.elements.shrink().map(|es| LwwSet { elements: es }))
+}
+
+impl <T> Encode for LwwSetOp<T> where T: Encode {
+    fn encode(&self, buf: &mut Vec<u8>) {
+        match *self {
+            LwwSetOp::Insert(ref element, tid) => { buf.push(0); element.encode(buf); tid.encode(buf); },
+            LwwSetOp::Remove(ref element, tid) => { buf.push(1); element.encode(buf); tid.encode(buf); },
+        }
     }
 }
 
-#[cfg(any(quickcheck, test))]
+impl <T> Decode for LwwSetOp<T> where T: Decode {
+    fn decode(reader: &mut Reader) -> Result<LwwSetOp<T>, DecodeError> {
+        let variant = try!(reader.read_u8());
+        if variant > 1 {
+            return Err(DecodeError::InvalidValue("unknown LwwSetOp variant"));
+        }
+        let element = try!(Decode::decode(reader));
+        let tid = try!(Decode::decode(reader));
+        Ok(if variant == 0 { LwwSetOp::Insert(element, tid) } else { LwwSetOp::Remove(element, tid) })
+    }
+}
+
+impl <T> Message for LwwSetOp<T> where T: Encode + Decode {
+    const TAG: u8 = 11;
+}
+
+impl <T, M> Reconcile for LwwSet<T, M>
+where T: Clone + Encode + Eq, M: MapStore<T, (bool, TransactionId)> + Clone + Eq {
+    fn fingerprints<F>(&self, mut f: F) where F: FnMut(u64, u64) {
+        for (element, &(is_present, tid)) in self.elements.iter() {
+            let key_hash = replication::fingerprint(|buf| element.encode(buf));
+            let entry_hash = replication::fingerprint(|buf| {
+                element.encode(buf);
+                is_present.encode(buf);
+                tid.encode(buf);
+            });
+            f(key_hash, entry_hash);
+        }
+    }
+
+    fn select<F>(&self, mut keep: F) -> LwwSet<T, M> where F: FnMut(u64) -> bool {
+        let elements = self.elements
+                           .iter()
+                           .filter(|&(element, _)| keep(replication::fingerprint(|buf| element.encode(buf))))
+                           .map(|(element, &entry)| (element.clone(), entry))
+                           .collect();
+        LwwSet { elements: elements, frontier: self.frontier, marker: PhantomData }
+    }
+}
+
+#[cfg(any(feature = "quickcheck", test))]
+impl <T, M> Arbitrary for LwwSet<T, M>
+where T: Arbitrary, M: MapStore<T, (bool, TransactionId)> + Arbitrary {
+    fn arbitrary<G: Gen>(g: &mut G) -> LwwSet<T, M> {
+        LwwSet { elements: Arbitrary::arbitrary(g), frontier: None, marker: PhantomData }
+    }
+    fn shrink(&self) -> Box<Iterator<Item=LwwSet<T, M>> + 'static> {
+        Box::new(self.elements.shrink().map(|es| LwwSet { elements: es, frontier: None, marker: PhantomData }))
+    }
+}
+
+#[cfg(any(feature = "quickcheck", test))]
 impl <T : Arbitrary> Arbitrary for LwwSetOp<T> {
     fn arbitrary<G: Gen>(g: &mut G) -> LwwSetOp<T> {
         if Arbitrary::arbitrary(g) {
@@ -281,6 +696,28 @@ impl <T : Arbitrary> Arbitrary for LwwSetOp<T> {
     }
 }
 
+/// An iterator over the elements of an `LwwSet`.
+pub struct Iter<'a, T: 'a> {
+    inner: Box<Iterator<Item=(&'a T, &'a (bool, TransactionId))> + 'a>,
+}
+
+impl <'a, T> Iterator for Iter<'a, T> {
+    type Item = &'a T;
+
+    fn next(&mut self) -> Option<&'a T> {
+        while let Some((element, &(is_present, _))) = self.inner.next() {
+            if is_present {
+                return Some(element);
+            }
+        }
+        None
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (0, self.inner.size_hint().1)
+    }
+}
+
 #[cfg(test)]
 mod test {
 
@@ -288,7 +725,11 @@ mod test {
 
     use quickcheck::quickcheck;
 
-    use {test, Crdt};
+    use {testkit, Crdt, TransactionId};
+    use observe::Observable;
+    use set::OrdLwwSet;
+    #[cfg(feature = "persistent")]
+    use set::PersistentLwwSet;
     use super::{LwwSet, LwwSetOp};
 
     type C = LwwSet<u32>;
@@ -296,22 +737,53 @@ mod test {
 
     #[test]
     fn check_apply_is_commutative() {
-        quickcheck(test::apply_is_commutative::<C> as fn(C, Vec<O>) -> bool);
+        quickcheck(testkit::apply_is_commutative::<C> as fn(C, Vec<O>) -> bool);
     }
 
     #[test]
     fn check_merge_is_commutative() {
-        quickcheck(test::merge_is_commutative::<C> as fn(C, Vec<C>) -> bool);
+        quickcheck(testkit::merge_is_commutative::<C> as fn(C, Vec<C>) -> bool);
     }
 
     #[test]
     fn check_ordering_lte() {
-        quickcheck(test::ordering_lte::<C> as fn(C, C) -> bool);
+        quickcheck(testkit::ordering_lte::<C> as fn(C, C) -> bool);
     }
 
     #[test]
     fn check_ordering_equality() {
-        quickcheck(test::ordering_equality::<C> as fn(C, C) -> bool);
+        quickcheck(testkit::ordering_equality::<C> as fn(C, C) -> bool);
+    }
+
+    #[test]
+    fn check_all() {
+        testkit::check_all::<C>();
+    }
+
+    #[cfg(feature = "serde")]
+    #[quickcheck]
+    fn check_serde_round_trip(crdt: C, op: O) -> bool {
+        ::test_util::serde_round_trip(crdt, op, |_, _| true)
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn check_serde_rejects_purged_tombstones() {
+        use serde_json;
+
+        let mut set = LwwSet::new();
+        set.insert(1u32, 1);
+        set.remove(2, 3);
+        set.purge_tombstones(2);
+        let json = serde_json::to_string(&set).unwrap();
+        assert_eq!(set, serde_json::from_str(&json).unwrap());
+
+        assert!(serde_json::from_str::<C>(r#"{"elements":{"2":[false,3]},"frontier":3}"#).is_err());
+    }
+
+    #[quickcheck]
+    fn check_diff_ops_is_merge(a: C, b: C) -> bool {
+        testkit::diff_ops_is_merge(a, b, C::diff_ops)
     }
 
     #[quickcheck]
@@ -330,4 +802,120 @@ mod test {
         a.insert(0, u64::MAX);
         a > b && b < a
     }
+
+    #[test]
+    fn check_insert_wins_ordering() {
+        let mut inserted = LwwSet::new();
+        let mut removed = LwwSet::new();
+        inserted.insert(1u32, 1);
+        removed.remove(1, 1);
+        assert!(inserted > removed && removed < inserted);
+
+        removed.merge(inserted.clone());
+        assert_eq!(inserted, removed);
+    }
+
+    #[test]
+    fn check_purge_tombstones() {
+        let mut set = LwwSet::new();
+        set.insert(1u32, 1);
+        set.remove(1, 2);
+        set.insert(2, 3);
+        set.remove(3, 6);
+        let stale = set.clone();
+
+        assert_eq!(1, set.purge_tombstones(5));
+        assert_eq!(0, set.purge_tombstones(4));
+        assert_eq!(Some(TransactionId::from(5)), set.frontier());
+        assert!(set > stale && stale < set);
+
+        // Operations at or before the frontier are rejected, even those which
+        // would have won before the tombstones were purged.
+        assert_eq!(None, set.insert(1, 4));
+        assert_eq!(None, set.remove(2, 4));
+        assert_eq!(None, set.insert(4, 5));
+        set.apply(LwwSetOp::Remove(2, TransactionId::from(5)));
+        assert!(set.contains(&2));
+
+        // A stale replica's state does not bring back a purged element.
+        let mut old = LwwSet::new();
+        old.insert(1u32, 1);
+        set.merge(old);
+        assert!(!set.contains(&1));
+
+        // Operations after the frontier are applied as usual.
+        assert!(set.insert(1, 6).is_some());
+        assert!(set.remove(2, 7).is_some());
+
+        // Merging spreads the frontier, and with it the purge.
+        let mut other = stale;
+        other.merge(set.clone());
+        assert_eq!(set, other);
+
+        // A new replica receives the elements at or before the frontier.
+        let mut new = LwwSet::new();
+        new.merge(set.clone());
+        assert_eq!(set, new);
+    }
+
+    /// Checks that purging the tombstones at a stable frontier, and then
+    /// replaying every operation, leaves the same elements as applying the
+    /// operations without purging.
+    #[quickcheck]
+    fn check_purge_preserves_elements(ops: Vec<(bool, u8, u8)>, frontier: u8) -> bool {
+        let frontier = TransactionId::from(frontier as u64);
+        let tid = |op: &O| match *op { LwwSetOp::Insert(_, tid) | LwwSetOp::Remove(_, tid) => tid };
+        let ops: Vec<O> = ops.into_iter()
+                             .map(|(insert, e, tid)| {
+                                 let tid = TransactionId::from(tid as u64);
+                                 if insert { LwwSetOp::Insert(e as u32, tid) } else { LwwSetOp::Remove(e as u32, tid) }
+                             })
+                             .collect();
+
+        let mut expected = C::new();
+        let mut set = C::new();
+        for op in ops.iter().cloned() {
+            expected.apply(op);
+        }
+        for op in ops.iter().filter(|op| tid(op) <= frontier).cloned() {
+            set.apply(op);
+        }
+        set.purge_tombstones(frontier);
+        for op in ops {
+            set.apply(op);
+        }
+        set.value() == expected.value()
+    }
+
+    #[test]
+    fn check_all_ordered() {
+        testkit::check_all::<OrdLwwSet<u32>>();
+    }
+
+    #[cfg(feature = "persistent")]
+    #[test]
+    fn check_all_persistent() {
+        testkit::check_all::<PersistentLwwSet<u32>>();
+    }
+
+    #[quickcheck]
+    fn check_ordered_iteration(ops: Vec<(bool, u8, u64)>, low: u8) -> bool {
+        let mut hashed = LwwSet::new();
+        let mut ordered = LwwSet::ordered();
+        for &(is_insert, element, tid) in &ops {
+            if is_insert {
+                hashed.insert(element, tid);
+                ordered.insert(element, tid);
+            } else {
+                hashed.remove(element, tid);
+                ordered.remove(element, tid);
+            }
+        }
+        let mut expected = hashed.iter().cloned().collect::<Vec<_>>();
+        expected.sort();
+        ordered.iter().cloned().collect::<Vec<_>>() == expected
+            && ordered.range(low..).cloned().collect::<Vec<_>>()
+                == expected.iter().cloned().filter(|&e| e >= low).collect::<Vec<_>>()
+            && hashed.content_hash() == ordered.content_hash()
+    }
 }
@@ -29,6 +29,11 @@
 //! non-deterministic. `LwwSet` should be preferred when the rate of operations
 //! on an element is small compared to the resolution of transaction IDs.
 //!
+//! The tombstones which `TpSet` and `LwwSet` keep for removed elements can be
+//! discarded with `purge_tombstones` once every replica has seen them. A
+//! `TpSet` collects its tombstones against a frontier over its elements, so
+//! only an ordered `TpSet` can discard them.
+//!
 //! ###### `PnSet`
 //!
 //! A counting add/remove set. Every element has an associated counter which is
@@ -47,13 +52,67 @@
 //! event of concurrent add and remove operations, add will take precedence.
 //! `OrSet` should be used in most cases where typical set semantics are
 //! needed.
+//!
+//! ##### Ordered Sets
+//!
+//! `GSet`, `TpSet`, `LwwSet` and `PnSet` are generic over the store which
+//! holds their elements, a `SetStore` or a `MapStore`. By default this is a
+//! hash table, whose iteration order varies between processes. The `ordered`
+//! constructors create sets backed by a `BTreeSet` or `BTreeMap` instead,
+//! which iterate in sorted order and support range queries; the `OrdGSet`,
+//! `OrdTpSet`, `OrdLwwSet` and `OrdPnSet` aliases name these types. Ordered
+//! and hashed replicas have the same encoding and the same `content_hash`.
+//! A `PnSet` store maps each element to the `Pn` counts of every replica
+//! which has inserted or removed it.
+//!
+//! ##### Persistent Sets
+//!
+//! With the `persistent` feature, the `persistent` constructors of `LwwSet`
+//! and `PnSet` create sets backed by a `HashTrieMap`, whose clones share
+//! their elements, so that cloning a set, for example to publish a snapshot to
+//! readers, takes constant time instead of copying every element. Lookups
+//! and merges of whole replicas are slower, so these sets pay off when they
+//! are cloned more often than they are merged; the `persistent` benchmark
+//! compares the two. The `PersistentLwwSet` and `PersistentPnSet` aliases
+//! name these types.
 
 pub use self::gset::{GSet, GSetOp};
 pub use self::tpset::{TpSet, TpSetOp};
 pub use self::lwwset::{LwwSet, LwwSetOp};
 pub use self::pnset::{PnSet, PnSetOp};
+pub use self::orset::{OrSet, OrSetOp};
+pub use self::store::{MapStore, SetStore};
+pub use pn::Pn;
+
+use std::collections::{BTreeMap, BTreeSet};
+
+use {ReplicaId, TransactionId};
+#[cfg(feature = "persistent")]
+use hamt::HashTrieMap;
+
+/// A `GSet` which keeps its elements in sorted order.
+pub type OrdGSet<T> = GSet<T, BTreeSet<T>>;
+
+/// A `TpSet` which keeps its elements in sorted order.
+pub type OrdTpSet<T> = TpSet<T, BTreeMap<T, bool>>;
+
+/// An `LwwSet` which keeps its elements in sorted order.
+pub type OrdLwwSet<T> = LwwSet<T, BTreeMap<T, (bool, TransactionId)>>;
+
+/// A `PnSet` which keeps its elements in sorted order.
+pub type OrdPnSet<T> = PnSet<T, BTreeMap<T, BTreeMap<ReplicaId, Pn>>>;
+
+/// An `LwwSet` which holds its elements in a persistent map.
+#[cfg(feature = "persistent")]
+pub type PersistentLwwSet<T> = LwwSet<T, HashTrieMap<T, (bool, TransactionId)>>;
+
+/// A `PnSet` which holds its elements in a persistent map.
+#[cfg(feature = "persistent")]
+pub type PersistentPnSet<T> = PnSet<T, HashTrieMap<T, BTreeMap<ReplicaId, Pn>>>;
 
+mod store;
 mod gset;
 mod tpset;
 mod lwwset;
 mod pnset;
+mod orset;
@@ -0,0 +1,549 @@
+use std::cmp::Ordering::{self, Greater, Less, Equal};
+use std::collections::{HashMap, HashSet};
+use std::collections::hash_map::{self, Entry};
+#[cfg(feature = "serde")]
+use std::convert::TryFrom;
+use std::hash::Hash;
+
+#[cfg(any(feature = "quickcheck", test))]
+use quickcheck::{Arbitrary, Gen};
+
+use {Crdt, ReplicaId};
+use observe::Observable;
+use super::store;
+use wire::{self, Decode, DecodeError, Encode, Message, Reader};
+
+/// A unique tag for an insert operation: the replica which performed it, and
+/// the number of inserts the replica had performed.
+type Tag = (ReplicaId, u64);
+
+/// The tags of the inserts of an element, and the tags of those inserts which
+/// have been removed. The removed tags are always a subset of the inserted
+/// tags.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[cfg_attr(feature = "serde", serde(try_from = "TagsFields"))]
+struct Tags {
+    inserted: HashSet<Tag>,
+    removed: HashSet<Tag>,
+}
+
+/// The fields of deserialized tags, before the removed tags are checked.
+#[cfg(feature = "serde")]
+#[derive(Deserialize)]
+struct TagsFields {
+    inserted: HashSet<Tag>,
+    removed: HashSet<Tag>,
+}
+
+/// An observed-remove set.
+///
+/// Every insert is tagged uniquely, and a remove removes only the tags which
+/// the replica has observed, so an insert which is concurrent with a remove of
+/// the same element takes precedence. The tags of removed inserts are kept as
+/// tombstones.
+#[derive(Clone, Debug)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct OrSet<T> where T: Eq + Hash {
+    replica_id: ReplicaId,
+    /// The number of inserts performed by this replica.
+    seq: u64,
+    elements: HashMap<T, Tags>,
+}
+
+/// An insert or remove operation over `OrSet` CRDTs.
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub enum OrSetOp<T> {
+    Insert(T, Tag),
+    Remove(T, Vec<Tag>),
+}
+
+impl <T> OrSet<T> where T: Clone + Eq + Hash {
+
+    /// Create a new observed-remove set with the provided replica id.
+    ///
+    /// ### Example
+    ///
+    /// ```
+    /// use crdt::set::OrSet;
+    ///
+    /// let mut set = OrSet::<i32>::new(0);
+    /// assert!(set.is_empty());
+    /// ```
+    pub fn new<R>(replica_id: R) -> OrSet<T> where R: Into<ReplicaId> {
+        OrSet { replica_id: replica_id.into(), seq: 0, elements: HashMap::new() }
+    }
+
+    /// Insert an element into an observed-remove set.
+    ///
+    /// Every insert returns an operation, even if the element is already in
+    /// the set, since each insert must be removed separately.
+    ///
+    /// ### Example
+    ///
+    /// ```
+    /// use crdt::set::OrSet;
+    ///
+    /// let mut set = OrSet::new(0);
+    /// set.insert("first-element");
+    /// assert!(set.contains(&"first-element"));
+    /// ```
+    pub fn insert(&mut self, element: T) -> OrSetOp<T> {
+        self.seq += 1;
+        let tag = (self.replica_id, self.seq);
+        self.elements.entry(element.clone()).or_default().inserted.insert(tag);
+        OrSetOp::Insert(element, tag)
+    }
+
+    /// Remove an element from an observed-remove set.
+    ///
+    /// Only the inserts of the element which this replica has observed are
+    /// removed. Returns `None` if the element is not in the set.
+    ///
+    /// ### Example
+    ///
+    /// ```
+    /// use crdt::Crdt;
+    /// use crdt::set::OrSet;
+    ///
+    /// let mut local = OrSet::new(0);
+    /// let mut remote = OrSet::new(1);
+    /// local.insert("first-element");
+    /// remote.merge(local.clone());
+    ///
+    /// // The remove does not affect the concurrent insert.
+    /// remote.remove("first-element");
+    /// local.insert("first-element");
+    /// local.merge(remote);
+    /// assert!(local.contains(&"first-element"));
+    /// ```
+    pub fn remove(&mut self, element: T) -> Option<OrSetOp<T>> {
+        let tags = match self.elements.get_mut(&element) {
+            Some(tags) => tags,
+            None => return None,
+        };
+        let observed: Vec<Tag> = tags.inserted.difference(&tags.removed).cloned().collect();
+        if observed.is_empty() {
+            return None;
+        }
+        tags.removed.extend(observed.iter().cloned());
+        Some(OrSetOp::Remove(element, observed))
+    }
+
+    pub fn len(&self) -> usize {
+        self.iter().count()
+    }
+
+    /// Returns true if the set contains the value.
+    pub fn contains(&self, value: &T) -> bool {
+        self.elements.get(value).map_or(false, Tags::is_present)
+    }
+
+    /// Returns true if the set contains no elements.
+    pub fn is_empty(&self) -> bool { self.len() == 0 }
+
+    pub fn iter<'a>(&'a self) -> Iter<'a, T> {
+        Iter { inner: self.elements.iter() }
+    }
+
+    /// Records that this replica has performed the insert with `tag`, so that
+    /// its own tags stay unique.
+    fn observe(&mut self, tag: Tag) {
+        if tag.0 == self.replica_id && tag.1 > self.seq {
+            self.seq = tag.1;
+        }
+    }
+}
+
+impl Tags {
+
+    /// Creates the tags of an element, checking that every removed tag has
+    /// been inserted.
+    fn new(inserted: HashSet<Tag>, removed: HashSet<Tag>) -> Result<Tags, &'static str> {
+        if removed.is_subset(&inserted) {
+            Ok(Tags { inserted: inserted, removed: removed })
+        } else {
+            Err("OrSet removes a tag which was never inserted")
+        }
+    }
+
+    fn is_present(&self) -> bool {
+        self.inserted.len() > self.removed.len()
+    }
+}
+
+impl <T> Crdt for OrSet<T> where T: Clone + Eq + Hash {
+
+    type Operation = OrSetOp<T>;
+
+    /// Merge a replica into the set.
+    ///
+    /// This method is used to perform state-based replication.
+    ///
+    /// ##### Example
+    ///
+    /// ```
+    /// # use crdt::set::OrSet;
+    /// use crdt::Crdt;
+    ///
+    /// let mut local = OrSet::new(0);
+    /// let mut remote = OrSet::new(1);
+    ///
+    /// local.insert(1i32);
+    /// remote.insert(2);
+    ///
+    /// local.merge(remote);
+    /// assert_eq!(2, local.len());
+    /// ```
+    fn merge(&mut self, other: OrSet<T>) {
+        for (element, other_tags) in other.elements.into_iter() {
+            for &tag in other_tags.inserted.iter() {
+                self.observe(tag);
+            }
+            match self.elements.entry(element) {
+                Entry::Occupied(mut entry) => {
+                    let tags = entry.get_mut();
+                    tags.inserted.extend(other_tags.inserted);
+                    tags.removed.extend(other_tags.removed);
+                },
+                Entry::Vacant(entry) => { entry.insert(other_tags); },
+            }
+        }
+    }
+
+    /// Apply an insert or remove operation to the set.
+    ///
+    /// This method is used to perform operation-based replication.
+    ///
+    /// Applying an operation to an `OrSet` is idempotent, and operations may
+    /// be applied in any order.
+    ///
+    /// ##### Example
+    ///
+    /// ```
+    /// # use crdt::set::OrSet;
+    /// # use crdt::Crdt;
+    /// let mut local = OrSet::new(0);
+    /// let mut remote = OrSet::new(1);
+    ///
+    /// let op = remote.insert(13i32);
+    ///
+    /// local.apply(op);
+    /// assert!(local.contains(&13));
+    /// ```
+    fn apply(&mut self, op: OrSetOp<T>) {
+        match op {
+            OrSetOp::Insert(element, tag) => {
+                self.observe(tag);
+                self.elements.entry(element).or_default().inserted.insert(tag);
+            },
+            OrSetOp::Remove(element, removed) => {
+                for &tag in removed.iter() {
+                    self.observe(tag);
+                }
+                let tags = self.elements.entry(element).or_default();
+                // A remove may arrive before the inserts it removes.
+                tags.inserted.extend(removed.iter().cloned());
+                tags.removed.extend(removed);
+            },
+        }
+    }
+}
+
+impl <T> Observable for OrSet<T> where T: Clone + Eq + Hash {
+
+    type Value = HashSet<T>;
+
+    fn value(&self) -> HashSet<T> {
+        self.iter().cloned().collect()
+    }
+}
+
+impl <T : Eq + Hash> PartialEq for OrSet<T> {
+    fn eq(&self, other: &OrSet<T>) -> bool {
+        self.elements == other.elements
+    }
+}
+
+impl <T : Eq + Hash> Eq for OrSet<T> {}
+
+impl <T : Eq + Hash> PartialOrd for OrSet<T> {
+    fn partial_cmp(&self, other: &OrSet<T>) -> Option<Ordering> {
+
+        fn a_gt_b<T>(a: &HashMap<T, Tags>, b: &HashMap<T, Tags>) -> bool where T: Eq + Hash {
+            a.iter().any(|(element, a_tags)| {
+                b.get(element).map_or(true, |b_tags| {
+                    !a_tags.inserted.is_subset(&b_tags.inserted) || !a_tags.removed.is_subset(&b_tags.removed)
+                })
+            })
+        }
+
+        let self_is_greater = a_gt_b(&self.elements, &other.elements);
+        let other_is_greater = a_gt_b(&other.elements, &self.elements);
+
+        if self_is_greater && other_is_greater {
+            None
+        } else if self_is_greater {
+            Some(Greater)
+        } else if other_is_greater {
+            Some(Less)
+        } else {
+            Some(Equal)
+        }
+    }
+}
+
+fn encode_tag(&(replica_id, seq): &Tag, buf: &mut Vec<u8>) {
+    replica_id.encode(buf);
+    seq.encode(buf);
+}
+
+fn decode_tag(reader: &mut Reader) -> Result<Tag, DecodeError> {
+    Ok((try!(Decode::decode(reader)), try!(Decode::decode(reader))))
+}
+
+impl <T> Encode for OrSet<T> where T: Encode + Eq + Hash {
+    fn encode(&self, buf: &mut Vec<u8>) {
+        self.replica_id.encode(buf);
+        self.seq.encode(buf);
+        wire::write_entries(buf, self.elements.iter(), |(element, tags), buf| {
+            element.encode(buf);
+            wire::write_entries(buf, tags.inserted.iter(), encode_tag);
+            wire::write_entries(buf, tags.removed.iter(), encode_tag);
+        });
+    }
+}
+
+impl <T> Decode for OrSet<T> where T: Decode + Eq + Hash {
+    fn decode(reader: &mut Reader) -> Result<OrSet<T>, DecodeError> {
+        let replica_id = try!(Decode::decode(reader));
+        let seq = try!(Decode::decode(reader));
+        let elements = try!(wire::read_map(reader, |reader| {
+            let element = try!(Decode::decode(reader));
+            let inserted: HashSet<Tag> = try!(store::read_set(reader, decode_tag));
+            let removed: HashSet<Tag> = try!(store::read_set(reader, decode_tag));
+            Ok((element, try!(Tags::new(inserted, removed).map_err(DecodeError::InvalidValue))))
+        }));
+        Ok(OrSet { replica_id: replica_id, seq: seq, elements: elements })
+    }
+}
+
+#[cfg(feature = "serde")]
+impl TryFrom<TagsFields> for Tags {
+    type Error = &'static str;
+
+    fn try_from(fields: TagsFields) -> Result<Tags, &'static str> {
+        Tags::new(fields.inserted, fields.removed)
+    }
+}
+
+impl <T> Message for OrSet<T> where T: Encode + Decode + Eq + Hash {
+    const TAG: u8 = 21;
+}
+
+impl <T> Encode for OrSetOp<T> where T: Encode {
+    fn encode(&self, buf: &mut Vec<u8>) {
+        match *self {
+            OrSetOp::Insert(ref element, ref tag) => {
+                buf.push(0);
+                element.encode(buf);
+                encode_tag(tag, buf);
+            },
+            OrSetOp::Remove(ref element, ref tags) => {
+                buf.push(1);
+                element.encode(buf);
+                wire::write_varint(buf, tags.len() as u64);
+                for tag in tags {
+                    encode_tag(tag, buf);
+                }
+            },
+        }
+    }
+}
+
+impl <T> Decode for OrSetOp<T> where T: Decode {
+    fn decode(reader: &mut Reader) -> Result<OrSetOp<T>, DecodeError> {
+        match try!(reader.read_u8()) {
+            0 => Ok(OrSetOp::Insert(try!(Decode::decode(reader)), try!(decode_tag(reader)))),
+            1 => {
+                let element = try!(Decode::decode(reader));
+                let len = try!(reader.read_len());
+                let mut tags = Vec::with_capacity(len);
+                for _ in 0..len {
+                    tags.push(try!(decode_tag(reader)));
+                }
+                Ok(OrSetOp::Remove(element, tags))
+            },
+            _ => Err(DecodeError::InvalidValue("unknown OrSetOp variant")),
+        }
+    }
+}
+
+impl <T> Message for OrSetOp<T> where T: Encode + Decode {
+    const TAG: u8 = 22;
+}
+
+#[cfg(any(feature = "quickcheck", test))]
+impl <T> Arbitrary for OrSet<T> where T: Arbitrary + Clone + Eq + Hash {
+    fn arbitrary<G>(g: &mut G) -> OrSet<T> where G: Gen {
+        use gen_replica_id;
+        let mut set = OrSet::new(gen_replica_id());
+        let ops: Vec<OrSetOp<T>> = Arbitrary::arbitrary(g);
+        for op in ops {
+            set.apply(op);
+        }
+        set
+    }
+    fn shrink(&self) -> Box<Iterator<Item=OrSet<T>> + 'static> {
+        let (replica_id, seq) = (self.replica_id, self.seq);
+        let elements: Vec<T> = self.elements.keys().cloned().collect();
+        let set = self.clone();
+        Box::new(elements.shrink().map(move |elements| {
+            let elements = elements.into_iter()
+                                   .filter_map(|element| set.elements.get(&element).map(|tags| (element.clone(), tags.clone())))
+                                   .collect();
+            OrSet { replica_id: replica_id, seq: seq, elements: elements }
+        }))
+    }
+}
+
+#[cfg(any(feature = "quickcheck", test))]
+impl <T> Arbitrary for OrSetOp<T> where T: Arbitrary {
+    fn arbitrary<G>(g: &mut G) -> OrSetOp<T> where G: Gen {
+        // Draw tags from a few replicas and sequence numbers, so that removes
+        // overlap with inserts.
+        let tag = |g: &mut G| (ReplicaId::from(u64::arbitrary(g) % 4), u64::arbitrary(g) % 8);
+        if Arbitrary::arbitrary(g) {
+            OrSetOp::Insert(Arbitrary::arbitrary(g), tag(g))
+        } else {
+            let len = usize::arbitrary(g) % 4;
+            OrSetOp::Remove(Arbitrary::arbitrary(g), (0..len).map(|_| tag(g)).collect())
+        }
+    }
+    fn shrink(&self) -> Box<Iterator<Item=OrSetOp<T>> + 'static> {
+        match self.clone() {
+            OrSetOp::Insert(element, tag) => Box::new(element.shrink().map(move |e| OrSetOp::Insert(e, tag))),
+            OrSetOp::Remove(element, tags) => {
+                Box::new((element, tags).shrink().map(|(e, tags)| OrSetOp::Remove(e, tags)))
+            },
+        }
+    }
+}
+
+pub struct Iter<'a, T: 'a> {
+    inner: hash_map::Iter<'a, T, Tags>,
+}
+
+impl<'a, T> Iterator for Iter<'a, T> {
+    type Item = &'a T;
+
+    fn next(&mut self) -> Option<&'a T> {
+        self.inner.by_ref().find(|&(_, tags)| tags.is_present()).map(|(element, _)| element)
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (0, self.inner.size_hint().1)
+    }
+}
+
+#[cfg(test)]
+mod test {
+
+    use quickcheck::quickcheck;
+
+    use {Crdt, ReplicaId, testkit};
+    use wire;
+    use super::{OrSet, OrSetOp};
+
+    type C = OrSet<u32>;
+    type O = OrSetOp<u32>;
+
+    #[test]
+    fn check_apply_is_commutative() {
+        quickcheck(testkit::apply_is_commutative::<C> as fn(C, Vec<O>) -> bool);
+    }
+
+    #[test]
+    fn check_all() {
+        testkit::check_all::<C>();
+    }
+
+    #[cfg(feature = "serde")]
+    #[quickcheck]
+    fn check_serde_round_trip(crdt: C, op: O) -> bool {
+        ::test_util::serde_round_trip(crdt, op, |a, b| a.replica_id == b.replica_id)
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn check_serde_rejects_unobserved_removes() {
+        use serde_json;
+
+        let mut set = OrSet::new(ReplicaId(0));
+        set.insert(1u32);
+        set.remove(1);
+        let json = serde_json::to_string(&set).unwrap();
+        assert_eq!(set, serde_json::from_str(&json).unwrap());
+
+        let json = r#"{"replica_id":0,"seq":1,"elements":{"1":{"inserted":[[0,1]],"removed":[[0,2]]}}}"#;
+        assert!(serde_json::from_str::<C>(json).is_err());
+    }
+
+    #[quickcheck]
+    fn check_wire_round_trip(crdt: C, op: O) -> bool {
+        wire::from_bytes::<C>(&wire::to_bytes(&crdt)).unwrap() == crdt
+            && wire::from_bytes::<O>(&wire::to_bytes(&op)).unwrap() == op
+    }
+
+    #[quickcheck]
+    fn check_local_insert_remove(elements: Vec<(bool, u8)>) -> bool {
+        let mut set = OrSet::new(ReplicaId(0));
+        let mut expected = ::std::collections::HashSet::new();
+        for (insert, element) in elements {
+            if insert {
+                set.insert(element);
+                expected.insert(element);
+            } else {
+                assert_eq!(expected.remove(&element), set.remove(element).is_some());
+            }
+        }
+        expected.iter().all(|element| set.contains(element)) && set.len() == expected.len()
+    }
+
+    #[test]
+    fn check_concurrent_insert_wins() {
+        let mut a = OrSet::new(1);
+        let mut b = OrSet::new(2);
+        a.insert(1u32);
+        b.merge(a.clone());
+
+        let remove = b.remove(1).unwrap();
+        let insert = a.insert(1);
+        a.apply(remove);
+        b.apply(insert);
+        assert!(a.contains(&1) && b.contains(&1));
+        assert_eq!(a, b);
+
+        // Once observed, the element can be removed everywhere.
+        let remove = b.remove(1).unwrap();
+        a.apply(remove);
+        assert!(!a.contains(&1) && !b.contains(&1));
+    }
+
+    #[test]
+    fn check_tags_are_unique() {
+        let mut a = OrSet::new(1);
+        a.insert(1u32);
+        a.insert(2);
+
+        // A replica which recovers an older copy of its state does not reuse
+        // the tags it has already handed out.
+        let mut recovered = OrSet::new(1);
+        recovered.merge(a.clone());
+        recovered.remove(1);
+        recovered.insert(1);
+        a.merge(recovered);
+        assert!(a.contains(&1));
+    }
+}
@@ -1,29 +1,55 @@
 use std::cmp::Ordering::{self, Greater, Less, Equal};
-use std::collections::hash_map::{self, HashMap};
+use std::collections::{BTreeMap, HashMap};
 use std::hash::Hash;
+use std::marker::PhantomData;
+use std::ops::RangeBounds;
 
-#[cfg(any(quickcheck, test))]
+#[cfg(any(feature = "quickcheck", test))]
 use quickcheck::{Arbitrary, Gen};
 
 use {Crdt, ReplicaId};
+#[cfg(feature = "persistent")]
+use hamt::HashTrieMap;
+use observe::Observable;
+use replication;
+use wire::{self, Decode, DecodeError, Encode, Message, Migrations, Reader};
 use pn::Pn;
+use retire::{self, Retirements};
+use super::store::MapStore;
 
 /// A counting add/remove set.
+///
+/// The per-element entries of departed replicas can be removed by retiring
+/// them, as with `GCounter`.
+///
+/// ##### Ordering
+///
+/// The elements are held in a hash map by default. A set created with
+/// `PnSet::ordered` holds them in a `BTreeMap`, so that it iterates, prints
+/// and serializes them in sorted order, and supports range queries. With the
+/// `persistent` feature, a set created with `PnSet::persistent` holds them in
+/// a `HashTrieMap`, so that it is cloned in constant time.
 #[derive(Clone, Debug)]
-pub struct PnSet<T> where T: Eq + Hash {
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct PnSet<T, M = HashMap<T, BTreeMap<ReplicaId, Pn>>> {
     replica_id: ReplicaId,
-    elements: HashMap<T, HashMap<ReplicaId, Pn>>,
+    elements: M,
+    #[cfg_attr(feature = "serde", serde(default))]
+    retirements: Retirements,
+    #[cfg_attr(feature = "serde", serde(skip))]
+    marker: PhantomData<T>,
 }
 
 /// An insert or remove operation over `PnSet` CRDTs.
 #[derive(Clone, Debug, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct PnSetOp<T> {
     element: T,
     replica_id: ReplicaId,
     pn: Pn,
 }
 
-fn count(replica_counts: &HashMap<ReplicaId, Pn>) -> i64 {
+fn count(replica_counts: &BTreeMap<ReplicaId, Pn>) -> i64 {
     replica_counts.values().fold(0, |sum, pn| sum + pn.count())
 }
 
@@ -41,8 +67,92 @@ impl <T> PnSet<T> where T: Clone + Eq + Hash {
     /// ```
     pub fn new<R>(replica_id: R) -> PnSet<T>
     where R: Into<ReplicaId> {
-        PnSet { replica_id: replica_id.into(), elements: HashMap::new() }
+        PnSet {
+            replica_id: replica_id.into(),
+            elements: HashMap::new(),
+            retirements: Retirements::new(),
+            marker: PhantomData,
+        }
+    }
+}
+
+impl <T> PnSet<T, BTreeMap<T, BTreeMap<ReplicaId, Pn>>> where T: Clone + Ord {
+
+    /// Create a new counting add/remove set with the provided replica id,
+    /// which keeps its elements in sorted order.
+    ///
+    /// ### Example
+    ///
+    /// ```
+    /// use crdt::set::PnSet;
+    ///
+    /// let mut set = PnSet::ordered(0);
+    /// set.insert(3);
+    /// set.insert(1);
+    /// set.insert(2);
+    /// assert_eq!(vec![&1, &2, &3], set.iter().collect::<Vec<_>>());
+    /// ```
+    pub fn ordered<R>(replica_id: R) -> PnSet<T, BTreeMap<T, BTreeMap<ReplicaId, Pn>>>
+    where R: Into<ReplicaId> {
+        PnSet {
+            replica_id: replica_id.into(),
+            elements: BTreeMap::new(),
+            retirements: Retirements::new(),
+            marker: PhantomData,
+        }
+    }
+
+    /// Returns an iterator over the elements of the set within `range`, in
+    /// sorted order.
+    ///
+    /// ### Example
+    ///
+    /// ```
+    /// use crdt::set::PnSet;
+    ///
+    /// let mut set = PnSet::ordered(0);
+    /// for element in 0..10 {
+    ///     set.insert(element);
+    /// }
+    /// set.remove(4);
+    /// assert_eq!(vec![&3, &5], set.range(3..6).collect::<Vec<_>>());
+    /// ```
+    pub fn range<'a, R>(&'a self, range: R) -> Iter<'a, T> where R: RangeBounds<T> {
+        Iter { inner: Box::new(self.elements.range(range)) }
     }
+}
+
+#[cfg(feature = "persistent")]
+impl <T> PnSet<T, HashTrieMap<T, BTreeMap<ReplicaId, Pn>>> where T: Clone + Eq + Hash {
+
+    /// Create a new counting add/remove set with the provided replica id,
+    /// which holds its elements in a persistent map, so that clones share its
+    /// elements.
+    ///
+    /// ### Example
+    ///
+    /// ```
+    /// use crdt::set::PnSet;
+    ///
+    /// let mut set = PnSet::persistent(0);
+    /// set.insert("a");
+    /// let snapshot = set.clone();
+    /// set.remove("a");
+    /// assert!(snapshot.contains(&"a"));
+    /// assert!(!set.contains(&"a"));
+    /// ```
+    pub fn persistent<R>(replica_id: R) -> PnSet<T, HashTrieMap<T, BTreeMap<ReplicaId, Pn>>>
+    where R: Into<ReplicaId> {
+        PnSet {
+            replica_id: replica_id.into(),
+            elements: HashTrieMap::new(),
+            retirements: Retirements::new(),
+            marker: PhantomData,
+        }
+    }
+}
+
+impl <T, M> PnSet<T, M> where T: Clone, M: MapStore<T, BTreeMap<ReplicaId, Pn>> {
 
     /// Insert an element into a counting add/remove set.
     ///
@@ -79,14 +189,53 @@ impl <T> PnSet<T> where T: Clone + Eq + Hash {
     /// Increments the count of an element in the set by the given amount.
     fn increment_element(&mut self, element: T, amount: i64) -> PnSetOp<T> {
         let pn = self.elements
-                     .entry(element.clone())
-                     .or_insert_with(|| HashMap::new())
+                     .get_or_insert_with(element.clone(), BTreeMap::new)
                      .entry(self.replica_id)
                      .or_insert(Pn::new());
         pn.increment(amount);
         PnSetOp { replica_id: self.replica_id, element: element, pn: pn.clone() }
     }
 
+    /// Returns the insert and remove operations which, when applied to `other`,
+    /// bring it up to date with this set.
+    ///
+    /// Applying the operations to `other` is equivalent to merging this set
+    /// into `other`. One operation is returned for every element and replica
+    /// whose count is not already reflected in `other`.
+    ///
+    /// ### Example
+    ///
+    /// ```
+    /// use crdt::Crdt;
+    /// use crdt::set::PnSet;
+    ///
+    /// let mut local = PnSet::new(0);
+    /// let mut remote = PnSet::new(1);
+    /// local.insert(1i32);
+    /// remote.insert(2);
+    /// local.remove(2);
+    ///
+    /// for op in local.diff_ops(&remote) {
+    ///     remote.apply(op);
+    /// }
+    /// assert!(remote.contains(&1));
+    /// assert!(!remote.contains(&2));
+    /// ```
+    pub fn diff_ops(&self, other: &PnSet<T, M>) -> Vec<PnSetOp<T>> {
+        let mut ops = Vec::new();
+        for (element, counts) in self.elements.iter() {
+            let other_counts = other.elements.get(element);
+            for (&replica_id, &pn) in counts.iter() {
+                let is_reflected = other_counts.and_then(|counts| counts.get(&replica_id))
+                                               .map_or(false, |other_pn| pn.p <= other_pn.p && pn.n <= other_pn.n);
+                if !is_reflected {
+                    ops.push(PnSetOp { element: element.clone(), replica_id: replica_id, pn: pn });
+                }
+            }
+        }
+        ops
+    }
+
     /// Returns the number of elements in the set.
     pub fn len(&self) -> usize {
         self.iter().count()
@@ -102,20 +251,134 @@ impl <T> PnSet<T> where T: Clone + Eq + Hash {
     /// Returns true if the set contains no elements.
     pub fn is_empty(&self) -> bool { self.len() == 0 }
 
-    pub fn is_subset(&self, other: &PnSet<T>) -> bool {
+    pub fn is_subset(&self, other: &PnSet<T, M>) -> bool {
         self.iter().all(|element| other.contains(element))
     }
 
-    pub fn is_disjoint(&self, other: &PnSet<T>) -> bool {
+    pub fn is_disjoint(&self, other: &PnSet<T, M>) -> bool {
         self.iter().all(|element| !other.contains(element))
     }
 
+    /// Returns an iterator over the elements of the set, in sorted order if
+    /// the set is ordered.
     pub fn iter<'a>(&'a self) -> Iter<'a, T> {
         Iter { inner: self.elements.iter() }
     }
+
+    /// Begin retiring a departed replica.
+    ///
+    /// The departed replica **must not** insert or remove elements again.
+    /// Once every live replica has seen the retirement, `compact` folds the
+    /// departed replica's counts into this replica's counts.
+    ///
+    /// ### Example
+    ///
+    /// ```
+    /// use crdt::Crdt;
+    /// use crdt::set::PnSet;
+    ///
+    /// let mut a = PnSet::new(1);
+    /// let mut b = PnSet::new(2);
+    /// let mut departed = PnSet::new(3);
+    /// departed.insert("element");
+    /// b.merge(departed);
+    ///
+    /// a.retire(3);
+    /// b.merge(a.clone());
+    /// a.merge(b.clone());
+    /// assert_eq!(1, a.compact(vec![1, 2]));
+    ///
+    /// b.merge(a.clone());
+    /// b.remove("element");
+    /// assert!(!b.contains(&"element"));
+    /// ```
+    ///
+    /// ### Panics
+    ///
+    /// Panics if `departed` is the replica ID of this set.
+    pub fn retire<R>(&mut self, departed: R) where R: Into<ReplicaId> {
+        self.retirements.retire(departed.into(), self.replica_id);
+    }
+
+    /// Returns true if every replica in `live` has seen the retirement of
+    /// `departed` in its current phase, as far as this replica knows.
+    pub fn is_retirement_stable<R, I, L>(&self, departed: R, live: I) -> bool
+    where R: Into<ReplicaId>, I: IntoIterator<Item=L>, L: Into<ReplicaId> {
+        self.retirements.is_stable(departed.into(), &retire::live_set(self.replica_id, live))
+    }
+
+    /// Advance the retirements owned by this replica which every replica in
+    /// `live` has seen, folding the counts of departed replicas into this
+    /// replica's counts, and dropping the retirements which are complete.
+    ///
+    /// `live` **must** include every replica which may still merge state into
+    /// the set. Returns the number of departed replicas folded.
+    pub fn compact<I, L>(&mut self, live: I) -> usize
+    where I: IntoIterator<Item=L>, L: Into<ReplicaId> {
+        let live = retire::live_set(self.replica_id, live);
+        let replica_id = self.replica_id;
+        let elements = &mut self.elements;
+        self.retirements.compact(replica_id, &live, |departed| {
+            for counts in elements.values_mut() {
+                if let Some(departed) = counts.remove(&departed) {
+                    counts.entry(replica_id).or_insert(Pn::new()).add(departed);
+                }
+            }
+        })
+    }
+
+    /// Discards the counts of retired replicas which have been folded, and the
+    /// elements left without counts.
+    fn discard_retired(&mut self) {
+        let retirements = &self.retirements;
+        self.elements.retain(|_, counts| {
+            let len = counts.len();
+            counts.retain(|&replica_id, _| !retirements.is_folded(replica_id));
+            counts.len() == len || !counts.is_empty()
+        });
+    }
 }
 
-impl <T> Crdt for PnSet<T> where T: Clone + Eq + Hash {
+impl <T, M> PnSet<T, M> where T: Encode, M: MapStore<T, BTreeMap<ReplicaId, Pn>> {
+
+    /// Returns a hash of the element counts of the set.
+    ///
+    /// The hash is computed over the canonical encoding of the counts, and
+    /// excludes the replica ID and retirements, so replicas which have
+    /// converged have the same hash, whatever their backing store, platform,
+    /// or version of Rust.
+    ///
+    /// ##### Example
+    ///
+    /// ```
+    /// use crdt::Crdt;
+    /// use crdt::set::PnSet;
+    ///
+    /// let mut hashed = PnSet::new(1);
+    /// let mut ordered = PnSet::ordered(2);
+    /// for element in 0..100 {
+    ///     ordered.apply(hashed.insert(element));
+    /// }
+    /// assert_eq!(hashed.content_hash(), ordered.content_hash());
+    /// ```
+    pub fn content_hash(&self) -> u64 {
+        replication::fingerprint(|buf| encode_elements(&self.elements, buf))
+    }
+}
+
+/// Encodes the counts of every element of a set.
+fn encode_elements<T, M>(elements: &M, buf: &mut Vec<u8>)
+where T: Encode, M: MapStore<T, BTreeMap<ReplicaId, Pn>> {
+    wire::write_entries(buf, elements.iter(), |(element, counts), buf| {
+        element.encode(buf);
+        wire::write_entries(buf, counts.iter(), |(replica_id, pn), buf| {
+            replica_id.encode(buf);
+            pn.encode(buf);
+        });
+    });
+}
+
+impl <T, M> Crdt for PnSet<T, M> where T: Clone + Eq, M: MapStore<T, BTreeMap<ReplicaId, Pn>> + Clone + Eq {
 
     type Operation = PnSetOp<T>;
 
@@ -142,9 +405,17 @@ impl <T> Crdt for PnSet<T> where T: Clone + Eq + Hash {
     /// assert!(local.contains(&1));
     /// assert_eq!(2, local.len());
     /// ```
-    fn merge(&mut self, other: PnSet<T>) {
-        for (element, other_count) in other.elements.into_iter() {
-            let self_count = self.elements.entry(element).or_insert_with(|| HashMap::new());
+    fn merge(&mut self, other: PnSet<T, M>) {
+        self.retirements.merge(other.retirements, self.replica_id);
+        self.discard_retired();
+        let retirements = &self.retirements;
+        for (element, mut other_count) in other.elements.into_iter() {
+            let len = other_count.len();
+            other_count.retain(|&replica_id, _| !retirements.is_folded(replica_id));
+            if other_count.is_empty() && len != 0 {
+                continue;
+            }
+            let self_count = self.elements.get_or_insert_with(element, BTreeMap::new);
             for (replica_id, pn) in other_count.into_iter() {
                 self_count.entry(replica_id)
                           .or_insert(Pn::new())
@@ -174,27 +445,39 @@ impl <T> Crdt for PnSet<T> where T: Clone + Eq + Hash {
     /// ```
     fn apply(&mut self, operation: PnSetOp<T>) {
         let PnSetOp { element, replica_id, pn } = operation;
+        if self.retirements.is_folded(replica_id) {
+            return;
+        }
         self.elements
-            .entry(element)
-            .or_insert_with(|| HashMap::new())
+            .get_or_insert_with(element, BTreeMap::new)
             .entry(replica_id)
             .or_insert(Pn::new())
             .merge(pn);
     }
 }
 
-impl <T : Eq + Hash> PartialEq for PnSet<T> {
-    fn eq(&self, other: &PnSet<T>) -> bool {
+impl <T, M> Observable for PnSet<T, M>
+where T: Clone + Eq, M: MapStore<T, BTreeMap<ReplicaId, Pn>> + Clone + Eq, M::Keys: Clone + PartialEq {
+
+    type Value = M::Keys;
+
+    fn value(&self) -> M::Keys {
+        self.iter().cloned().collect()
+    }
+}
+
+impl <T, M> PartialEq for PnSet<T, M> where M: PartialEq {
+    fn eq(&self, other: &PnSet<T, M>) -> bool {
         self.elements == other.elements
     }
 }
 
-impl <T : Eq + Hash> Eq for PnSet<T> {}
+impl <T, M> Eq for PnSet<T, M> where M: Eq {}
 
-impl <T : Eq + Hash> PartialOrd for PnSet<T> {
-    fn partial_cmp(&self, other: &PnSet<T>) -> Option<Ordering> {
+impl <T, M> PartialOrd for PnSet<T, M> where M: MapStore<T, BTreeMap<ReplicaId, Pn>> + PartialEq {
+    fn partial_cmp(&self, other: &PnSet<T, M>) -> Option<Ordering> {
 
-        fn a_gt_b(a: &HashMap<ReplicaId, Pn>, b: &HashMap<ReplicaId, Pn>) -> bool {
+        fn a_gt_b(a: &BTreeMap<ReplicaId, Pn>, b: &BTreeMap<ReplicaId, Pn>) -> bool {
             a.len() > b.len() ||
                 a.iter().any(|(replica_id, a_pn)| {
                     b.get(replica_id)
@@ -232,25 +515,92 @@ impl <T : Eq + Hash> PartialOrd for PnSet<T> {
     }
 }
 
-#[cfg(any(quickcheck, test))]
-impl <T> Arbitrary for PnSet<T> where T: Arbitrary + Clone + Eq + Hash {
-    fn arbitrary<G>(g: &mut G) -> PnSet<T> where G: Gen {
+impl <T, M> Encode for PnSet<T, M> where T: Encode, M: MapStore<T, BTreeMap<ReplicaId, Pn>> {
+    fn encode(&self, buf: &mut Vec<u8>) {
+        self.replica_id.encode(buf);
+        encode_elements(&self.elements, buf);
+        self.retirements.encode(buf);
+    }
+}
+
+impl <T, M> Decode for PnSet<T, M> where T: Decode, M: MapStore<T, BTreeMap<ReplicaId, Pn>> {
+    fn decode(reader: &mut Reader) -> Result<PnSet<T, M>, DecodeError> {
+        let mut set: PnSet<T, M> = try!(decode_schema_1(reader));
+        set.retirements = try!(Decode::decode(reader));
+        Ok(set)
+    }
+}
+
+/// Decodes the schema 1 encoding, which has no retirements.
+fn decode_schema_1<T, M>(reader: &mut Reader) -> Result<PnSet<T, M>, DecodeError>
+where T: Decode, M: MapStore<T, BTreeMap<ReplicaId, Pn>> {
+    let replica_id = try!(Decode::decode(reader));
+    let elements = try!(wire::read_map(reader, |reader| {
+        let element = try!(Decode::decode(reader));
+        let counts = try!(wire::read_map(reader, |reader| {
+            Ok((try!(Decode::decode(reader)), try!(Decode::decode(reader))))
+        }));
+        Ok((element, counts))
+    }));
+    Ok(PnSet { replica_id: replica_id, elements: elements, retirements: Retirements::new(), marker: PhantomData })
+}
+
+impl <T, M> Message for PnSet<T, M> where T: Encode + Decode, M: MapStore<T, BTreeMap<ReplicaId, Pn>> {
+    const TAG: u8 = 12;
+    const SCHEMA: u32 = 2;
+
+    fn migrations() -> Migrations<PnSet<T, M>> {
+        Migrations::new().register(1, decode_schema_1)
+    }
+}
+
+impl <T> Encode for PnSetOp<T> where T: Encode {
+    fn encode(&self, buf: &mut Vec<u8>) {
+        self.element.encode(buf);
+        self.replica_id.encode(buf);
+        self.pn.encode(buf);
+    }
+}
+
+impl <T> Decode for PnSetOp<T> where T: Decode {
+    fn decode(reader: &mut Reader) -> Result<PnSetOp<T>, DecodeError> {
+        let element = try!(Decode::decode(reader));
+        let replica_id = try!(Decode::decode(reader));
+        Ok(PnSetOp { element: element, replica_id: replica_id, pn: try!(Decode::decode(reader)) })
+    }
+}
+
+impl <T> Message for PnSetOp<T> where T: Encode + Decode {
+    const TAG: u8 = 13;
+}
+
+#[cfg(any(feature = "quickcheck", test))]
+impl <T, M> Arbitrary for PnSet<T, M>
+where T: Arbitrary, M: MapStore<T, BTreeMap<ReplicaId, Pn>> + Arbitrary {
+    fn arbitrary<G>(g: &mut G) -> PnSet<T, M> where G: Gen {
         use gen_replica_id;
         PnSet {
             replica_id: gen_replica_id(),
             elements: Arbitrary::arbitrary(g),
+            retirements: Retirements::new(),
+            marker: PhantomData,
         }
     }
-    fn shrink(&self) -> Box<Iterator<Item=PnSet<T>> + 'static> {
+    fn shrink(&self) -> Box<Iterator<Item=PnSet<T, M>> + 'static> {
         let replica_id: ReplicaId = self.replica_id;
         Box::new(
             self.elements
                 .shrink()
-                .map(move |es| PnSet { replica_id: replica_id, elements: es }))
+                .map(move |es| PnSet {
+                    replica_id: replica_id,
+                    elements: es,
+                    retirements: Retirements::new(),
+                    marker: PhantomData,
+                }))
     }
 }
 
-#[cfg(any(quickcheck, test))]
+#[cfg(any(feature = "quickcheck", test))]
 impl <T> Arbitrary for PnSetOp<T> where T: Arbitrary {
     fn arbitrary<G>(g: &mut G) -> PnSetOp<T> where G: Gen {
         PnSetOp {
@@ -271,8 +621,9 @@ impl <T> Arbitrary for PnSetOp<T> where T: Arbitrary {
     }
 }
 
+/// An iterator over the elements of a `PnSet`.
 pub struct Iter<'a, T: 'a> {
-    inner: hash_map::Iter<'a, T, HashMap<ReplicaId, Pn>>,
+    inner: Box<Iterator<Item=(&'a T, &'a BTreeMap<ReplicaId, Pn>)> + 'a>,
 }
 
 impl<'a, T> Iterator for Iter<'a, T> {
@@ -297,7 +648,10 @@ mod test {
 
     use quickcheck::quickcheck;
 
-    use {Crdt, ReplicaId, test};
+    use {Crdt, ReplicaId, testkit};
+    use set::OrdPnSet;
+    #[cfg(feature = "persistent")]
+    use set::PersistentPnSet;
     use super::{PnSet, PnSetOp};
 
     type C = PnSet<u32>;
@@ -305,22 +659,40 @@ mod test {
 
     #[test]
     fn check_apply_is_commutative() {
-        quickcheck(test::apply_is_commutative::<C> as fn(C, Vec<O>) -> bool);
+        quickcheck(testkit::apply_is_commutative::<C> as fn(C, Vec<O>) -> bool);
     }
 
     #[test]
     fn check_merge_is_commutative() {
-        quickcheck(test::merge_is_commutative::<C> as fn(C, Vec<C>) -> bool);
+        quickcheck(testkit::merge_is_commutative::<C> as fn(C, Vec<C>) -> bool);
     }
 
     #[test]
     fn check_ordering_lte() {
-        quickcheck(test::ordering_lte::<C> as fn(C, C) -> bool);
+        quickcheck(testkit::ordering_lte::<C> as fn(C, C) -> bool);
     }
 
     #[test]
     fn check_ordering_equality() {
-        quickcheck(test::ordering_equality::<C> as fn(C, C) -> bool);
+        quickcheck(testkit::ordering_equality::<C> as fn(C, C) -> bool);
+    }
+
+    #[test]
+    fn check_all() {
+        testkit::check_all::<C>();
+    }
+
+    #[cfg(feature = "serde")]
+    #[quickcheck]
+    fn check_serde_round_trip(crdt: C, op: O) -> bool {
+        ::test_util::serde_round_trip(crdt, op, |a, b| a.replica_id == b.replica_id)
+    }
+
+    #[quickcheck]
+    fn check_diff_ops_is_merge(mut a: C, b: C) -> bool {
+        // Empty per-element counts are never produced by local operations.
+        a.elements.retain(|_, counts| !counts.is_empty());
+        testkit::diff_ops_is_merge(a, b, C::diff_ops)
     }
 
     #[quickcheck]
@@ -339,4 +711,64 @@ mod test {
         a.insert(0);
         a > b && b < a
     }
+
+    #[test]
+    fn check_retirement() {
+        let mut a = PnSet::new(1);
+        let mut b = PnSet::new(2);
+        let mut departed = PnSet::new(3);
+        departed.insert(1u8);
+        departed.insert(2);
+        departed.remove(2);
+        b.merge(departed);
+
+        a.retire(3);
+        b.merge(a.clone());
+        a.merge(b.clone());
+        assert_eq!(1, a.compact(vec![2]));
+        b.merge(a.clone());
+        a.merge(b.clone());
+        a.compact(vec![2]);
+        b.merge(a.clone());
+
+        // Once the retirement is dropped, stale operations and states from
+        // the departed replica are still discarded.
+        let mut stale = PnSet::new(3);
+        stale.insert(1);
+        let op = stale.insert(2);
+        for set in &mut [a, b] {
+            set.apply(op.clone());
+            set.merge(stale.clone());
+            assert!(set.contains(&1));
+            assert!(!set.contains(&2));
+            assert!(set.elements.values().all(|counts| !counts.contains_key(&ReplicaId(3))));
+        }
+    }
+
+    #[test]
+    fn check_all_ordered() {
+        testkit::check_all::<OrdPnSet<u32>>();
+    }
+
+    #[cfg(feature = "persistent")]
+    #[test]
+    fn check_all_persistent() {
+        testkit::check_all::<PersistentPnSet<u32>>();
+    }
+
+    #[quickcheck]
+    fn check_ordered_iteration(ops: Vec<(bool, u8)>, low: u8) -> bool {
+        let mut hashed = PnSet::new(1);
+        let mut ordered = PnSet::ordered(2);
+        for &(is_insert, element) in &ops {
+            let op = if is_insert { hashed.insert(element) } else { hashed.remove(element) };
+            ordered.apply(op);
+        }
+        let mut expected = hashed.iter().cloned().collect::<Vec<_>>();
+        expected.sort();
+        ordered.iter().cloned().collect::<Vec<_>>() == expected
+            && ordered.range(low..).cloned().collect::<Vec<_>>()
+                == expected.iter().cloned().filter(|&e| e >= low).collect::<Vec<_>>()
+            && hashed.content_hash() == ordered.content_hash()
+    }
 }
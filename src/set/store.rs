@@ -0,0 +1,185 @@
+use std::cmp::Ordering;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::hash::Hash;
+use std::iter::FromIterator;
+
+#[cfg(feature = "persistent")]
+use hamt::HashTrieMap;
+use wire::{DecodeError, Reader};
+
+/// A set which holds the elements of a `GSet`.
+///
+/// `SetStore` is implemented for `HashSet`, which is the default, and for
+/// `BTreeSet`, which keeps the elements in sorted order.
+pub trait SetStore<T>: Default + Extend<T> + FromIterator<T> + IntoIterator<Item=T> {
+
+    /// Returns true if the set contains the value.
+    fn contains(&self, value: &T) -> bool;
+
+    /// Inserts a value, returning true if it was not already present.
+    fn insert(&mut self, value: T) -> bool;
+
+    /// Returns the number of values in the set.
+    fn len(&self) -> usize;
+
+    /// Returns true if the set contains no values.
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Returns an iterator over the values of the set, in the order of the
+    /// store.
+    fn iter<'a>(&'a self) -> Box<ExactSizeIterator<Item=&'a T> + 'a> where T: 'a;
+}
+
+/// A map which holds the elements of a `TpSet`, `LwwSet` or `PnSet`, with
+/// the state of each element.
+///
+/// `MapStore` is implemented for `HashMap`, which is the default, for
+/// `BTreeMap`, which keeps the elements in sorted order, and with the
+/// `persistent` feature for `HashTrieMap`, which is cloned in constant time.
+pub trait MapStore<K, V>: Default + FromIterator<(K, V)> + IntoIterator<Item=(K, V)> {
+
+    /// The set of keys matching this map, in which sets backed by the map
+    /// return their observed values.
+    type Keys: FromIterator<K>;
+
+    /// Returns the value for a key.
+    fn get(&self, key: &K) -> Option<&V>;
+
+    /// Returns a mutable reference to the value for a key.
+    fn get_mut(&mut self, key: &K) -> Option<&mut V>;
+
+    /// Returns a mutable reference to the value for a key, inserting the
+    /// result of `default` if the key is not present.
+    fn get_or_insert_with<F>(&mut self, key: K, default: F) -> &mut V where F: FnOnce() -> V;
+
+    /// Inserts a value for a key, returning the previous value.
+    fn insert(&mut self, key: K, value: V) -> Option<V>;
+
+    /// Removes the value for a key, returning it.
+    fn remove(&mut self, key: &K) -> Option<V>;
+
+    /// Retains only the entries for which `f` returns true.
+    fn retain<F>(&mut self, f: F) where F: FnMut(&K, &mut V) -> bool;
+
+    /// Returns the number of entries in the map.
+    fn len(&self) -> usize;
+
+    /// Returns true if the map holds no entries.
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Returns true if the map holds a value for the key.
+    fn contains_key(&self, key: &K) -> bool {
+        self.get(key).is_some()
+    }
+
+    /// Returns an iterator over the entries of the map, in the order of the
+    /// store.
+    fn iter<'a>(&'a self) -> Box<ExactSizeIterator<Item=(&'a K, &'a V)> + 'a> where K: 'a, V: 'a;
+
+    /// Returns an iterator over mutable references to the values of the map.
+    fn values_mut<'a>(&'a mut self) -> Box<Iterator<Item=&'a mut V> + 'a> where K: 'a, V: 'a;
+
+    /// Compares two keys in the order of the store, or returns `None` if the
+    /// store does not order its keys.
+    fn compare_keys(_a: &K, _b: &K) -> Option<Ordering> {
+        None
+    }
+}
+
+impl <T> SetStore<T> for HashSet<T> where T: Eq + Hash {
+    fn contains(&self, value: &T) -> bool { HashSet::contains(self, value) }
+    fn insert(&mut self, value: T) -> bool { HashSet::insert(self, value) }
+    fn len(&self) -> usize { HashSet::len(self) }
+    fn iter<'a>(&'a self) -> Box<ExactSizeIterator<Item=&'a T> + 'a> where T: 'a {
+        Box::new(HashSet::iter(self))
+    }
+}
+
+impl <T> SetStore<T> for BTreeSet<T> where T: Ord {
+    fn contains(&self, value: &T) -> bool { BTreeSet::contains(self, value) }
+    fn insert(&mut self, value: T) -> bool { BTreeSet::insert(self, value) }
+    fn len(&self) -> usize { BTreeSet::len(self) }
+    fn iter<'a>(&'a self) -> Box<ExactSizeIterator<Item=&'a T> + 'a> where T: 'a {
+        Box::new(BTreeSet::iter(self))
+    }
+}
+
+impl <K, V> MapStore<K, V> for HashMap<K, V> where K: Eq + Hash {
+    type Keys = HashSet<K>;
+
+    fn get(&self, key: &K) -> Option<&V> { HashMap::get(self, key) }
+    fn get_mut(&mut self, key: &K) -> Option<&mut V> { HashMap::get_mut(self, key) }
+    fn get_or_insert_with<F>(&mut self, key: K, default: F) -> &mut V where F: FnOnce() -> V {
+        self.entry(key).or_insert_with(default)
+    }
+    fn insert(&mut self, key: K, value: V) -> Option<V> { HashMap::insert(self, key, value) }
+    fn remove(&mut self, key: &K) -> Option<V> { HashMap::remove(self, key) }
+    fn retain<F>(&mut self, f: F) where F: FnMut(&K, &mut V) -> bool { HashMap::retain(self, f) }
+    fn len(&self) -> usize { HashMap::len(self) }
+    fn iter<'a>(&'a self) -> Box<ExactSizeIterator<Item=(&'a K, &'a V)> + 'a> where K: 'a, V: 'a {
+        Box::new(HashMap::iter(self))
+    }
+    fn values_mut<'a>(&'a mut self) -> Box<Iterator<Item=&'a mut V> + 'a> where K: 'a, V: 'a {
+        Box::new(HashMap::values_mut(self))
+    }
+}
+
+impl <K, V> MapStore<K, V> for BTreeMap<K, V> where K: Ord {
+    type Keys = BTreeSet<K>;
+
+    fn get(&self, key: &K) -> Option<&V> { BTreeMap::get(self, key) }
+    fn get_mut(&mut self, key: &K) -> Option<&mut V> { BTreeMap::get_mut(self, key) }
+    fn get_or_insert_with<F>(&mut self, key: K, default: F) -> &mut V where F: FnOnce() -> V {
+        self.entry(key).or_insert_with(default)
+    }
+    fn insert(&mut self, key: K, value: V) -> Option<V> { BTreeMap::insert(self, key, value) }
+    fn remove(&mut self, key: &K) -> Option<V> { BTreeMap::remove(self, key) }
+    fn retain<F>(&mut self, f: F) where F: FnMut(&K, &mut V) -> bool { BTreeMap::retain(self, f) }
+    fn len(&self) -> usize { BTreeMap::len(self) }
+    fn iter<'a>(&'a self) -> Box<ExactSizeIterator<Item=(&'a K, &'a V)> + 'a> where K: 'a, V: 'a {
+        Box::new(BTreeMap::iter(self))
+    }
+    fn values_mut<'a>(&'a mut self) -> Box<Iterator<Item=&'a mut V> + 'a> where K: 'a, V: 'a {
+        Box::new(BTreeMap::values_mut(self))
+    }
+    fn compare_keys(a: &K, b: &K) -> Option<Ordering> { Some(a.cmp(b)) }
+}
+
+#[cfg(feature = "persistent")]
+impl <K, V> MapStore<K, V> for HashTrieMap<K, V> where K: Clone + Eq + Hash, V: Clone {
+    type Keys = HashSet<K>;
+
+    fn get(&self, key: &K) -> Option<&V> { HashTrieMap::get(self, key) }
+    fn get_mut(&mut self, key: &K) -> Option<&mut V> { HashTrieMap::get_mut(self, key) }
+    fn get_or_insert_with<F>(&mut self, key: K, default: F) -> &mut V where F: FnOnce() -> V {
+        self.entry(key).or_insert_with(default)
+    }
+    fn insert(&mut self, key: K, value: V) -> Option<V> { HashTrieMap::insert(self, key, value) }
+    fn remove(&mut self, key: &K) -> Option<V> { HashTrieMap::remove(self, key) }
+    fn retain<F>(&mut self, f: F) where F: FnMut(&K, &mut V) -> bool { HashTrieMap::retain(self, f) }
+    fn len(&self) -> usize { HashTrieMap::len(self) }
+    fn iter<'a>(&'a self) -> Box<ExactSizeIterator<Item=(&'a K, &'a V)> + 'a> where K: 'a, V: 'a {
+        Box::new(HashTrieMap::iter(self))
+    }
+    fn values_mut<'a>(&'a mut self) -> Box<Iterator<Item=&'a mut V> + 'a> where K: 'a, V: 'a {
+        Box::new(HashTrieMap::values_mut(self))
+    }
+}
+
+/// Reads a sequence of elements written by `wire::write_entries` into a set
+/// store, rejecting duplicates.
+pub fn read_set<T, S, F>(reader: &mut Reader, mut decode_element: F) -> Result<S, DecodeError>
+where S: SetStore<T>, F: FnMut(&mut Reader) -> Result<T, DecodeError> {
+    let len = try!(reader.read_len());
+    let mut set = S::default();
+    for _ in 0..len {
+        if !set.insert(try!(decode_element(reader))) {
+            return Err(DecodeError::InvalidValue("duplicate entry"));
+        }
+    }
+    Ok(set)
+}
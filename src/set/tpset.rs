@@ -1,22 +1,61 @@
 use std::cmp::Ordering::{self, Greater, Less, Equal};
-use std::collections::hash_map::Entry::{Occupied, Vacant};
-use std::collections::{HashMap};
+use std::collections::{BTreeMap, HashMap};
+#[cfg(feature = "serde")]
+use std::convert::TryFrom;
 use std::fmt::{Debug, Formatter, Error};
 use std::hash::Hash;
+use std::ops::RangeBounds;
 
-#[cfg(any(quickcheck, test))]
+#[cfg(any(feature = "quickcheck", test))]
 use quickcheck::{Arbitrary, Gen};
 
 use Crdt;
+use observe::Observable;
+use replication::{self, Reconcile};
+use super::store::MapStore;
+use wire::{self, Decode, DecodeError, Encode, Message, Migrations, Reader};
 
 /// A two-phase set.
+///
+/// ##### Garbage Collection
+///
+/// Removed elements leave a tombstone, so that the element can never be
+/// inserted again. Tombstones do not record when the element was removed, so
+/// they are collected against a frontier over the elements themselves. This
+/// suits sets of monotonically allocated elements, such as transaction IDs or
+/// sequence numbers, where every operation on the elements up to some point
+/// has reached every replica. Only ordered sets, which compare their elements
+/// through their store, can collect tombstones. See `purge_tombstones` for
+/// the operations which are rejected afterwards.
+///
+/// ##### Ordering
+///
+/// The elements are held in a `HashMap` by default. A set created with
+/// `TpSet::ordered` holds them in a `BTreeMap`, so that it iterates, prints
+/// and serializes them in sorted order, and supports range queries.
 #[derive(Clone, Default, Eq, PartialEq)]
-pub struct TpSet<T> where T: Eq + Hash {
-    elements: HashMap<T, bool>,
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[cfg_attr(feature = "serde", serde(try_from = "TpSetFields<T, M>"))]
+#[cfg_attr(feature = "serde", serde(bound(deserialize = "T: ::serde::Deserialize<'de>, \
+                                                         M: MapStore<T, bool> + ::serde::Deserialize<'de>")))]
+pub struct TpSet<T, M = HashMap<T, bool>> {
+    elements: M,
+    frontier: Option<T>,
+}
+
+/// The fields of a deserialized set, before its frontier is checked.
+#[cfg(feature = "serde")]
+#[derive(Deserialize)]
+#[serde(bound(deserialize = "T: ::serde::Deserialize<'de>, M: ::serde::Deserialize<'de>"))]
+struct TpSetFields<T, M> {
+    elements: M,
+    #[serde(default)]
+    frontier: Option<T>,
 }
 
 /// An insert or remove operation over `TpSet` CRDTs.
 #[derive(Clone, Debug, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub enum TpSetOp<T> {
     Insert(T),
     Remove(T),
@@ -35,9 +74,88 @@ impl <T> TpSet<T> where T: Clone + Eq + Hash {
     /// assert!(set.is_empty());
     /// ```
     pub fn new() -> TpSet<T> {
-        TpSet { elements: HashMap::new() }
+        TpSet { elements: HashMap::new(), frontier: None }
+    }
+}
+
+impl <T> TpSet<T, BTreeMap<T, bool>> where T: Clone + Ord {
+
+    /// Create a new two-phase set which keeps its elements in sorted order.
+    ///
+    /// ### Example
+    ///
+    /// ```
+    /// use crdt::set::TpSet;
+    ///
+    /// let mut set = TpSet::ordered();
+    /// set.insert(3);
+    /// set.insert(1);
+    /// set.insert(2);
+    /// set.remove(2);
+    /// assert_eq!(vec![&1, &3], set.iter().collect::<Vec<_>>());
+    /// ```
+    pub fn ordered() -> TpSet<T, BTreeMap<T, bool>> {
+        TpSet { elements: BTreeMap::new(), frontier: None }
+    }
+
+    /// Returns an iterator over the elements of the set within `range`, in
+    /// sorted order.
+    ///
+    /// ### Example
+    ///
+    /// ```
+    /// use crdt::set::TpSet;
+    ///
+    /// let mut set = TpSet::ordered();
+    /// for element in 0..10 {
+    ///     set.insert(element);
+    /// }
+    /// set.remove(4);
+    /// assert_eq!(vec![&3, &5], set.range(3..6).collect::<Vec<_>>());
+    /// ```
+    pub fn range<'a, R>(&'a self, range: R) -> Iter<'a, T> where R: RangeBounds<T> {
+        Iter { inner: Box::new(self.elements.range(range)) }
     }
 
+    /// Discards the tombstones of removed elements at or below `frontier`,
+    /// returning the number of tombstones discarded.
+    ///
+    /// `frontier` **must** be stable: every replica must have applied every
+    /// insert and remove operation on the elements at or below it. The
+    /// frontier spreads to other replicas through merges, which purge their
+    /// own tombstones in turn.
+    ///
+    /// Afterwards, every insert and remove of an element at or below the
+    /// frontier is rejected, whether it is applied locally, applied as an
+    /// operation, or merged from another replica's state. In particular, a
+    /// purged element cannot be inserted again, and a present element at or
+    /// below the frontier can no longer be removed. When the frontier is
+    /// stable, each such operation has already been applied, so rejecting it
+    /// changes nothing. Operations on elements above the frontier are applied
+    /// as usual.
+    ///
+    /// ### Example
+    ///
+    /// ```
+    /// use crdt::set::TpSet;
+    ///
+    /// let mut set = TpSet::ordered();
+    /// set.insert(1);
+    /// set.insert(2);
+    /// set.remove(1);
+    ///
+    /// assert_eq!(1, set.purge_tombstones(2));
+    /// assert!(set.insert(1).is_none());
+    /// assert!(set.remove(2).is_none());
+    /// assert!(set.insert(3).is_some());
+    /// ```
+    pub fn purge_tombstones(&mut self, frontier: T) -> usize {
+        self.purge(frontier)
+    }
+}
+
+impl <T, M> TpSet<T, M> where T: Clone, M: MapStore<T, bool> {
+
     /// Insert an element into a two-phase set.
     ///
     /// ### Example
@@ -50,7 +168,7 @@ impl <T> TpSet<T> where T: Clone + Eq + Hash {
     /// assert!(set.contains(&"first-element"));
     /// ```
     pub fn insert(&mut self, element: T) -> Option<TpSetOp<T>> {
-        if self.elements.contains_key(&element) {
+        if self.elements.contains_key(&element) || self.is_stable(&element) {
             None
         } else {
             self.elements.insert(element.clone(), true);
@@ -72,19 +190,63 @@ impl <T> TpSet<T> where T: Clone + Eq + Hash {
     /// assert!(!set.contains(&"first-element"));
     /// ```
     pub fn remove(&mut self, element: T) -> Option<TpSetOp<T>> {
-        match self.elements.entry(element.clone()) {
-            Vacant(entry) => {
-                entry.insert(false);
-                Some(TpSetOp::Remove(element))
-            },
-            Occupied(ref mut entry) if *entry.get() => {
-                entry.insert(false);
+        if self.is_stable(&element) {
+            return None;
+        }
+        match self.elements.get(&element) {
+            Some(&false) => None,
+            _ => {
+                self.elements.insert(element.clone(), false);
                 Some(TpSetOp::Remove(element))
             },
-            Occupied(_) => None,
         }
     }
 
+    /// Returns the stability frontier of the set, if its tombstones have been
+    /// purged.
+    pub fn frontier(&self) -> Option<&T> {
+        self.frontier.as_ref()
+    }
+
+    /// Returns the insert and remove operations which, when applied to `other`,
+    /// bring it up to date with this set.
+    ///
+    /// Applying the operations to `other` is equivalent to merging this set
+    /// into `other`. Elements removed from this set produce only a remove
+    /// operation, even if `other` has never seen them inserted. Elements at
+    /// or below the frontier of `other` produce no operations.
+    ///
+    /// ### Example
+    ///
+    /// ```
+    /// use crdt::Crdt;
+    /// use crdt::set::TpSet;
+    ///
+    /// let mut local = TpSet::new();
+    /// let mut remote = TpSet::new();
+    /// local.insert(1i32);
+    /// local.insert(2);
+    /// local.remove(2);
+    ///
+    /// for op in local.diff_ops(&remote) {
+    ///     remote.apply(op);
+    /// }
+    /// assert_eq!(local, remote);
+    /// ```
+    pub fn diff_ops(&self, other: &TpSet<T, M>) -> Vec<TpSetOp<T>> {
+        self.elements
+            .iter()
+            .filter(|&(element, _)| !other.is_stable(element))
+            .filter_map(|(element, &is_present)| {
+                match (is_present, other.elements.get(element)) {
+                    (true, None) => Some(TpSetOp::Insert(element.clone())),
+                    (false, None) | (false, Some(&true)) => Some(TpSetOp::Remove(element.clone())),
+                    _ => None,
+                }
+            })
+            .collect()
+    }
+
     /// Returns the number of elements in the set.
     pub fn len(&self) -> usize {
         self.elements.iter().filter(|&(_, &is_present)| is_present).count()
@@ -98,22 +260,85 @@ impl <T> TpSet<T> where T: Clone + Eq + Hash {
     /// Returns true if the set contains no elements.
     pub fn is_empty(&self) -> bool{ self.len() == 0 }
 
-    pub fn is_subset(&self, other: &TpSet<T>) -> bool {
+    pub fn is_subset(&self, other: &TpSet<T, M>) -> bool {
         for (element, &is_present) in self.elements.iter() {
             if is_present && !other.contains(element) { return false; }
         }
         true
     }
 
-    pub fn is_disjoint(&self, other: &TpSet<T>) -> bool {
+    pub fn is_disjoint(&self, other: &TpSet<T, M>) -> bool {
         for (element, &is_present) in self.elements.iter() {
             if is_present && other.contains(element) { return false; }
         }
         true
     }
+
+    /// Returns an iterator over the elements of the set, in sorted order if
+    /// the set is ordered.
+    pub fn iter<'a>(&'a self) -> Iter<'a, T> {
+        Iter { inner: self.elements.iter() }
+    }
+
+    /// Discards the tombstones at or below `frontier`, and sets the frontier.
+    fn purge(&mut self, frontier: T) -> usize {
+        if self.is_stable(&frontier) {
+            return 0;
+        }
+        let len = self.elements.len();
+        self.elements.retain(|element, &mut is_present| is_present || !is_at_or_below::<T, M>(element, &frontier));
+        self.frontier = Some(frontier);
+        len - self.elements.len()
+    }
+}
+
+impl <T, M> TpSet<T, M> where M: MapStore<T, bool> {
+
+    /// Returns true if the element is at or below the frontier.
+    fn is_stable(&self, element: &T) -> bool {
+        self.frontier.as_ref().map_or(false, |frontier| is_at_or_below::<T, M>(element, frontier))
+    }
+
+    /// Returns true if this set's frontier is at or above the frontier of
+    /// `other`.
+    fn has_reached(&self, other: &TpSet<T, M>) -> bool {
+        other.frontier.as_ref().map_or(true, |frontier| self.is_stable(frontier))
+    }
+
+    /// Checks that the frontier is held by an ordered set, and that no
+    /// tombstone at or below it remains.
+    fn validate(&self) -> Result<(), &'static str> {
+        if let Some(ref frontier) = self.frontier {
+            if M::compare_keys(frontier, frontier).is_none() {
+                return Err("frontier in an unordered set");
+            }
+        }
+        if self.elements.iter().any(|(element, &is_present)| !is_present && self.is_stable(element)) {
+            return Err("tombstone at or below the frontier");
+        }
+        Ok(())
+    }
 }
 
-impl <T> Crdt for TpSet<T> where T: Clone + Eq + Hash {
+impl <T, M> TpSet<T, M> where T: Encode, M: MapStore<T, bool> {
+
+    /// Returns a hash of the elements and tombstones of the set.
+    ///
+    /// The hash is computed over the canonical encoding of the set, so
+    /// replicas holding the same state have the same hash, whatever their
+    /// backing store, platform, or version of Rust.
+    pub fn content_hash(&self) -> u64 {
+        replication::fingerprint(|buf| self.encode(buf))
+    }
+}
+
+/// Returns true if the element is at or below the frontier, as compared by
+/// the store. A store which does not order its elements has no frontier.
+fn is_at_or_below<T, M>(element: &T, frontier: &T) -> bool where M: MapStore<T, bool> {
+    M::compare_keys(element, frontier).map_or(false, |ordering| ordering != Greater)
+}
+
+impl <T, M> Crdt for TpSet<T, M> where T: Clone + Eq, M: MapStore<T, bool> + Clone + Eq {
 
     type Operation = TpSetOp<T>;
 
@@ -141,12 +366,21 @@ impl <T> Crdt for TpSet<T> where T: Clone + Eq + Hash {
     /// assert!(local.contains(&2));
     /// assert_eq!(1, local.len());
     /// ```
-    fn merge(&mut self, other: TpSet<T>) {
+    fn merge(&mut self, other: TpSet<T, M>) {
+        if let Some(frontier) = other.frontier.clone() {
+            self.purge(frontier);
+        }
+        // A replica which has reached this set's frontier holds the final
+        // state of its elements at or below it, so its elements are kept
+        // even though the equivalent operations would be rejected.
+        let complete = other.has_reached(self);
         for (element, is_present) in other.elements.into_iter() {
+            if self.is_stable(&element) && !(is_present && complete) {
+                continue;
+            }
             if is_present {
-                match self.elements.entry(element) {
-                    Occupied(_) => (),
-                    Vacant(entry) => { entry.insert(is_present); },
+                if !self.elements.contains_key(&element) {
+                    self.elements.insert(element, is_present);
                 }
             } else {
                 self.elements.insert(element, is_present);
@@ -179,13 +413,28 @@ impl <T> Crdt for TpSet<T> where T: Clone + Eq + Hash {
     }
 }
 
-impl <T : Eq + Hash> PartialOrd for TpSet<T> {
-    fn partial_cmp(&self, other: &TpSet<T>) -> Option<Ordering> {
-        if self.elements == other.elements {
+impl <T, M> Observable for TpSet<T, M>
+where T: Clone + Eq, M: MapStore<T, bool> + Clone + Eq, M::Keys: Clone + PartialEq {
+
+    type Value = M::Keys;
+
+    fn value(&self) -> M::Keys {
+        self.elements
+            .iter()
+            .filter(|&(_, &is_present)| is_present)
+            .map(|(element, _)| element.clone())
+            .collect()
+    }
+}
+
+impl <T, M> PartialOrd for TpSet<T, M> where T: PartialEq, M: MapStore<T, bool> + PartialEq {
+    fn partial_cmp(&self, other: &TpSet<T, M>) -> Option<Ordering> {
+        if self == other {
             return Some(Equal);
         }
-        let mut self_is_greater = true;
-        let mut other_is_greater = true;
+        // A tombstone at or below a set's frontier has been purged by it.
+        let mut self_is_greater = self.has_reached(other);
+        let mut other_is_greater = other.has_reached(self);
         for (element, &is_present) in other.elements.iter() {
             if is_present {
                 if !self.elements.contains_key(element) {
@@ -195,6 +444,7 @@ impl <T : Eq + Hash> PartialOrd for TpSet<T> {
             } else {
                 match self.elements.get(element) {
                     Some(&false) => (),
+                    None if self.is_stable(element) => (),
                     _ => {
                         self_is_greater = false;
                         break;
@@ -211,6 +461,7 @@ impl <T : Eq + Hash> PartialOrd for TpSet<T> {
             } else {
                 match other.elements.get(element) {
                     Some(&false) => (),
+                    None if other.is_stable(element) => (),
                     _ => {
                         other_is_greater = false;
                         break;
@@ -228,7 +479,7 @@ impl <T : Eq + Hash> PartialOrd for TpSet<T> {
     }
 }
 
-impl <T> Debug for TpSet<T> where T: Debug + Eq + Hash {
+impl <T, M> Debug for TpSet<T, M> where T: Debug, M: MapStore<T, bool> {
      fn fmt(&self, f: &mut Formatter) -> Result<(), Error> {
          try!(write!(f, "{{present: {{"));
          for (i, x) in self.elements
@@ -248,21 +499,116 @@ impl <T> Debug for TpSet<T> where T: Debug + Eq + Hash {
              if i != 0 { try!(write!(f, ", ")); }
              try!(write!(f, "{:?}", *x))
          }
-         write!(f, "}}}}")
+         try!(write!(f, "}}"));
+         if let Some(ref frontier) = self.frontier {
+             try!(write!(f, ", frontier: {:?}", frontier));
+         }
+         write!(f, "}}")
      }
 }
 
-#[cfg(any(quickcheck, test))]
-impl <T> Arbitrary for TpSet<T> where T: Arbitrary + Clone + Eq + Hash {
-    fn arbitrary<G: Gen>(g: &mut G) -> TpSet<T> {
-        TpSet { elements: Arbitrary::arbitrary(g) }
+impl <T, M> Encode for TpSet<T, M> where T: Encode, M: MapStore<T, bool> {
+    fn encode(&self, buf: &mut Vec<u8>) {
+        wire::write_entries(buf, self.elements.iter(), |(element, is_present), buf| {
+            element.encode(buf);
+            is_present.encode(buf);
+        });
+        self.frontier.encode(buf);
+    }
+}
+
+impl <T, M> Decode for TpSet<T, M> where T: Decode, M: MapStore<T, bool> {
+    fn decode(reader: &mut Reader) -> Result<TpSet<T, M>, DecodeError> {
+        let mut set: TpSet<T, M> = try!(decode_schema_1(reader));
+        set.frontier = try!(Decode::decode(reader));
+        try!(set.validate().map_err(DecodeError::InvalidValue));
+        Ok(set)
+    }
+}
+
+/// Decodes the schema 1 encoding, which predates tombstone garbage
+/// collection.
+fn decode_schema_1<T, M>(reader: &mut Reader) -> Result<TpSet<T, M>, DecodeError>
+where T: Decode, M: MapStore<T, bool> {
+    let elements = try!(wire::read_map(reader, |reader| {
+        Ok((try!(Decode::decode(reader)), try!(Decode::decode(reader))))
+    }));
+    Ok(TpSet { elements: elements, frontier: None })
+}
+
+#[cfg(feature = "serde")]
+impl <T, M> TryFrom<TpSetFields<T, M>> for TpSet<T, M> where M: MapStore<T, bool> {
+    type Error = &'static str;
+
+    fn try_from(fields: TpSetFields<T, M>) -> Result<TpSet<T, M>, &'static str> {
+        let set = TpSet { elements: fields.elements, frontier: fields.frontier };
+        try!(set.validate());
+        Ok(set)
+    }
+}
+
+impl <T, M> Message for TpSet<T, M> where T: Encode + Decode, M: MapStore<T, bool> {
+    const TAG: u8 = 8;
+    const SCHEMA: u32 = 2;
+
+    fn migrations() -> Migrations<TpSet<T, M>> {
+        Migrations::new().register(1, decode_schema_1)
+    }
+}
+
+impl <T> Encode for TpSetOp<T> where T: Encode {
+    fn encode(&self, buf: &mut Vec<u8>) {
+        match *self {
+            TpSetOp::Insert(ref element) => { buf.push(0); element.encode(buf); },
+            TpSetOp::Remove(ref element) => { buf.push(1); element.encode(buf); },
+        }
+    }
+}
+
+impl <T> Decode for TpSetOp<T> where T: Decode {
+    fn decode(reader: &mut Reader) -> Result<TpSetOp<T>, DecodeError> {
+        match try!(reader.read_u8()) {
+            0 => Ok(TpSetOp::Insert(try!(Decode::decode(reader)))),
+            1 => Ok(TpSetOp::Remove(try!(Decode::decode(reader)))),
+            _ => Err(DecodeError::InvalidValue("unknown TpSetOp variant")),
+        }
     }
-    fn shrink(&self) -> Box<Iterator<Item=TpSet<T>> + 'static> {
-        Box::new(self.elements.shrink().map(|elements| TpSet { elements: elements }))
+}
+
+impl <T> Message for TpSetOp<T> where T: Encode + Decode {
+    const TAG: u8 = 9;
+}
+
+impl <T, M> Reconcile for TpSet<T, M> where T: Clone + Encode + Eq, M: MapStore<T, bool> + Clone + Eq {
+    fn fingerprints<F>(&self, mut f: F) where F: FnMut(u64, u64) {
+        for (element, is_present) in self.elements.iter() {
+            let key_hash = replication::fingerprint(|buf| element.encode(buf));
+            let entry_hash = replication::fingerprint(|buf| { element.encode(buf); is_present.encode(buf); });
+            f(key_hash, entry_hash);
+        }
+    }
+
+    fn select<F>(&self, mut keep: F) -> TpSet<T, M> where F: FnMut(u64) -> bool {
+        let elements = self.elements
+                           .iter()
+                           .filter(|&(element, _)| keep(replication::fingerprint(|buf| element.encode(buf))))
+                           .map(|(element, &is_present)| (element.clone(), is_present))
+                           .collect();
+        TpSet { elements: elements, frontier: self.frontier.clone() }
+    }
+}
+
+#[cfg(any(feature = "quickcheck", test))]
+impl <T, M> Arbitrary for TpSet<T, M> where T: Arbitrary, M: MapStore<T, bool> + Arbitrary {
+    fn arbitrary<G: Gen>(g: &mut G) -> TpSet<T, M> {
+        TpSet { elements: Arbitrary::arbitrary(g), frontier: None }
+    }
+    fn shrink(&self) -> Box<Iterator<Item=TpSet<T, M>> + 'static> {
+        Box::new(self.elements.shrink().map(|elements| TpSet { elements: elements, frontier: None }))
     }
 }
 
-#[cfg(any(quickcheck, test))]
+#[cfg(any(feature = "quickcheck", test))]
 impl <T> Arbitrary for TpSetOp<T> where T: Arbitrary {
     fn arbitrary<G: Gen>(g: &mut G) -> TpSetOp<T> {
         if Arbitrary::arbitrary(g) {
@@ -283,12 +629,36 @@ impl <T> Arbitrary for TpSetOp<T> where T: Arbitrary {
     }
 }
 
+/// An iterator over the elements of a `TpSet`.
+pub struct Iter<'a, T: 'a> {
+    inner: Box<Iterator<Item=(&'a T, &'a bool)> + 'a>,
+}
+
+impl <'a, T> Iterator for Iter<'a, T> {
+    type Item = &'a T;
+
+    fn next(&mut self) -> Option<&'a T> {
+        while let Some((element, &is_present)) = self.inner.next() {
+            if is_present {
+                return Some(element);
+            }
+        }
+        None
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (0, self.inner.size_hint().1)
+    }
+}
+
 #[cfg(test)]
 mod test {
 
     use quickcheck::quickcheck;
 
-    use {test, Crdt};
+    use {testkit, Crdt};
+    use observe::Observable;
+    use set::OrdTpSet;
     use super::{TpSet, TpSetOp};
 
     type C = TpSet<u32>;
@@ -296,22 +666,54 @@ mod test {
 
     #[test]
     fn check_apply_is_commutative() {
-        quickcheck(test::apply_is_commutative::<C> as fn(C, Vec<O>) -> bool);
+        quickcheck(testkit::apply_is_commutative::<C> as fn(C, Vec<O>) -> bool);
     }
 
     #[test]
     fn check_merge_is_commutative() {
-        quickcheck(test::merge_is_commutative::<C> as fn(C, Vec<C>) -> bool);
+        quickcheck(testkit::merge_is_commutative::<C> as fn(C, Vec<C>) -> bool);
     }
 
     #[test]
     fn check_ordering_lte() {
-        quickcheck(test::ordering_lte::<C> as fn(C, C) -> bool);
+        quickcheck(testkit::ordering_lte::<C> as fn(C, C) -> bool);
     }
 
     #[test]
     fn check_ordering_equality() {
-        quickcheck(test::ordering_equality::<C> as fn(C, C) -> bool);
+        quickcheck(testkit::ordering_equality::<C> as fn(C, C) -> bool);
+    }
+
+    #[test]
+    fn check_all() {
+        testkit::check_all::<C>();
+    }
+
+    #[cfg(feature = "serde")]
+    #[quickcheck]
+    fn check_serde_round_trip(crdt: C, op: O) -> bool {
+        ::test_util::serde_round_trip(crdt, op, |_, _| true)
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn check_serde_rejects_purged_tombstones() {
+        use serde_json;
+
+        let mut set = TpSet::ordered();
+        set.insert(1u32);
+        set.remove(2);
+        set.purge_tombstones(1);
+        let json = serde_json::to_string(&set).unwrap();
+        assert_eq!(set, serde_json::from_str(&json).unwrap());
+
+        assert!(serde_json::from_str::<OrdTpSet<u32>>(r#"{"elements":{"1":false},"frontier":1}"#).is_err());
+        assert!(serde_json::from_str::<C>(r#"{"elements":{"2":true},"frontier":1}"#).is_err());
+    }
+
+    #[quickcheck]
+    fn check_diff_ops_is_merge(a: C, b: C) -> bool {
+        testkit::diff_ops_is_merge(a, b, C::diff_ops)
     }
 
     #[quickcheck]
@@ -335,4 +737,116 @@ mod test {
         }
         a > b && b < a
     }
+
+    #[test]
+    fn check_purge_tombstones() {
+        let mut set = TpSet::ordered();
+        set.insert(1u32);
+        set.insert(2);
+        set.insert(4);
+        set.remove(1);
+        set.remove(3);
+        set.remove(4);
+        let stale = set.clone();
+
+        assert_eq!(2, set.purge_tombstones(3));
+        assert_eq!(0, set.purge_tombstones(2));
+        assert_eq!(Some(&3), set.frontier());
+        assert!(set > stale && stale < set);
+
+        // Operations on elements at or below the frontier are rejected,
+        // including removing an element which is still present.
+        assert_eq!(None, set.insert(1));
+        assert_eq!(None, set.insert(0));
+        set.apply(TpSetOp::Remove(2));
+        assert!(set.contains(&2));
+
+        // A stale replica's state does not bring back a purged element.
+        let mut old = TpSet::ordered();
+        old.insert(1u32);
+        set.merge(old);
+        assert!(!set.contains(&1));
+
+        // Operations on elements above the frontier are applied as usual.
+        assert_eq!(None, set.insert(4));
+        assert_eq!(Some(TpSetOp::Insert(5)), set.insert(5));
+
+        // Merging spreads the frontier, and with it the purge.
+        let mut other = stale;
+        other.merge(set.clone());
+        assert_eq!(set, other);
+
+        // A new replica receives the elements at or below the frontier.
+        let mut new = TpSet::ordered();
+        new.merge(set.clone());
+        assert_eq!(set, new);
+    }
+
+    /// Checks that purging the tombstones at a stable frontier, and then
+    /// replaying every operation, leaves the same elements as applying the
+    /// operations without purging.
+    #[quickcheck]
+    fn check_purge_preserves_elements(ops: Vec<(bool, u8)>, frontier: u8) -> bool {
+        let element = |op: &TpSetOp<u8>| match *op { TpSetOp::Insert(e) | TpSetOp::Remove(e) => e };
+        let ops: Vec<_> = ops.into_iter()
+                             .map(|(insert, e)| if insert { TpSetOp::Insert(e) } else { TpSetOp::Remove(e) })
+                             .collect();
+
+        let mut expected = TpSet::ordered();
+        let mut set = TpSet::ordered();
+        for op in ops.iter().cloned() {
+            expected.apply(op);
+        }
+        for op in ops.iter().filter(|op| element(op) <= frontier).cloned() {
+            set.apply(op);
+        }
+        set.purge_tombstones(frontier);
+        for op in ops {
+            set.apply(op);
+        }
+        set.value() == expected.value()
+    }
+
+    /// An element without an order, which a set held in a hash table
+    /// accepts.
+    #[derive(Clone, Debug, PartialEq, Eq, Hash)]
+    struct Unordered(u8);
+
+    #[test]
+    fn check_unordered_elements() {
+        let mut a = TpSet::new();
+        let mut b = TpSet::new();
+        a.insert(Unordered(1));
+        b.insert(Unordered(2));
+        b.remove(Unordered(1));
+        a.merge(b.clone());
+        assert!(a.value().contains(&Unordered(2)) && !a.contains(&Unordered(1)));
+        assert!(a >= b && b >= a);
+    }
+
+    #[test]
+    fn check_all_ordered() {
+        testkit::check_all::<OrdTpSet<u32>>();
+    }
+
+    #[quickcheck]
+    fn check_ordered_iteration(ops: Vec<(bool, u8)>, low: u8) -> bool {
+        let mut hashed = TpSet::new();
+        let mut ordered = TpSet::ordered();
+        for &(is_insert, element) in &ops {
+            if is_insert {
+                hashed.insert(element);
+                ordered.insert(element);
+            } else {
+                hashed.remove(element);
+                ordered.remove(element);
+            }
+        }
+        let mut expected = hashed.iter().cloned().collect::<Vec<_>>();
+        expected.sort();
+        ordered.iter().cloned().collect::<Vec<_>>() == expected
+            && ordered.range(low..).cloned().collect::<Vec<_>>()
+                == expected.iter().cloned().filter(|&e| e >= low).collect::<Vec<_>>()
+            && hashed.content_hash() == ordered.content_hash()
+    }
 }
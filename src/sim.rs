@@ -0,0 +1,504 @@
+//! Deterministic network simulation of CRDT replicas.
+//!
+//! A `Simulation` runs a fixed number of replicas of a CRDT, drives random
+//! local mutations through a user-supplied generator, and replicates the
+//! resulting operations and states through a simulated network which drops,
+//! duplicates, delays, and partitions messages. Once the random schedule is
+//! exhausted the network is brought to quiescence, and all replicas are
+//! checked for equality.
+//!
+//! Every simulation is a pure function of its seed, so failures are
+//! reproducible. When replicas fail to converge, the schedule of events which
+//! led to the failure is shrunk to a minimal failing schedule before being
+//! reported.
+//!
+//! This module is only available when the `testkit` feature is enabled.
+//!
+//! ##### Example
+//!
+//! ```
+//! use crdt::counter::GCounter;
+//! use crdt::sim::{Config, Simulation};
+//!
+//! let mut sim = Simulation::new(Config::default(),
+//!                               |replica| GCounter::new(replica as u64),
+//!                               |counter: &mut GCounter, _| Some(counter.increment(1)));
+//! sim.check(100);
+//! ```
+
+use std::collections::HashSet;
+use std::fmt::{self, Debug, Display, Formatter};
+
+use rand::{Rng, SeedableRng, XorShiftRng};
+
+use Crdt;
+
+/// The kind of messages replicas exchange in a simulation.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Replication {
+    /// Replicas broadcast an operation for every local mutation.
+    Operations,
+    /// Replicas periodically send their full state to a random peer.
+    States,
+    /// Replicas broadcast operations and periodically send their full state.
+    Mixed,
+}
+
+/// Simulation parameters.
+#[derive(Clone, Debug)]
+pub struct Config {
+    /// The number of replicas.
+    pub replicas: usize,
+    /// The number of simulation steps before the network is brought to
+    /// quiescence.
+    pub steps: usize,
+    /// The kind of messages exchanged by replicas.
+    pub replication: Replication,
+    /// The probability that a message is dropped.
+    pub drop_rate: f64,
+    /// The probability that a message is delivered twice.
+    pub duplicate_rate: f64,
+    /// The maximum number of steps a message may be delayed.
+    pub max_delay: usize,
+    /// The probability per step that the network is partitioned, or that an
+    /// existing partition heals.
+    pub partition_rate: f64,
+    /// Whether replicas exchange their full state at quiescence.
+    ///
+    /// When disabled, every message which was never delivered is delivered at
+    /// quiescence instead, modelling a reliable broadcast with
+    /// retransmission.
+    pub anti_entropy: bool,
+}
+
+impl Default for Config {
+    fn default() -> Config {
+        Config {
+            replicas: 3,
+            steps: 50,
+            replication: Replication::Mixed,
+            drop_rate: 0.1,
+            duplicate_rate: 0.1,
+            max_delay: 5,
+            partition_rate: 0.05,
+            anti_entropy: true,
+        }
+    }
+}
+
+/// An event in a simulation schedule.
+pub enum Event<C> where C: Crdt {
+    /// A local mutation at `replica`, producing operation message `id`.
+    Mutate { id: usize, replica: usize, op: C::Operation },
+    /// Replica `from` sends its current state to `to` as message `id`.
+    SendState { id: usize, from: usize, to: usize },
+    /// Message `id` is delivered to replica `to`.
+    Deliver { id: usize, to: usize },
+}
+
+/// A failure of replicas to converge.
+#[derive(Clone)]
+pub struct Failure<C> where C: Crdt {
+    /// The seed of the failing simulation.
+    pub seed: u64,
+    /// The minimal schedule of events which leads to divergence.
+    pub schedule: Vec<Event<C>>,
+    /// The replicas after running the minimal schedule to quiescence.
+    pub replicas: Vec<C>,
+}
+
+impl <C> Clone for Event<C> where C: Crdt {
+    fn clone(&self) -> Event<C> {
+        match *self {
+            Event::Mutate { id, replica, ref op } => Event::Mutate { id: id, replica: replica, op: op.clone() },
+            Event::SendState { id, from, to } => Event::SendState { id: id, from: from, to: to },
+            Event::Deliver { id, to } => Event::Deliver { id: id, to: to },
+        }
+    }
+}
+
+impl <C> Debug for Event<C> where C: Crdt, C::Operation: Debug {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match *self {
+            Event::Mutate { id, replica, ref op } =>
+                write!(f, "Mutate {{ id: {}, replica: {}, op: {:?} }}", id, replica, op),
+            Event::SendState { id, from, to } =>
+                write!(f, "SendState {{ id: {}, from: {}, to: {} }}", id, from, to),
+            Event::Deliver { id, to } =>
+                write!(f, "Deliver {{ id: {}, to: {} }}", id, to),
+        }
+    }
+}
+
+impl <C> Debug for Failure<C> where C: Crdt + Debug, C::Operation: Debug {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        f.debug_struct("Failure")
+         .field("seed", &self.seed)
+         .field("schedule", &self.schedule)
+         .field("replicas", &self.replicas)
+         .finish()
+    }
+}
+
+impl <C> Display for Failure<C> where C: Crdt + Debug, C::Operation: Debug {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        try!(writeln!(f, "replicas diverged (seed {}) after schedule:", self.seed));
+        for event in self.schedule.iter() {
+            try!(match *event {
+                Event::Mutate { id, replica, ref op } =>
+                    writeln!(f, "  #{}: replica {} mutates: {:?}", id, replica, op),
+                Event::SendState { id, from, to } =>
+                    writeln!(f, "  #{}: replica {} sends state to replica {}", id, from, to),
+                Event::Deliver { id, to } =>
+                    writeln!(f, "  #{} delivered to replica {}", id, to),
+            });
+        }
+        try!(writeln!(f, "replicas at quiescence:"));
+        for (i, replica) in self.replicas.iter().enumerate() {
+            try!(writeln!(f, "  {}: {:?}", i, replica));
+        }
+        Ok(())
+    }
+}
+
+enum Message<C> where C: Crdt {
+    Op(usize, C::Operation),
+    State(usize, C),
+}
+
+/// The replicas and in-flight messages of a simulation.
+struct Network<C> where C: Crdt {
+    replicas: Vec<C>,
+    messages: Vec<Option<Message<C>>>,
+    delivered: HashSet<(usize, usize)>,
+}
+
+impl <C> Network<C> where C: Crdt {
+
+    fn new<N>(replicas: usize, new_replica: &mut N) -> Network<C> where N: FnMut(usize) -> C {
+        Network {
+            replicas: (0..replicas).map(|i| new_replica(i)).collect(),
+            messages: Vec::new(),
+            delivered: HashSet::new(),
+        }
+    }
+
+    fn insert_message(&mut self, id: usize, message: Message<C>) {
+        while self.messages.len() <= id {
+            self.messages.push(None);
+        }
+        self.messages[id] = Some(message);
+    }
+
+    fn execute(&mut self, event: &Event<C>) {
+        match *event {
+            Event::Mutate { id, replica, ref op } => {
+                self.replicas[replica].apply(op.clone());
+                self.insert_message(id, Message::Op(replica, op.clone()));
+            },
+            Event::SendState { id, from, to: _ } => {
+                let state = self.replicas[from].clone();
+                self.insert_message(id, Message::State(from, state));
+            },
+            Event::Deliver { id, to } => {
+                match self.messages.get(id) {
+                    Some(&Some(Message::Op(_, ref op))) => self.replicas[to].apply(op.clone()),
+                    Some(&Some(Message::State(_, ref state))) => self.replicas[to].merge(state.clone()),
+                    _ => return,
+                }
+                self.delivered.insert((id, to));
+            },
+        }
+    }
+
+    /// Brings the network to quiescence.
+    fn quiesce(&mut self, anti_entropy: bool) {
+        if anti_entropy {
+            for from in 0..self.replicas.len() {
+                let state = self.replicas[from].clone();
+                for to in (0..self.replicas.len()).filter(|&to| to != from) {
+                    self.replicas[to].merge(state.clone());
+                }
+            }
+        } else {
+            for id in 0..self.messages.len() {
+                let targets: Vec<usize> = match self.messages[id] {
+                    Some(Message::Op(origin, _)) | Some(Message::State(origin, _)) =>
+                        (0..self.replicas.len()).filter(|&to| to != origin).collect(),
+                    None => continue,
+                };
+                for to in targets {
+                    if !self.delivered.contains(&(id, to)) {
+                        self.execute(&Event::Deliver { id: id, to: to });
+                    }
+                }
+            }
+        }
+    }
+
+    fn has_converged(&self) -> bool {
+        self.replicas.windows(2).all(|pair| pair[0] == pair[1])
+    }
+}
+
+/// A deterministic simulation of replicas exchanging messages over an
+/// unreliable network.
+pub struct Simulation<C, N, G> where C: Crdt {
+    config: Config,
+    new_replica: N,
+    generate: G,
+    _crdt: ::std::marker::PhantomData<C>,
+}
+
+impl <C, N, G> Simulation<C, N, G>
+where C: Crdt,
+      N: FnMut(usize) -> C,
+      G: FnMut(&mut C, &mut XorShiftRng) -> Option<C::Operation> {
+
+    /// Create a new simulation.
+    ///
+    /// `new_replica` is called with the index of each replica to create its
+    /// initial state. `generate` is called with a replica and a seeded random
+    /// number generator to perform a random local mutation, and returns the
+    /// operation produced by the mutation, if any. Applying the returned
+    /// operation to the original replica must reproduce the mutation.
+    pub fn new(config: Config, new_replica: N, generate: G) -> Simulation<C, N, G> {
+        Simulation {
+            config: config,
+            new_replica: new_replica,
+            generate: generate,
+            _crdt: ::std::marker::PhantomData,
+        }
+    }
+
+    /// Run the simulation with the provided seed.
+    ///
+    /// Returns the converged replicas, or the minimal failing schedule if the
+    /// replicas diverge.
+    pub fn run(&mut self, seed: u64) -> Result<Vec<C>, Failure<C>> {
+        let schedule = self.schedule(seed);
+        let network = self.replay(&schedule);
+        if network.has_converged() {
+            return Ok(network.replicas);
+        }
+        let schedule = self.shrink(schedule);
+        let network = self.replay(&schedule);
+        Err(Failure { seed: seed, schedule: schedule, replicas: network.replicas })
+    }
+
+    /// Run the simulation once for each seed in `0..seeds`, panicking with the
+    /// minimal failing schedule if the replicas diverge.
+    pub fn check(&mut self, seeds: u64) where C: Debug, C::Operation: Debug {
+        for seed in 0..seeds {
+            if let Err(failure) = self.run(seed) {
+                panic!("{}", failure);
+            }
+        }
+    }
+
+    /// Generates a random schedule of events.
+    fn schedule(&mut self, seed: u64) -> Vec<Event<C>> {
+        let mut rng = XorShiftRng::from_seed([seed as u32, (seed >> 32) as u32, 0x9e3779b9, 0x243f6a88]);
+        let mut network = Network::new(self.config.replicas, &mut self.new_replica);
+        let mut schedule = Vec::new();
+        let mut pending: Vec<(usize, usize, usize)> = Vec::new();
+        let mut partition: Option<Vec<bool>> = None;
+        let mut next_id = 0;
+        let replicas = self.config.replicas;
+
+        for step in 0..self.config.steps {
+            if replicas > 1 && rng.gen::<f64>() < self.config.partition_rate {
+                partition = match partition {
+                    Some(_) => None,
+                    None => {
+                        let mut sides: Vec<bool> = (0..replicas).map(|_| rng.gen()).collect();
+                        sides[0] = true;
+                        sides[1] = false;
+                        Some(sides)
+                    },
+                };
+            }
+
+            let replica = rng.gen_range(0, replicas);
+            let mut scratch = network.replicas[replica].clone();
+            if let Some(op) = (self.generate)(&mut scratch, &mut rng) {
+                let id = next_id;
+                next_id += 1;
+                let event = Event::Mutate { id: id, replica: replica, op: op };
+                network.execute(&event);
+                schedule.push(event);
+                if self.config.replication != Replication::States {
+                    for to in (0..replicas).filter(|&to| to != replica) {
+                        self.send(&mut rng, step, id, to, &mut pending);
+                    }
+                }
+            }
+
+            if self.config.replication != Replication::Operations && replicas > 1 {
+                let from = rng.gen_range(0, replicas);
+                let to = (from + rng.gen_range(1, replicas)) % replicas;
+                let id = next_id;
+                next_id += 1;
+                let event = Event::SendState { id: id, from: from, to: to };
+                network.execute(&event);
+                schedule.push(event);
+                self.send(&mut rng, step, id, to, &mut pending);
+            }
+
+            pending.sort();
+            let due = pending.iter().take_while(|&&(at, _, _)| at <= step).count();
+            for (_, id, to) in pending.drain(..due).collect::<Vec<_>>() {
+                let from = match network.messages[id] {
+                    Some(Message::Op(from, _)) | Some(Message::State(from, _)) => from,
+                    None => continue,
+                };
+                if partition.as_ref().map_or(false, |sides| sides[from] != sides[to]) {
+                    continue;
+                }
+                let event = Event::Deliver { id: id, to: to };
+                network.execute(&event);
+                schedule.push(event);
+            }
+        }
+        schedule
+    }
+
+    /// Schedules the delivery of a message, subject to drops, duplicates, and
+    /// delays.
+    fn send(&self,
+            rng: &mut XorShiftRng,
+            step: usize,
+            id: usize,
+            to: usize,
+            pending: &mut Vec<(usize, usize, usize)>) {
+        if rng.gen::<f64>() < self.config.drop_rate {
+            return;
+        }
+        let copies = if rng.gen::<f64>() < self.config.duplicate_rate { 2 } else { 1 };
+        for _ in 0..copies {
+            let delay = rng.gen_range(0, self.config.max_delay + 1);
+            pending.push((step + delay, id, to));
+        }
+    }
+
+    /// Runs a schedule to quiescence.
+    fn replay(&mut self, schedule: &[Event<C>]) -> Network<C> {
+        let mut network = Network::new(self.config.replicas, &mut self.new_replica);
+        for event in schedule {
+            network.execute(event);
+        }
+        network.quiesce(self.config.anti_entropy);
+        network
+    }
+
+    /// Shrinks a failing schedule by removing events until no single event can
+    /// be removed without the replicas converging.
+    fn shrink(&mut self, mut schedule: Vec<Event<C>>) -> Vec<Event<C>> {
+        let mut i = 0;
+        while i < schedule.len() {
+            let mut candidate = schedule.clone();
+            candidate.remove(i);
+            if self.replay(&candidate).has_converged() {
+                i += 1;
+            } else {
+                schedule = candidate;
+            }
+        }
+        schedule
+    }
+}
+
+#[cfg(test)]
+mod test {
+
+    use std::cmp::Ordering;
+
+    use rand::Rng;
+
+    use Crdt;
+    use counter::{GCounter, PnCounter};
+    use set::{LwwSet, PnSet, TpSet};
+    use super::{Config, Event, Replication, Simulation};
+
+    #[test]
+    fn check_gcounter_converges() {
+        Simulation::new(Config::default(),
+                        |replica| GCounter::new(replica as u64),
+                        |counter: &mut GCounter, rng| Some(counter.increment(rng.gen_range(0, 10))))
+            .check(50);
+    }
+
+    #[test]
+    fn check_pncounter_converges_with_reliable_ops() {
+        let config = Config {
+            replication: Replication::Operations,
+            drop_rate: 0.0,
+            anti_entropy: false,
+            ..Config::default()
+        };
+        Simulation::new(config,
+                        |replica| PnCounter::new(replica as u64),
+                        |counter: &mut PnCounter, rng| Some(counter.increment(rng.gen_range(-5, 5))))
+            .check(50);
+    }
+
+    #[test]
+    fn check_sets_converge() {
+        Simulation::new(Config::default(),
+                        |_| TpSet::new(),
+                        |set: &mut TpSet<u8>, rng| {
+                            let element = rng.gen_range(0, 8);
+                            if rng.gen() { set.insert(element) } else { set.remove(element) }
+                        })
+            .check(50);
+        Simulation::new(Config::default(),
+                        |_| LwwSet::new(),
+                        |set: &mut LwwSet<u8>, rng| {
+                            let (element, tid) = (rng.gen_range(0, 8), rng.gen_range(0u64, 16));
+                            if rng.gen() { set.insert(element, tid) } else { set.remove(element, tid) }
+                        })
+            .check(50);
+        Simulation::new(Config::default(),
+                        |replica| PnSet::new(replica as u64),
+                        |set: &mut PnSet<u8>, rng| {
+                            let element = rng.gen_range(0, 8);
+                            Some(if rng.gen() { set.insert(element) } else { set.remove(element) })
+                        })
+            .check(50);
+    }
+
+    /// A register which incorrectly lets every merge overwrite its value.
+    #[derive(Clone, Debug, PartialEq, Eq)]
+    struct Overwrite(u8);
+
+    impl PartialOrd for Overwrite {
+        fn partial_cmp(&self, other: &Overwrite) -> Option<Ordering> {
+            if self == other { Some(Ordering::Equal) } else { None }
+        }
+    }
+
+    impl Crdt for Overwrite {
+        type Operation = u8;
+        fn merge(&mut self, other: Overwrite) { *self = other; }
+        fn apply(&mut self, op: u8) { self.0 = op; }
+    }
+
+    #[test]
+    fn check_divergence_is_reported_minimally() {
+        let config = Config {
+            replication: Replication::Operations,
+            anti_entropy: false,
+            ..Config::default()
+        };
+        let mut sim = Simulation::new(config,
+                                      |_| Overwrite(0),
+                                      |_: &mut Overwrite, rng| Some(rng.gen_range(1, 255)));
+        let failure = (0..100).filter_map(|seed| sim.run(seed).err()).next()
+                              .expect("overwrite register should diverge");
+        assert!(failure.schedule.len() <= 2, "{}", failure);
+        assert!(failure.schedule.iter().all(|event| match *event {
+            Event::Mutate { .. } => true,
+            _ => false,
+        }));
+    }
+}
@@ -0,0 +1,650 @@
+//! Durable replicas backed by an append-only operation log.
+//!
+//! An `OpLog` owns a replica and records every operation applied to it in a
+//! log directory. Operations are appended to log segments, and a snapshot of
+//! the replica is periodically written, after which a new segment is started.
+//! When the log is reopened the replica is recovered by loading the latest
+//! snapshot and replaying the operations logged after it.
+//!
+//! The directory holds two kinds of files, named by a sequence number:
+//!
+//! * `<seq>.log` is a log segment, holding the operations with sequence
+//!   numbers starting at `seq`.
+//! * `<seq>.snapshot` is a snapshot of the replica after the operations with
+//!   sequence numbers less than `seq` have been applied.
+//!
+//! Every operation and snapshot is stored in a record holding its length, a
+//! CRC-32 checksum of the length, a CRC-32 checksum of the payload, and its
+//! `wire` encoding. A record which is cut short at the end of the last
+//! segment is the result of a crash during an append; it is discarded during
+//! recovery and reported in `Recovery`. Any other truncated or corrupt record
+//! fails recovery.
+//!
+//! ##### Example
+//!
+//! ```
+//! # use std::env;
+//! # use std::fs;
+//! use crdt::counter::GCounter;
+//! use crdt::storage::OpLog;
+//!
+//! # let dir = env::temp_dir().join(format!("crdt-oplog-doc-{}", std::process::id()));
+//! let mut log = OpLog::open(&dir, GCounter::new(1)).unwrap();
+//! log.update(|counter| counter.increment(3)).unwrap();
+//! drop(log);
+//!
+//! let log = OpLog::open(&dir, GCounter::new(1)).unwrap();
+//! assert_eq!(3, log.count());
+//! # fs::remove_dir_all(&dir).unwrap();
+//! ```
+
+use std::error;
+use std::fmt::{self, Display, Formatter};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Write};
+use std::ops::Deref;
+use std::path::{Path, PathBuf};
+
+use Crdt;
+use wire::{self, DecodeError, Message};
+
+const LOG_EXTENSION: &'static str = "log";
+const SNAPSHOT_EXTENSION: &'static str = "snapshot";
+const TEMP_EXTENSION: &'static str = "tmp";
+
+/// The size of a record header: a 4 byte length followed by a 4 byte
+/// checksum of the length and a 4 byte checksum of the payload.
+const HEADER_LEN: usize = 12;
+
+/// An error reading or writing an operation log.
+#[derive(Debug)]
+pub enum Error {
+    /// An I/O error.
+    Io(io::Error),
+    /// The record at `offset` in the file does not match its checksum.
+    Corrupt { path: PathBuf, offset: u64 },
+    /// The record at `offset` in the file is incomplete, and is not at the end
+    /// of the log.
+    Truncated { path: PathBuf, offset: u64 },
+    /// The record at `offset` in the file could not be decoded.
+    Decode { path: PathBuf, offset: u64, error: DecodeError },
+    /// The log segment holding the operation with this sequence number is
+    /// missing.
+    MissingSegment(u64),
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match *self {
+            Error::Io(ref error) => write!(f, "I/O error: {}", error),
+            Error::Corrupt { ref path, offset } =>
+                write!(f, "corrupt record at offset {} of {}", offset, path.display()),
+            Error::Truncated { ref path, offset } =>
+                write!(f, "truncated record at offset {} of {}", offset, path.display()),
+            Error::Decode { ref path, offset, ref error } =>
+                write!(f, "unable to decode record at offset {} of {}: {}", offset, path.display(), error),
+            Error::MissingSegment(seq) => write!(f, "missing log segment for operation {}", seq),
+        }
+    }
+}
+
+impl error::Error for Error {
+    fn description(&self) -> &str {
+        match *self {
+            Error::Io(_) => "I/O error",
+            Error::Corrupt { .. } => "corrupt record",
+            Error::Truncated { .. } => "truncated record",
+            Error::Decode { .. } => "unable to decode record",
+            Error::MissingSegment(_) => "missing log segment",
+        }
+    }
+
+    fn cause(&self) -> Option<&error::Error> {
+        match *self {
+            Error::Io(ref error) => Some(error),
+            Error::Decode { ref error, .. } => Some(error),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(error: io::Error) -> Error {
+        Error::Io(error)
+    }
+}
+
+/// Options for an operation log.
+#[derive(Clone, Debug)]
+pub struct Config {
+    /// The number of operations logged between automatic snapshots. Zero
+    /// disables automatic snapshots.
+    pub snapshot_interval: u64,
+    /// Whether every append is synced to disk before returning.
+    pub sync: bool,
+}
+
+impl Default for Config {
+    fn default() -> Config {
+        Config { snapshot_interval: 1024, sync: true }
+    }
+}
+
+/// A report of the recovery performed when opening an operation log.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Recovery {
+    /// The sequence number of the snapshot the replica was loaded from, if
+    /// any.
+    pub snapshot: Option<u64>,
+    /// The number of logged operations replayed on top of the snapshot.
+    pub replayed: u64,
+    /// The number of bytes of an incomplete record discarded from the end of
+    /// the log.
+    pub discarded_bytes: u64,
+}
+
+/// A replica which records every operation applied to it in an append-only
+/// log.
+pub struct OpLog<C> where C: Crdt {
+    dir: PathBuf,
+    config: Config,
+    crdt: C,
+    /// The sequence number of the next operation.
+    next_seq: u64,
+    /// The sequence number of the latest snapshot.
+    snapshot_seq: u64,
+    segment: File,
+    recovery: Recovery,
+}
+
+impl <C> OpLog<C> where C: Crdt + Message, C::Operation: Message {
+
+    /// Open the operation log in `dir` with the default configuration,
+    /// creating it if necessary.
+    ///
+    /// If the log is new, the replica starts out as `initial`. Otherwise it is
+    /// recovered from the log, and `initial` is only used if the log holds no
+    /// snapshot.
+    pub fn open<P>(dir: P, initial: C) -> Result<OpLog<C>, Error> where P: AsRef<Path> {
+        OpLog::open_with_config(dir, initial, Config::default())
+    }
+
+    /// Open the operation log in `dir`, creating it if necessary.
+    pub fn open_with_config<P>(dir: P, initial: C, config: Config) -> Result<OpLog<C>, Error>
+    where P: AsRef<Path> {
+        let dir = dir.as_ref().to_path_buf();
+        try!(fs::create_dir_all(&dir));
+        let (segments, snapshots) = try!(list(&dir));
+
+        let mut crdt = initial;
+        let mut recovery = Recovery::default();
+        let mut snapshot_seq = 0;
+        if let Some(&seq) = snapshots.last() {
+            let path = file_path(&dir, seq, SNAPSHOT_EXTENSION);
+            let bytes = try!(read_file(&path));
+            let (payload, len) = match try!(read_record(&path, &bytes, 0)) {
+                Some(record) => record,
+                None => return Err(Error::Truncated { path: path, offset: 0 }),
+            };
+            if len != bytes.len() {
+                return Err(Error::Corrupt { path: path, offset: len as u64 });
+            }
+            crdt = try!(decode(&path, 0, payload));
+            snapshot_seq = seq;
+            recovery.snapshot = Some(seq);
+        }
+
+        let mut next_seq = segments.first().cloned().unwrap_or(snapshot_seq);
+        if next_seq > snapshot_seq {
+            return Err(Error::MissingSegment(snapshot_seq));
+        }
+        for (i, &start) in segments.iter().enumerate() {
+            if start != next_seq {
+                return Err(Error::MissingSegment(next_seq));
+            }
+            let path = file_path(&dir, start, LOG_EXTENSION);
+            let bytes = try!(read_file(&path));
+            let mut offset = 0;
+            while offset < bytes.len() {
+                let (payload, len) = match try!(read_record(&path, &bytes, offset)) {
+                    Some(record) => record,
+                    None if i + 1 == segments.len() => {
+                        try!(try!(OpenOptions::new().write(true).open(&path)).set_len(offset as u64));
+                        recovery.discarded_bytes = (bytes.len() - offset) as u64;
+                        break;
+                    },
+                    None => return Err(Error::Truncated { path: path, offset: offset as u64 }),
+                };
+                if next_seq >= snapshot_seq {
+                    crdt.apply(try!(decode(&path, offset, payload)));
+                    recovery.replayed += 1;
+                }
+                next_seq += 1;
+                offset += len;
+            }
+        }
+        if next_seq < snapshot_seq {
+            return Err(Error::MissingSegment(next_seq));
+        }
+
+        let segment_start = segments.last().cloned().unwrap_or(next_seq);
+        let segment = try!(OpenOptions::new().append(true).create(true)
+                                             .open(file_path(&dir, segment_start, LOG_EXTENSION)));
+        Ok(OpLog {
+            dir: dir,
+            config: config,
+            crdt: crdt,
+            next_seq: next_seq,
+            snapshot_seq: snapshot_seq,
+            segment: segment,
+            recovery: recovery,
+        })
+    }
+
+    /// Perform a local mutation on the replica, and log the resulting
+    /// operation.
+    ///
+    /// Returns the operation to replicate to remote replicas, if the mutation
+    /// produced one. If logging the operation fails, the mutation has still
+    /// been applied to the in-memory replica.
+    pub fn update<F, R>(&mut self, mutation: F) -> Result<Option<C::Operation>, Error>
+    where F: FnOnce(&mut C) -> R, R: Into<Option<C::Operation>> {
+        let op = mutation(&mut self.crdt).into();
+        if let Some(ref op) = op {
+            try!(self.append(op));
+        }
+        Ok(op)
+    }
+
+    /// Apply an operation received from a remote replica, and log it.
+    ///
+    /// If logging the operation fails, it has still been applied to the
+    /// in-memory replica.
+    pub fn apply(&mut self, op: C::Operation) -> Result<(), Error> {
+        self.crdt.apply(op.clone());
+        self.append(&op)
+    }
+
+    /// Merge a replica received from a remote replica, and write a snapshot.
+    pub fn merge(&mut self, other: C) -> Result<(), Error> {
+        self.crdt.merge(other);
+        self.snapshot()
+    }
+
+    /// Write a snapshot of the replica and start a new log segment.
+    pub fn snapshot(&mut self) -> Result<(), Error> {
+        let temp_path = file_path(&self.dir, self.next_seq, TEMP_EXTENSION);
+        {
+            let mut file = try!(File::create(&temp_path));
+            try!(file.write_all(&record(&wire::to_bytes(&self.crdt))));
+            try!(file.sync_all());
+        }
+        try!(fs::rename(&temp_path, file_path(&self.dir, self.next_seq, SNAPSHOT_EXTENSION)));
+        try!(self.sync_dir());
+
+        if self.next_seq != self.snapshot_seq {
+            self.segment = try!(OpenOptions::new().append(true).create(true)
+                                                  .open(file_path(&self.dir, self.next_seq, LOG_EXTENSION)));
+        }
+        self.snapshot_seq = self.next_seq;
+        Ok(())
+    }
+
+    /// Remove the log segments and snapshots which are covered by the latest
+    /// snapshot.
+    ///
+    /// Returns the number of files removed.
+    pub fn compact(&mut self) -> Result<usize, Error> {
+        let (segments, snapshots) = try!(list(&self.dir));
+        let mut removed = 0;
+        for &seq in snapshots.iter().filter(|&&seq| seq < self.snapshot_seq) {
+            try!(fs::remove_file(file_path(&self.dir, seq, SNAPSHOT_EXTENSION)));
+            removed += 1;
+        }
+        for window in segments.windows(2).filter(|window| window[1] <= self.snapshot_seq) {
+            try!(fs::remove_file(file_path(&self.dir, window[0], LOG_EXTENSION)));
+            removed += 1;
+        }
+        if removed > 0 {
+            try!(self.sync_dir());
+        }
+        Ok(removed)
+    }
+
+    /// Sync the current log segment to disk.
+    pub fn sync(&mut self) -> Result<(), Error> {
+        self.segment.sync_data().map_err(Error::from)
+    }
+
+    /// Returns the report of the recovery performed when the log was opened.
+    pub fn recovery(&self) -> &Recovery {
+        &self.recovery
+    }
+
+    /// Returns the sequence number of the next logged operation.
+    pub fn next_seq(&self) -> u64 {
+        self.next_seq
+    }
+
+    /// Returns the replica, closing the log.
+    pub fn into_inner(self) -> C {
+        self.crdt
+    }
+
+    fn append(&mut self, op: &C::Operation) -> Result<(), Error> {
+        try!(self.segment.write_all(&record(&wire::to_bytes(op))));
+        if self.config.sync {
+            try!(self.segment.sync_data());
+        }
+        self.next_seq += 1;
+        if self.config.snapshot_interval > 0
+            && self.next_seq - self.snapshot_seq >= self.config.snapshot_interval {
+            try!(self.snapshot());
+        }
+        Ok(())
+    }
+
+    fn sync_dir(&self) -> Result<(), Error> {
+        File::open(&self.dir).and_then(|dir| dir.sync_all()).map_err(Error::from)
+    }
+}
+
+impl <C> Deref for OpLog<C> where C: Crdt {
+    type Target = C;
+
+    fn deref(&self) -> &C {
+        &self.crdt
+    }
+}
+
+fn file_path(dir: &Path, seq: u64, extension: &str) -> PathBuf {
+    dir.join(format!("{:020}.{}", seq, extension))
+}
+
+/// Returns the sorted sequence numbers of the log segments and snapshots in
+/// `dir`, removing leftover temporary files.
+fn list(dir: &Path) -> Result<(Vec<u64>, Vec<u64>), Error> {
+    let mut segments = Vec::new();
+    let mut snapshots = Vec::new();
+    for entry in try!(fs::read_dir(dir)) {
+        let path = try!(entry).path();
+        let seq = match path.file_stem().and_then(|stem| stem.to_str()).and_then(|stem| stem.parse().ok()) {
+            Some(seq) => seq,
+            None => continue,
+        };
+        match path.extension().and_then(|extension| extension.to_str()) {
+            Some(LOG_EXTENSION) => segments.push(seq),
+            Some(SNAPSHOT_EXTENSION) => snapshots.push(seq),
+            Some(TEMP_EXTENSION) => try!(fs::remove_file(&path)),
+            _ => (),
+        }
+    }
+    segments.sort();
+    snapshots.sort();
+    Ok((segments, snapshots))
+}
+
+fn read_file(path: &Path) -> Result<Vec<u8>, Error> {
+    let mut bytes = Vec::new();
+    try!(try!(File::open(path)).read_to_end(&mut bytes));
+    Ok(bytes)
+}
+
+/// Frames a payload in a record.
+fn record(payload: &[u8]) -> Vec<u8> {
+    let mut buf = Vec::with_capacity(HEADER_LEN + payload.len());
+    let len = u32_to_le(payload.len() as u32);
+    buf.extend_from_slice(&len);
+    buf.extend_from_slice(&u32_to_le(crc32(&len)));
+    buf.extend_from_slice(&u32_to_le(crc32(payload)));
+    buf.extend_from_slice(payload);
+    buf
+}
+
+/// Reads the record at `offset`, returning its payload and total length, or
+/// `None` if the record is incomplete.
+///
+/// The length is checked against its own checksum before it is trusted, so a
+/// corrupt length is reported rather than mistaken for an incomplete record.
+fn read_record<'a>(path: &Path, bytes: &'a [u8], offset: usize) -> Result<Option<(&'a [u8], usize)>, Error> {
+    let bytes = &bytes[offset..];
+    if bytes.len() < HEADER_LEN {
+        return Ok(None);
+    }
+    if crc32(&bytes[0..4]) != u32_from_le(&bytes[4..8]) {
+        return Err(Error::Corrupt { path: path.to_path_buf(), offset: offset as u64 });
+    }
+    let len = u32_from_le(&bytes[0..4]) as usize;
+    if bytes.len() - HEADER_LEN < len {
+        return Ok(None);
+    }
+    let payload = &bytes[HEADER_LEN..HEADER_LEN + len];
+    if crc32(payload) != u32_from_le(&bytes[8..12]) {
+        return Err(Error::Corrupt { path: path.to_path_buf(), offset: offset as u64 });
+    }
+    Ok(Some((payload, HEADER_LEN + len)))
+}
+
+fn decode<M>(path: &Path, offset: usize, payload: &[u8]) -> Result<M, Error> where M: Message {
+    wire::from_bytes(payload).map_err(|error| {
+        Error::Decode { path: path.to_path_buf(), offset: offset as u64, error: error }
+    })
+}
+
+fn u32_to_le(value: u32) -> [u8; 4] {
+    [value as u8, (value >> 8) as u8, (value >> 16) as u8, (value >> 24) as u8]
+}
+
+fn u32_from_le(bytes: &[u8]) -> u32 {
+    bytes[0] as u32 | (bytes[1] as u32) << 8 | (bytes[2] as u32) << 16 | (bytes[3] as u32) << 24
+}
+
+/// Computes the CRC-32 (IEEE) checksum of `bytes`.
+fn crc32(bytes: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for &byte in bytes {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 == 1 { (crc >> 1) ^ 0xedb88320 } else { crc >> 1 };
+        }
+    }
+    !crc
+}
+
+#[cfg(test)]
+mod test {
+
+    use std::fs::{self, OpenOptions};
+    use std::io::{Seek, SeekFrom, Write};
+
+    use tempdir::TempDir;
+
+    use Crdt;
+    use counter::GCounter;
+    use set::{PnSet, PnSetOp};
+    use super::{crc32, file_path, list, Config, Error, OpLog, LOG_EXTENSION};
+
+    fn config(snapshot_interval: u64) -> Config {
+        Config { snapshot_interval: snapshot_interval, sync: false }
+    }
+
+    #[test]
+    fn check_crc32() {
+        assert_eq!(0, crc32(b""));
+        assert_eq!(0xcbf43926, crc32(b"123456789"));
+    }
+
+    #[quickcheck]
+    fn check_recovery(ops: Vec<PnSetOp<u8>>, snapshot_interval: u8) -> bool {
+        let dir = TempDir::new("oplog").unwrap();
+        let config = config(snapshot_interval as u64 % 8);
+        let mut expected = PnSet::new(0);
+        {
+            let mut log = OpLog::open_with_config(dir.path(), PnSet::new(0), config.clone()).unwrap();
+            for op in ops.iter().cloned() {
+                expected.apply(op.clone());
+                log.apply(op).unwrap();
+            }
+        }
+        let log = OpLog::open_with_config(dir.path(), PnSet::new(0), config).unwrap();
+        log.next_seq() == ops.len() as u64
+            && log.recovery().discarded_bytes == 0
+            && log.into_inner() == expected
+    }
+
+    #[test]
+    fn check_update() {
+        let dir = TempDir::new("oplog").unwrap();
+        let mut log = OpLog::open(dir.path(), GCounter::new(1)).unwrap();
+        assert!(log.update(|counter| counter.increment(5)).unwrap().is_some());
+        let mut set = OpLog::open(dir.path().join("set"), PnSet::new(1)).unwrap();
+        assert!(set.update(|set| set.insert(3)).unwrap().is_some());
+        drop(log);
+
+        let log = OpLog::open(dir.path(), GCounter::new(1)).unwrap();
+        assert_eq!(5, log.count());
+        assert_eq!(1, log.recovery().replayed);
+    }
+
+    #[test]
+    fn check_snapshot_and_compaction() {
+        let dir = TempDir::new("oplog").unwrap();
+        let mut log = OpLog::open_with_config(dir.path(), GCounter::new(1), config(10)).unwrap();
+        for _ in 0..25 {
+            log.update(|counter| counter.increment(1)).unwrap();
+        }
+        assert_eq!((vec![0, 10, 20], vec![10, 20]), list(dir.path()).unwrap());
+
+        assert_eq!(3, log.compact().unwrap());
+        assert_eq!((vec![20], vec![20]), list(dir.path()).unwrap());
+        drop(log);
+
+        let log = OpLog::open_with_config(dir.path(), GCounter::new(1), config(10)).unwrap();
+        assert_eq!(25, log.count());
+        assert_eq!(Some(20), log.recovery().snapshot);
+        assert_eq!(5, log.recovery().replayed);
+    }
+
+    #[test]
+    fn check_merge() {
+        let dir = TempDir::new("oplog").unwrap();
+        let mut log = OpLog::open_with_config(dir.path(), GCounter::new(1), config(0)).unwrap();
+        log.update(|counter| counter.increment(1)).unwrap();
+        let mut other = GCounter::new(2);
+        other.increment(7);
+        log.merge(other).unwrap();
+        log.update(|counter| counter.increment(1)).unwrap();
+        drop(log);
+
+        let log = OpLog::open_with_config(dir.path(), GCounter::new(1), config(0)).unwrap();
+        assert_eq!(9, log.count());
+        assert_eq!(Some(1), log.recovery().snapshot);
+        assert_eq!(1, log.recovery().replayed);
+    }
+
+    #[test]
+    fn check_truncated_tail() {
+        let dir = TempDir::new("oplog").unwrap();
+        {
+            let mut log = OpLog::open_with_config(dir.path(), GCounter::new(1), config(0)).unwrap();
+            for _ in 0..3 {
+                log.update(|counter| counter.increment(1)).unwrap();
+            }
+        }
+        let path = file_path(dir.path(), 0, LOG_EXTENSION);
+        let len = fs::metadata(&path).unwrap().len();
+        OpenOptions::new().write(true).open(&path).unwrap().set_len(len - 2).unwrap();
+
+        let mut log = OpLog::open_with_config(dir.path(), GCounter::new(1), config(0)).unwrap();
+        assert_eq!(2, log.count());
+        assert_eq!(2, log.recovery().replayed);
+        assert!(log.recovery().discarded_bytes > 0);
+
+        // The log remains usable after the incomplete record is discarded.
+        log.update(|counter| counter.increment(1)).unwrap();
+        drop(log);
+        let log = OpLog::open_with_config(dir.path(), GCounter::new(1), config(0)).unwrap();
+        assert_eq!(3, log.count());
+        assert_eq!(0, log.recovery().discarded_bytes);
+    }
+
+    #[test]
+    fn check_corruption_is_detected() {
+        let dir = TempDir::new("oplog").unwrap();
+        {
+            let mut log = OpLog::open_with_config(dir.path(), GCounter::new(1), config(0)).unwrap();
+            for _ in 0..3 {
+                log.update(|counter| counter.increment(1)).unwrap();
+            }
+        }
+        let path = file_path(dir.path(), 0, LOG_EXTENSION);
+        let mut file = OpenOptions::new().write(true).open(&path).unwrap();
+        file.seek(SeekFrom::Start(9)).unwrap();
+        file.write_all(&[0xff]).unwrap();
+
+        match OpLog::<GCounter>::open(dir.path(), GCounter::new(1)) {
+            Err(Error::Corrupt { path: ref corrupt, offset: 0 }) if *corrupt == path => (),
+            Err(error) => panic!("unexpected error: {}", error),
+            Ok(_) => panic!("corruption was not detected"),
+        }
+    }
+
+    #[test]
+    fn check_corrupt_length_is_detected() {
+        let dir = TempDir::new("oplog").unwrap();
+        {
+            let mut log = OpLog::open_with_config(dir.path(), GCounter::new(1), config(0)).unwrap();
+            for _ in 0..3 {
+                log.update(|counter| counter.increment(1)).unwrap();
+            }
+        }
+        let path = file_path(dir.path(), 0, LOG_EXTENSION);
+        let len = fs::metadata(&path).unwrap().len();
+        let second = len / 3;
+        let mut file = OpenOptions::new().write(true).open(&path).unwrap();
+        file.seek(SeekFrom::Start(second + 3)).unwrap();
+        file.write_all(&[0x7f]).unwrap();
+        drop(file);
+
+        match OpLog::<GCounter>::open(dir.path(), GCounter::new(1)) {
+            Err(Error::Corrupt { path: ref corrupt, offset }) if *corrupt == path && offset == second => (),
+            Err(error) => panic!("unexpected error: {}", error),
+            Ok(_) => panic!("corruption was not detected"),
+        }
+        // The valid records following the corrupt length are not discarded.
+        assert_eq!(len, fs::metadata(&path).unwrap().len());
+    }
+
+    #[test]
+    fn check_missing_segment_is_detected() {
+        let dir = TempDir::new("oplog").unwrap();
+        {
+            let mut log = OpLog::open_with_config(dir.path(), GCounter::new(1), config(2)).unwrap();
+            for _ in 0..5 {
+                log.update(|counter| counter.increment(1)).unwrap();
+            }
+        }
+        fs::remove_file(file_path(dir.path(), 2, LOG_EXTENSION)).unwrap();
+        fs::remove_file(file_path(dir.path(), 4, "snapshot")).unwrap();
+
+        match OpLog::<GCounter>::open(dir.path(), GCounter::new(1)) {
+            Err(Error::MissingSegment(2)) => (),
+            Err(error) => panic!("unexpected error: {}", error),
+            Ok(_) => panic!("missing segment was not detected"),
+        }
+    }
+
+    #[test]
+    fn check_decode_error_is_reported() {
+        let dir = TempDir::new("oplog").unwrap();
+        {
+            let mut log = OpLog::open(dir.path(), GCounter::new(1)).unwrap();
+            log.update(|counter| counter.increment(1)).unwrap();
+        }
+        match OpLog::<PnSet<u8>>::open(dir.path(), PnSet::new(1)) {
+            Err(Error::Decode { offset: 0, .. }) => (),
+            Err(error) => panic!("unexpected error: {}", error),
+            Ok(_) => panic!("decode error was not reported"),
+        }
+    }
+}
@@ -0,0 +1,26 @@
+//! Helpers shared by the unit tests of the CRDTs.
+
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+use serde_json;
+
+use Crdt;
+
+/// Checks that a replica and an operation survive a round trip through JSON,
+/// and that `same` holds between the replica and its decoded copy.
+///
+/// `same` checks the parts of the replica which equality ignores, such as its
+/// replica ID. Operations are compared by their encodings, since not every
+/// operation type implements `PartialEq`.
+pub fn serde_round_trip<C, F>(crdt: C, op: C::Operation, same: F) -> bool
+where C: Crdt + Serialize + DeserializeOwned,
+      C::Operation: Serialize + DeserializeOwned,
+      F: Fn(&C, &C) -> bool {
+    let json = serde_json::to_string(&op).unwrap();
+    let decoded_op: C::Operation = serde_json::from_str(&json).unwrap();
+    if serde_json::to_string(&decoded_op).unwrap() != json {
+        return false;
+    }
+    let decoded: C = serde_json::from_str(&serde_json::to_string(&crdt).unwrap()).unwrap();
+    decoded == crdt && same(&decoded, &crdt)
+}
@@ -0,0 +1,194 @@
+//! Property checkers for CRDT implementations.
+//!
+//! These functions check the algebraic laws which every `Crdt` implementation
+//! must uphold. They are intended to be used with
+//! [quickcheck](https://github.com/BurntSushi/quickcheck), either individually
+//! or all together through `check_all`, with `diff_ops_is_merge` checked
+//! separately for CRDTs which compute the difference between replicas as
+//! operations. This module is only available when the `testkit` feature is
+//! enabled.
+//!
+//! ##### Example
+//!
+//! ```
+//! use crdt::set::GSet;
+//! use crdt::testkit;
+//!
+//! testkit::check_all::<GSet<u32>>();
+//! ```
+
+use std::cmp::Ordering::{self, Equal};
+use std::fmt::Debug;
+
+use quickcheck::{quickcheck, Arbitrary};
+use rand::{thread_rng, Rng};
+
+use Crdt;
+
+/// Checks that applying operations in any order results in the same replica.
+pub fn apply_is_commutative<C>(crdt: C, mut ops: Vec<C::Operation>) -> bool where C: Crdt {
+    let expected = ops.iter()
+                      .cloned()
+                      .fold(crdt.clone(), |mut crdt, op| {
+                          crdt.apply(op);
+                          crdt
+                      });
+
+    thread_rng().shuffle(&mut ops[..]);
+
+    expected == ops.into_iter()
+                   .fold(crdt.clone(), |mut crdt, op| {
+                       crdt.apply(op);
+                       crdt
+                   })
+}
+
+/// Checks that merging replicas in any order results in the same replica.
+pub fn merge_is_commutative<C>(crdt: C, mut crdts: Vec<C>) -> bool where C: Crdt {
+    let expected: C = crdts.iter()
+                           .cloned()
+                           .fold(crdt.clone(), |mut crdt, other| {
+                               crdt.merge(other);
+                               crdt
+                           });
+
+    thread_rng().shuffle(&mut crdts[..]);
+
+    expected == crdts.into_iter()
+                     .fold(crdt.clone(), |mut crdt, other| {
+                         crdt.merge(other);
+                         crdt
+                     })
+}
+
+/// Checks that merging `(a ∪ b) ∪ c` is equal to merging `a ∪ (b ∪ c)`.
+pub fn merge_is_associative<C>(a: C, b: C, c: C) -> bool where C: Crdt {
+    let mut ab_c = a.clone();
+    ab_c.merge(b.clone());
+    ab_c.merge(c.clone());
+
+    let mut bc = b;
+    bc.merge(c);
+    let mut a_bc = a;
+    a_bc.merge(bc);
+
+    ab_c == a_bc
+}
+
+/// Checks that merging a replica more than once has no further effect.
+pub fn merge_is_idempotent<C>(mut a: C, b: C) -> bool where C: Crdt {
+    let unmerged = a.clone();
+    let mut merged_self = a.clone();
+    merged_self.merge(a.clone());
+
+    a.merge(b.clone());
+    let expected = a.clone();
+    a.merge(b);
+
+    merged_self == unmerged && a == expected
+}
+
+/// Checks that applying an operation more than once has no further effect.
+///
+/// This law only holds for CRDTs with idempotent operation-based replication.
+pub fn apply_is_idempotent<C>(mut crdt: C, op: C::Operation) -> bool where C: Crdt {
+    crdt.apply(op.clone());
+    let expected = crdt.clone();
+    crdt.apply(op);
+    crdt == expected
+}
+
+/// Checks that operation-based and state-based replication are equivalent.
+///
+/// The operations are applied to a copy of `base`, which is then merged into
+/// `crdt`. This must result in the same replica as merging `base` into `crdt`
+/// and applying the operations directly.
+pub fn merge_is_apply<C>(crdt: C, base: C, ops: Vec<C::Operation>) -> bool where C: Crdt {
+    let mut remote = base.clone();
+    for op in ops.iter().cloned() {
+        remote.apply(op);
+    }
+    let mut merged = crdt.clone();
+    merged.merge(remote);
+
+    let mut applied = crdt;
+    applied.merge(base);
+    for op in ops.into_iter() {
+        applied.apply(op);
+    }
+
+    merged == applied
+}
+
+/// Checks that applying an operation never moves a replica backwards in the
+/// partial order.
+pub fn apply_is_monotonic<C>(mut crdt: C, op: C::Operation) -> bool where C: Crdt {
+    let before = crdt.clone();
+    crdt.apply(op);
+    before <= crdt && is_consistent(&before, &crdt)
+}
+
+/// Checks that a merged replica is greater than or equal to the replica merged
+/// into it.
+pub fn ordering_lte<C>(mut a: C, b: C) -> bool where C: Crdt {
+    a.merge(b.clone());
+    b <= a && is_consistent(&a, &b)
+}
+
+/// Checks that two replicas which have merged each other are equal.
+pub fn ordering_equality<C>(mut a: C, mut b: C) -> bool where C: Crdt {
+    a.merge(b.clone());
+    b.merge(a.clone());
+    let a_eq_b = a == b;
+    let b_eq_a = b == a;
+    a_eq_b
+        && b_eq_a
+        && a.partial_cmp(&b) == Some(Equal)
+        && b.partial_cmp(&a) == Some(Equal)
+}
+
+/// Checks that comparing `a` with `b` gives the reverse of comparing `b` with
+/// `a`, and that `==` agrees with `partial_cmp`.
+fn is_consistent<C>(a: &C, b: &C) -> bool where C: Crdt {
+    let a_cmp_b = a.partial_cmp(b);
+    let b_cmp_a = b.partial_cmp(a);
+    a_cmp_b == b_cmp_a.map(Ordering::reverse) && (a == b) == (a_cmp_b == Some(Equal))
+}
+
+/// Checks that applying the operations returned by `diff_ops` is equivalent to
+/// merging `a` into `b`, and that nothing is left to apply afterwards.
+///
+/// Not every CRDT can compute the operations which bring one replica up to
+/// date with another, so this law is not part of `check_all`. CRDTs which
+/// provide a `diff_ops` method should check it separately.
+pub fn diff_ops_is_merge<C, F>(a: C, b: C, diff_ops: F) -> bool
+where C: Crdt, F: Fn(&C, &C) -> Vec<C::Operation> {
+    let mut merged = b.clone();
+    merged.merge(a.clone());
+
+    let applied = diff_ops(&a, &b).into_iter()
+                                  .fold(b, |mut crdt, op| {
+                                      crdt.apply(op);
+                                      crdt
+                                  });
+
+    merged == applied && diff_ops(&a, &merged).is_empty()
+}
+
+/// Checks every law in this module against arbitrary replicas and operations
+/// of `C`, panicking if any law fails.
+///
+/// `apply_is_idempotent` is included, so this should only be used with CRDTs
+/// whose operation-based replication is idempotent.
+pub fn check_all<C>()
+where C: Crdt + Arbitrary + Debug, C::Operation: Arbitrary + Debug {
+    quickcheck(apply_is_commutative::<C> as fn(C, Vec<C::Operation>) -> bool);
+    quickcheck(merge_is_commutative::<C> as fn(C, Vec<C>) -> bool);
+    quickcheck(merge_is_associative::<C> as fn(C, C, C) -> bool);
+    quickcheck(merge_is_idempotent::<C> as fn(C, C) -> bool);
+    quickcheck(apply_is_idempotent::<C> as fn(C, C::Operation) -> bool);
+    quickcheck(merge_is_apply::<C> as fn(C, C, Vec<C::Operation>) -> bool);
+    quickcheck(apply_is_monotonic::<C> as fn(C, C::Operation) -> bool);
+    quickcheck(ordering_lte::<C> as fn(C, C) -> bool);
+    quickcheck(ordering_equality::<C> as fn(C, C) -> bool);
+}
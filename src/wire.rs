@@ -0,0 +1,828 @@
+//! A compact, versioned binary encoding for CRDT states and operations.
+//!
+//! Every message begins with a one byte format version, a one byte type tag,
+//! and the schema version of the type's encoding, followed by the encoded
+//! body. Replica IDs, transaction IDs, counts, and lengths are encoded as
+//! [LEB128](https://en.wikipedia.org/wiki/LEB128) variable-length integers,
+//! and signed integers are zig-zag encoded first, so small values take a
+//! single byte.
+//!
+//! Map and set entries are encoded in a canonical order, so equal replicas
+//! always encode to identical bytes.
+//!
+//! Decoding never panics: malformed, truncated, or unsupported input results
+//! in a `DecodeError`.
+//!
+//! ##### Schema evolution
+//!
+//! Each message type carries its own schema version, which is incremented
+//! whenever the encoding of the type changes. Messages written with an older
+//! schema are decoded by the upgrade functions registered in the type's
+//! `Migrations`, so persisted states remain readable after an upgrade. For
+//! example, the first schema of `LwwSet` encoded each entry with a presence
+//! flag and a `u64` timestamp, and those states still decode into the
+//! current `LwwSet`.
+//!
+//! Version 1 of the format, the first version of the wire format, has no
+//! schema version in the header; its messages are decoded as schema 1.
+//!
+//! ##### Example
+//!
+//! ```
+//! use crdt::counter::GCounter;
+//! use crdt::wire;
+//!
+//! let mut counter = GCounter::new(42);
+//! counter.increment(300);
+//!
+//! let bytes = wire::to_bytes(&counter);
+//! assert_eq!(10, bytes.len());
+//!
+//! let decoded: GCounter = wire::from_bytes(&bytes).unwrap();
+//! assert_eq!(counter, decoded);
+//! ```
+
+use std::error;
+use std::fmt::{self, Display, Formatter};
+use std::str;
+
+use {ReplicaId, TransactionId};
+use set::MapStore;
+
+/// The current version of the wire format.
+pub const VERSION: u8 = 2;
+
+/// An error decoding a message.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum DecodeError {
+    /// The input ended before the message was complete.
+    UnexpectedEof,
+    /// The message was encoded with an unsupported format version.
+    UnsupportedVersion(u8),
+    /// The message was encoded with a schema version which has no registered
+    /// upgrade.
+    UnsupportedSchema(u32),
+    /// The message holds a different type than the one being decoded.
+    UnexpectedTag { expected: u8, found: u8 },
+    /// A variable-length integer does not fit in its type.
+    Overflow,
+    /// The input contains a value which is not valid for its type.
+    InvalidValue(&'static str),
+    /// The input continues after the end of the message.
+    TrailingBytes(usize),
+}
+
+impl Display for DecodeError {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match *self {
+            DecodeError::UnexpectedEof => write!(f, "unexpected end of input"),
+            DecodeError::UnsupportedVersion(version) =>
+                write!(f, "unsupported wire format version {}", version),
+            DecodeError::UnsupportedSchema(schema) => write!(f, "unsupported schema version {}", schema),
+            DecodeError::UnexpectedTag { expected, found } =>
+                write!(f, "unexpected message type {} (expected {})", found, expected),
+            DecodeError::Overflow => write!(f, "integer overflow"),
+            DecodeError::InvalidValue(description) => write!(f, "invalid value: {}", description),
+            DecodeError::TrailingBytes(count) => write!(f, "{} trailing bytes after message", count),
+        }
+    }
+}
+
+impl error::Error for DecodeError {
+    fn description(&self) -> &str {
+        match *self {
+            DecodeError::UnexpectedEof => "unexpected end of input",
+            DecodeError::UnsupportedVersion(_) => "unsupported wire format version",
+            DecodeError::UnsupportedSchema(_) => "unsupported schema version",
+            DecodeError::UnexpectedTag { .. } => "unexpected message type",
+            DecodeError::Overflow => "integer overflow",
+            DecodeError::InvalidValue(description) => description,
+            DecodeError::TrailingBytes(_) => "trailing bytes after message",
+        }
+    }
+}
+
+/// A type which can be encoded in the wire format.
+pub trait Encode {
+
+    /// Appends the encoding of this value to `buf`.
+    fn encode(&self, buf: &mut Vec<u8>);
+}
+
+/// A type which can be decoded from the wire format.
+pub trait Decode : Sized {
+
+    /// Decodes a value from the reader.
+    fn decode(reader: &mut Reader) -> Result<Self, DecodeError>;
+}
+
+/// A top-level message type with a unique type tag.
+pub trait Message : Encode + Decode {
+
+    /// The type tag identifying this message type.
+    const TAG: u8;
+
+    /// The schema version of this type's encoding.
+    const SCHEMA: u32 = 1;
+
+    /// Returns the upgrade functions for older schema versions of this type.
+    fn migrations() -> Migrations<Self> {
+        Migrations::new()
+    }
+}
+
+/// Encodes a message, including its format version, type tag, and schema
+/// version.
+pub fn to_bytes<M>(message: &M) -> Vec<u8> where M: Message {
+    let mut buf = vec![VERSION, M::TAG];
+    write_varint(&mut buf, M::SCHEMA as u64);
+    message.encode(&mut buf);
+    buf
+}
+
+/// Decodes a message, checking its format version and type tag.
+///
+/// Messages written with an older schema version are upgraded by the type's
+/// `Message::migrations`.
+pub fn from_bytes<M>(bytes: &[u8]) -> Result<M, DecodeError> where M: Message {
+    decode_message(bytes, |schema, reader| M::migrations().upgrade(schema, reader))
+}
+
+/// An upgrade function, which decodes the body of a message written with an
+/// older schema version.
+pub type Upgrade<M> = fn(&mut Reader) -> Result<M, DecodeError>;
+
+/// The upgrade functions for older schema versions of a message type.
+///
+/// ##### Example
+///
+/// ```
+/// use crdt::set::LwwSet;
+/// use crdt::wire::{self, Decode, DecodeError, Message, Reader};
+///
+/// /// A schema which predates the wire format, holding a list of elements.
+/// fn upgrade_from_list(reader: &mut Reader) -> Result<LwwSet<u32>, DecodeError> {
+///     let mut set = LwwSet::new();
+///     for element in try!(Vec::<u32>::decode(reader)) {
+///         set.insert(element, 0);
+///     }
+///     Ok(set)
+/// }
+///
+/// let bytes = [wire::VERSION, <LwwSet<u32> as Message>::TAG, 0, 2, 7, 8];
+/// let set = LwwSet::migrations()
+///                  .register(0, upgrade_from_list)
+///                  .from_bytes(&bytes)
+///                  .unwrap();
+/// assert!(set.contains(&7) && set.contains(&8));
+/// ```
+pub struct Migrations<M> {
+    upgrades: Vec<(u32, Upgrade<M>)>,
+}
+
+impl <M> Migrations<M> where M: Message {
+
+    /// Create an empty set of migrations.
+    pub fn new() -> Migrations<M> {
+        Migrations { upgrades: Vec::new() }
+    }
+
+    /// Registers the upgrade function for messages written with `schema`,
+    /// replacing any previously registered upgrade for the same schema.
+    pub fn register(mut self, schema: u32, upgrade: Upgrade<M>) -> Migrations<M> {
+        self.upgrades.retain(|&(s, _)| s != schema);
+        self.upgrades.push((schema, upgrade));
+        self
+    }
+
+    /// Decodes a message, upgrading it with the registered upgrade functions
+    /// if it was written with an older schema version.
+    pub fn from_bytes(&self, bytes: &[u8]) -> Result<M, DecodeError> {
+        decode_message(bytes, |schema, reader| self.upgrade(schema, reader))
+    }
+
+    fn upgrade(&self, schema: u32, reader: &mut Reader) -> Result<M, DecodeError> {
+        match self.upgrades.iter().find(|&&(s, _)| s == schema) {
+            Some(&(_, upgrade)) => upgrade(reader),
+            None => Err(DecodeError::UnsupportedSchema(schema)),
+        }
+    }
+}
+
+impl <M> Default for Migrations<M> where M: Message {
+    fn default() -> Migrations<M> {
+        Migrations::new()
+    }
+}
+
+fn decode_message<M, F>(bytes: &[u8], upgrade: F) -> Result<M, DecodeError>
+where M: Message, F: FnOnce(u32, &mut Reader) -> Result<M, DecodeError> {
+    let mut reader = Reader::new(bytes);
+    let version = try!(reader.read_u8());
+    if version == 0 || version > VERSION {
+        return Err(DecodeError::UnsupportedVersion(version));
+    }
+    let tag = try!(reader.read_u8());
+    if tag != M::TAG {
+        return Err(DecodeError::UnexpectedTag { expected: M::TAG, found: tag });
+    }
+    let schema = if version == 1 { 1 } else { try!(u32::decode(&mut reader)) };
+    let message = if schema == M::SCHEMA {
+        try!(M::decode(&mut reader))
+    } else {
+        try!(upgrade(schema, &mut reader))
+    };
+    match reader.remaining() {
+        0 => Ok(message),
+        remaining => Err(DecodeError::TrailingBytes(remaining)),
+    }
+}
+
+/// A cursor over an encoded message.
+pub struct Reader<'a> {
+    buf: &'a [u8],
+}
+
+impl <'a> Reader<'a> {
+
+    /// Create a new reader over the provided bytes.
+    pub fn new(buf: &'a [u8]) -> Reader<'a> {
+        Reader { buf: buf }
+    }
+
+    /// Returns the number of unread bytes.
+    pub fn remaining(&self) -> usize {
+        self.buf.len()
+    }
+
+    /// Reads a single byte.
+    pub fn read_u8(&mut self) -> Result<u8, DecodeError> {
+        match self.buf.split_first() {
+            Some((&byte, rest)) => {
+                self.buf = rest;
+                Ok(byte)
+            },
+            None => Err(DecodeError::UnexpectedEof),
+        }
+    }
+
+    /// Reads `len` bytes.
+    pub fn read_bytes(&mut self, len: usize) -> Result<&'a [u8], DecodeError> {
+        if len > self.buf.len() {
+            return Err(DecodeError::UnexpectedEof);
+        }
+        let (bytes, rest) = self.buf.split_at(len);
+        self.buf = rest;
+        Ok(bytes)
+    }
+
+    /// Reads an unsigned variable-length integer.
+    pub fn read_varint(&mut self) -> Result<u64, DecodeError> {
+        let mut value: u64 = 0;
+        for shift in (0..10).map(|i| i * 7) {
+            let byte = try!(self.read_u8());
+            let bits = (byte & 0x7f) as u64;
+            if shift == 63 && bits > 1 {
+                return Err(DecodeError::Overflow);
+            }
+            value |= bits << shift;
+            if byte & 0x80 == 0 {
+                return Ok(value);
+            }
+        }
+        Err(DecodeError::Overflow)
+    }
+
+    /// Reads a length prefix, checking that it does not exceed the number of
+    /// remaining bytes.
+    pub fn read_len(&mut self) -> Result<usize, DecodeError> {
+        let len = try!(self.read_varint());
+        if len > self.remaining() as u64 {
+            return Err(DecodeError::UnexpectedEof);
+        }
+        Ok(len as usize)
+    }
+}
+
+/// Appends an unsigned variable-length integer to `buf`.
+pub fn write_varint(buf: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        buf.push((value as u8) | 0x80);
+        value >>= 7;
+    }
+    buf.push(value as u8);
+}
+
+/// Appends a length-prefixed sequence of entries to `buf` in canonical order.
+///
+/// Each entry is encoded by `encode_entry`, and the encoded entries are
+/// sorted by their bytes, so the output does not depend on iteration order.
+pub fn write_entries<I, F>(buf: &mut Vec<u8>, entries: I, mut encode_entry: F)
+where I: ExactSizeIterator, F: FnMut(I::Item, &mut Vec<u8>) {
+    write_varint(buf, entries.len() as u64);
+    let mut scratch = Vec::new();
+    let mut ranges = Vec::with_capacity(entries.len());
+    for entry in entries {
+        let start = scratch.len();
+        encode_entry(entry, &mut scratch);
+        ranges.push((start, scratch.len()));
+    }
+    ranges.sort_by(|&(a_start, a_end), &(b_start, b_end)| {
+        scratch[a_start..a_end].cmp(&scratch[b_start..b_end])
+    });
+    for (start, end) in ranges {
+        buf.extend_from_slice(&scratch[start..end]);
+    }
+}
+
+/// Reads a length-prefixed sequence of map entries written by `write_entries`
+/// into a map store, rejecting duplicate keys.
+pub fn read_map<K, V, M, F>(reader: &mut Reader, mut decode_entry: F) -> Result<M, DecodeError>
+where M: MapStore<K, V>, F: FnMut(&mut Reader) -> Result<(K, V), DecodeError> {
+    let len = try!(reader.read_len());
+    let mut map = M::default();
+    for _ in 0..len {
+        let (key, value) = try!(decode_entry(reader));
+        if map.insert(key, value).is_some() {
+            return Err(DecodeError::InvalidValue("duplicate entry"));
+        }
+    }
+    Ok(map)
+}
+
+fn zigzag(value: i64) -> u64 {
+    ((value << 1) ^ (value >> 63)) as u64
+}
+
+fn unzigzag(value: u64) -> i64 {
+    ((value >> 1) as i64) ^ -((value & 1) as i64)
+}
+
+macro_rules! unsigned {
+    ($ty:ty) => {
+        impl Encode for $ty {
+            fn encode(&self, buf: &mut Vec<u8>) {
+                write_varint(buf, *self as u64);
+            }
+        }
+
+        impl Decode for $ty {
+            fn decode(reader: &mut Reader) -> Result<$ty, DecodeError> {
+                let value = try!(reader.read_varint());
+                if value > <$ty>::max_value() as u64 {
+                    return Err(DecodeError::Overflow);
+                }
+                Ok(value as $ty)
+            }
+        }
+    }
+}
+
+macro_rules! signed {
+    ($ty:ty) => {
+        impl Encode for $ty {
+            fn encode(&self, buf: &mut Vec<u8>) {
+                write_varint(buf, zigzag(*self as i64));
+            }
+        }
+
+        impl Decode for $ty {
+            fn decode(reader: &mut Reader) -> Result<$ty, DecodeError> {
+                let value = unzigzag(try!(reader.read_varint()));
+                if value < <$ty>::min_value() as i64 || value > <$ty>::max_value() as i64 {
+                    return Err(DecodeError::Overflow);
+                }
+                Ok(value as $ty)
+            }
+        }
+    }
+}
+
+unsigned!(u16);
+unsigned!(u32);
+unsigned!(u64);
+unsigned!(usize);
+signed!(i8);
+signed!(i16);
+signed!(i32);
+signed!(i64);
+signed!(isize);
+
+impl Encode for u8 {
+    fn encode(&self, buf: &mut Vec<u8>) {
+        buf.push(*self);
+    }
+}
+
+impl Decode for u8 {
+    fn decode(reader: &mut Reader) -> Result<u8, DecodeError> {
+        reader.read_u8()
+    }
+}
+
+impl Encode for bool {
+    fn encode(&self, buf: &mut Vec<u8>) {
+        buf.push(*self as u8);
+    }
+}
+
+impl Decode for bool {
+    fn decode(reader: &mut Reader) -> Result<bool, DecodeError> {
+        match try!(reader.read_u8()) {
+            0 => Ok(false),
+            1 => Ok(true),
+            _ => Err(DecodeError::InvalidValue("invalid boolean")),
+        }
+    }
+}
+
+impl Encode for String {
+    fn encode(&self, buf: &mut Vec<u8>) {
+        write_varint(buf, self.len() as u64);
+        buf.extend_from_slice(self.as_bytes());
+    }
+}
+
+impl Decode for String {
+    fn decode(reader: &mut Reader) -> Result<String, DecodeError> {
+        let len = try!(reader.read_len());
+        let bytes = try!(reader.read_bytes(len));
+        str::from_utf8(bytes).map(|s| s.to_string())
+                             .map_err(|_| DecodeError::InvalidValue("invalid UTF-8"))
+    }
+}
+
+impl <T> Encode for Vec<T> where T: Encode {
+    fn encode(&self, buf: &mut Vec<u8>) {
+        write_varint(buf, self.len() as u64);
+        for element in self.iter() {
+            element.encode(buf);
+        }
+    }
+}
+
+impl <T> Decode for Vec<T> where T: Decode {
+    fn decode(reader: &mut Reader) -> Result<Vec<T>, DecodeError> {
+        let len = try!(reader.read_len());
+        let mut vec = Vec::with_capacity(len);
+        for _ in 0..len {
+            vec.push(try!(T::decode(reader)));
+        }
+        Ok(vec)
+    }
+}
+
+impl <T> Encode for Option<T> where T: Encode {
+    fn encode(&self, buf: &mut Vec<u8>) {
+        match *self {
+            None => buf.push(0),
+            Some(ref value) => { buf.push(1); value.encode(buf); },
+        }
+    }
+}
+
+impl <T> Decode for Option<T> where T: Decode {
+    fn decode(reader: &mut Reader) -> Result<Option<T>, DecodeError> {
+        match try!(reader.read_u8()) {
+            0 => Ok(None),
+            1 => Ok(Some(try!(T::decode(reader)))),
+            _ => Err(DecodeError::InvalidValue("invalid option")),
+        }
+    }
+}
+
+impl Encode for ReplicaId {
+    fn encode(&self, buf: &mut Vec<u8>) {
+        write_varint(buf, self.id());
+    }
+}
+
+impl Decode for ReplicaId {
+    fn decode(reader: &mut Reader) -> Result<ReplicaId, DecodeError> {
+        reader.read_varint().map(ReplicaId::from)
+    }
+}
+
+impl Encode for TransactionId {
+    fn encode(&self, buf: &mut Vec<u8>) {
+        write_varint(buf, self.id());
+    }
+}
+
+impl Decode for TransactionId {
+    fn decode(reader: &mut Reader) -> Result<TransactionId, DecodeError> {
+        reader.read_varint().map(TransactionId::from)
+    }
+}
+
+#[cfg(test)]
+mod test {
+
+    use std::{i64, u64};
+    use std::fs::File;
+    use std::io::Read;
+    use std::path::Path;
+
+    use Crdt;
+    use counter::{GCounter, GCounterOp, PnCounter, PnCounterOp};
+    use register::LwwRegister;
+    use set::{GSet, GSetOp, LwwSet, LwwSetOp, OrdTpSet, PnSet, PnSetOp, TpSet, TpSetOp};
+    use super::{from_bytes, to_bytes, Decode, DecodeError, Message, Migrations, Reader, VERSION};
+    use super::{unzigzag, write_varint, zigzag};
+
+    fn round_trip<M>(message: &M) -> bool where M: Message {
+        let bytes = to_bytes(message);
+        match from_bytes::<M>(&bytes) {
+            Ok(decoded) => to_bytes(&decoded) == bytes,
+            Err(_) => false,
+        }
+    }
+
+    fn truncations_fail<M>(message: &M) -> bool where M: Message {
+        let bytes = to_bytes(message);
+        (0..bytes.len()).all(|len| from_bytes::<M>(&bytes[..len]).is_err())
+    }
+
+    fn varint_round_trip(value: u64) -> bool {
+        let mut buf = Vec::new();
+        write_varint(&mut buf, value);
+        Reader::new(&buf).read_varint() == Ok(value)
+    }
+
+    #[quickcheck]
+    fn check_varint_round_trip(value: u64) -> bool {
+        varint_round_trip(value)
+    }
+
+    #[quickcheck]
+    fn check_zigzag_round_trip(value: i64) -> bool {
+        unzigzag(zigzag(value)) == value
+    }
+
+    #[test]
+    fn check_varint_edge_cases() {
+        for &value in [0, 1, 127, 128, 16383, 16384, u64::MAX].iter() {
+            assert!(varint_round_trip(value));
+        }
+        assert_eq!(i64::MIN, unzigzag(zigzag(i64::MIN)));
+        assert_eq!(i64::MAX, unzigzag(zigzag(i64::MAX)));
+        assert_eq!(Err(DecodeError::Overflow), Reader::new(&[0xff; 10]).read_varint());
+        assert_eq!(Err(DecodeError::Overflow), Reader::new(&[0xff; 11]).read_varint());
+        assert_eq!(Err(DecodeError::UnexpectedEof), Reader::new(&[0x80]).read_varint());
+    }
+
+    #[quickcheck]
+    fn check_counters(a: GCounter, b: GCounterOp, c: PnCounter, d: PnCounterOp) -> bool {
+        round_trip(&a) && round_trip(&b) && round_trip(&c) && round_trip(&d)
+            && truncations_fail(&a) && truncations_fail(&d)
+    }
+
+    #[quickcheck]
+    fn check_register(a: LwwRegister<String>) -> bool {
+        round_trip(&a) && truncations_fail(&a)
+    }
+
+    #[quickcheck]
+    fn check_sets(a: GSet<u32>, b: GSetOp<String>, c: TpSet<i16>, d: TpSetOp<u8>,
+                  e: LwwSet<u64>, f: LwwSetOp<i64>, g: PnSet<u16>, h: PnSetOp<u32>) -> bool {
+        round_trip(&a) && round_trip(&b) && round_trip(&c) && round_trip(&d)
+            && round_trip(&e) && round_trip(&f) && round_trip(&g) && round_trip(&h)
+            && truncations_fail(&e) && truncations_fail(&g)
+    }
+
+    #[quickcheck]
+    fn check_arbitrary_input_does_not_panic(bytes: Vec<u8>) -> bool {
+        let _ = from_bytes::<GCounter>(&bytes);
+        let _ = from_bytes::<PnSet<String>>(&bytes);
+        let _ = from_bytes::<LwwSetOp<u32>>(&bytes);
+        let mut tagged = vec![VERSION, <TpSet<i32> as Message>::TAG];
+        tagged.extend(bytes);
+        let _ = from_bytes::<TpSet<i32>>(&tagged);
+        true
+    }
+
+    #[test]
+    fn check_header() {
+        let bytes = to_bytes(&GCounter::new(1));
+        assert_eq!(Err(DecodeError::UnexpectedTag { expected: <PnCounter as Message>::TAG,
+                                                    found: <GCounter as Message>::TAG }),
+                   from_bytes::<PnCounter>(&bytes));
+
+        let mut unsupported = bytes.clone();
+        unsupported[0] = VERSION + 1;
+        assert_eq!(Err(DecodeError::UnsupportedVersion(VERSION + 1)),
+                   from_bytes::<GCounter>(&unsupported));
+
+        let mut trailing = bytes.clone();
+        trailing.push(0);
+        assert_eq!(Err(DecodeError::TrailingBytes(1)), from_bytes::<GCounter>(&trailing));
+    }
+
+    #[test]
+    fn check_schema() {
+        let mut set = LwwSet::new();
+        set.insert(1u32, 1);
+        let mut bytes = to_bytes(&set);
+        assert_eq!(<LwwSet<u32> as Message>::SCHEMA as u8, bytes[2]);
+
+        bytes[2] = 4;
+        assert_eq!(Err(DecodeError::UnsupportedSchema(4)), from_bytes::<LwwSet<u32>>(&bytes));
+
+        fn upgrade(reader: &mut Reader) -> Result<LwwSet<u32>, DecodeError> {
+            let mut set = LwwSet::new();
+            set.insert(try!(u32::decode(reader)), 1);
+            Ok(set)
+        }
+        let migrations = Migrations::new().register(4, upgrade);
+        assert_eq!(Ok(set), migrations.from_bytes(&[VERSION, bytes[1], 4, 1]));
+    }
+
+    #[test]
+    fn check_lwwset_upgrade() {
+        let mut set = LwwSet::new();
+        set.insert(1u32, 1);
+        set.insert(2, 2);
+        set.remove(3, 3);
+        // The schema 1 encoding, with a presence flag and a timestamp per entry.
+        let bytes = [1, 10, 3, 1, 1, 1, 2, 1, 2, 3, 0, 3];
+        assert_eq!(Ok(set), from_bytes(&bytes));
+
+        let present_and_removed = [VERSION, 10, 2, 1, 1, 1, 1, 1, 2];
+        assert_eq!(Err(DecodeError::InvalidValue("element is both present and removed")),
+                   from_bytes::<LwwSet<u32>>(&present_and_removed));
+    }
+
+    #[test]
+    fn check_tombstone_purge_upgrade() {
+        // The encodings which predate tombstone garbage collection.
+        let mut tpset = TpSet::new();
+        tpset.insert(1u32);
+        tpset.insert(2);
+        tpset.remove(2);
+        let bytes = [VERSION, 8, 1, 2, 1, 1, 2, 0];
+        assert_eq!(Ok(tpset), from_bytes(&bytes));
+
+        let mut lwwset = LwwSet::new();
+        lwwset.insert("b".to_string(), 2);
+        lwwset.remove("a".to_string(), 3);
+        lwwset.remove("c".to_string(), 400);
+        let bytes = [VERSION, 10, 2, 1, 1, 98, 2, 2, 1, 97, 3, 1, 99, 144, 3];
+        assert_eq!(Ok(lwwset), from_bytes(&bytes));
+
+        let purged_tombstone = [VERSION, 8, 2, 1, 1, 0, 1, 1];
+        assert_eq!(Err(DecodeError::InvalidValue("tombstone at or below the frontier")),
+                   from_bytes::<OrdTpSet<u32>>(&purged_tombstone));
+        let unordered_frontier = [VERSION, 8, 2, 1, 1, 1, 1, 1];
+        assert_eq!(Err(DecodeError::InvalidValue("frontier in an unordered set")),
+                   from_bytes::<TpSet<u32>>(&unordered_frontier));
+        let purged_tombstone = [VERSION, 10, 3, 0, 1, 1, 3, 1, 3];
+        assert_eq!(Err(DecodeError::InvalidValue("tombstone at or before the frontier")),
+                   from_bytes::<LwwSet<u32>>(&purged_tombstone));
+    }
+
+    #[test]
+    fn check_retirement_upgrade() {
+        // The schema 1 encodings, which predate replica retirement.
+        let mut gcounter = GCounter::new(1);
+        gcounter.increment(5);
+        gcounter.apply(GCounter::new(1000).increment(300));
+        let bytes = [VERSION, 1, 1, 1, 2, 1, 5, 232, 7, 172, 2];
+        assert_eq!(to_bytes(&gcounter), to_bytes(&from_bytes::<GCounter>(&bytes).unwrap()));
+
+        let mut pncounter = PnCounter::new(1);
+        pncounter.increment(-7);
+        let mut other = PnCounter::new(2);
+        other.increment(200);
+        other.increment(-3);
+        pncounter.merge(other);
+        let bytes = [VERSION, 3, 1, 1, 2, 1, 0, 7, 2, 200, 1, 3];
+        assert_eq!(to_bytes(&pncounter), to_bytes(&from_bytes::<PnCounter>(&bytes).unwrap()));
+
+        let mut pnset = PnSet::new(1);
+        pnset.insert(1u32);
+        pnset.insert(2);
+        pnset.remove(1);
+        pnset.insert(1);
+        let bytes = [VERSION, 12, 1, 1, 2, 1, 1, 1, 2, 1, 2, 1, 1, 1, 0];
+        assert_eq!(to_bytes(&pnset), to_bytes(&from_bytes::<PnSet<u32>>(&bytes).unwrap()));
+    }
+
+    fn golden(file: &str) -> Vec<u8> {
+        let path = format!("{}/tests/golden/{}", env!("CARGO_MANIFEST_DIR"), file);
+        let mut bytes = Vec::new();
+        File::open(&path).and_then(|mut file| file.read_to_end(&mut bytes)).expect(&path);
+        bytes
+    }
+
+    /// Checks that the golden files for every format version and schema
+    /// decode to the message, and that the message encodes to the golden file
+    /// for the current version and schema.
+    ///
+    /// `v<version>/<name>.bin` holds the encoding first written in a format
+    /// version, and `v<version>/<name>.schema<schema>.bin` the encoding of
+    /// each later schema of the type. Golden files are never rewritten: a
+    /// change to an encoding adds a file for the new schema.
+    fn check_golden<M>(name: &str, message: &M) where M: Message {
+        let mut files: Vec<String> = (1..VERSION + 1).map(|version| format!("v{}/{}.bin", version, name)).collect();
+        for schema in 2..M::SCHEMA + 1 {
+            let file = format!("v{}/{}.schema{}.bin", VERSION, name, schema);
+            if Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/golden").join(&file).exists() {
+                files.push(file);
+            }
+        }
+        let current = golden(files.last().unwrap());
+        assert_eq!(current, to_bytes(message), "encoding of {} changed", name);
+        for file in &files {
+            let decoded = from_bytes::<M>(&golden(file)).unwrap_or_else(|error| panic!("{}: {}", file, error));
+            assert_eq!(current, to_bytes(&decoded), "{} decoded incorrectly", file);
+        }
+    }
+
+    #[test]
+    fn check_golden_files() {
+        let mut gcounter = GCounter::new(1);
+        gcounter.increment(5);
+        let gcounter_op = GCounter::new(1000).increment(300);
+        gcounter.apply(gcounter_op.clone());
+        check_golden("gcounter", &gcounter);
+        check_golden("gcounter_op", &gcounter_op);
+
+        let mut pncounter = PnCounter::new(1);
+        pncounter.increment(-7);
+        let mut other = PnCounter::new(2);
+        other.increment(200);
+        let pncounter_op = other.increment(-3);
+        pncounter.merge(other);
+        check_golden("pncounter", &pncounter);
+        check_golden("pncounter_op", &pncounter_op);
+
+        check_golden("lwwregister", &LwwRegister::new("hello".to_string(), 99));
+
+        let mut gset = GSet::new();
+        gset.insert(1u32);
+        gset.insert(2);
+        let gset_op = gset.insert(300).unwrap();
+        check_golden("gset", &gset);
+        check_golden("gset_op", &gset_op);
+
+        let mut tpset = TpSet::new();
+        tpset.insert(1u32);
+        tpset.insert(2);
+        let tpset_op = tpset.remove(2).unwrap();
+        check_golden("tpset", &tpset);
+        check_golden("tpset_op", &tpset_op);
+
+        let mut lwwset = LwwSet::new();
+        lwwset.insert("a".to_string(), 1);
+        lwwset.insert("b".to_string(), 2);
+        lwwset.remove("a".to_string(), 3);
+        let lwwset_op = lwwset.remove("c".to_string(), 400).unwrap();
+        check_golden("lwwset", &lwwset);
+        check_golden("lwwset_op", &lwwset_op);
+
+        let mut pnset = PnSet::new(1);
+        pnset.insert(1u32);
+        pnset.insert(2);
+        pnset.remove(1);
+        let pnset_op = pnset.insert(1);
+        check_golden("pnset", &pnset);
+        check_golden("pnset_op", &pnset_op);
+    }
+
+    #[test]
+    fn check_canonical_encoding() {
+        let mut a = GSet::new();
+        let mut b = GSet::new();
+        for i in 0..100u32 {
+            a.insert(i);
+            b.insert(99 - i);
+        }
+        assert_eq!(to_bytes(&a), to_bytes(&b));
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn check_gcounter_is_compact() {
+        use serde_json;
+        use Crdt;
+
+        let mut counter = GCounter::new(0);
+        for replica in 0..5000u64 {
+            let mut replica = GCounter::new(replica);
+            replica.increment(replica.replica_id().id() % 1000 + 1);
+            counter.merge(replica);
+        }
+        let json = serde_json::to_vec(&counter).unwrap();
+        let bytes = to_bytes(&counter);
+
+        // Varints take one byte below 128, and two bytes below 16384.
+        let varint_len = |value: u64| if value < 0x80 { 1 } else { 2 };
+        let header = 3;
+        let replica_id = 1;
+        let entries = varint_len(5000) + (0..5000u64).map(|id| varint_len(id) + varint_len(id % 1000 + 1)).sum::<usize>();
+        let retirements = 2;
+        assert_eq!(19245, header + replica_id + entries + retirements);
+        assert_eq!(header + replica_id + entries + retirements, bytes.len());
+        assert!(bytes.len() * 2 < json.len(), "{} bytes vs {} bytes of JSON", bytes.len(), json.len());
+    }
+}
@@ -0,0 +1,175 @@
+#![cfg(feature = "net")]
+
+extern crate crdt;
+extern crate tempdir;
+
+use std::io::{self, Cursor, Read, Write};
+use std::net::TcpStream;
+use std::sync::Arc;
+use std::thread;
+
+use crdt::counter::GCounter;
+use crdt::net::{self, Error, Server};
+use crdt::set::{GSet, LwwSet};
+use tempdir::TempDir;
+
+/// Returns the edits made by one of two replicas: it inserts its own
+/// elements, and removes every third element of the other replica.
+fn edits(i: u32) -> Vec<(bool, u32, u64)> {
+    let mut edits = Vec::new();
+    for n in 0..100u32 {
+        edits.push((true, n * 2 + i, (n * 10 + i) as u64));
+        if n % 3 == 0 {
+            edits.push((false, n * 2 + 1 - i, (n * 10 + 5 + i) as u64));
+        }
+    }
+    edits
+}
+
+fn apply(set: &mut LwwSet<u32>, (insert, element, tid): (bool, u32, u64)) {
+    if insert {
+        set.insert(element, tid);
+    } else {
+        set.remove(element, tid);
+    }
+}
+
+/// Edits both servers' replicas concurrently while the servers sync with
+/// each other, then checks that they converge after a final sync.
+fn check_concurrent_edits(a: Server<LwwSet<u32>>, b: Server<LwwSet<u32>>) {
+    let (a, b) = (Arc::new(a), Arc::new(b));
+    let threads: Vec<_> = vec![(0, a.clone(), b.clone()), (1, b.clone(), a.clone())].into_iter().map(|(i, local, remote)| {
+        thread::spawn(move || {
+            for (n, edit) in edits(i).into_iter().enumerate() {
+                apply(&mut local.lock(), edit);
+                if n % 10 == 0 {
+                    local.sync_with(remote.address()).unwrap();
+                }
+            }
+        })
+    }).collect();
+    for thread in threads {
+        thread.join().unwrap();
+    }
+
+    assert_eq!(b.replica_id(), a.sync_with(b.address()).unwrap());
+
+    let mut expected = LwwSet::new();
+    for edit in edits(0).into_iter().chain(edits(1)) {
+        apply(&mut expected, edit);
+    }
+    assert_eq!(132, expected.len());
+    assert!(*a.lock() == expected);
+    assert!(*b.lock() == expected);
+}
+
+#[test]
+fn check_tcp_servers_converge() {
+    let a = Server::bind_tcp("127.0.0.1:0", 1, LwwSet::new()).unwrap();
+    let b = Server::bind_tcp("127.0.0.1:0", 2, LwwSet::new()).unwrap();
+    check_concurrent_edits(a, b);
+}
+
+#[test]
+fn check_unix_servers_converge() {
+    let dir = TempDir::new("crdt-net").unwrap();
+    let a = Server::bind_unix(dir.path().join("a.sock"), 1, LwwSet::new()).unwrap();
+    let b = Server::bind_unix(dir.path().join("b.sock"), 2, LwwSet::new()).unwrap();
+    check_concurrent_edits(a, b);
+}
+
+#[test]
+fn check_counters_converge() {
+    let servers: Vec<_> = (1..4u64).map(|id| Server::bind_tcp("127.0.0.1:0", id, GCounter::new(id)).unwrap())
+                                   .collect();
+    let servers = Arc::new(servers);
+    let threads: Vec<_> = (0..3).map(|i| {
+        let servers = servers.clone();
+        thread::spawn(move || {
+            for n in 0..50 {
+                servers[i].lock().increment(1);
+                servers[i].sync_with(servers[(i + 1 + n % 2) % 3].address()).unwrap();
+            }
+        })
+    }).collect();
+    for thread in threads {
+        thread.join().unwrap();
+    }
+
+    servers[0].sync_with(servers[1].address()).unwrap();
+    servers[0].sync_with(servers[2].address()).unwrap();
+    servers[1].sync_with(servers[0].address()).unwrap();
+    for server in servers.iter() {
+        assert_eq!(150, server.lock().count());
+    }
+}
+
+#[test]
+fn check_handshake() {
+    let server = Server::bind_tcp("127.0.0.1:0", 1, GCounter::new(1)).unwrap();
+    server.lock().increment(1);
+
+    // A replica with the same ID is rejected.
+    match net::sync_with(server.address(), 1.into(), &mut GCounter::new(1)) {
+        Err(Error::Handshake(_)) => (),
+        other => panic!("unexpected result: {:?}", other),
+    }
+
+    // A replica of a different type is rejected.
+    match net::sync_with(server.address(), 2.into(), &mut GSet::<u32>::new()) {
+        Err(Error::Handshake(_)) => (),
+        other => panic!("unexpected result: {:?}", other),
+    }
+
+    // The server continues to serve compatible peers.
+    let mut counter = GCounter::new(2);
+    counter.increment(2);
+    assert_eq!(1, net::sync_with(server.address(), 2.into(), &mut counter).unwrap().id());
+    assert_eq!(3, counter.count());
+    assert_eq!(3, server.lock().count());
+}
+
+#[test]
+fn check_stalled_peer() {
+    let a = Server::bind_tcp("127.0.0.1:0", 1, GCounter::new(1)).unwrap();
+    let b = Server::bind_tcp("127.0.0.1:0", 2, GCounter::new(2)).unwrap();
+    b.lock().increment(2);
+
+    // A peer which connects and never sends anything does not delay others.
+    let _stalled = match *b.address() {
+        net::Address::Tcp(address) => TcpStream::connect(address).unwrap(),
+        _ => unreachable!(),
+    };
+    assert_eq!(2, a.sync_with(b.address()).unwrap().id());
+    assert_eq!(2, a.lock().count());
+}
+
+/// A stream which reads from a buffer and discards writes.
+struct Replay(Cursor<Vec<u8>>);
+
+impl Read for Replay {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.0.read(buf)
+    }
+}
+
+impl Write for Replay {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[test]
+fn check_truncated_frame() {
+    // The peer claims a frame of the maximum length, then closes the stream.
+    let len = net::MAX_FRAME_LEN as u32;
+    let mut stream = Replay(Cursor::new(vec![len as u8, (len >> 8) as u8, (len >> 16) as u8, (len >> 24) as u8, 2]));
+    match net::sync(&mut stream, 1.into(), &mut GCounter::new(1)) {
+        Err(Error::Io(ref error)) if error.kind() == io::ErrorKind::UnexpectedEof => (),
+        other => panic!("unexpected result: {:?}", other),
+    }
+}